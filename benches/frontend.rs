@@ -1,34 +1,33 @@
 use criterion::{criterion_group, criterion_main, Criterion};
-use sage::{
-    lir::*,
-    parse::*,
-    targets::{self, CompiledTarget},
-    vm::*,
-};
+use sage::{vm::*, Artifacts, Compiler, Target};
 use std::fs::{read_to_string, write};
 
-const CALL_STACK_SIZE: usize = 8192;
-
 fn read_file(filename: &str) -> String {
     read_to_string(filename).unwrap()
 }
 
-fn parse_frontend_file(filename: &str) -> Expr {
-    parse_frontend(read_file(filename), Some(filename)).unwrap()
+fn compile_frontend_file_to(filename: &str, target: Target) -> Artifacts {
+    Compiler::new(read_file(filename))
+        .filename(filename)
+        .target(target)
+        .compile()
+        .unwrap()
 }
 
 fn compile_frontend_file(filename: &str) -> StandardProgram {
-    match parse_frontend_file(filename).compile().unwrap() {
-        // If we got back a valid program, assemble it and return the result.
-        Ok(asm_code) => asm_code.assemble(CALL_STACK_SIZE).unwrap().into(),
-        Err(asm_code) => asm_code.assemble(CALL_STACK_SIZE).unwrap(),
+    match compile_frontend_file_to(filename, Target::StdVM)
+        .vm
+        .unwrap()
+    {
+        Ok(vm_code) => vm_code.into(),
+        Err(vm_code) => vm_code,
     }
 }
 
 fn compile_to_c(filename: &str) -> String {
-    let program = compile_frontend_file(filename);
-    let c_code = targets::C.build_std(&program).unwrap();
-    return c_code;
+    compile_frontend_file_to(filename, Target::C)
+        .output
+        .unwrap()
 }
 
 fn compile_with_gcc(filename: &str) {
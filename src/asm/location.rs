@@ -578,6 +578,33 @@ impl Location {
         self.std_binop(vm::StandardOp::Pow, src, result)
     }
 
+    /// This cell <<= source cell.
+    pub(crate) fn shift_left(
+        &self,
+        src: &Self,
+        result: &mut dyn VirtualMachineProgram,
+    ) -> Result<(), Error> {
+        self.std_binop(vm::StandardOp::ShiftLeft, src, result)
+    }
+
+    /// This cell >>= source cell, copying the sign bit.
+    pub(crate) fn shift_right(
+        &self,
+        src: &Self,
+        result: &mut dyn VirtualMachineProgram,
+    ) -> Result<(), Error> {
+        self.std_binop(vm::StandardOp::ShiftRight, src, result)
+    }
+
+    /// This cell >>= source cell, filling with zeros.
+    pub(crate) fn logical_shift_right(
+        &self,
+        src: &Self,
+        result: &mut dyn VirtualMachineProgram,
+    ) -> Result<(), Error> {
+        self.std_binop(vm::StandardOp::LogicalShiftRight, src, result)
+    }
+
     /// This cell = a constant value.
     pub(crate) fn set(&self, val: i64, result: &mut dyn VirtualMachineProgram) {
         result.set_register(val);
@@ -29,6 +29,7 @@
 use ::core::fmt::{Display, Formatter, Result as FmtResult};
 use ::std::collections::HashMap;
 
+use crate::parse::SourceCodeLocation;
 use log::{debug, error, trace, warn};
 
 pub mod core;
@@ -37,7 +38,7 @@ pub mod location;
 pub mod std;
 
 pub use self::core::{CoreOp, CoreProgram};
-pub use self::std::{StandardOp, StandardProgram};
+pub use self::std::{StandardAssembler, StandardOp, StandardProgram};
 pub use globals::Globals;
 pub use location::{Location, A, B, C, D, E, F, FP, GP, REGISTERS, SP};
 pub(crate) use location::{FP_STACK, STACK_START, START_OF_FP_STACK, TMP};
@@ -58,6 +59,14 @@ pub trait AssemblyProgram {
     fn comment(&mut self, comment: String) {
         self.op(CoreOp::Comment(comment))
     }
+    /// Start compiling the code for an expression at a location in the source code.
+    /// Programs which record source locations write the location in a comment.
+    fn enter_location(&mut self, _loc: &SourceCodeLocation) {}
+    /// Finish compiling the code for the expression at the last location entered.
+    fn exit_location(&mut self) {}
+    /// Name the function whose code is being compiled, after its `Fn` operation.
+    /// Programs which record source locations write the names in a comment.
+    fn name_function(&mut self, _mangled_name: &str, _common_name: Option<&str>) {}
     /// Is the given label defined yet in the operations?
     /// I.E., has a `CoreOp::Fn` with this label been inserted
     /// into the program code yet?
@@ -174,6 +183,8 @@ pub enum Error {
     Unmatched(CoreOp, usize),
     /// The given instruction was not expected, or cannot be used in this context.
     Unexpected(CoreOp, usize),
+    /// The globals need more than the given number of cells reserved for them.
+    TooManyGlobals(usize),
 }
 
 impl From<crate::vm::Error> for Error {
@@ -193,6 +204,13 @@ impl Display for Error {
             Self::UndefinedGlobal(name) => write!(f, "Undefined global {}", name),
             Self::Unmatched(op, i) => write!(f, "Unmatched {} at instruction #{}", op, i),
             Self::Unexpected(op, i) => write!(f, "Unexpected {} at instruction #{}", op, i),
+            Self::TooManyGlobals(size) => {
+                write!(
+                    f,
+                    "Globals need more than the {} cells reserved for them",
+                    size
+                )
+            }
         }
     }
 }
@@ -48,6 +48,15 @@ impl StandardProgram {
         Self { code, labels }
     }
 
+    /// Remove the instructions after the first `len`, along with the labels they define.
+    pub fn truncate(&mut self, len: usize) {
+        for op in self.code.drain(len.min(self.code.len())..) {
+            if let StandardOp::CoreOp(CoreOp::Fn(label)) = op {
+                self.labels.remove(&label);
+            }
+        }
+    }
+
     /// Get the size of the globals.
     fn get_size_of_globals(&self, env: &mut Env) -> Result<usize, Error> {
         for op in &self.code {
@@ -73,30 +82,7 @@ impl StandardProgram {
         // Get the size of the globals
         let size_of_globals = self.get_size_of_globals(&mut env)?;
 
-        // Create the stack of frame pointers starting directly after the last register
-        // let start_of_fp_stack = F.offset(1);
-        START_OF_FP_STACK.copy_address_to(&FP_STACK, &mut result);
-        info!(
-            "Frame pointer stack begins at {FP_STACK:?}, and is {} cells long.",
-            allowed_recursion_depth
-        );
-        let end_of_fp_stack = START_OF_FP_STACK.offset(allowed_recursion_depth as isize);
-
-        // Copy the address just after the allocated space to the global pointer.
-        let starting_gp_addr = end_of_fp_stack;
-        starting_gp_addr.copy_address_to(&GP, &mut result);
-        info!(
-            "Global pointer is initialized to point to {starting_gp_addr:?}, and is {} cells long.",
-            size_of_globals
-        );
-
-        // Allocate the global variables
-        let starting_sp_addr = starting_gp_addr.offset(size_of_globals as isize);
-        info!("Stack pointer is initialized to point to {starting_sp_addr:?}.");
-        starting_sp_addr.copy_address_to(&SP, &mut result);
-        starting_sp_addr.copy_address_to(&STACK_START, &mut result);
-
-        SP.copy_to(&FP, &mut result);
+        assemble_prologue(allowed_recursion_depth, size_of_globals, &mut result);
         for (i, op) in self.code.iter().enumerate() {
             op.assemble(i, &mut env, &mut result)?
         }
@@ -109,6 +95,102 @@ impl StandardProgram {
     }
 }
 
+/// Set up the registers before a program is run: the frame pointer stack, the
+/// global pointer, and the stack pointer, which starts after the globals.
+fn assemble_prologue(
+    allowed_recursion_depth: usize,
+    size_of_globals: usize,
+    result: &mut dyn VirtualMachineProgram,
+) {
+    // Create the stack of frame pointers starting directly after the last register
+    // let start_of_fp_stack = F.offset(1);
+    START_OF_FP_STACK.copy_address_to(&FP_STACK, result);
+    info!(
+        "Frame pointer stack begins at {FP_STACK:?}, and is {} cells long.",
+        allowed_recursion_depth
+    );
+    let end_of_fp_stack = START_OF_FP_STACK.offset(allowed_recursion_depth as isize);
+
+    // Copy the address just after the allocated space to the global pointer.
+    let starting_gp_addr = end_of_fp_stack;
+    starting_gp_addr.copy_address_to(&GP, result);
+    info!(
+        "Global pointer is initialized to point to {starting_gp_addr:?}, and is {} cells long.",
+        size_of_globals
+    );
+
+    // Allocate the global variables
+    let starting_sp_addr = starting_gp_addr.offset(size_of_globals as isize);
+    info!("Stack pointer is initialized to point to {starting_sp_addr:?}.");
+    starting_sp_addr.copy_address_to(&SP, result);
+    starting_sp_addr.copy_address_to(&STACK_START, result);
+
+    SP.copy_to(&FP, result);
+}
+
+/// Assembles a standard program one part at a time, for a program which is run
+/// while it's still being written, like the lines entered into the REPL.
+///
+/// The virtual machine code of each part goes after the code of the parts before it,
+/// and it can call their functions and use their globals. Since the stack starts
+/// right after the globals, a fixed number of cells is reserved for the globals
+/// of every part.
+#[derive(Clone)]
+pub struct StandardAssembler {
+    /// The labels and globals declared by the parts assembled so far.
+    env: Env,
+    /// The number of cells reserved for the globals.
+    size_of_globals: usize,
+}
+
+impl StandardAssembler {
+    /// Create an assembler, along with the virtual machine code which sets up
+    /// the registers before the first part is run.
+    pub fn new(
+        allowed_recursion_depth: usize,
+        size_of_globals: usize,
+    ) -> (Self, vm::StandardProgram) {
+        let mut prologue = vm::StandardProgram(vec![]);
+        assemble_prologue(allowed_recursion_depth, size_of_globals, &mut prologue);
+        let assembler = Self {
+            env: Env::default(),
+            size_of_globals,
+        };
+        (assembler, prologue)
+    }
+
+    /// Assemble the instructions of a program after the first `start`, which haven't
+    /// been assembled yet. If they can't be assembled, nothing they declare is kept.
+    pub fn assemble(
+        &mut self,
+        program: &StandardProgram,
+        start: usize,
+    ) -> Result<vm::StandardProgram, Error> {
+        let mut result = vm::StandardProgram(vec![]);
+        let mut env = self.env.clone();
+
+        let part = &program.code[start..];
+        for op in part {
+            if let StandardOp::CoreOp(CoreOp::Global { name, size }) = op {
+                env.declare_global(name, *size);
+            }
+        }
+        if env.get_size_of_globals() > self.size_of_globals {
+            return Err(Error::TooManyGlobals(self.size_of_globals));
+        }
+
+        for (i, op) in part.iter().enumerate() {
+            op.assemble(start + i, &mut env, &mut result)?
+        }
+        if let Ok((unmatched, last_instruction)) = env.pop_matching(program.code.len()) {
+            return Err(Error::Unmatched(unmatched, last_instruction));
+        }
+
+        self.env = env;
+        Ok(result.flatten())
+    }
+}
+
 impl fmt::Display for StandardProgram {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let mut indent = 0;
@@ -225,6 +307,30 @@ pub enum StandardOp {
     /// Take the square root of a cell (float).
     Sqrt(Location),
 
+    /// Shift the destination cell (integer) left by the number of bits in the source cell.
+    ShiftLeft {
+        /// The source cell.
+        src: Location,
+        /// The destination cell.
+        dst: Location,
+    },
+    /// Shift the destination cell (integer) right by the number of bits in the source cell,
+    /// copying the sign bit into the vacated bits.
+    ShiftRight {
+        /// The source cell.
+        src: Location,
+        /// The destination cell.
+        dst: Location,
+    },
+    /// Shift the destination cell (integer) right by the number of bits in the source cell,
+    /// filling the vacated bits with zeros.
+    LogicalShiftRight {
+        /// The source cell.
+        src: Location,
+        /// The destination cell.
+        dst: Location,
+    },
+
     /// Add the source cell (float) to the destination cell (float).
     Add {
         /// The source cell.
@@ -405,6 +511,22 @@ impl StandardOp {
                 }
             }
 
+            Self::ShiftLeft { src, dst } => {
+                if dst.shift_left(src, result).is_err() {
+                    unsupported(self.clone())?
+                }
+            }
+            Self::ShiftRight { src, dst } => {
+                if dst.shift_right(src, result).is_err() {
+                    unsupported(self.clone())?
+                }
+            }
+            Self::LogicalShiftRight { src, dst } => {
+                if dst.logical_shift_right(src, result).is_err() {
+                    unsupported(self.clone())?
+                }
+            }
+
             Self::IsLess { a, b, dst } => {
                 if a.is_less_than_float(b, dst, result).is_err() {
                     unsupported(self.clone())?
@@ -500,6 +622,9 @@ impl fmt::Display for StandardOp {
             Self::ToInt(loc) => write!(f, "to-int {loc}"),
 
             Self::Pow { src, dst } => write!(f, "pow {src}, {dst}"),
+            Self::ShiftLeft { src, dst } => write!(f, "shl {src}, {dst}"),
+            Self::ShiftRight { src, dst } => write!(f, "shr {src}, {dst}"),
+            Self::LogicalShiftRight { src, dst } => write!(f, "lshr {src}, {dst}"),
             Self::Sqrt(loc) => write!(f, "sqrt {loc}"),
 
             Self::Add { src, dst } => write!(f, "add-f {src}, {dst}"),
@@ -5,22 +5,27 @@
 //! the supported targets provided by the compiler.
 use clap::*;
 use sage::{
+    compiler::{self, with_stack_size, Artifacts, Compiler, DEFAULT_STACK_SIZE_MB},
     lir::*,
     parse::*,
-    targets::{self, CompiledTarget},
     vm::*,
     LOGO_WITH_COLOR, *,
 };
 use std::{
+    collections::HashMap,
     fmt,
     fs::{read_to_string, write},
 };
 
+use codespan_reporting::{
+    diagnostic::{Diagnostic, Label, Severity},
+    files::SimpleFiles,
+    term::termcolor::{ColorChoice, StandardStream},
+};
 use log::error;
 
-// The stack sizes of the threads used to compile the code.
-const RELEASE_STACK_SIZE_MB: usize = 512;
-const DEBUG_STACK_SIZE_MB: usize = RELEASE_STACK_SIZE_MB;
+mod debug;
+mod repl;
 
 #[derive(clap::ValueEnum, Default, Clone, Debug, PartialEq)]
 enum LogLevel {
@@ -40,7 +45,7 @@ enum LogLevel {
 }
 
 /// The target options to compile the given source code to.
-#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
 enum TargetType {
     /// Execute the source code in the interpreter.
     Run,
@@ -60,8 +65,39 @@ enum TargetType {
     X86,
 }
 
+/// The stages of the compiler which can be written to files with `--emit`.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum Emit {
+    /// The LIR code, after the source code is parsed and desugared.
+    Lir,
+    /// The core variant of the assembly language.
+    CoreASM,
+    /// The standard variant of the assembly language.
+    StdASM,
+    /// The virtual machine code, of whichever variant the program compiles to.
+    VM,
+    /// C source code (GCC only).
+    C,
+    /// x86 assembly code.
+    X86,
+}
+
+impl Emit {
+    /// The extension of the file the stage is written to.
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Lir => "lir.sg",
+            Self::CoreASM => "core.asm.sg",
+            Self::StdASM => "std.asm.sg",
+            Self::VM => "vm.sg",
+            Self::C => "c",
+            Self::X86 => "s",
+        }
+    }
+}
+
 /// The source language options to compile.
-#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
 enum SourceType {
     /// Compile Sage Frontend code.
     Sage,
@@ -77,13 +113,41 @@ enum SourceType {
     StdVM,
 }
 
+impl From<SourceType> for compiler::SourceType {
+    fn from(src_type: SourceType) -> Self {
+        match src_type {
+            SourceType::Sage => Self::Sage,
+            SourceType::LowIR => Self::LowIR,
+            SourceType::CoreASM => Self::CoreASM,
+            SourceType::StdASM => Self::StdASM,
+            SourceType::CoreVM => Self::CoreVM,
+            SourceType::StdVM => Self::StdVM,
+        }
+    }
+}
+
+/// The formats to print diagnostics in.
+#[derive(clap::ValueEnum, Default, Clone, Copy, Debug, PartialEq)]
+enum MessageFormat {
+    /// Print the diagnostics along with the source code they point to.
+    #[default]
+    Human,
+    /// Print each diagnostic as a JSON object on its own line.
+    Json,
+}
+
 /// The argument parser for the CLI.
 #[derive(Parser, Debug)]
 #[clap(author, version, about = Some(LOGO_WITH_COLOR), long_about = Some(LOGO_WITH_COLOR), max_term_width=90)]
+#[clap(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
+    /// The tool to run instead of the compiler.
+    #[clap(subcommand)]
+    command: Option<Command>,
+
     /// The input file to compiler.
-    #[clap(value_parser)]
-    input: String,
+    #[clap(value_parser, required = true)]
+    input: Option<String>,
 
     /// The file to write the output of the compiler to.
     #[clap(short, long, value_parser, default_value = "out")]
@@ -97,10 +161,32 @@ struct Args {
     #[clap(short, value_parser, default_value = "run")]
     target_type: TargetType,
 
+    /// The stages of the compiler to write out, instead of compiling to the target.
+    /// Each stage is written to the output file with its own extension.
+    #[clap(
+        long,
+        value_parser,
+        value_delimiter = ',',
+        conflicts_with = "target_type"
+    )]
+    emit: Vec<Emit>,
+
     /// The number of cells allocated for the call stack.
     #[clap(short, long, value_parser, default_value = "8192")]
     call_stack_size: usize,
 
+    /// Run the program in the interpreter's memcheck mode, and report its
+    /// use-after-free, out-of-bounds, and uninitialized memory accesses and leaks.
+    #[clap(long, conflicts_with = "emit")]
+    memcheck: bool,
+
+    /// Run the program in the interpreter's profiling mode. A summary of the
+    /// instructions it ran by opcode and by function is printed, and its call
+    /// stacks are written to the output file in the folded format read by
+    /// flamegraph tools.
+    #[clap(long, conflicts_with_all = ["emit", "memcheck"])]
+    profile: bool,
+
     /// The log level to use.
     #[clap(short, long, value_parser, default_value = "off")]
     log_level: LogLevel,
@@ -109,6 +195,190 @@ struct Args {
     /// also enable debug logging.
     #[clap(short, long, value_parser)]
     debug: Option<String>,
+
+    /// The format to print errors and warnings in.
+    #[clap(long, value_parser, default_value = "human")]
+    message_format: MessageFormat,
+
+    /// Treat all warnings as errors.
+    #[clap(long)]
+    deny_warnings: bool,
+
+    /// Don't report the given kind of warning.
+    #[clap(long, value_name = "WARNING", value_parser = builder::PossibleValuesParser::new(Warning::NAMES))]
+    allow: Vec<String>,
+
+    /// Treat the given kind of warning as an error.
+    #[clap(long, value_name = "WARNING", value_parser = builder::PossibleValuesParser::new(Warning::NAMES))]
+    deny: Vec<String>,
+}
+
+/// The tools the CLI provides besides the compiler.
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Format Sage source files in place.
+    Fmt {
+        /// The files to format.
+        #[clap(value_parser, required = true)]
+        files: Vec<String>,
+
+        /// Don't write the files, but fail if any of them aren't formatted.
+        #[clap(long)]
+        check: bool,
+    },
+    /// Start an interactive session for Sage code.
+    Repl,
+    /// Run a program in an interactive debugger.
+    Debug {
+        /// The file to debug.
+        #[clap(value_parser)]
+        file: String,
+
+        /// The source language of the file.
+        #[clap(short, value_parser, default_value = "sage")]
+        source_type: SourceType,
+    },
+}
+
+/// How a kind of warning is reported.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum WarningLevel {
+    /// Don't report the warning.
+    Allow,
+    /// Report the warning, but continue compiling.
+    Warn,
+    /// Report the warning as an error, and stop compiling.
+    Deny,
+}
+
+/// The options for reporting the errors and warnings found by the compiler.
+#[derive(Clone, Debug, Default)]
+struct DiagnosticOptions {
+    /// The format to print the diagnostics in.
+    format: MessageFormat,
+    /// Treat all warnings as errors, unless they're explicitly allowed.
+    deny_all: bool,
+    /// The kinds of warnings not to report.
+    allow: Vec<String>,
+    /// The kinds of warnings to treat as errors.
+    deny: Vec<String>,
+}
+
+impl DiagnosticOptions {
+    /// Get the level to report a given warning at.
+    /// Denying a kind of warning takes precedence over allowing it.
+    fn level(&self, warning: &Warning) -> WarningLevel {
+        let name = warning.name();
+        if self.deny.iter().any(|denied| denied == name) {
+            WarningLevel::Deny
+        } else if self.allow.iter().any(|allowed| allowed == name) {
+            WarningLevel::Allow
+        } else if self.deny_all {
+            WarningLevel::Deny
+        } else {
+            WarningLevel::Warn
+        }
+    }
+
+    /// Report the warnings found while compiling the given source code.
+    /// If any of the warnings are denied, this returns an error.
+    fn report(&self, warnings: &[Warning], code: &str) -> Result<(), Error> {
+        let mut denied = 0;
+        for warning in warnings {
+            let severity = match self.level(warning) {
+                WarningLevel::Allow => continue,
+                WarningLevel::Warn => Severity::Warning,
+                WarningLevel::Deny => {
+                    denied += 1;
+                    Severity::Error
+                }
+            };
+            if self.format == MessageFormat::Json {
+                let severity = match severity {
+                    Severity::Warning => "warning",
+                    _ => "error",
+                };
+                let diagnostic = json_diagnostic(
+                    severity,
+                    Some(warning.code()),
+                    warning.to_string(),
+                    warning.location(),
+                    &[],
+                    &[],
+                );
+                eprintln!("{diagnostic}");
+                continue;
+            }
+
+            let message = format!("{warning} [{}]", warning.name());
+            match warning.location() {
+                Some(loc) => {
+                    emit_diagnostic(severity, loc, &read_source(loc, code), message, &[], &[])
+                }
+                None => emit(
+                    Diagnostic::new(severity).with_message(message),
+                    &SimpleFiles::<String, String>::new(),
+                ),
+            }
+        }
+        if denied > 0 {
+            return Err(Error::DeniedWarnings(denied));
+        }
+        Ok(())
+    }
+
+    /// Report an error which stopped the compilation.
+    fn report_error(&self, err: &Error) {
+        match self.format {
+            MessageFormat::Human => error!("{err:#?}"),
+            MessageFormat::Json => {
+                for diagnostic in err.to_json(None) {
+                    eprintln!("{diagnostic}");
+                }
+            }
+        }
+    }
+}
+
+/// Create a JSON diagnostic with a message about some location in the source code,
+/// secondary locations related to it, and notes about how to fix it.
+fn json_diagnostic(
+    severity: &str,
+    code: Option<&str>,
+    message: String,
+    loc: Option<&SourceCodeLocation>,
+    labels: &[(&SourceCodeLocation, &str)],
+    notes: &[&str],
+) -> serde_json::Value {
+    let labels: Vec<_> = labels
+        .iter()
+        .map(|(loc, message)| {
+            serde_json::json!({
+                "file": loc.filename,
+                "span": {
+                    "start": loc.offset,
+                    "end": loc.offset + loc.length.unwrap_or(0),
+                },
+                "line": loc.line,
+                "column": loc.column,
+                "message": message,
+            })
+        })
+        .collect();
+    serde_json::json!({
+        "severity": severity,
+        "code": code,
+        "message": message,
+        "file": loc.and_then(|loc| loc.filename.clone()),
+        "span": loc.map(|loc| serde_json::json!({
+            "start": loc.offset,
+            "end": loc.offset + loc.length.unwrap_or(0),
+        })),
+        "line": loc.map(|loc| loc.line),
+        "column": loc.map(|loc| loc.column),
+        "labels": labels,
+        "notes": notes,
+    })
 }
 
 /// The types of errors returned by the CLI.
@@ -133,6 +403,27 @@ enum Error {
     BuildError(String),
     /// Invalid source code (expected core but got standard).
     InvalidSource(String),
+    /// A memory error found by running the program in memcheck mode.
+    MemoryError(MemoryError),
+    /// Warnings which were denied were reported.
+    DeniedWarnings(usize),
+    /// Files which aren't formatted were found with `fmt --check`.
+    Unformatted(usize),
+    /// Several independent errors, sorted by their location in the source code.
+    Many(Vec<Self>),
+}
+
+impl From<compiler::Error> for Error {
+    fn from(err: compiler::Error) -> Self {
+        match err {
+            compiler::Error::Parse(e) => Self::Parse(e),
+            compiler::Error::Lir(e) => Self::LirError(*e),
+            compiler::Error::Asm(e) => Self::AsmError(e),
+            compiler::Error::Interpreter(e) => Self::InterpreterError(e),
+            compiler::Error::Build(e) => Self::BuildError(e),
+            compiler::Error::InvalidSource(e) => Self::InvalidSource(e),
+        }
+    }
 }
 
 impl Error {
@@ -141,19 +432,75 @@ impl Error {
             Self::LirError(lir::Error::Annotated(ref err, ref metadata)) => {
                 if let Some(loc) = metadata.location().cloned() {
                     Self::WithSourceCode {
+                        source_code: read_source(&loc, code),
                         loc,
-                        source_code: code.to_owned(),
                         err: Box::new(Error::LirError(*err.clone())),
                     }
                 } else {
                     self
                 }
             }
+            Self::LirError(lir::Error::Many(errs)) => {
+                let mut errs: Vec<_> = errs
+                    .into_iter()
+                    .map(|err| Self::LirError(err).annotate_with_source(code))
+                    .collect();
+                // Report the errors in the order they appear in the source code,
+                // followed by the errors without a location.
+                errs.sort_by_key(|err| match err {
+                    Self::WithSourceCode { loc, .. } => (false, loc.filename.clone(), loc.offset),
+                    _ => (true, None, 0),
+                });
+                Self::Many(errs)
+            }
+            Self::MemoryError(ref err) => match err.location.clone() {
+                Some(loc) => Self::WithSourceCode {
+                    source_code: read_source(&loc, code),
+                    loc,
+                    err: Box::new(self),
+                },
+                None => self,
+            },
             _ => self,
         }
     }
 }
 
+impl Error {
+    /// Get the JSON diagnostics for this error. Lists of errors have a diagnostic
+    /// for each of their errors.
+    fn to_json(&self, loc: Option<&SourceCodeLocation>) -> Vec<serde_json::Value> {
+        match self {
+            Self::WithSourceCode { loc, err, .. } => err.to_json(Some(loc)),
+            Self::Many(errs) => errs.iter().flat_map(|err| err.to_json(None)).collect(),
+            Self::LirError(err) => err
+                .errors()
+                .into_iter()
+                .map(|err| {
+                    json_diagnostic(
+                        "error",
+                        Some(err.code()),
+                        err.to_string(),
+                        loc.or_else(|| err.location()),
+                        &err.labels(),
+                        &err.notes(),
+                    )
+                })
+                .collect(),
+            // The denied warnings have already been reported.
+            Self::DeniedWarnings(_) => vec![],
+            _ => vec![json_diagnostic(
+                "error",
+                None,
+                format!("{self:?}"),
+                loc,
+                &[],
+                &[],
+            )],
+        }
+    }
+}
+
 impl fmt::Debug for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -166,290 +513,334 @@ impl fmt::Debug for Error {
                 source_code,
                 err,
             } => {
-                // use codespan_reporting::files::SimpleFiles;
-                use codespan_reporting::diagnostic::{Diagnostic, Label};
-                use codespan_reporting::files::SimpleFiles;
-                use codespan_reporting::term::{
-                    emit,
-                    termcolor::{ColorChoice, StandardStream},
+                let (labels, notes) = match err.as_ref() {
+                    Error::LirError(err) => (err.labels(), err.notes()),
+                    _ => (vec![], vec![]),
                 };
-                use no_comment::{languages, IntoWithoutComments};
-
-                let SourceCodeLocation {
-                    line,
-                    column,
-                    filename,
-                    offset,
-                    length,
-                } = loc;
-
-                let mut files = SimpleFiles::new();
-
-                let source_code = source_code
-                    .to_string()
-                    .chars()
-                    .without_comments(languages::rust())
-                    .collect::<String>();
-
-                let filename = filename.clone().unwrap_or("unknown".to_string());
-
-                let file_id = files.add(
-                    filename.clone(),
+                emit_diagnostic(
+                    Severity::Error,
+                    loc,
                     source_code,
+                    format!("{err:?}"),
+                    &labels,
+                    &notes,
                 );
-
-                let loc = format!("{}:{}:{}:{}", filename, line, column, offset);
-
-                // let code = format!("{}\n{}^", code, " ".repeat(*column - 1));
-                // write!(f, "Error at {}:\n{}\n{:?}", loc, code, err)?
-
-                let diagnostic = Diagnostic::error()
-                    .with_message(format!("Error at {}", loc))
-                    .with_labels(vec![Label::primary(
-                        file_id,
-                        *offset..*offset + length.unwrap_or(0),
-                    )
-                        .with_message(format!("{err:?}"))]);
-
-                let writer = StandardStream::stderr(ColorChoice::Always);
-                let config = codespan_reporting::term::Config::default();
-
-                emit(&mut writer.lock(), &config, &files, &diagnostic).unwrap();
-
                 Ok(())
             }
             Error::InterpreterError(e) => write!(f, "Interpreter error: {}", e),
             Error::BuildError(e) => write!(f, "Build error: {}", e),
             Error::InvalidSource(e) => write!(f, "Invalid source: {}", e),
+            Error::MemoryError(e) => write!(f, "Memory error: {}", e),
+            Error::DeniedWarnings(n) => write!(f, "Aborting due to {} denied warning(s)", n),
+            Error::Unformatted(n) => write!(f, "{} file(s) would be reformatted", n),
+            Error::Many(errs) => {
+                for err in errs {
+                    // Errors with source code are emitted directly to stderr.
+                    let text = format!("{:?}", err);
+                    if !text.is_empty() {
+                        writeln!(f, "{}", text)?;
+                    }
+                }
+                write!(f, "Aborting due to {} errors", errs.len())
+            }
         }
     }
 }
 
-/// Compile a given source language to virtual machine code.
-fn compile_source_to_vm(
+/// Get the source code that a location points to.
+fn read_source(loc: &SourceCodeLocation, code: &str) -> String {
+    // The location may be in an imported module,
+    // so prefer the contents of the file it points to.
+    loc.filename
+        .as_deref()
+        .and_then(|filename| read_to_string(filename).ok())
+        .unwrap_or_else(|| code.to_owned())
+}
+
+/// Print a diagnostic to stderr.
+fn emit(diagnostic: Diagnostic<usize>, files: &SimpleFiles<String, String>) {
+    let writer = StandardStream::stderr(ColorChoice::Always);
+    let config = codespan_reporting::term::Config::default();
+
+    codespan_reporting::term::emit(&mut writer.lock(), &config, files, &diagnostic).unwrap();
+}
+
+/// Print a diagnostic pointing at the given location in the source code to stderr,
+/// along with any secondary locations related to it and notes about it.
+fn emit_diagnostic(
+    severity: Severity,
+    loc: &SourceCodeLocation,
+    source_code: &str,
+    message: String,
+    labels: &[(&SourceCodeLocation, &str)],
+    notes: &[&str],
+) {
+    let SourceCodeLocation {
+        line,
+        column,
+        filename,
+        offset,
+        length,
+    } = loc;
+
+    let mut files = SimpleFiles::new();
+
+    let filename = filename.clone().unwrap_or("unknown".to_string());
+
+    let file_id = files.add(filename.clone(), source_code.to_string());
+    let mut file_ids = HashMap::new();
+    file_ids.insert(filename.clone(), file_id);
+
+    let loc = format!("{}:{}:{}:{}", filename, line, column, offset);
+    let kind = match severity {
+        Severity::Warning => "Warning",
+        _ => "Error",
+    };
+
+    let diagnostic = Diagnostic::new(severity)
+        .with_message(format!("{kind} at {loc}"))
+        .with_labels(vec![Label::primary(
+            file_id,
+            *offset..*offset + length.unwrap_or(0),
+        )
+        .with_message(message)]);
+
+    // Secondary labels may point into other files, like an imported module.
+    let mut secondary = vec![];
+    for (label_loc, label_message) in labels {
+        let label_filename = label_loc.filename.clone().unwrap_or("unknown".to_string());
+        let label_file_id = *file_ids
+            .entry(label_filename.clone())
+            .or_insert_with(|| files.add(label_filename, read_source(label_loc, source_code)));
+        secondary.push(
+            Label::secondary(
+                label_file_id,
+                label_loc.offset..label_loc.offset + label_loc.length.unwrap_or(0),
+            )
+            .with_message(*label_message),
+        );
+    }
+    let diagnostic = diagnostic
+        .with_labels(secondary)
+        .with_notes(notes.iter().map(|note| note.to_string()).collect());
+
+    emit(diagnostic, &files);
+}
+
+/// Compile code in a given source language to a target with the library's compiler,
+/// and report the warnings found in the source code.
+fn compile_artifacts(
     filename: Option<&str>,
-    src: String,
+    src: &str,
     src_type: SourceType,
+    target: Target,
     call_stack_size: usize,
-) -> Result<Result<sage::vm::CoreProgram, sage::vm::StandardProgram>, Error> {
-    match src_type {
-        SourceType::StdVM => {
-            // Simply parse the virtual machine code
-            parse_vm(src).map_err(Error::Parse)
-        }
-        SourceType::CoreVM => {
-            // Parse the virtual machine code
-            match parse_vm(src).map_err(Error::Parse)? {
-                // If we got a core program back, return it.
-                Ok(prog) => Ok(Ok(prog)),
-                // Otherwise, our core program was actually a standard program. Throw an error.
-                Err(_) => Err(Error::InvalidSource(
-                    "expected core VM program, got standard VM program".to_string(),
-                )),
-            }
-        }
-        SourceType::StdASM => {
-            // Parse the assembly code.
-            // Then, assembly the program with the given recursion depth,
-            // and return the virtual machine output.
-            match parse_asm(src).map_err(Error::Parse)? {
-                Ok(prog) => Ok(Ok(prog
-                    .assemble(call_stack_size)
-                    .map_err(Error::AsmError)?)),
-                Err(prog) => Ok(Err(prog
-                    .assemble(call_stack_size)
-                    .map_err(Error::AsmError)?)),
-            }
-        }
-        SourceType::CoreASM => {
-            // Parse the assembly code.
-            match parse_asm(src).map_err(Error::Parse)? {
-                // If we got back a core program, assembly it and return the virtual machine code.
-                Ok(prog) => Ok(Ok(prog
-                    .assemble(call_stack_size)
-                    .map_err(Error::AsmError)?)),
-                // Otherwise, our core program was actually a standard program. Throw an error.
-                Err(_) => Err(Error::InvalidSource(
-                    "expected core assembly program, got standard assembly program".to_string(),
-                )),
-            }
-        }
-        SourceType::LowIR => {
-            // Parse the lower intermediate representation code.
-            match parse_lir(src)
-                .map_err(Error::Parse)?
-                .compile()
-                .map_err(Error::LirError)?
-            {
-                // If we got back a valid program, assemble it and return the result.
-                Ok(asm_code) => Ok(Ok(asm_code
-                    .assemble(call_stack_size)
-                    .map_err(Error::AsmError)?)),
-                Err(asm_code) => Ok(Err(asm_code
-                    .assemble(call_stack_size)
-                    .map_err(Error::AsmError)?)),
-            }
-        }
-        SourceType::Sage => {
-            match parse_frontend(&src, filename)
-                .map_err(Error::Parse)?
-                .compile()
-                .map_err(Error::LirError)
-                .map_err(|e| e.annotate_with_source(&src))?
-            {
-                // If we got back a valid program, assemble it and return the result.
-                Ok(asm_code) => Ok(Ok(asm_code
-                    .assemble(call_stack_size)
-                    .map_err(Error::AsmError)?)),
-                Err(asm_code) => Ok(Err(asm_code
-                    .assemble(call_stack_size)
-                    .map_err(Error::AsmError)?)),
-            }
-        }
+    source_locations: bool,
+    diagnostics: &DiagnosticOptions,
+) -> Result<Artifacts, Error> {
+    let mut compiler = Compiler::new(src)
+        .source_type(src_type.into())
+        .target(target)
+        .call_stack_size(call_stack_size)
+        .source_locations(source_locations);
+    if let Some(filename) = filename {
+        compiler = compiler.filename(filename);
     }
+    let (result, found) = compiler.compile_with_warnings();
+    diagnostics.report(&found, src)?;
+    result.map_err(|e| Error::from(e).annotate_with_source(src))
 }
 
-/// Compile code in a given source language to assembly code.
-fn compile_source_to_asm(
+/// Compile code in a given source language to a given target language.
+#[allow(clippy::too_many_arguments)]
+fn compile(
     filename: Option<&str>,
     src: String,
     src_type: SourceType,
-) -> Result<Result<sage::asm::CoreProgram, sage::asm::StandardProgram>, Error> {
-    match src_type {
-        // If the source language is standard assembly, then parse it and return it.
-        SourceType::StdASM => parse_asm(src).map_err(Error::Parse),
-        // If the source language is core assembly, then parse it and return it if it's actually a core variant program.
-        // Otherwise, throw an error.
-        SourceType::CoreASM => match parse_asm(src).map_err(Error::Parse)? {
-            Ok(prog) => Ok(Ok(prog)),
-            Err(_) => Err(Error::InvalidSource(
-                "expected core assembly program, got standard assembly program".to_string(),
-            )),
+    target: TargetType,
+    output: String,
+    call_stack_size: usize,
+    memcheck: bool,
+    profile: bool,
+    debug: bool,
+    diagnostics: &DiagnosticOptions,
+) -> Result<(), Error> {
+    if memcheck && target != TargetType::Run {
+        return Err(Error::InvalidSource(
+            "memcheck mode only works when running the program".to_string(),
+        ));
+    }
+    if profile && target != TargetType::Run {
+        return Err(Error::InvalidSource(
+            "profiling mode only works when running the program".to_string(),
+        ));
+    }
+    let artifacts = compile_artifacts(
+        filename,
+        &src,
+        src_type,
+        match target {
+            // Code is run in the interpreter as virtual machine code.
+            TargetType::Run => Target::StdVM,
+            TargetType::CoreASM => Target::CoreASM,
+            TargetType::StdASM => Target::StdASM,
+            TargetType::CoreVM => Target::CoreVM,
+            TargetType::StdVM => Target::StdVM,
+            TargetType::SageOS => Target::SageOS,
+            TargetType::C => Target::C,
+            TargetType::X86 => Target::X86,
         },
-        // If the source language is LIR, parse it and compile it to assembly code.
-        SourceType::LowIR => parse_lir(src)
-            .map_err(Error::Parse)?
-            .compile()
-            .map_err(Error::LirError),
-
-        // If the source language is Sage, parse it and compile it to assembly code.
-        SourceType::Sage => parse_frontend(&src, filename)
-            .map_err(Error::Parse)?
-            .compile()
-            .map_err(Error::LirError)
-            .map_err(|e| e.annotate_with_source(&src)),
-        // If the source language is a virtual machine program,
-        // then we cannot compile it to assembly. Throw an error.
-        SourceType::CoreVM | SourceType::StdVM => Err(Error::InvalidSource(
-            "cannot compile a core VM program to assembly".to_string(),
-        )),
+        call_stack_size,
+        // The memory checker points to the source code of the errors it finds,
+        // and the profiler names the functions.
+        memcheck || profile,
+        diagnostics,
+    )?;
+
+    // If the target is `Run`, then execute the code with the interpreter.
+    if let TargetType::Run = target {
+        if profile {
+            let (result, profile) = artifacts.run_with_profile(StandardDevice::default());
+            // The profile of a program which failed is still reported.
+            eprint!("{profile}");
+            write_file(format!("{output}.folded"), profile.folded_stacks())?;
+            result?;
+            return Ok(());
+        }
+        if !memcheck {
+            artifacts.run(StandardDevice::default())?;
+            return Ok(());
+        }
+        let (result, errors) = artifacts.run_with_memcheck(StandardDevice::default());
+        // The memory errors found before the program failed are reported too.
+        let mut errs: Vec<_> = errors
+            .into_iter()
+            .map(|err| Error::MemoryError(err).annotate_with_source(&src))
+            .collect();
+        if let Err(err) = result {
+            errs.push(err.into());
+        }
+        return match errs.len() {
+            0 => Ok(()),
+            1 => Err(errs.remove(0)),
+            _ => Err(Error::Many(errs)),
+        };
     }
+
+    let (extension, contents) = match (target, &artifacts.asm, &artifacts.vm, &artifacts.output) {
+        // The C, x86, and SageOS targets are built by the compiler.
+        (TargetType::SageOS | TargetType::C, _, _, Some(code)) => ("c", code.clone()),
+        (TargetType::X86, _, _, Some(code)) => ("s", code.clone()),
+        // The compiler has already checked that core targets got core code.
+        (TargetType::CoreASM | TargetType::StdASM, Some(Ok(asm_code)), _, _) if debug => {
+            ("asm.sg", format!("{asm_code:#}"))
+        }
+        (TargetType::CoreASM | TargetType::StdASM, Some(Ok(asm_code)), _, _) => {
+            ("asm.sg", asm_code.to_string())
+        }
+        (TargetType::StdASM, Some(Err(asm_code)), _, _) if debug => {
+            ("asm.sg", format!("{asm_code:#}"))
+        }
+        (TargetType::StdASM, Some(Err(asm_code)), _, _) => ("asm.sg", asm_code.to_string()),
+        (TargetType::CoreVM | TargetType::StdVM, _, Some(Ok(vm_code)), _) if debug => {
+            ("vm.sg", format!("{vm_code:#}"))
+        }
+        (TargetType::CoreVM | TargetType::StdVM, _, Some(Ok(vm_code)), _) => {
+            ("vm.sg", vm_code.to_string())
+        }
+        (TargetType::StdVM, _, Some(Err(vm_code)), _) if debug => ("vm.sg", format!("{vm_code:#}")),
+        (TargetType::StdVM, _, Some(Err(vm_code)), _) => ("vm.sg", vm_code.to_string()),
+        _ => unreachable!("the compiler always produces the code for {target:?}"),
+    };
+    write_file(format!("{output}.{extension}"), contents)
 }
 
-/// Compile code in a given source language to a given target language.
-fn compile(
+/// Compile code in a given source language once, and write each of the given
+/// stages of the compilation to its own file.
+#[allow(clippy::too_many_arguments)]
+fn emit_stages(
     filename: Option<&str>,
     src: String,
     src_type: SourceType,
-    target: TargetType,
+    stages: &[Emit],
     output: String,
     call_stack_size: usize,
     debug: bool,
+    diagnostics: &DiagnosticOptions,
 ) -> Result<(), Error> {
-    match target {
-        // If the target is `Run`, then compile the code and execute it with the interpreter.
-        TargetType::Run => match compile_source_to_vm(filename, src, src_type, call_stack_size)? {
-            // If the code is core variant virtual machine code
-            Ok(vm_code) => {
-                CoreInterpreter::new(StandardDevice::default())
-                    .run(&vm_code)
-                    .map_err(Error::InterpreterError)?;
-            }
-            // If the code is standard variant virtual machine code
-            Err(vm_code) => {
-                StandardInterpreter::new(StandardDevice::default())
-                    .run(&vm_code)
-                    .map_err(Error::InterpreterError)?;
+    let needs_vm = stages
+        .iter()
+        .any(|stage| matches!(stage, Emit::VM | Emit::C | Emit::X86));
+    let needs_asm = needs_vm
+        || stages
+            .iter()
+            .any(|stage| matches!(stage, Emit::CoreASM | Emit::StdASM));
+
+    // Run each stage of the pipeline once, as far as the requested stages need.
+    let target = if needs_vm {
+        Target::StdVM
+    } else if needs_asm {
+        Target::StdASM
+    } else {
+        Target::Lir
+    };
+    let artifacts = compile_artifacts(
+        filename,
+        &src,
+        src_type,
+        target,
+        call_stack_size,
+        false,
+        diagnostics,
+    )?;
+
+    // A stage which can't be emitted doesn't stop the others from being written.
+    let mut errors = vec![];
+    for &stage in stages {
+        let contents = match (stage, &artifacts.lir, &artifacts.asm, &artifacts.vm) {
+            (Emit::Lir, Some(expr), _, _) => format!("{expr:#}\n"),
+            (Emit::Lir, None, _, _) => {
+                errors.push(Error::InvalidSource(
+                    "cannot emit LIR code from assembly or virtual machine code".to_string(),
+                ));
+                continue;
             }
-        },
-        // If the target is SageOS source code, then compile the code to virtual machine code,
-        // and then use the SageOS target implementation to build the output source code.
-        TargetType::SageOS => write_file(
-            format!("{output}.c"),
-            match compile_source_to_vm(filename, src, src_type, call_stack_size)? {
-                Ok(vm_code) => targets::SageOS.build_core(&vm_code.flatten()),
-                Err(vm_code) => targets::SageOS.build_std(&vm_code.flatten()),
+            (Emit::CoreASM | Emit::StdASM, _, None, _) => {
+                errors.push(Error::InvalidSource(
+                    "cannot emit assembly code from virtual machine code".to_string(),
+                ));
+                continue;
             }
-            .map_err(Error::BuildError)?,
-        )?,
-        // If the target is C source code, then compile the code to virtual machine code,
-        // and then use the C target implementation to build the output source code.
-        TargetType::C => write_file(
-            format!("{output}.c"),
-            match compile_source_to_vm(filename, src, src_type, call_stack_size)? {
-                Ok(vm_code) => targets::C.build_core(&vm_code.flatten()),
-                Err(vm_code) => targets::C.build_std(&vm_code.flatten()),
+            (Emit::CoreASM, _, Some(Err(_)), _) => {
+                errors.push(Error::InvalidSource(
+                    "expected core assembly program, got standard assembly program".to_string(),
+                ));
+                continue;
             }
-            .map_err(Error::BuildError)?,
-        )?,
-        // If the target is x86 assembly code, then compile the code to virtual machine code,
-        // and then use the x86 target implementation to build the output source code.
-        TargetType::X86 => write_file(
-            format!("{output}.s"),
-            match compile_source_to_vm(filename, src, src_type, call_stack_size)? {
-                Ok(vm_code) => targets::X86::default().build_core(&vm_code.flatten()),
-                Err(vm_code) => targets::X86::default().build_std(&vm_code.flatten()),
+            (Emit::CoreASM | Emit::StdASM, _, Some(Ok(asm_code)), _) if debug => {
+                format!("{asm_code:#}")
             }
-            .map_err(Error::BuildError)?,
-        )?,
-        // If the target is core virtual machine code, then try to compile the source to the core variant.
-        // If not possible, throw an error.
-        TargetType::CoreVM => match compile_source_to_vm(filename, src, src_type, call_stack_size)?
-        {
-            Ok(vm_code) if debug => write_file(
-                format!("{output}.vm.sg"),
-                format!("{:#}", vm_code.flatten()),
-            ),
-            Ok(vm_code) => write_file(format!("{output}.vm.sg"), vm_code.flatten().to_string()),
-            Err(_) => Err(Error::InvalidSource(
-                "expected core VM program, got standard VM program".to_string(),
-            )),
-        }?,
-        // If the target is standard virtual machine code, the compile it to virtual machine code.
-        // If the result is core variant, we don't care. Just return the generated code.
-        TargetType::StdVM => write_file(
-            format!("{output}.vm.sg"),
-            match compile_source_to_vm(filename, src, src_type, call_stack_size)? {
-                Ok(vm_code) if debug => format!("{:#}", vm_code.flatten()),
-                Err(vm_code) if debug => format!("{:#}", vm_code.flatten()),
-                Ok(vm_code) => vm_code.flatten().to_string(),
-                Err(vm_code) => vm_code.flatten().to_string(),
-            },
-        )?,
-        // If the target is core assembly code, then try to compile the source to the core variant.
-        // If not possible, throw an error.
-        TargetType::CoreASM => match compile_source_to_asm(filename, src, src_type)? {
-            Ok(asm_code) if debug => {
-                write_file(format!("{output}.asm.sg"), format!("{:#}", asm_code))
+            (Emit::CoreASM | Emit::StdASM, _, Some(Ok(asm_code)), _) => asm_code.to_string(),
+            (Emit::StdASM, _, Some(Err(asm_code)), _) if debug => format!("{asm_code:#}"),
+            (Emit::StdASM, _, Some(Err(asm_code)), _) => asm_code.to_string(),
+            (_, _, _, None) => {
+                unreachable!("the virtual machine code is always built for {stage:?}")
             }
-            Ok(asm_code) => write_file(format!("{output}.asm.sg"), asm_code.to_string()),
-            Err(_) => Err(Error::InvalidSource(
-                "expected core assembly program, got standard assembly program".to_string(),
-            )),
-        }?,
-        // If the target is standard assembly code, then try to compile the source to the standard variant.
-        // If the result is core variant, we don't care. Just return the generated code.
-        TargetType::StdASM => write_file(
-            format!("{output}.asm.sg"),
-            match compile_source_to_asm(filename, src, src_type)? {
-                Ok(core_asm_code) if debug => format!("{:#}", core_asm_code),
-                Err(std_asm_code) if debug => format!("{:#}", std_asm_code),
-                Ok(core_asm_code) => core_asm_code.to_string(),
-                Err(std_asm_code) => std_asm_code.to_string(),
-            },
-        )?,
+            (Emit::VM, _, _, Some(Ok(vm_code))) if debug => format!("{vm_code:#}"),
+            (Emit::VM, _, _, Some(Err(vm_code))) if debug => format!("{vm_code:#}"),
+            (Emit::VM, _, _, Some(Ok(vm_code))) => vm_code.to_string(),
+            (Emit::VM, _, _, Some(Err(vm_code))) => vm_code.to_string(),
+            (Emit::C, _, _, Some(_)) => artifacts.build(Target::C)?,
+            (Emit::X86, _, _, Some(_)) => artifacts.build(Target::X86)?,
+        };
+        write_file(format!("{output}.{}", stage.extension()), contents)?;
+    }
+
+    match errors.len() {
+        0 => Ok(()),
+        1 => Err(errors.remove(0)),
+        _ => Err(Error::Many(errors)),
     }
-    Ok(())
 }
 
 /// Write some contents to a file.
@@ -462,6 +853,39 @@ fn read_file(name: &str) -> Result<String, Error> {
     read_to_string(name).map_err(Error::IO)
 }
 
+/// Format the given source files in place. With `check`, the files are left
+/// alone, and the ones which would change are listed instead.
+fn format_files(files: &[String], check: bool) -> Result<(), Error> {
+    let mut errors = vec![];
+    let mut unformatted = 0;
+    for file in files {
+        let result = read_file(file).and_then(|code| {
+            let formatted = format_frontend(&code, Some(file)).map_err(Error::Parse)?;
+            if formatted == code {
+                Ok(())
+            } else if check {
+                println!("Would reformat {file}");
+                unformatted += 1;
+                Ok(())
+            } else {
+                write_file(file.clone(), formatted)
+            }
+        });
+        if let Err(e) = result {
+            errors.push(e);
+        }
+    }
+
+    if unformatted > 0 {
+        errors.push(Error::Unformatted(unformatted));
+    }
+    match errors.len() {
+        0 => Ok(()),
+        1 => Err(errors.remove(0)),
+        _ => Err(Error::Many(errors)),
+    }
+}
+
 /// Run the CLI.
 fn cli() {
     // Parse the arguments to the CLI.
@@ -472,58 +896,110 @@ fn cli() {
     let target = args.debug.as_deref();
 
     // Set the log level.
-    builder.filter(target, match args.log_level {
-        LogLevel::Error if args.debug.is_none() => log::LevelFilter::Error,
-        LogLevel::Warn if args.debug.is_none() => log::LevelFilter::Warn,
-        LogLevel::Off if args.debug.is_none() => log::LevelFilter::Error,
-        LogLevel::Info if args.debug.is_none() =>log::LevelFilter::Info,
-        LogLevel::Trace => log::LevelFilter::Trace,
-        _ => log::LevelFilter::Debug,
-    });
+    builder.filter(
+        target,
+        match args.log_level {
+            LogLevel::Error if args.debug.is_none() => log::LevelFilter::Error,
+            LogLevel::Warn if args.debug.is_none() => log::LevelFilter::Warn,
+            // JSON diagnostics shouldn't be interleaved with log messages.
+            LogLevel::Off if args.debug.is_none() && args.message_format == MessageFormat::Json => {
+                log::LevelFilter::Off
+            }
+            LogLevel::Off if args.debug.is_none() => log::LevelFilter::Error,
+            LogLevel::Info if args.debug.is_none() => log::LevelFilter::Info,
+            LogLevel::Trace => log::LevelFilter::Trace,
+            _ => log::LevelFilter::Debug,
+        },
+    );
 
     builder.init();
 
-    match read_file(&args.input) {
-        Ok(file_contents) => {
-            match compile(
-                Some(&args.input),
-                file_contents,
-                args.source_type,
-                args.target_type,
-                args.output,
+    let diagnostics = DiagnosticOptions {
+        format: args.message_format,
+        deny_all: args.deny_warnings,
+        allow: args.allow,
+        deny: args.deny,
+    };
+
+    if let Some(Command::Fmt { files, check }) = &args.command {
+        if let Err(e) = format_files(files, *check) {
+            diagnostics.report_error(&e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Command::Repl) = &args.command {
+        if let Err(e) = repl::repl(args.call_stack_size, &diagnostics) {
+            diagnostics.report_error(&Error::IO(e));
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Command::Debug { file, source_type }) = &args.command {
+        let result = read_file(file).and_then(|src| {
+            // The debugger shows the source code the instructions were compiled from.
+            let artifacts = compile_artifacts(
+                Some(file),
+                &src,
+                *source_type,
+                Target::StdVM,
                 args.call_stack_size,
-                args.debug.is_some(),
-            ) {
-                Ok(_) => {}
-                Err(e) => {
-                    error!("{e:#?}");
-                }
+                true,
+                &diagnostics,
+            )?;
+            debug::debug(&artifacts, file, &src).map_err(Error::IO)
+        });
+        if let Err(e) = result {
+            diagnostics.report_error(&e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let input = args.input.expect("the input file is required");
+    match read_file(&input) {
+        Ok(file_contents) => {
+            let result = if args.emit.is_empty() {
+                compile(
+                    Some(&input),
+                    file_contents,
+                    args.source_type,
+                    args.target_type,
+                    args.output,
+                    args.call_stack_size,
+                    args.memcheck,
+                    args.profile,
+                    args.debug.is_some(),
+                    &diagnostics,
+                )
+            } else {
+                emit_stages(
+                    Some(&input),
+                    file_contents,
+                    args.source_type,
+                    &args.emit,
+                    args.output,
+                    args.call_stack_size,
+                    args.debug.is_some(),
+                    &diagnostics,
+                )
+            };
+            if let Err(e) = result {
+                diagnostics.report_error(&e);
+                std::process::exit(1);
             }
         }
         Err(e) => {
             error!("Error reading file: {e:?}");
+            std::process::exit(1);
         }
     }
 }
 
 fn main() {
-    // If we're in debug mode, start the compilation in a separate thread.
+    // Start the compilation in a separate thread.
     // This is to allow the process to have more stack space.
-    if !cfg!(debug_assertions) {
-        let child = std::thread::Builder::new()
-            .stack_size(RELEASE_STACK_SIZE_MB * 1024 * 1024)
-            .spawn(cli)
-            .unwrap();
-
-        // Wait for the thread to finish.
-        child.join().unwrap()
-    } else {
-        let child = std::thread::Builder::new()
-            .stack_size(DEBUG_STACK_SIZE_MB * 1024 * 1024)
-            .spawn(cli)
-            .unwrap();
-
-        // Wait for the thread to finish.
-        child.join().unwrap()
-    }
+    with_stack_size(DEFAULT_STACK_SIZE_MB, cli)
 }
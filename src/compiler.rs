@@ -0,0 +1,412 @@
+//! # Compiler Module
+//!
+//! This module implements the pipeline which compiles source code in any of
+//! sage's source languages to any of its targets. Embedders, like the web
+//! compiler, the CLI, and the benchmarks, use it instead of wiring the stages
+//! together themselves.
+//!
+//! ```rust
+//! use sage::{vm::TestingDevice, Compiler, Target};
+//!
+//! let artifacts = Compiler::new("println(1 + 2);")
+//!     .target(Target::StdVM)
+//!     .compile()
+//!     .unwrap();
+//! let device = artifacts.run(TestingDevice::new("")).unwrap();
+//! assert_eq!(device.output_str(), "3\n");
+//! ```
+//!
+//! The type checker and the assembler recurse deeply on large programs, so
+//! they're best run with [`with_stack_size`] and [`DEFAULT_STACK_SIZE_MB`]. The
+//! artifacts aren't `Send`, so they should be used inside the same thread.
+use crate::{
+    asm,
+    lir::{self, Compile, Expr, Warning},
+    parse::{parse_asm, parse_frontend, parse_lir, parse_vm},
+    targets::{self, CompiledTarget},
+    vm::{self, CoreInterpreter, Device, MemoryError, Profile, StandardInterpreter},
+};
+use std::fmt;
+
+/// The default size of the call stack for compiled programs, in cells.
+pub const DEFAULT_CALL_STACK_SIZE: usize = 8192;
+
+/// The size of the stack the CLI compiles code with, in megabytes.
+pub const DEFAULT_STACK_SIZE_MB: usize = 512;
+
+/// Run a function on a new thread with a stack of the given size in megabytes,
+/// and return its result.
+pub fn with_stack_size<T: Send>(megabytes: usize, f: impl FnOnce() -> T + Send) -> T {
+    std::thread::scope(|scope| {
+        std::thread::Builder::new()
+            .stack_size(megabytes * 1024 * 1024)
+            .spawn_scoped(scope, f)
+            .unwrap()
+            .join()
+            .unwrap()
+    })
+}
+
+/// The source languages the compiler accepts.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SourceType {
+    /// Sage frontend code.
+    #[default]
+    Sage,
+    /// LIR code.
+    LowIR,
+    /// Core variant assembly code.
+    CoreASM,
+    /// Standard variant assembly code.
+    StdASM,
+    /// Core variant virtual machine code.
+    CoreVM,
+    /// Standard variant virtual machine code.
+    StdVM,
+}
+
+/// The stages the compiler can compile source code to.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Target {
+    /// LIR code.
+    Lir,
+    /// The core variant of the assembly language.
+    CoreASM,
+    /// The standard variant of the assembly language.
+    StdASM,
+    /// The core variant of the virtual machine.
+    CoreVM,
+    /// The standard variant of the virtual machine.
+    #[default]
+    StdVM,
+    /// My OS source code (GCC only).
+    SageOS,
+    /// C source code (GCC only).
+    C,
+    /// x86 assembly code.
+    X86,
+}
+
+impl Target {
+    /// Does compiling to this target require assembly code?
+    fn needs_asm(self) -> bool {
+        self != Self::Lir
+    }
+
+    /// Does compiling to this target require virtual machine code?
+    fn needs_vm(self) -> bool {
+        !matches!(self, Self::Lir | Self::CoreASM | Self::StdASM)
+    }
+}
+
+/// The types of errors returned by the compiler.
+#[derive(Clone, Debug)]
+pub enum Error {
+    /// Error parsing the source code.
+    Parse(String),
+    /// Error generated when compiling LIR code.
+    Lir(Box<lir::Error>),
+    /// Error generated when assembling the assembly code.
+    Asm(asm::Error),
+    /// Error generated by the interpreter executing the code.
+    Interpreter(String),
+    /// Error when building the virtual machine code for a given target.
+    Build(String),
+    /// Invalid source code for the target (expected core but got standard).
+    InvalidSource(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Parse(e) => write!(f, "Parse error: {e}"),
+            Self::Lir(e) => write!(f, "LIR error: {e}"),
+            Self::Asm(e) => write!(f, "Assembly error: {e:?}"),
+            Self::Interpreter(e) => write!(f, "Interpreter error: {e}"),
+            Self::Build(e) => write!(f, "Build error: {e}"),
+            Self::InvalidSource(e) => write!(f, "Invalid source: {e}"),
+        }
+    }
+}
+
+/// The code produced by each stage of a compilation. A stage is only present
+/// if the source language came before it, and the target needed it.
+#[derive(Clone, Default)]
+pub struct Artifacts {
+    /// The LIR code, if the source was Sage or LIR code.
+    pub lir: Option<Expr>,
+    /// The assembly code, as a core program or a fallback standard program.
+    pub asm: Option<Result<asm::CoreProgram, asm::StandardProgram>>,
+    /// The flattened virtual machine code, as a core program or a fallback standard program.
+    pub vm: Option<Result<vm::CoreProgram, vm::StandardProgram>>,
+    /// The code built for the target, if it was SageOS, C, or x86.
+    pub output: Option<String>,
+}
+
+impl Artifacts {
+    /// Build the virtual machine code for a SageOS, C, or x86 target.
+    pub fn build(&self, target: Target) -> Result<String, Error> {
+        let vm_code = self.vm.as_ref().ok_or_else(|| {
+            Error::InvalidSource("no virtual machine code was compiled".to_string())
+        })?;
+        match (target, vm_code) {
+            (Target::SageOS, Ok(vm_code)) => targets::SageOS.build_core(vm_code),
+            (Target::SageOS, Err(vm_code)) => targets::SageOS.build_std(vm_code),
+            (Target::C, Ok(vm_code)) => targets::C.build_core(vm_code),
+            (Target::C, Err(vm_code)) => targets::C.build_std(vm_code),
+            (Target::X86, Ok(vm_code)) => targets::X86::default().build_core(vm_code),
+            (Target::X86, Err(vm_code)) => targets::X86::default().build_std(vm_code),
+            _ => {
+                return Err(Error::InvalidSource(format!(
+                    "cannot build virtual machine code for {target:?}"
+                )))
+            }
+        }
+        .map_err(Error::Build)
+    }
+
+    /// Run the virtual machine code in the interpreter with the given device,
+    /// and return the device afterwards.
+    pub fn run<T: Device>(&self, device: T) -> Result<T, Error> {
+        match &self.vm {
+            Some(Ok(vm_code)) => CoreInterpreter::new(device).run(vm_code),
+            Some(Err(vm_code)) => StandardInterpreter::new(device).run(vm_code),
+            None => {
+                return Err(Error::InvalidSource(
+                    "no virtual machine code was compiled".to_string(),
+                ))
+            }
+        }
+        .map_err(Error::Interpreter)
+    }
+
+    /// Run the virtual machine code in the standard interpreter's memcheck mode
+    /// with the given device, and return the device afterwards along with the
+    /// memory errors found. Core programs are run as standard programs.
+    ///
+    /// The memory errors are returned even if the program fails. Compile the
+    /// code with `Compiler::source_locations` to find where they are in the source.
+    pub fn run_with_memcheck<T: Device>(&self, device: T) -> (Result<T, Error>, Vec<MemoryError>) {
+        let vm_code = match &self.vm {
+            Some(Ok(vm_code)) => vm::StandardProgram::from(vm_code.clone()),
+            Some(Err(vm_code)) => vm_code.clone(),
+            None => {
+                return (
+                    Err(Error::InvalidSource(
+                        "no virtual machine code was compiled".to_string(),
+                    )),
+                    vec![],
+                )
+            }
+        };
+        let mut interpreter = StandardInterpreter::new(device).with_memcheck();
+        let result = interpreter.execute(&vm_code).map_err(Error::Interpreter);
+        let errors = interpreter.memory_errors().to_vec();
+        (result.map(|_| interpreter.into_device()), errors)
+    }
+
+    /// Run the virtual machine code in the interpreter's profiling mode with the
+    /// given device, and return the device afterwards along with the profile.
+    ///
+    /// The profile is returned even if the program fails. Compile the code with
+    /// `Compiler::source_locations` to name the functions in the profile.
+    pub fn run_with_profile<T: Device>(&self, device: T) -> (Result<T, Error>, Profile) {
+        match &self.vm {
+            Some(Ok(vm_code)) => {
+                let mut interpreter = CoreInterpreter::new(device).with_profile();
+                let result = interpreter.execute(vm_code).map_err(Error::Interpreter);
+                let profile = interpreter.profile().cloned().unwrap_or_default();
+                (result.map(|_| interpreter.into_device()), profile)
+            }
+            Some(Err(vm_code)) => {
+                let mut interpreter = StandardInterpreter::new(device).with_profile();
+                let result = interpreter.execute(vm_code).map_err(Error::Interpreter);
+                let profile = interpreter.profile().cloned().unwrap_or_default();
+                (result.map(|_| interpreter.into_device()), profile)
+            }
+            None => (
+                Err(Error::InvalidSource(
+                    "no virtual machine code was compiled".to_string(),
+                )),
+                Profile::default(),
+            ),
+        }
+    }
+}
+
+/// A builder for compiling source code to a target.
+#[derive(Clone, Debug)]
+pub struct Compiler {
+    /// The source code to compile.
+    source: String,
+    /// The name of the file the source code came from, used in diagnostics.
+    filename: Option<String>,
+    /// The language of the source code.
+    source_type: SourceType,
+    /// The stage to compile the source code to.
+    target: Target,
+    /// The number of cells allocated for the call stack.
+    call_stack_size: usize,
+    /// Record the source code locations of the compiled code in comments.
+    source_locations: bool,
+}
+
+impl Compiler {
+    /// Create a compiler for some Sage source code, which compiles it to
+    /// standard virtual machine code.
+    pub fn new(source: impl ToString) -> Self {
+        Self {
+            source: source.to_string(),
+            filename: None,
+            source_type: SourceType::default(),
+            target: Target::default(),
+            call_stack_size: DEFAULT_CALL_STACK_SIZE,
+            source_locations: false,
+        }
+    }
+
+    /// Set the name of the file the source code came from.
+    pub fn filename(mut self, filename: impl ToString) -> Self {
+        self.filename = Some(filename.to_string());
+        self
+    }
+
+    /// Set the language of the source code.
+    pub fn source_type(mut self, source_type: SourceType) -> Self {
+        self.source_type = source_type;
+        self
+    }
+
+    /// Set the stage to compile the source code to.
+    pub fn target(mut self, target: Target) -> Self {
+        self.target = target;
+        self
+    }
+
+    /// Set the number of cells allocated for the call stack.
+    pub fn call_stack_size(mut self, call_stack_size: usize) -> Self {
+        self.call_stack_size = call_stack_size;
+        self
+    }
+
+    /// Record the source code locations of the compiled code in comments in the
+    /// assembly and virtual machine code, so tools like the memory checker can
+    /// point back to the source code. This makes the code larger and slower.
+    pub fn source_locations(mut self, source_locations: bool) -> Self {
+        self.source_locations = source_locations;
+        self
+    }
+
+    /// Compile the source code to the target.
+    pub fn compile(&self) -> Result<Artifacts, Error> {
+        self.compile_with_warnings().0
+    }
+
+    /// Compile the source code to the target, like `compile`, and also
+    /// return the warnings reported while type checking the code.
+    ///
+    /// The warnings are returned even if the compilation fails.
+    pub fn compile_with_warnings(&self) -> (Result<Artifacts, Error>, Vec<Warning>) {
+        let mut warnings = vec![];
+        let result = self.compile_stages(&mut warnings);
+        (result, warnings)
+    }
+
+    /// Run each stage of the pipeline once, as far as the target needs.
+    fn compile_stages(&self, warnings: &mut Vec<Warning>) -> Result<Artifacts, Error> {
+        let src = &self.source;
+        let mut artifacts = Artifacts::default();
+
+        // Parse the source code into the first stage it belongs to.
+        match self.source_type {
+            SourceType::Sage => {
+                artifacts.lir =
+                    Some(parse_frontend(src, self.filename.as_deref()).map_err(Error::Parse)?);
+            }
+            SourceType::LowIR => artifacts.lir = Some(parse_lir(src).map_err(Error::Parse)?),
+            SourceType::CoreASM | SourceType::StdASM => {
+                let asm_code = parse_asm(src).map_err(Error::Parse)?;
+                if self.source_type == SourceType::CoreASM && asm_code.is_err() {
+                    return Err(Error::InvalidSource(
+                        "expected core assembly program, got standard assembly program".to_string(),
+                    ));
+                }
+                artifacts.asm = Some(asm_code);
+            }
+            SourceType::CoreVM | SourceType::StdVM => {
+                let vm_code = parse_vm(src).map_err(Error::Parse)?;
+                if self.source_type == SourceType::CoreVM && vm_code.is_err() {
+                    return Err(Error::InvalidSource(
+                        "expected core VM program, got standard VM program".to_string(),
+                    ));
+                }
+                artifacts.vm = Some(match vm_code {
+                    Ok(vm_code) => Ok(vm_code.flatten()),
+                    Err(vm_code) => Err(vm_code.flatten()),
+                });
+            }
+        }
+
+        if self.target == Target::Lir && artifacts.lir.is_none() {
+            return Err(Error::InvalidSource(
+                "cannot compile assembly or virtual machine code to LIR".to_string(),
+            ));
+        }
+
+        // Compile the LIR code to assembly code.
+        if self.target.needs_asm() {
+            if let Some(expr) = &artifacts.lir {
+                let (result, found) = if self.source_locations {
+                    expr.clone().compile_with_source_locations()
+                } else {
+                    expr.clone().compile_with_warnings()
+                };
+                warnings.extend(found);
+                artifacts.asm = Some(result.map_err(|e| Error::Lir(Box::new(e)))?);
+            }
+        }
+        match (&artifacts.asm, self.target) {
+            (None, Target::CoreASM | Target::StdASM) => {
+                return Err(Error::InvalidSource(
+                    "cannot compile a VM program to assembly".to_string(),
+                ))
+            }
+            (Some(Err(_)), Target::CoreASM) => {
+                return Err(Error::InvalidSource(
+                    "expected core assembly program, got standard assembly program".to_string(),
+                ))
+            }
+            _ => {}
+        }
+
+        // Assemble the assembly code into virtual machine code.
+        if self.target.needs_vm() {
+            match &artifacts.asm {
+                Some(Ok(asm_code)) => {
+                    artifacts.vm = Some(Ok(asm_code
+                        .assemble(self.call_stack_size)
+                        .map_err(Error::Asm)?
+                        .flatten()))
+                }
+                Some(Err(asm_code)) => {
+                    artifacts.vm = Some(Err(asm_code
+                        .assemble(self.call_stack_size)
+                        .map_err(Error::Asm)?
+                        .flatten()))
+                }
+                None => {}
+            }
+        }
+        if let (Some(Err(_)), Target::CoreVM) = (&artifacts.vm, self.target) {
+            return Err(Error::InvalidSource(
+                "expected core VM program, got standard VM program".to_string(),
+            ));
+        }
+
+        // Build the virtual machine code for the target.
+        if matches!(self.target, Target::SageOS | Target::C | Target::X86) {
+            artifacts.output = Some(artifacts.build(self.target)?);
+        }
+        Ok(artifacts)
+    }
+}
@@ -0,0 +1,460 @@
+//! # The Sage Debugger
+//!
+//! This module implements `sage debug`, an interactive debugger for programs run
+//! in the standard interpreter. The program is compiled with its source code
+//! locations recorded, so breakpoints can be set on lines of source code as well
+//! as on instructions, and the debugger can show where the program stopped.
+//!
+//! The program starts stopped before its first instruction. It can be stepped
+//! through one instruction at a time, or run until it reaches a breakpoint or a
+//! watched cell on the tape changes. When it's stopped, the register, the tape
+//! around the pointer, the stack of dereferences, and the call stack can be shown.
+
+use crate::read_source;
+use sage::{
+    vm::{SourceMap, StandardDevice, StandardInterpreter, StandardOp, StandardProgram},
+    Artifacts,
+};
+use std::{
+    collections::BTreeMap,
+    io::{self, IsTerminal, Write},
+};
+
+const HELP: &str = "\
+Commands:
+  step, s              Run the next instruction
+  next, n              Run the next instruction, stepping over function calls
+  finish, f            Run until the current function returns
+  continue, c          Run until a breakpoint or a watchpoint stops the program
+  run, r               Restart the program from the beginning
+  break, b <place>     Stop at an instruction `<n>`, or a line `:<line>` or `<file>:<line>`
+  delete, d [<n>]      Delete the breakpoint with the given number, or all of them
+  watch, w <address>   Stop when the cell at an address on the tape changes
+  unwatch <address>    Stop watching the cell at an address
+  info, i              List the breakpoints and watchpoints
+  print, p             Print the register, the pointer, and the next instruction
+  tape, t [<radius>]   Print the cells on the tape around the pointer
+  refs                 Print the stack of pointers saved by dereferences
+  backtrace, bt        Print the call stack
+  list, l              Print the instructions around the next instruction
+  help, h              Print this message
+  quit, q              Exit the debugger
+
+An empty line repeats the last command.";
+
+/// A place in the program where the debugger stops it.
+struct Breakpoint {
+    /// The number the breakpoint is deleted with.
+    id: usize,
+    /// Where the user asked the program to stop.
+    place: String,
+    /// The instructions the program stops at.
+    instructions: Vec<usize>,
+}
+
+/// Why the program stopped running.
+enum Stop {
+    /// The command which ran the program finished.
+    Done,
+    /// The program reached the breakpoint with the given number.
+    Breakpoint(usize),
+    /// A watched cell changed.
+    Watchpoint { address: usize, old: i64, new: i64 },
+    /// The program exited.
+    Exited,
+    /// The interpreter returned an error.
+    Error(String),
+}
+
+/// A program being debugged.
+struct Debugger {
+    /// The program's virtual machine code.
+    code: StandardProgram,
+    /// The source code locations and functions of the program's instructions.
+    source_map: SourceMap,
+    /// The name of the file the program was compiled from.
+    filename: String,
+    /// The source code the program was compiled from.
+    source: String,
+    /// The interpreter running the program.
+    interpreter: StandardInterpreter<StandardDevice>,
+    /// Did the interpreter stop with an error?
+    failed: bool,
+    /// The breakpoints set so far, and the number of the next one.
+    breakpoints: Vec<Breakpoint>,
+    next_breakpoint: usize,
+    /// The watched cells, and the value each one had when it was last checked.
+    watchpoints: BTreeMap<usize, i64>,
+}
+
+impl Debugger {
+    fn new(code: StandardProgram, filename: &str, source: &str) -> Self {
+        let source_map = SourceMap::new(code.0.iter().map(|op| match op {
+            StandardOp::CoreOp(op) => Some(op),
+            _ => None,
+        }));
+        let mut interpreter = StandardInterpreter::new(StandardDevice::default());
+        interpreter.load(&code);
+        Self {
+            code,
+            source_map,
+            filename: filename.to_string(),
+            source: source.to_string(),
+            interpreter,
+            failed: false,
+            breakpoints: vec![],
+            next_breakpoint: 1,
+            watchpoints: BTreeMap::new(),
+        }
+    }
+
+    /// Get the value of a cell on the tape.
+    fn cell(&self, address: usize) -> i64 {
+        self.interpreter.tape().get(address).copied().unwrap_or(0)
+    }
+
+    /// Describe the function an instruction is in.
+    fn function(&self, instruction: usize) -> String {
+        match self.source_map.function(instruction) {
+            Some(n) => match self.source_map.function_name(n) {
+                Some(name) => name.to_string(),
+                None => format!("function #{n}"),
+            },
+            None => "<top level>".to_string(),
+        }
+    }
+
+    /// Describe where an instruction is in the source code.
+    fn location(&self, instruction: usize) -> String {
+        match self.source_map.location(instruction) {
+            Some(loc) => format!(
+                "{}:{}:{}",
+                loc.filename.as_deref().unwrap_or(&self.filename),
+                loc.line,
+                loc.column
+            ),
+            None => "an unknown location".to_string(),
+        }
+    }
+
+    /// Print the next instruction, and the line of source code it belongs to.
+    fn print_position(&self) {
+        let i = self.interpreter.instruction();
+        match self.code.0.get(i) {
+            Some(op) => println!("#{i} {op}"),
+            None => println!("#{i} (end of program)"),
+        }
+        println!("  in {} at {}", self.function(i), self.location(i));
+        if let Some(loc) = self.source_map.location(i) {
+            let source = read_source(loc, &self.source);
+            if let Some(line) = source.lines().nth(loc.line.saturating_sub(1)) {
+                println!("{:>6} | {line}", loc.line);
+            }
+        }
+    }
+
+    /// Run the program until it stops at a breakpoint or a watchpoint, it
+    /// exits, or the given condition holds after an instruction.
+    fn run(&mut self, stop: impl Fn(&StandardInterpreter<StandardDevice>) -> bool) -> Stop {
+        loop {
+            if self.failed || self.interpreter.is_done() {
+                return Stop::Exited;
+            }
+            if let Err(e) = self.interpreter.step(&self.code) {
+                self.failed = true;
+                return Stop::Error(e);
+            }
+            if self.interpreter.is_done() {
+                return Stop::Exited;
+            }
+            let changed = self
+                .watchpoints
+                .iter()
+                .map(|(&address, &old)| (address, old, self.cell(address)))
+                .find(|(_, old, new)| old != new);
+            if let Some((address, old, new)) = changed {
+                self.watchpoints.insert(address, new);
+                return Stop::Watchpoint { address, old, new };
+            }
+            let i = self.interpreter.instruction();
+            if let Some(breakpoint) = self
+                .breakpoints
+                .iter()
+                .find(|breakpoint| breakpoint.instructions.contains(&i))
+            {
+                return Stop::Breakpoint(breakpoint.id);
+            }
+            if stop(&self.interpreter) {
+                return Stop::Done;
+            }
+        }
+    }
+
+    /// Report why the program stopped.
+    fn report(&self, stop: Stop) {
+        match stop {
+            Stop::Done => {}
+            Stop::Breakpoint(id) => println!("Stopped at breakpoint {id}."),
+            Stop::Watchpoint { address, old, new } => {
+                println!("Cell {address} changed from {old} to {new}.")
+            }
+            Stop::Exited if self.failed => {
+                println!("The program stopped with an error, use `run` to restart it.");
+                return;
+            }
+            Stop::Exited => {
+                println!("The program exited.");
+                return;
+            }
+            Stop::Error(e) => {
+                println!("The program stopped with an error: {e}");
+                return;
+            }
+        }
+        self.print_position();
+    }
+
+    /// Start running the program from the beginning again.
+    fn restart(&mut self) {
+        self.interpreter = StandardInterpreter::new(StandardDevice::default());
+        self.interpreter.load(&self.code);
+        self.failed = false;
+        for value in self.watchpoints.values_mut() {
+            *value = 0;
+        }
+        println!("Restarted the program.");
+        self.print_position();
+    }
+
+    /// Set a breakpoint at an instruction, or at a line of source code.
+    fn set_breakpoint(&mut self, place: &str) {
+        let instructions = if let Some((filename, line)) = place.rsplit_once(':') {
+            let filename = if filename.is_empty() {
+                &self.filename
+            } else {
+                filename
+            };
+            match line.parse() {
+                Ok(line) => self.source_map.instructions_at_line(Some(filename), line),
+                Err(_) => {
+                    println!("Invalid line number `{line}`");
+                    return;
+                }
+            }
+        } else {
+            match place.parse() {
+                Ok(i) if i < self.code.0.len() => vec![i],
+                Ok(_) => {
+                    println!("The program only has {} instructions", self.code.0.len());
+                    return;
+                }
+                Err(_) => {
+                    println!(
+                        "Expected an instruction `<n>`, or a line `:<line>` or `<file>:<line>`"
+                    );
+                    return;
+                }
+            }
+        };
+        if instructions.is_empty() {
+            println!("No code was compiled from {place}");
+            return;
+        }
+
+        let id = self.next_breakpoint;
+        self.next_breakpoint += 1;
+        println!(
+            "Breakpoint {id} at {}",
+            instructions
+                .iter()
+                .map(|i| format!("#{i}"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        self.breakpoints.push(Breakpoint {
+            id,
+            place: place.to_string(),
+            instructions,
+        });
+    }
+
+    /// Delete a breakpoint by its number, or all of them.
+    fn delete_breakpoint(&mut self, id: &str) {
+        if id.is_empty() {
+            self.breakpoints.clear();
+            println!("Deleted all breakpoints.");
+            return;
+        }
+        let before = self.breakpoints.len();
+        self.breakpoints
+            .retain(|breakpoint| Some(breakpoint.id) != id.parse().ok());
+        if self.breakpoints.len() == before {
+            println!("No breakpoint {id}");
+        } else {
+            println!("Deleted breakpoint {id}.");
+        }
+    }
+
+    /// Watch a cell on the tape, or stop watching it.
+    fn watch(&mut self, address: &str, watch: bool) {
+        let address = match address.parse() {
+            Ok(address) => address,
+            Err(_) => {
+                println!("Expected the address of a cell on the tape");
+                return;
+            }
+        };
+        if watch {
+            let value = self.cell(address);
+            self.watchpoints.insert(address, value);
+            println!("Watching cell {address}, which holds {value}.");
+        } else if self.watchpoints.remove(&address).is_some() {
+            println!("Stopped watching cell {address}.");
+        } else {
+            println!("Cell {address} isn't being watched");
+        }
+    }
+
+    /// List the breakpoints and watchpoints.
+    fn print_info(&self) {
+        if self.breakpoints.is_empty() && self.watchpoints.is_empty() {
+            println!("No breakpoints or watchpoints.");
+        }
+        for breakpoint in &self.breakpoints {
+            println!("Breakpoint {} at {}", breakpoint.id, breakpoint.place);
+        }
+        for (address, value) in &self.watchpoints {
+            println!("Watching cell {address}, which holds {value}");
+        }
+    }
+
+    /// Print the register, the pointer, and the next instruction.
+    fn print_state(&self) {
+        println!("register = {}", self.interpreter.register());
+        println!("pointer  = {}", self.interpreter.pointer());
+        self.print_position();
+    }
+
+    /// Print the cells on the tape around the pointer.
+    fn print_tape(&self, radius: &str) {
+        let radius = radius.parse().unwrap_or(4);
+        let pointer = self.interpreter.pointer();
+        for address in pointer.saturating_sub(radius)..=pointer + radius {
+            let marker = if address == pointer { ">" } else { " " };
+            println!("{marker} {address:>8}: {}", self.cell(address));
+        }
+    }
+
+    /// Print the stack of pointers saved by dereferences, the most recent first.
+    fn print_refs(&self) {
+        let refs = self.interpreter.refs();
+        if refs.is_empty() {
+            println!("No dereferences to undo.");
+        }
+        for (n, pointer) in refs.iter().rev().enumerate() {
+            println!("#{n} {pointer}");
+        }
+    }
+
+    /// Print the call stack, the current function first.
+    fn print_backtrace(&self) {
+        let current = self.interpreter.instruction();
+        let calls = self.interpreter.calls().iter().rev();
+        for (n, &i) in std::iter::once(&current).chain(calls).enumerate() {
+            println!(
+                "#{n} {} at {} (instruction #{i})",
+                self.function(i),
+                self.location(i)
+            );
+        }
+    }
+
+    /// Print the instructions around the next instruction.
+    fn print_instructions(&self) {
+        let current = self.interpreter.instruction();
+        let end = (current + 6).min(self.code.0.len());
+        for i in current.saturating_sub(5)..end {
+            let marker = if i == current { ">" } else { " " };
+            println!("{marker} #{i:<6} {}", self.code.0[i]);
+        }
+    }
+
+    /// Run a command entered into the debugger. This returns `false` when the debugger should exit.
+    fn run_command(&mut self, line: &str) -> bool {
+        let (command, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let rest = rest.trim();
+        match command {
+            "step" | "s" => {
+                let stop = self.run(|_| true);
+                self.report(stop)
+            }
+            "next" | "n" => {
+                let depth = self.interpreter.calls().len();
+                let stop = self.run(|interpreter| interpreter.calls().len() <= depth);
+                self.report(stop)
+            }
+            "finish" | "f" => {
+                let depth = self.interpreter.calls().len();
+                let stop = self.run(|interpreter| interpreter.calls().len() < depth);
+                self.report(stop)
+            }
+            "continue" | "c" => {
+                let stop = self.run(|_| false);
+                self.report(stop)
+            }
+            "run" | "r" => self.restart(),
+            "break" | "b" => self.set_breakpoint(rest),
+            "delete" | "d" => self.delete_breakpoint(rest),
+            "watch" | "w" => self.watch(rest, true),
+            "unwatch" => self.watch(rest, false),
+            "info" | "i" => self.print_info(),
+            "print" | "p" => self.print_state(),
+            "tape" | "t" => self.print_tape(rest),
+            "refs" => self.print_refs(),
+            "backtrace" | "bt" => self.print_backtrace(),
+            "list" | "l" => self.print_instructions(),
+            "help" | "h" => println!("{HELP}"),
+            "quit" | "q" => return false,
+            _ => println!("Unknown command `{command}`, try `help`"),
+        }
+        true
+    }
+}
+
+/// Debug a compiled program on stdin until it's closed or `quit` is entered.
+/// The prompts are only printed when stdin is a terminal.
+pub(crate) fn debug(artifacts: &Artifacts, filename: &str, source: &str) -> io::Result<()> {
+    let code = match &artifacts.vm {
+        Some(Ok(code)) => code.clone().into(),
+        Some(Err(code)) => code.clone(),
+        None => unreachable!("the debugger compiles the program to virtual machine code"),
+    };
+    let mut debugger = Debugger::new(code, filename, source);
+    let interactive = io::stdin().is_terminal();
+    println!(
+        "Debugging {filename} ({} instructions), type `help` for the commands.",
+        debugger.code.0.len()
+    );
+    debugger.print_position();
+
+    let mut last = String::new();
+    loop {
+        if interactive {
+            print!("(sage) ");
+        }
+        io::stdout().flush()?;
+        // The program reads from stdin too, so it isn't locked between commands.
+        let mut line = String::new();
+        if io::stdin().read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let line = match line.trim() {
+            "" => last.clone(),
+            line => line.to_string(),
+        };
+        if !debugger.run_command(&line) {
+            return Ok(());
+        }
+        last = line;
+    }
+}
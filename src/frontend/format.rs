@@ -0,0 +1,583 @@
+//! # Formatter
+//!
+//! This module implements the formatter for the frontend language, which prints
+//! source code in a canonical style.
+//!
+//! The formatter works on the tokens of the program, and uses the parse tree to
+//! decide where each of them goes: the rule a token belongs to determines the
+//! spacing around it, and the declarations and delimited lists in the program
+//! determine the line breaks and indentation. Comments aren't part of the parse
+//! tree, so they're kept where they were written, relative to the tokens around them.
+
+use super::parse::{comment_spans, strip_comments, FrontendParser, Rule};
+use pest::{iterators::Pair, Parser};
+
+/// The string used for each level of indentation.
+const INDENT: &str = "    ";
+
+/// The punctuation which is lexed as a single token, longest first.
+const PUNCTUATION: &[&str] = &[
+    "..=", ">>>", "..", "->", "=>", "==", "!=", "<=", ">=", "<<", ">>", "&&", "||", "~|", "~&",
+];
+
+/// The rules whose text is printed as a single token.
+const ATOMIC_RULES: &[Rule] = &[
+    Rule::qualified_symbol,
+    Rule::label,
+    Rule::symbol,
+    Rule::int,
+    Rule::float,
+    Rule::string,
+    Rule::char,
+    Rule::stmt_assign_ops,
+    Rule::expr_unary_op,
+    Rule::expr_keyword_unary_op,
+    Rule::closure_move,
+    Rule::const_int,
+    Rule::const_float,
+    Rule::const_bool,
+    Rule::const_char,
+    Rule::const_none,
+    Rule::const_null,
+    Rule::const_symbol,
+    Rule::const_string,
+    Rule::pattern_symbol,
+    Rule::type_int,
+    Rule::type_cell,
+    Rule::type_float,
+    Rule::type_bool,
+    Rule::type_char,
+    Rule::type_none,
+    Rule::type_never,
+];
+
+/// The rules which are printed on their own lines, like declarations and statements in a block.
+const LINE_RULES: &[Rule] = &[
+    Rule::decl,
+    Rule::decl_import,
+    Rule::decl_use,
+    Rule::decl_imp_child_decl,
+    Rule::decl_trait_method,
+];
+
+/// The rules whose braces always contain one item per line.
+const MULTILINE_RULES: &[Rule] = &[
+    Rule::stmt_block,
+    Rule::decl_impl,
+    Rule::decl_trait,
+    Rule::decl_struct,
+    Rule::decl_enum,
+    Rule::stmt_match,
+];
+
+/// The rules whose angle brackets delimit type parameters or arguments.
+const TYPE_BRACKET_RULES: &[Rule] = &[
+    Rule::type_application_suffix,
+    Rule::type_parameters,
+    Rule::bounded_type_parameters,
+    Rule::const_size_of_type,
+];
+
+/// The rules whose opening parenthesis directly follows the name before it.
+const CALL_RULES: &[Rule] = &[
+    Rule::expr_call,
+    Rule::decl_proc_block,
+    Rule::decl_proc_expr,
+    Rule::decl_extern,
+    Rule::decl_trait_method,
+    Rule::const_size_of_type,
+    Rule::const_size_of_expr,
+];
+
+/// The rules of the names which are followed directly by the parenthesized
+/// argument of a call or of an enum variant.
+const NAME_RULES: &[Rule] = &[
+    Rule::symbol,
+    Rule::qualified_symbol,
+    Rule::const_symbol,
+    Rule::pattern_symbol,
+];
+
+/// The rules of the operators written directly before their operand.
+const PREFIX_RULES: &[Rule] = &[
+    Rule::expr_unary_op,
+    Rule::type_ptr,
+    Rule::type_mut_ptr,
+    Rule::pattern_ptr,
+];
+
+/// The kinds of tokens in the source code.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    /// A keyword, a name, or a literal.
+    Word,
+    /// An operator or a delimiter.
+    Punct,
+    /// A comment which runs to the end of the line.
+    LineComment,
+    /// A comment between `/*` and `*/`.
+    BlockComment,
+}
+
+/// A token in the source code.
+#[derive(Clone, Debug)]
+struct Token<'a> {
+    /// The text of the token.
+    text: &'a str,
+    /// The offset of the start of the token in the source code.
+    start: usize,
+    /// The offset of the end of the token in the source code.
+    end: usize,
+    /// The kind of the token.
+    kind: Kind,
+    /// The rule the token belongs to. Comments don't belong to any rule.
+    rule: Option<Rule>,
+    /// The parse tree node the token belongs to.
+    node: usize,
+    /// Whether the token starts a declaration or statement, which goes on its own line.
+    starts_line: bool,
+    /// Whether the token is the bar before the parameters of a closure.
+    opens_params: bool,
+}
+
+impl Token<'_> {
+    fn is_comment(&self) -> bool {
+        matches!(self.kind, Kind::LineComment | Kind::BlockComment)
+    }
+
+    fn belongs_to(&self, rules: &[Rule]) -> bool {
+        self.rule.is_some_and(|rule| rules.contains(&rule))
+    }
+}
+
+/// A pair of delimiters in the source code, and the items between them.
+#[derive(Clone, Debug)]
+struct Group {
+    /// Whether the items start on a new line, instead of right after the opening delimiter.
+    multiline: bool,
+    /// Whether each item goes on its own line. Otherwise, the line breaks between the
+    /// items are kept as they were written.
+    one_per_line: bool,
+    /// Whether there's nothing between the delimiters, not even a comment.
+    empty: bool,
+    /// The index of the trailing comma after the last item, if it can be left out.
+    redundant_comma: Option<usize>,
+}
+
+/// Collects the tokens of a program from its parse tree.
+struct Tokenizer<'a> {
+    /// The code of the program, with its comments stripped.
+    code: &'a str,
+    tokens: Vec<Token<'a>>,
+    nodes: usize,
+}
+
+impl<'a> Tokenizer<'a> {
+    /// Add the tokens of a node in the parse tree.
+    fn visit(&mut self, pair: Pair<Rule>) {
+        let node = self.nodes;
+        self.nodes += 1;
+        let rule = pair.as_rule();
+        let span = pair.as_span();
+        let first = self.tokens.len();
+
+        if ATOMIC_RULES.contains(&rule) {
+            let kind = match rule {
+                Rule::stmt_assign_ops | Rule::expr_unary_op => Kind::Punct,
+                _ => Kind::Word,
+            };
+            self.push(span.start(), span.end(), kind, Some(rule), node);
+        } else {
+            // The text between the children of a node is made of the literals in its rule.
+            let mut offset = span.start();
+            for child in pair.into_inner() {
+                self.lex(offset, child.as_span().start(), rule, node);
+                offset = child.as_span().end();
+                self.visit(child);
+            }
+            self.lex(offset, span.end(), rule, node);
+        }
+
+        if rule == Rule::expr_closure {
+            if let Some(token) = self.tokens[first..]
+                .iter_mut()
+                .find(|token| token.node == node && token.text == "|")
+            {
+                token.opens_params = true;
+            }
+        }
+        if LINE_RULES.contains(&rule) {
+            if let Some(token) = self.tokens.get_mut(first) {
+                token.starts_line = true;
+            }
+        }
+    }
+
+    /// Add the tokens in some text that only contains literals of the given rule.
+    fn lex(&mut self, start: usize, end: usize, rule: Rule, node: usize) {
+        let mut offset = start;
+        while offset < end {
+            let rest = &self.code[offset..end];
+            let c = rest.chars().next().unwrap();
+            let (length, kind) = if c.is_whitespace() {
+                offset += c.len_utf8();
+                continue;
+            } else if c.is_alphanumeric() || c == '_' {
+                let length = rest
+                    .find(|c: char| !c.is_alphanumeric() && c != '_')
+                    .unwrap_or(rest.len());
+                (length, Kind::Word)
+            } else {
+                let length = PUNCTUATION
+                    .iter()
+                    .find(|punct| rest.starts_with(*punct))
+                    .map(|punct| punct.len())
+                    .unwrap_or(c.len_utf8());
+                (length, Kind::Punct)
+            };
+            self.push(offset, offset + length, kind, Some(rule), node);
+            offset += length;
+        }
+    }
+
+    fn push(&mut self, start: usize, end: usize, kind: Kind, rule: Option<Rule>, node: usize) {
+        self.tokens.push(Token {
+            text: &self.code[start..end],
+            start,
+            end,
+            kind,
+            rule,
+            node,
+            starts_line: false,
+            opens_params: false,
+        });
+    }
+}
+
+/// Get the tokens of a program, including its comments, in order.
+/// The program is parsed from the code with its comments stripped.
+fn tokenize<'a>(code: &'a str, stripped: &'a str, program: Pair<Rule>) -> Vec<Token<'a>> {
+    let mut tokenizer = Tokenizer {
+        code: stripped,
+        tokens: vec![],
+        nodes: 0,
+    };
+    tokenizer.visit(program);
+
+    let mut tokens = tokenizer.tokens;
+    for span in comment_spans(code) {
+        let text = code[span.clone()].trim_end();
+        let kind = if text.starts_with("/*") {
+            Kind::BlockComment
+        } else {
+            Kind::LineComment
+        };
+        tokens.push(Token {
+            text,
+            start: span.start,
+            end: span.start + text.len(),
+            kind,
+            rule: None,
+            node: usize::MAX,
+            starts_line: false,
+            opens_params: false,
+        });
+    }
+    tokens.sort_by_key(|token| token.start);
+    tokens
+}
+
+/// The pairs of delimiters in a program.
+struct Groups {
+    /// The group opened by each token.
+    opened: Vec<Option<Group>>,
+    /// The opening delimiter of the group closed by each token.
+    closed: Vec<Option<usize>>,
+    /// The opening delimiter of the innermost group containing each token.
+    enclosing: Vec<Option<usize>>,
+}
+
+impl Groups {
+    /// Find the pairs of delimiters in a program.
+    fn new(code: &str, tokens: &[Token]) -> Self {
+        let mut groups = Self {
+            opened: vec![None; tokens.len()],
+            closed: vec![None; tokens.len()],
+            enclosing: vec![None; tokens.len()],
+        };
+        let mut open = vec![];
+        for (i, token) in tokens.iter().enumerate() {
+            let is_type_bracket = token.belongs_to(TYPE_BRACKET_RULES);
+            let is_close = match token.text {
+                _ if token.is_comment() => false,
+                "(" | "[" | "{" => {
+                    groups.enclosing[i] = open.last().copied();
+                    open.push(i);
+                    continue;
+                }
+                "<" if is_type_bracket => {
+                    groups.enclosing[i] = open.last().copied();
+                    open.push(i);
+                    continue;
+                }
+                ")" | "]" | "}" => true,
+                ">" => is_type_bracket,
+                _ => false,
+            };
+            if !is_close {
+                groups.enclosing[i] = open.last().copied();
+                continue;
+            }
+
+            let start = open.pop().expect("unbalanced delimiters");
+            groups.enclosing[i] = open.last().copied();
+            groups.closed[i] = Some(start);
+            let opening = &tokens[start];
+            let inner = &tokens[start + 1..i];
+            // The items of a list go on their own lines if the first one was written on its own line.
+            let first_code = inner.iter().find(|token| !token.is_comment());
+            let one_per_line = opening.belongs_to(MULTILINE_RULES);
+            let multiline = one_per_line
+                || (opening.text != "<"
+                    && first_code
+                        .is_some_and(|token| code[opening.end..token.start].contains('\n')));
+
+            // A trailing comma is only needed when there's a single item, like in a tuple.
+            let commas = (start + 1..i)
+                .filter(|j| tokens[*j].text == "," && tokens[*j].node == opening.node)
+                .collect::<Vec<_>>();
+            let last_code = (start + 1..i).rev().find(|j| !tokens[*j].is_comment());
+            let redundant_comma = match commas.last() {
+                Some(comma) if commas.len() >= 2 && last_code == Some(*comma) => Some(*comma),
+                _ => None,
+            };
+
+            groups.opened[start] = Some(Group {
+                multiline,
+                one_per_line,
+                empty: inner.is_empty(),
+                redundant_comma,
+            });
+        }
+        groups
+    }
+
+    /// Is this token a comma which is left out?
+    fn is_redundant(&self, i: usize) -> bool {
+        self.enclosing[i]
+            .and_then(|open| self.opened[open].as_ref())
+            .is_some_and(|group| group.redundant_comma == Some(i))
+    }
+
+    /// Does this token close a multiline group?
+    fn closes_multiline(&self, i: usize) -> bool {
+        self.closed[i]
+            .and_then(|open| self.opened[open].as_ref())
+            .is_some_and(|group| group.multiline)
+    }
+
+    /// Get the group whose items this token separates, if it's a comma.
+    fn separated_by(&self, tokens: &[Token], i: usize) -> Option<&Group> {
+        let open = self.enclosing[i]?;
+        if tokens[i].text == "," && tokens[open].node == tokens[i].node {
+            self.opened[open].as_ref()
+        } else {
+            None
+        }
+    }
+}
+
+/// Is there a space between two tokens on the same line?
+fn is_spaced(prev: &Token, token: &Token, is_open: bool, is_close: bool) -> bool {
+    if prev.is_comment() || token.is_comment() {
+        return true;
+    }
+    // Delimiters hug their contents.
+    if is_open || is_close || matches!(token.text, "," | ";") {
+        return false;
+    }
+    match (prev.text, token.text) {
+        // Member access, ranges, and the `?` operator.
+        (".", _) | (_, ".") | ("..", _) | (_, "..") | ("..=", _) | (_, "..=") => false,
+        (_, "?") if token.rule == Some(Rule::expr_try) => false,
+        // Type annotations and labels, but not the ternary operator.
+        (_, ":") => token.rule == Some(Rule::expr_ternary),
+        // Calls, indices, type arguments, and the arguments of enum variants.
+        (_, "(") if token.belongs_to(CALL_RULES) => false,
+        (_, "(") if prev.belongs_to(NAME_RULES) => false,
+        (_, "[") if token.rule == Some(Rule::expr_index) => false,
+        (_, "<") if token.belongs_to(TYPE_BRACKET_RULES) => false,
+        // The parameters of closures.
+        ("|", _) if prev.opens_params => false,
+        (_, "|") if token.rule == Some(Rule::expr_closure) => token.opens_params,
+        // Prefix operators, like `-x`, `&mut x`, and `*x`.
+        _ if prev.belongs_to(PREFIX_RULES) && prev.kind == Kind::Punct => prev
+            .text
+            .ends_with(|c: char| c.is_alphanumeric() || c == '_'),
+        _ => true,
+    }
+}
+
+/// Prints the tokens of a program in the canonical style.
+#[derive(Default)]
+struct Printer {
+    /// The formatted code.
+    out: String,
+    /// The indentation of the delimiters of each open multiline group.
+    levels: Vec<usize>,
+    /// Whether the next token has to start a new line, and if so, whether it starts
+    /// a new item of the enclosing group (as opposed to continuing the current one).
+    pending: Option<bool>,
+}
+
+impl Printer {
+    /// The indentation of a new line: either the start of an item of the innermost
+    /// multiline group, or the continuation of the current item.
+    fn level(&self, starts_item: bool) -> usize {
+        let level = self.levels.last().map(|level| level + 1).unwrap_or(0);
+        if starts_item {
+            level
+        } else {
+            level + 1
+        }
+    }
+
+    /// Start a new line with the given indentation, optionally after a blank line.
+    fn newline(&mut self, level: usize, blank: bool) {
+        if self.out.is_empty() {
+            return;
+        }
+        self.out.truncate(self.out.trim_end_matches(' ').len());
+        self.out.push('\n');
+        if blank {
+            self.out.push('\n');
+        }
+        self.out.push_str(&INDENT.repeat(level));
+    }
+
+    /// Make the next token start a new line.
+    fn break_line(&mut self, starts_item: bool) {
+        self.pending = Some(self.pending.unwrap_or(false) || starts_item);
+    }
+}
+
+/// Format a program in the canonical style, keeping its comments.
+pub fn format(code: impl ToString, filename: Option<&str>) -> Result<String, String> {
+    let code = code.to_string();
+    let stripped = strip_comments(&code);
+    let program = parse_program(&stripped, filename)?;
+    let tokens = tokenize(&code, &stripped, program);
+    let groups = Groups::new(&code, &tokens);
+
+    let mut printer = Printer::default();
+    // The last token printed, and the last token printed which isn't a comment.
+    let mut prev: Option<usize> = None;
+    let mut prev_code: Option<usize> = None;
+    for (i, token) in tokens.iter().enumerate() {
+        // Repeated semicolons and redundant trailing commas are left out.
+        let is_repeated = token.text == ";"
+            && prev_code.is_some_and(|j| tokens[j].text == ";" && tokens[j].node == token.node);
+        if is_repeated || groups.is_redundant(i) {
+            continue;
+        }
+
+        let gap = prev
+            .map(|j| &code[tokens[j].end..token.start])
+            .unwrap_or("");
+        // Blank lines between items are kept, but not at the start or end of a group.
+        let blank = gap.matches('\n').count() >= 2
+            && prev.is_some_and(|j| groups.opened[j].is_none())
+            && groups.closed[i].is_none();
+
+        if token.is_comment() {
+            let trailing = prev.is_some() && !gap.contains('\n');
+            if trailing {
+                printer.out.push(' ');
+            } else {
+                // A comment on its own line is indented like the code after it.
+                let next = (i..tokens.len()).find(|j| !tokens[*j].is_comment());
+                let starts_item = printer.pending == Some(true)
+                    || next.is_none_or(|j| tokens[j].starts_line || groups.closes_multiline(j));
+                printer.newline(printer.level(starts_item), blank);
+            }
+            printer.out.push_str(token.text);
+
+            // Code after a comment goes on the next line, unless it was on the same line.
+            let ends_line = token.kind == Kind::LineComment
+                || !trailing
+                || token.text.contains('\n')
+                || tokens
+                    .get(i + 1)
+                    .is_none_or(|next| code[token.end..next.start].contains('\n'));
+            if ends_line {
+                printer.break_line(false);
+            }
+            prev = Some(i);
+            continue;
+        }
+
+        if let Some(group) = groups.closed[i].and_then(|open| groups.opened[open].as_ref()) {
+            if group.multiline {
+                let level = printer.levels.pop().unwrap();
+                if !group.empty {
+                    printer.newline(level, false);
+                }
+            }
+        } else if token.starts_line {
+            printer.newline(printer.level(true), blank);
+        } else if let Some(starts_item) = printer.pending {
+            printer.newline(printer.level(starts_item), blank);
+        } else if let Some(j) = prev_code.filter(|j| tokens[*j].text == "," && gap.contains('\n')) {
+            // Other line breaks after commas are kept.
+            let starts_item = groups
+                .separated_by(&tokens, j)
+                .is_some_and(|group| group.multiline);
+            printer.newline(printer.level(starts_item), blank && starts_item);
+        } else if let Some(j) = prev {
+            let is_open = groups.opened[j].is_some();
+            let is_close = groups.closed[i].is_some();
+            if is_spaced(&tokens[j], token, is_open, is_close) {
+                printer.out.push(' ');
+            }
+        }
+        printer.pending = None;
+        printer.out.push_str(token.text);
+
+        if let Some(group) = &groups.opened[i] {
+            if group.multiline {
+                printer.levels.push(printer.level(true));
+                if !group.empty {
+                    printer.break_line(true);
+                }
+            }
+        }
+        if groups
+            .separated_by(&tokens, i)
+            .is_some_and(|group| group.one_per_line)
+        {
+            printer.break_line(true);
+        }
+        prev = Some(i);
+        prev_code = Some(i);
+    }
+
+    let mut out = printer.out.trim_end().to_string();
+    if !out.is_empty() {
+        out.push('\n');
+    }
+    // Make sure the formatted code is still a valid program.
+    parse_program(&strip_comments(&out), filename)
+        .map_err(|e| format!("formatting produced invalid code: {e}"))?;
+    Ok(out)
+}
+
+/// Parse a program, with the comments already stripped.
+fn parse_program<'a>(code: &'a str, filename: Option<&str>) -> Result<Pair<'a, Rule>, String> {
+    match FrontendParser::parse(Rule::program, code) {
+        Ok(mut pairs) => Ok(pairs.next().unwrap()),
+        Err(e) => Err(match filename {
+            Some(filename) => e.with_path(filename).to_string(),
+            None => e.to_string(),
+        }),
+    }
+}
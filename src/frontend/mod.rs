@@ -1,19 +1,41 @@
 //! # Frontend Module
-//! 
+//!
 //! This module implements the frontend of the compiler, which is responsible for parsing
 //! the source code and converting it into the LIR.
 
+mod format;
 mod parse;
 use crate::side_effects::Output;
-use no_comment::{languages, IntoWithoutComments};
+pub use format::format;
 use parse::*;
 
+/// Find the declarations of the procedures, types, and constants in some source code,
+/// and the locations of their names. This is used by editor tooling to jump to definitions.
+pub fn definitions(
+    code: impl ToString,
+    filename: Option<&str>,
+) -> Result<Vec<(String, crate::parse::SourceCodeLocation)>, String> {
+    let code = strip_comments(&code.to_string());
+
+    parse_definitions(code.as_ref(), filename).map_err(|e| e.to_string())
+}
+
+/// Is the given source code a single expression (as opposed to statements or declarations)?
+pub fn is_expr(code: impl ToString) -> bool {
+    use pest::Parser;
+    let code = strip_comments(&code.to_string());
+    let code = code.trim();
+
+    match FrontendParser::parse(Rule::expr, code) {
+        Ok(mut pairs) => pairs
+            .next()
+            .is_some_and(|pair| pair.as_span().end() == code.len()),
+        Err(_) => false,
+    }
+}
+
 pub fn parse(code: impl ToString, filename: Option<&str>) -> Result<crate::lir::Expr, String> {
-    let code = code
-        .to_string()
-        .chars()
-        .without_comments(languages::rust())
-        .collect::<String>();
+    let code = strip_comments(&code.to_string());
 
     match parse_frontend(code.as_ref(), filename) {
         Ok(result) => {
@@ -1,10 +1,160 @@
 use crate::{lir::*, parse::SourceCodeLocation};
-use pest::{error::Error, iterators::Pair, Parser};
+use pest::{
+    error::{Error, ErrorVariant},
+    iterators::{Pair, Pairs},
+    Parser, Span,
+};
 use pest_derive::Parser;
+use std::{
+    collections::HashMap,
+    ops::Range,
+    path::{Path, PathBuf},
+};
 
 #[derive(Parser)]
 #[grammar = "frontend/parse.pest"] // relative to src
-struct FrontendParser;
+pub(super) struct FrontendParser;
+
+/// The state used while parsing a single source file.
+#[derive(Clone, Debug, Default)]
+pub struct Context {
+    /// The name of the file being parsed, used for source code locations.
+    filename: Option<String>,
+    /// The namespaces of the imported modules, mapped to the prefix
+    /// their items are declared under.
+    namespaces: HashMap<String, String>,
+    /// The unqualified symbols which refer to an item declared under
+    /// a qualified name: the items of the module being parsed, and
+    /// the items brought into scope with `use`.
+    renames: HashMap<String, String>,
+}
+
+impl Context {
+    fn new(filename: Option<&str>) -> Self {
+        Self {
+            filename: filename.map(|x| x.to_string()),
+            ..Self::default()
+        }
+    }
+
+    /// Get the name an item written in the source code is declared under.
+    fn resolve(&self, name: &str) -> String {
+        match name.split_once("::") {
+            Some((namespace, item)) => match self.namespaces.get(namespace) {
+                Some(prefix) => format!("{prefix}::{item}"),
+                None => name.to_string(),
+            },
+            None => self
+                .renames
+                .get(name)
+                .cloned()
+                .unwrap_or_else(|| name.to_string()),
+        }
+    }
+
+    /// Get the context for a scope where the given names are bound locally,
+    /// and shadow any items with the same names.
+    fn shadow<'a>(&self, names: impl IntoIterator<Item = &'a String>) -> Self {
+        let mut result = self.clone();
+        for name in names {
+            result.renames.remove(name);
+        }
+        result
+    }
+
+    /// Get the location of the given span of this file.
+    fn location(&self, span: Span) -> SourceCodeLocation {
+        let (line, column) = span.start_pos().line_col();
+        SourceCodeLocation {
+            filename: self.filename.clone(),
+            line,
+            column,
+            length: Some(span.end() - span.start()),
+            offset: span.start(),
+        }
+    }
+
+    /// Create an error pointing at the given span of this file.
+    fn error(&self, message: String, span: Span) -> Box<Error<Rule>> {
+        let error = Error::new_from_span(ErrorVariant::CustomError { message }, span);
+        Box::new(match &self.filename {
+            Some(filename) => error.with_path(filename),
+            None => error,
+        })
+    }
+}
+
+/// The modules loaded while parsing a program.
+#[derive(Default)]
+struct Modules {
+    /// The files currently being loaded, in the order they were imported.
+    /// This is used to detect import cycles.
+    loading: Vec<PathBuf>,
+    /// The prefix each loaded file's items are declared under.
+    loaded: HashMap<PathBuf, String>,
+}
+
+impl Modules {
+    /// Load the module imported by the file of the given context.
+    /// The path is relative to the importing file.
+    ///
+    /// This returns the prefix the module's items are declared under,
+    /// and the module's declarations if it hasn't been loaded before.
+    fn load(
+        &mut self,
+        path: &str,
+        namespace: &str,
+        ctx: &Context,
+        span: Span,
+    ) -> Result<(String, Vec<Declaration>), Box<Error<Rule>>> {
+        let path = match &ctx.filename {
+            Some(filename) => Path::new(filename)
+                .parent()
+                .unwrap_or(Path::new(""))
+                .join(path),
+            None => PathBuf::from(path),
+        };
+        let (code, canonical) = std::fs::read_to_string(&path)
+            .and_then(|code| Ok((code, path.canonicalize()?)))
+            .map_err(|e| {
+                ctx.error(
+                    format!("could not read module `{}`: {e}", path.display()),
+                    span,
+                )
+            })?;
+
+        if let Some(start) = self.loading.iter().position(|p| *p == canonical) {
+            let cycle = self.loading[start..]
+                .iter()
+                .chain(std::iter::once(&canonical))
+                .map(|p| p.display().to_string())
+                .collect::<Vec<_>>()
+                .join(" -> ");
+            return Err(ctx.error(format!("import cycle detected: {cycle}"), span));
+        }
+        // A module is only declared the first time it is imported.
+        if let Some(prefix) = self.loaded.get(&canonical) {
+            return Ok((prefix.clone(), vec![]));
+        }
+
+        // Make sure two different files with the same name don't
+        // declare their items under the same prefix.
+        let mut prefix = namespace.to_string();
+        let mut count = 1;
+        while self.loaded.values().any(|p| *p == prefix) {
+            count += 1;
+            prefix = format!("{namespace}{count}");
+        }
+        self.loaded.insert(canonical.clone(), prefix.clone());
+
+        let code = strip_comments(&code);
+        let ctx = Context::new(Some(&path.to_string_lossy()));
+        self.loading.push(canonical);
+        let decls = parse_source(&code, ctx, Some(&prefix), self);
+        self.loading.pop();
+        Ok((prefix, decls?))
+    }
+}
 
 #[derive(Clone, Debug)]
 pub enum Statement {
@@ -19,9 +169,12 @@ pub enum Statement {
     If(Expr, Box<Self>, Option<Box<Self>>),
     When(ConstExpr, Box<Self>, Option<Box<Self>>),
     IfLet(Pattern, Expr, Box<Self>, Option<Box<Self>>),
-    While(Expr, Box<Self>),
-    For(Box<Self>, Expr, Box<Self>, Box<Self>),
+    While(Option<String>, Expr, Box<Self>),
+    For(Option<String>, Box<Self>, Expr, Box<Self>, Box<Self>),
+    ForIn(Option<String>, Pattern, Expr, Box<Self>),
     Return(Expr),
+    Break(Option<String>),
+    Continue(Option<String>),
     Block(Vec<Declaration>),
     LetIn(Vec<(String, Mutability, Option<Type>, Expr)>, Box<Self>),
     LetStaticIn(Vec<(String, Mutability, Type, ConstExpr)>, Box<Self>),
@@ -29,6 +182,20 @@ pub enum Statement {
 }
 
 impl Statement {
+    /// Get the names this statement binds in the rest of its scope.
+    fn get_bound_names(&self) -> Vec<String> {
+        match self {
+            Self::AnnotatedWithSource { stmt, .. } => stmt.get_bound_names(),
+            Self::Let(defs) => defs.iter().map(|(name, ..)| name.clone()).collect(),
+            Self::LetStatic(defs) => defs.iter().map(|(name, ..)| name.clone()).collect(),
+            Self::LetPattern(defs) => defs
+                .iter()
+                .flat_map(|(pat, _)| get_pattern_names(pat))
+                .collect(),
+            _ => vec![],
+        }
+    }
+
     fn with_loc(self, loc: SourceCodeLocation) -> Self {
         match self {
             Self::AnnotatedWithSource { .. } => self,
@@ -44,6 +211,20 @@ impl Statement {
 
         let stmt = match (self, rest.clone()) {
             (Self::AnnotatedWithSource { stmt, loc }, _) => {
+                // Point the initial values of variables at their own `let` statement,
+                // because consecutive `let` statements are merged into one declaration.
+                // The declaration itself isn't annotated, so the code which runs the
+                // rest of its scope doesn't belong to the first `let` statement.
+                if let Self::Let(defs) = *stmt {
+                    return Self::Let(
+                        defs.into_iter()
+                            .map(|(name, mutability, ty, expr)| {
+                                (name, mutability, ty, expr.annotate(loc.clone()))
+                            })
+                            .collect(),
+                    )
+                    .to_expr(rest);
+                }
                 return stmt.to_expr(rest).annotate(loc);
             }
             (Self::Assign(lhs, op, rhs), _) => {
@@ -85,17 +266,67 @@ impl Statement {
                 Box::new(body.to_expr(None)),
                 Box::new(else_body.to_expr(None)),
             ),
-            (Self::While(cond, body), _) => {
-                Expr::While(Box::new(cond), Box::new(body.to_expr(None)))
+            (Self::While(label, cond, body), _) => {
+                let result = Expr::While(Box::new(cond), Box::new(body.to_expr(None)));
+                match label {
+                    Some(label) => result.labeled(label),
+                    None => result,
+                }
             }
-            (Self::For(init, cond, step, body), _) => {
-                // init.to_expr(Some(Expr::While(Box::new(cond), Box::new(Expr::Many(vec![body.to_expr(None), step.to_expr(None)])))))
-                init.to_expr(Some(Expr::While(
-                    Box::new(cond),
-                    Box::new(body.to_expr(Some(step.to_expr(None)))),
-                )))
+            (Self::For(label, init, cond, step, body), _) => {
+                let body = body.to_expr(None);
+                let step = step.to_expr(None);
+                let continues = body.get_loop_exits().iter().any(|exit| match exit {
+                    Expr::Continue(None) => true,
+                    Expr::Continue(Some(name)) => Some(name) == label.as_ref(),
+                    _ => false,
+                });
+                let label_loop = |result: Expr| match &label {
+                    Some(label) => result.labeled(label),
+                    None => result,
+                };
+
+                init.to_expr(Some(if continues {
+                    // A `continue` skips the rest of the body, but the step must still run.
+                    // So, run the step before checking the condition on every iteration
+                    // except for the first one. The flag's name can't be written in the
+                    // source, so it can't be shadowed.
+                    let first = Expr::var("#first");
+                    Expr::let_var(
+                        "#first",
+                        Mutability::Mutable,
+                        Some(Type::Bool),
+                        ConstExpr::Bool(true),
+                        label_loop(Expr::While(
+                            Box::new(Expr::Many(vec![
+                                first.clone().if_then(
+                                    first
+                                        .refer(Mutability::Mutable)
+                                        .deref_mut(ConstExpr::Bool(false)),
+                                    step,
+                                ),
+                                cond,
+                            ])),
+                            Box::new(body),
+                        )),
+                    )
+                } else {
+                    label_loop(Expr::While(
+                        Box::new(cond),
+                        Box::new(Expr::Many(vec![body, step])),
+                    ))
+                }))
+            }
+            (Self::ForIn(label, pat, iterable, body), _) => {
+                let result = Expr::ForIn(pat, Box::new(iterable), Box::new(body.to_expr(None)));
+                match label {
+                    Some(label) => result.labeled(label),
+                    None => result,
+                }
             }
             (Self::Return(val), _) => Expr::Return(Box::new(val)),
+            (Self::Break(label), _) => Expr::Break(label),
+            (Self::Continue(label), _) => Expr::Continue(label),
 
             (Self::Block(stmts), Some(Expr::Many(mut rest))) => {
                 rest.insert(
@@ -162,33 +393,88 @@ impl Statement {
 #[derive(Clone, Debug)]
 pub enum Declaration {
     Impl(Type, Vec<(String, ConstExpr)>),
+    Trait(String, Vec<(String, Type)>),
+    /// An implementation of a trait, with the location of its `impl ... for ...` header.
+    ImplTrait(String, Type, Vec<(String, ConstExpr)>, SourceCodeLocation),
     Struct(String, Vec<(String, Type)>),
     Extern(String, Vec<(Option<String>, Type)>, Type),
     Enum(String, Vec<(String, Option<Type>)>),
     Const(Vec<(String, ConstExpr)>),
+    /// A procedure, with the locations of its signature and of each of its parameters.
     Proc(
         String,
         Vec<(String, Mutability, Type)>,
         Option<Type>,
         Box<Statement>,
+        (SourceCodeLocation, Vec<SourceCodeLocation>),
     ),
+    /// A polymorphic procedure, with the locations of its signature and of each of its parameters.
     PolyProc(
         String,
         Vec<String>,
+        Vec<(String, String)>,
         Vec<(String, Mutability, Type)>,
         Option<Type>,
         Box<Statement>,
+        (SourceCodeLocation, Vec<SourceCodeLocation>),
     ),
     Type(Vec<(String, Type)>),
     Statement(Statement),
 }
 
 impl Declaration {
+    /// Declare the items defined by this declaration under the given module prefix.
+    fn qualify(self, prefix: &str) -> Self {
+        let qualify = |name: String| format!("{prefix}::{name}");
+        match self {
+            Self::Struct(name, fields) => Self::Struct(qualify(name), fields),
+            Self::Extern(name, args, ret) => Self::Extern(qualify(name), args, ret),
+            Self::Enum(name, variants) => Self::Enum(qualify(name), variants),
+            Self::Const(consts) => Self::Const(
+                consts
+                    .into_iter()
+                    .map(|(name, c)| (qualify(name), c))
+                    .collect(),
+            ),
+            Self::Proc(name, params, ret, body, loc) => {
+                Self::Proc(qualify(name), params, ret, body, loc)
+            }
+            Self::PolyProc(name, ty_params, bounds, params, ret, body, loc) => {
+                Self::PolyProc(qualify(name), ty_params, bounds, params, ret, body, loc)
+            }
+            Self::Trait(name, methods) => Self::Trait(qualify(name), methods),
+            Self::Type(types) => Self::Type(
+                types
+                    .into_iter()
+                    .map(|(name, ty)| (qualify(name), ty))
+                    .collect(),
+            ),
+            Self::Impl(..) | Self::ImplTrait(..) | Self::Statement(..) => self,
+        }
+    }
+
+    /// Get the names this declaration binds in the rest of its scope.
+    fn get_bound_names(&self) -> Vec<String> {
+        match self {
+            Self::Struct(name, _)
+            | Self::Extern(name, _, _)
+            | Self::Enum(name, _)
+            | Self::Proc(name, ..)
+            | Self::Trait(name, _)
+            | Self::PolyProc(name, ..) => vec![name.clone()],
+            Self::Const(consts) => consts.iter().map(|(name, _)| name.clone()).collect(),
+            Self::Type(types) => types.iter().map(|(name, _)| name.clone()).collect(),
+            Self::Statement(stmt) => stmt.get_bound_names(),
+            Self::Impl(..) | Self::ImplTrait(..) => vec![],
+        }
+    }
+
     fn proc_to_expr(
         name: String,
         args: Vec<(String, Mutability, Type)>,
         ret: Option<Type>,
         body: Statement,
+        (loc, param_locs): (SourceCodeLocation, Vec<SourceCodeLocation>),
     ) -> Procedure {
         Procedure::new(
             Some(name),
@@ -196,6 +482,29 @@ impl Declaration {
             ret.unwrap_or(Type::None),
             body.to_expr(None),
         )
+        .with_annotation(Annotation::Location(loc))
+        .with_param_annotations(param_locs.into_iter().map(Annotation::Location).collect())
+    }
+
+    fn poly_proc_to_expr(
+        name: String,
+        ty_params: Vec<String>,
+        bounds: Vec<(String, String)>,
+        args: Vec<(String, Mutability, Type)>,
+        ret: Option<Type>,
+        body: Statement,
+        (loc, param_locs): (SourceCodeLocation, Vec<SourceCodeLocation>),
+    ) -> PolyProcedure {
+        PolyProcedure::new(
+            name,
+            ty_params,
+            args,
+            ret.unwrap_or(Type::None),
+            body.to_expr(None),
+        )
+        .with_bounds(bounds)
+        .with_annotation(Annotation::Location(loc))
+        .with_param_annotations(param_locs.into_iter().map(Annotation::Location).collect())
     }
 
     fn to_expr(self, rest: Option<Expr>) -> Expr {
@@ -204,7 +513,8 @@ impl Declaration {
             (Self::Extern(name, args, ret), rest) => Self::Const(vec![(
                 name.clone(),
                 ConstExpr::FFIProcedure(FFIProcedure::new(
-                    name,
+                    // The foreign symbol is never qualified with a module prefix.
+                    name.rsplit("::").next().unwrap_or(&name).to_string(),
                     args.into_iter().map(|(_, x)| x).collect(),
                     ret,
                 )),
@@ -213,6 +523,17 @@ impl Declaration {
             (Self::Impl(ty, methods), _) => {
                 rest_expr.with(crate::lir::Declaration::Impl(ty, methods))
             }
+            (Self::Trait(name, methods), _) => {
+                rest_expr.with(crate::lir::Declaration::Trait(name, methods))
+            }
+            (Self::ImplTrait(trait_name, ty, methods, loc), _) => {
+                rest_expr.with(crate::lir::Declaration::ImplTrait(
+                    trait_name,
+                    ty,
+                    methods,
+                    Annotation::Location(loc),
+                ))
+            }
             (Self::Struct(name, fields), _) => {
                 rest_expr.with((name, Type::Struct(fields.into_iter().collect())))
             }
@@ -255,19 +576,18 @@ impl Declaration {
                 })
             }
             (Self::Const(consts), _) => rest_expr.with(consts),
-            (Self::Proc(name, params, ret, stmt), _) => {
-                rest_expr.with((name.clone(), Self::proc_to_expr(name, params, ret, *stmt)))
-            }
-            (Self::PolyProc(name, ty_params, params, ret, stmt), _) => rest_expr.with((
+            (Self::Proc(name, params, ret, stmt, loc), _) => rest_expr.with((
                 name.clone(),
-                ConstExpr::PolyProc(PolyProcedure::new(
-                    name,
-                    ty_params,
-                    params,
-                    ret.unwrap_or(Type::None),
-                    stmt.to_expr(None),
-                )),
+                Self::proc_to_expr(name, params, ret, *stmt, loc),
             )),
+            (Self::PolyProc(name, ty_params, bounds, params, ret, stmt, loc), _) => {
+                rest_expr.with((
+                    name.clone(),
+                    ConstExpr::PolyProc(Self::poly_proc_to_expr(
+                        name, ty_params, bounds, params, ret, *stmt, loc,
+                    )),
+                ))
+            }
             (Self::Type(types), _) => rest_expr.with(types),
             (Self::Statement(stmt), Some(rest)) => stmt.to_expr(Some(rest)),
             (Self::Statement(stmt), None) => stmt.to_expr(None),
@@ -294,8 +614,274 @@ impl Program {
 }
 
 pub fn parse_frontend(code: &str, filename: Option<&str>) -> Result<Expr, Box<Error<Rule>>> {
-    let x = FrontendParser::parse(Rule::program, code)?;
-    Ok(parse_program(x.into_iter().next().unwrap(), filename).to_expr())
+    let mut modules = Modules::default();
+    // Importing the root file from one of its modules is a cycle too.
+    if let Some(path) = filename.and_then(|x| Path::new(x).canonicalize().ok()) {
+        modules.loading.push(path);
+    }
+    let decls = parse_source(code, Context::new(filename), None, &mut modules)?;
+    Ok(Program(decls).to_expr())
+}
+
+/// Parse the declarations of a source file, along with the declarations
+/// of the modules it imports. If the file is a module, its top-level items
+/// are declared under the given prefix.
+fn parse_source(
+    code: &str,
+    mut ctx: Context,
+    prefix: Option<&str>,
+    modules: &mut Modules,
+) -> Result<Vec<Declaration>, Box<Error<Rule>>> {
+    let program = FrontendParser::parse(Rule::program, code)
+        .map_err(|e| {
+            Box::new(match &ctx.filename {
+                Some(filename) => e.with_path(filename),
+                None => e,
+            })
+        })?
+        .next()
+        .unwrap();
+    let pairs = program.into_inner().collect::<Vec<_>>();
+
+    if let Some(prefix) = prefix {
+        for pair in &pairs {
+            for name in parse_declared_names(pair.clone()) {
+                let qualified = format!("{prefix}::{name}");
+                ctx.renames.insert(name, qualified);
+            }
+        }
+    }
+
+    // Load the imported modules first, so that their items
+    // can be referred to anywhere in the file.
+    let mut imports = HashMap::new();
+    for (i, pair) in pairs.iter().enumerate() {
+        match pair.as_rule() {
+            Rule::decl_import => {
+                let mut inner_rules = pair.clone().into_inner();
+                let path = inner_rules.next().unwrap().into_inner().as_str();
+                let namespace = match inner_rules.next() {
+                    Some(alias) => alias.as_str().to_string(),
+                    None => Path::new(path)
+                        .file_stem()
+                        .map(|x| x.to_string_lossy())
+                        .unwrap_or_default()
+                        .replace(|c: char| !c.is_ascii_alphanumeric(), "_"),
+                };
+                // Two modules under one namespace would shadow each other's items.
+                if ctx.namespaces.contains_key(&namespace) {
+                    return Err(ctx.error(
+                        format!("the namespace `{namespace}` is already used by another import, give this module a different one with `as`"),
+                        pair.as_span(),
+                    ));
+                }
+                let (prefix, decls) = modules.load(path, &namespace, &ctx, pair.as_span())?;
+                ctx.namespaces.insert(namespace, prefix);
+                imports.insert(i, decls);
+            }
+            Rule::decl_use => {
+                let mut inner_rules = pair.clone().into_inner();
+                let path = inner_rules.next().unwrap().as_str();
+                let (namespace, item) = match path.rsplit_once("::") {
+                    Some((namespace, item)) if ctx.namespaces.contains_key(namespace) => {
+                        (namespace, item)
+                    }
+                    Some((namespace, _)) => {
+                        return Err(ctx.error(
+                            format!("module `{namespace}` has not been imported"),
+                            pair.as_span(),
+                        ))
+                    }
+                    None => {
+                        return Err(ctx.error(
+                            format!("expected a module item, like `module::{path}`"),
+                            pair.as_span(),
+                        ))
+                    }
+                };
+                let alias = inner_rules.next().map(|x| x.as_str()).unwrap_or(item);
+                let qualified = format!("{}::{item}", ctx.namespaces[namespace]);
+                ctx.renames.insert(alias.to_string(), qualified);
+            }
+            _ => {}
+        }
+    }
+
+    let mut decls = vec![];
+    for (i, pair) in pairs.into_iter().enumerate() {
+        match pair.as_rule() {
+            Rule::decl_import => decls.extend(imports.remove(&i).unwrap()),
+            Rule::decl_use => {}
+            _ => {
+                let decl = parse_decl(pair, &ctx);
+                decls.push(match prefix {
+                    Some(prefix) => decl.qualify(prefix),
+                    None => decl,
+                });
+            }
+        }
+    }
+    Ok(decls)
+}
+
+/// Get the names of the items defined by a top-level declaration.
+fn parse_declared_names(pair: Pair<Rule>) -> Vec<String> {
+    match pair.as_rule() {
+        Rule::decl | Rule::decl_proc => pair.into_inner().flat_map(parse_declared_names).collect(),
+        Rule::decl_proc_block
+        | Rule::decl_proc_expr
+        | Rule::decl_type
+        | Rule::decl_unit
+        | Rule::decl_struct
+        | Rule::decl_enum
+        | Rule::decl_const
+        | Rule::decl_extern => pair
+            .into_inner()
+            .filter(|x| x.as_rule() == Rule::symbol)
+            .map(|x| x.as_str().to_string())
+            .collect(),
+        _ => vec![],
+    }
+}
+
+/// Find the byte ranges of the comments in some source code: line comments starting
+/// with `//` or `#`, and block comments between `/*` and `*/`, which nest. Comment markers
+/// inside string and character literals are left alone.
+pub(super) fn comment_spans(code: &str) -> Vec<Range<usize>> {
+    let chars = code.char_indices().collect::<Vec<_>>();
+    let offset = |i: usize| {
+        chars
+            .get(i)
+            .map(|(offset, _)| *offset)
+            .unwrap_or(code.len())
+    };
+    let char_at = |i: usize| chars.get(i).map(|(_, c)| *c);
+
+    let mut spans = vec![];
+    let mut i = 0;
+    while let Some(c) = char_at(i) {
+        match (c, char_at(i + 1)) {
+            // A line comment, up to the newline.
+            ('/', Some('/')) | ('#', _) => {
+                let start = i;
+                while char_at(i).is_some_and(|c| c != '\n') {
+                    i += 1;
+                }
+                spans.push(offset(start)..offset(i));
+                continue;
+            }
+            // A block comment, which may contain other block comments.
+            ('/', Some('*')) => {
+                let start = i;
+                let mut depth = 0;
+                while let Some(c) = char_at(i) {
+                    match (c, char_at(i + 1)) {
+                        ('/', Some('*')) => depth += 1,
+                        ('*', Some('/')) => depth -= 1,
+                        _ => {
+                            i += 1;
+                            continue;
+                        }
+                    }
+                    i += 2;
+                    if depth == 0 {
+                        break;
+                    }
+                }
+                spans.push(offset(start)..offset(i));
+                continue;
+            }
+            // A string literal, which may contain escaped quotes.
+            ('"', _) => {
+                i += 1;
+                while char_at(i).is_some_and(|c| c != '"') {
+                    if char_at(i) == Some('\\') {
+                        i += 1;
+                    }
+                    i += 1;
+                }
+            }
+            // An escaped character literal, like `'\''`.
+            ('\'', Some('\\')) => {
+                i += 3;
+                continue;
+            }
+            // A character literal, like `'/'`. Otherwise, this is a loop label.
+            ('\'', Some(_)) if char_at(i + 2) == Some('\'') => i += 2,
+            _ => {}
+        }
+        i += 1;
+    }
+    spans
+}
+
+/// Replace the comments in some source code with spaces, so the locations of the rest
+/// of the code don't change. Newlines in block comments are kept.
+pub fn strip_comments(code: &str) -> String {
+    let mut result = String::with_capacity(code.len());
+    let mut last = 0;
+    for span in comment_spans(code) {
+        result.push_str(&code[last..span.start]);
+        // Replace each character with as many spaces as it has bytes.
+        for c in code[span.clone()].chars() {
+            if c == '\n' {
+                result.push('\n');
+            } else {
+                result.extend(std::iter::repeat_n(' ', c.len_utf8()));
+            }
+        }
+        last = span.end;
+    }
+    result.push_str(&code[last..]);
+    result
+}
+
+/// Find the declarations of the procedures, types, and constants in some source code,
+/// at any depth, and the locations of their names. This doesn't load any imported modules.
+pub fn parse_definitions(
+    code: &str,
+    filename: Option<&str>,
+) -> Result<Vec<(String, SourceCodeLocation)>, Box<Error<Rule>>> {
+    let program = FrontendParser::parse(Rule::program, code)
+        .map_err(|e| {
+            Box::new(match filename {
+                Some(filename) => e.with_path(filename),
+                None => e,
+            })
+        })?
+        .next()
+        .unwrap();
+
+    let mut definitions = vec![];
+    for pair in program.into_inner().flatten() {
+        match pair.as_rule() {
+            Rule::decl_proc_block
+            | Rule::decl_proc_expr
+            | Rule::decl_type
+            | Rule::decl_unit
+            | Rule::decl_struct
+            | Rule::decl_enum
+            | Rule::decl_trait
+            | Rule::decl_const
+            | Rule::decl_extern => {}
+            _ => continue,
+        }
+        for symbol in pair.into_inner().filter(|x| x.as_rule() == Rule::symbol) {
+            let span = symbol.as_span();
+            let (line, column) = span.start_pos().line_col();
+            definitions.push((
+                symbol.as_str().to_string(),
+                SourceCodeLocation {
+                    filename: filename.map(|x| x.to_string()),
+                    line,
+                    column,
+                    length: Some(span.end() - span.start()),
+                    offset: span.start(),
+                },
+            ));
+        }
+    }
+    Ok(definitions)
 }
 
 fn parse_symbol(pair: Pair<Rule>) -> (Mutability, String) {
@@ -309,45 +895,74 @@ fn parse_symbol(pair: Pair<Rule>) -> (Mutability, String) {
     }
 }
 
-fn parse_program(pair: Pair<Rule>, filename: Option<&str>) -> Program {
-    Program(pair.into_inner().map(|x| parse_decl(x, filename)).collect())
-}
-
-fn parse_decl(pair: Pair<Rule>, filename: Option<&str>) -> Declaration {
+fn parse_decl(pair: Pair<Rule>, ctx: &Context) -> Declaration {
     match pair.as_rule() {
         Rule::decl | Rule::decl_proc => pair
             .into_inner()
-            .map(|x| parse_decl(x, filename))
+            .map(|x| parse_decl(x, ctx))
             .next()
             .unwrap(),
 
+        Rule::decl_trait => {
+            let mut inner_rules = pair.into_inner();
+            let name = inner_rules.next().unwrap().as_str().to_string();
+            let mut methods = vec![];
+            for pair in inner_rules {
+                let mut inner_rules = pair.into_inner();
+                let method = inner_rules.next().unwrap().as_str().to_string();
+                let mut params = vec![];
+                let mut ret = Type::None;
+                for pair in inner_rules {
+                    match pair.as_rule() {
+                        Rule::decl_proc_param => {
+                            let mut inner_rules = pair.into_inner();
+                            inner_rules.next();
+                            params.push(parse_type(inner_rules.next().unwrap(), ctx));
+                        }
+                        Rule::r#type => ret = parse_type(pair, ctx),
+                        other => panic!("unexpected rule {:?}", other),
+                    }
+                }
+                methods.push((method, Type::Proc(params, Box::new(ret))));
+            }
+            Declaration::Trait(name, methods)
+        }
+
         Rule::decl_impl => {
+            let span = pair.as_span();
             let mut inner_rules = pair.into_inner();
-            let ty = parse_type(inner_rules.next().unwrap());
+            let mut trait_name = None;
+            if inner_rules.peek().map(|pair| pair.as_rule()) == Some(Rule::trait_name) {
+                trait_name = Some(ctx.resolve(inner_rules.next().unwrap().as_str()));
+            }
+            let ty_pair = inner_rules.next().unwrap();
+            // The header is everything up to the end of the implementing type.
+            let (line, column) = span.start_pos().line_col();
+            let loc = SourceCodeLocation {
+                filename: ctx.filename.clone(),
+                line,
+                column,
+                length: Some(ty_pair.as_span().end() - span.start()),
+                offset: span.start(),
+            };
+            let ty = parse_type(ty_pair, ctx);
             let mut constants = vec![];
             while inner_rules.peek().is_some() {
-                let decl = parse_decl(inner_rules.next().unwrap(), filename);
+                let decl = parse_decl(inner_rules.next().unwrap(), ctx);
                 match decl {
                     Declaration::Const(mut decls) => constants.append(&mut decls),
-                    Declaration::Proc(name, args, ret, body) => constants.push((
+                    Declaration::Proc(name, args, ret, body, loc) => constants.push((
                         name.clone(),
-                        ConstExpr::Proc(Procedure::new(
-                            Some(name),
-                            args,
-                            ret.unwrap_or(Type::None),
-                            body.to_expr(None),
-                        )),
-                    )),
-                    Declaration::PolyProc(name, ty_params, args, ret, body) => constants.push((
-                        name.clone(),
-                        ConstExpr::PolyProc(PolyProcedure::new(
-                            name,
-                            ty_params,
-                            args,
-                            ret.unwrap_or(Type::None),
-                            body.to_expr(None),
-                        )),
+                        ConstExpr::Proc(Declaration::proc_to_expr(name, args, ret, *body, loc)),
                     )),
+                    Declaration::PolyProc(name, ty_params, bounds, args, ret, body, loc) => {
+                        constants.push((
+                            name.clone(),
+                            ConstExpr::PolyProc(Declaration::poly_proc_to_expr(
+                                name, ty_params, bounds, args, ret, *body, loc,
+                            )),
+                        ))
+                    }
                     Declaration::Type(types) => {
                         for (name, ty) in types {
                             constants.push((name, ConstExpr::Type(ty)))
@@ -371,54 +986,99 @@ fn parse_decl(pair: Pair<Rule>, filename: Option<&str>) -> Declaration {
                     }
                 }
             }
-            Declaration::Impl(ty, constants)
+            match trait_name {
+                Some(trait_name) => Declaration::ImplTrait(trait_name, ty, constants, loc),
+                None => Declaration::Impl(ty, constants),
+            }
         }
 
-        Rule::decl_imp_child_decl => parse_decl(pair.into_inner().next().unwrap(), filename),
+        Rule::decl_imp_child_decl => parse_decl(pair.into_inner().next().unwrap(), ctx),
 
         Rule::decl_proc_block | Rule::decl_proc_expr => {
+            let span = pair.as_span();
             let mut inner_rules = pair.into_inner();
             let name = inner_rules.next().unwrap().as_str().to_string();
 
             let mut ty_params = vec![];
+            let mut bounds = vec![];
             if let Some(ty_params_pair) = inner_rules.peek() {
-                if ty_params_pair.as_rule() == Rule::type_parameters
+                if ty_params_pair.as_rule() == Rule::bounded_type_parameters
                     && ty_params_pair.into_inner().count() > 0
                 {
                     let ty_params_pair = inner_rules.next().unwrap();
                     for ty_param_pair in ty_params_pair.into_inner() {
-                        ty_params.push(ty_param_pair.as_str().to_string());
+                        let mut inner_rules = ty_param_pair.into_inner();
+                        let ty_param = inner_rules.next().unwrap().as_str().to_string();
+                        for trait_name in inner_rules {
+                            bounds.push((ty_param.clone(), ctx.resolve(trait_name.as_str())));
+                        }
+                        ty_params.push(ty_param);
                     }
                 }
             }
 
+            // The type parameters shadow any items with the same names.
+            let ctx = &ctx.shadow(&ty_params);
             let mut params = vec![];
+            let mut param_locs = vec![];
             let mut ret = None;
             let mut stmt = Statement::Block(vec![]);
+            let mut body_start = span.end();
             for pair in inner_rules {
                 match pair.as_rule() {
                     Rule::decl_proc_param => {
+                        param_locs.push(ctx.location(pair.as_span()));
                         let mut inner_rules = pair.into_inner();
                         let (mutability, name) = parse_symbol(inner_rules.next().unwrap());
-                        let ty = parse_type(inner_rules.next().unwrap());
+                        let ty = parse_type(inner_rules.next().unwrap(), ctx);
                         params.push((name, mutability, ty));
                     }
                     Rule::r#type => {
-                        ret = Some(parse_type(pair));
+                        ret = Some(parse_type(pair, ctx));
                     }
                     Rule::stmt_block => {
-                        stmt = parse_stmt(pair, filename);
+                        body_start = pair.as_span().start();
+                        let ctx = ctx.shadow(params.iter().map(|(name, _, _)| name));
+                        stmt = parse_stmt(pair, &ctx);
                     }
                     Rule::expr => {
-                        stmt = Statement::Expr(parse_expr(pair));
+                        let body_span = pair.as_span();
+                        body_start = body_span.start();
+                        let (line, column) = body_span.start_pos().line_col();
+                        let ctx = ctx.shadow(params.iter().map(|(name, _, _)| name));
+                        // Annotate the body like a statement, so errors in it point at it.
+                        stmt = Statement::AnnotatedWithSource {
+                            stmt: Box::new(Statement::Expr(parse_expr(pair, &ctx))),
+                            loc: SourceCodeLocation {
+                                filename: ctx.filename.clone(),
+                                line,
+                                column,
+                                length: Some(body_span.end() - body_span.start()),
+                                offset: body_span.start(),
+                            },
+                        };
                     }
                     other => panic!("unexpected rule {:?}", other),
                 }
             }
+            // The signature is everything before the body, except the `=`.
+            let signature = span.as_str()[..body_start - span.start()]
+                .trim_end()
+                .trim_end_matches('=')
+                .trim_end();
+            let (line, column) = span.start_pos().line_col();
+            let loc = SourceCodeLocation {
+                filename: ctx.filename.clone(),
+                line,
+                column,
+                length: Some(signature.len()),
+                offset: span.start(),
+            };
+            let locs = (loc, param_locs);
             if ty_params.is_empty() {
-                Declaration::Proc(name, params, ret, Box::new(stmt))
+                Declaration::Proc(name, params, ret, Box::new(stmt), locs)
             } else {
-                Declaration::PolyProc(name, ty_params, params, ret, Box::new(stmt))
+                Declaration::PolyProc(name, ty_params, bounds, params, ret, Box::new(stmt), locs)
             }
         }
         Rule::decl_type => {
@@ -439,7 +1099,7 @@ fn parse_decl(pair: Pair<Rule>, filename: Option<&str>) -> Declaration {
                     }
                 }
 
-                let ty = parse_type(inner_rules.next().unwrap());
+                let ty = parse_type(inner_rules.next().unwrap(), ctx);
                 if ty_params.is_empty() {
                     types.push((name, ty));
                 } else {
@@ -454,7 +1114,7 @@ fn parse_decl(pair: Pair<Rule>, filename: Option<&str>) -> Declaration {
             let mut types = Vec::new();
             while inner_rules.peek().is_some() {
                 let name = inner_rules.next().unwrap().as_str().to_string();
-                let ty = parse_type(inner_rules.next().unwrap());
+                let ty = parse_type(inner_rules.next().unwrap(), ctx);
                 types.push((name.clone(), Type::Unit(name, Box::new(ty))));
             }
 
@@ -480,7 +1140,7 @@ fn parse_decl(pair: Pair<Rule>, filename: Option<&str>) -> Declaration {
             while inner_rules.peek().is_some() {
                 let mut inner_rules = inner_rules.next().unwrap().into_inner();
                 let name = inner_rules.next().unwrap().as_str().to_string();
-                let ty = parse_type(inner_rules.next().unwrap());
+                let ty = parse_type(inner_rules.next().unwrap(), ctx);
                 fields.push((name, ty));
             }
             if ty_params.is_empty() {
@@ -524,7 +1184,8 @@ fn parse_decl(pair: Pair<Rule>, filename: Option<&str>) -> Declaration {
 
                 if let Some(ty) = ty {
                     if ty.as_rule() == Rule::r#type {
-                        variants.push((variant_name.as_str().to_string(), Some(parse_type(ty))));
+                        variants
+                            .push((variant_name.as_str().to_string(), Some(parse_type(ty, ctx))));
                     } else {
                         variants.push((variant_name.as_str().to_string(), None));
                     }
@@ -573,11 +1234,11 @@ fn parse_decl(pair: Pair<Rule>, filename: Option<&str>) -> Declaration {
                     Rule::decl_proc_param => {
                         let mut inner_rules = pair.into_inner();
                         let (_mutability, name) = parse_symbol(inner_rules.next().unwrap());
-                        let ty = parse_type(inner_rules.next().unwrap());
+                        let ty = parse_type(inner_rules.next().unwrap(), ctx);
                         args.push((Some(name), ty));
                     }
                     Rule::r#type => {
-                        ret = Some(parse_type(pair));
+                        ret = Some(parse_type(pair, ctx));
                     }
                     other => panic!("unexpected rule {:?}", other),
                 }
@@ -589,25 +1250,34 @@ fn parse_decl(pair: Pair<Rule>, filename: Option<&str>) -> Declaration {
             let mut defs = Vec::new();
             while inner_rules.peek().is_some() {
                 let name = inner_rules.next().unwrap().as_str().to_string();
-                let expr = parse_const(inner_rules.next().unwrap());
+                let expr = parse_const(inner_rules.next().unwrap(), ctx);
                 defs.push((name, expr));
             }
             Declaration::Const(defs)
         }
-        Rule::stmt | Rule::stmt_block => Declaration::Statement(parse_stmt(pair, filename)),
+        Rule::stmt | Rule::stmt_block => Declaration::Statement(parse_stmt(pair, ctx)),
         Rule::EOI => Declaration::Statement(Statement::Block(vec![])),
         other => panic!("Unexpected rule: {:?}: {:?}", other, pair),
     }
 }
 
-fn parse_stmt(pair: Pair<Rule>, filename: Option<&str>) -> Statement {
+/// Parse the optional label of a loop, or of a `break` or `continue`.
+fn parse_label(pairs: &mut Pairs<Rule>) -> Option<String> {
+    if pairs.peek()?.as_rule() != Rule::label {
+        return None;
+    }
+    // Strip the leading quote.
+    pairs.next().map(|label| label.as_str()[1..].to_string())
+}
+
+fn parse_stmt(pair: Pair<Rule>, ctx: &Context) -> Statement {
     let span = pair.as_span();
     let (line, column) = span.start_pos().line_col();
     let length = span.end_pos().pos() - span.start_pos().pos();
     let offset = span.start_pos().pos();
 
     let loc = SourceCodeLocation {
-        filename: filename.map(|x| x.to_string()),
+        filename: ctx.filename.clone(),
         line,
         column,
         length: Some(length),
@@ -617,7 +1287,7 @@ fn parse_stmt(pair: Pair<Rule>, filename: Option<&str>) -> Statement {
     match pair.as_rule() {
         Rule::stmt | Rule::long_stmt | Rule::short_stmt | Rule::stmt_let_in => pair
             .into_inner()
-            .map(|x| parse_stmt(x, filename))
+            .map(|x| parse_stmt(x, ctx))
             .next()
             .unwrap(),
 
@@ -626,8 +1296,8 @@ fn parse_stmt(pair: Pair<Rule>, filename: Option<&str>) -> Statement {
             let mut defs = vec![];
             while inner_rules.peek().is_some() {
                 let (mutability, symbol) = parse_symbol(inner_rules.next().unwrap());
-                let ty = parse_type(inner_rules.next().unwrap());
-                let expr = parse_const(inner_rules.next().unwrap());
+                let ty = parse_type(inner_rules.next().unwrap(), ctx);
+                let expr = parse_const(inner_rules.next().unwrap(), ctx);
                 defs.push((symbol, mutability, ty, expr));
             }
             Statement::LetStatic(defs)
@@ -638,48 +1308,46 @@ fn parse_stmt(pair: Pair<Rule>, filename: Option<&str>) -> Statement {
             let mut defs = vec![];
             while inner_rules.clone().count() > 1 {
                 let (mutability, symbol) = parse_symbol(inner_rules.next().unwrap());
-                let ty = parse_type(inner_rules.next().unwrap());
-                let expr = parse_const(inner_rules.next().unwrap());
+                let ty = parse_type(inner_rules.next().unwrap(), ctx);
+                let expr = parse_const(inner_rules.next().unwrap(), ctx);
                 defs.push((symbol, mutability, ty, expr));
             }
             let last = inner_rules.next().unwrap();
+            let ctx = &ctx.shadow(defs.iter().map(|(name, ..)| name));
             match last.as_rule() {
-                Rule::stmt_block => {
-                    Statement::LetStaticIn(defs, Box::new(parse_stmt(last, filename)))
-                }
+                Rule::stmt_block => Statement::LetStaticIn(defs, Box::new(parse_stmt(last, ctx))),
                 Rule::expr => {
-                    Statement::LetStaticIn(defs, Box::new(Statement::Expr(parse_expr(last))))
+                    Statement::LetStaticIn(defs, Box::new(Statement::Expr(parse_expr(last, ctx))))
                 }
                 other => unreachable!("Unexpected rule {:?}", other),
             }
         }
-        Rule::stmt_match => Statement::Expr(parse_match(pair)),
+        Rule::stmt_match => Statement::Expr(parse_match(pair, ctx)),
 
         Rule::stmt_block => {
             let inner_rules = pair.into_inner();
+            let mut ctx = ctx.clone();
             let mut stmts = Vec::new();
             for stmt in inner_rules {
-                stmts.push(parse_decl(stmt, filename));
+                let decl = parse_decl(stmt, &ctx);
+                ctx = ctx.shadow(&decl.get_bound_names());
+                stmts.push(decl);
             }
             Statement::Block(stmts)
         }
 
         Rule::stmt_if => {
             let mut inner_rules = pair.into_inner();
-            let cond = parse_expr(inner_rules.next().unwrap());
-            let body = parse_stmt(inner_rules.next().unwrap(), filename);
-            let else_body = inner_rules
-                .next()
-                .map(|x| Box::new(parse_stmt(x, filename)));
+            let cond = parse_expr(inner_rules.next().unwrap(), ctx);
+            let body = parse_stmt(inner_rules.next().unwrap(), ctx);
+            let else_body = inner_rules.next().map(|x| Box::new(parse_stmt(x, ctx)));
             Statement::If(cond, Box::new(body), else_body)
         }
         Rule::stmt_when => {
             let mut inner_rules = pair.into_inner();
-            let cond = parse_const(inner_rules.next().unwrap());
-            let body = parse_stmt(inner_rules.next().unwrap(), filename);
-            let else_body = inner_rules
-                .next()
-                .map(|x| Box::new(parse_stmt(x, filename)));
+            let cond = parse_const(inner_rules.next().unwrap(), ctx);
+            let body = parse_stmt(inner_rules.next().unwrap(), ctx);
+            let else_body = inner_rules.next().map(|x| Box::new(parse_stmt(x, ctx)));
             Statement::When(cond, Box::new(body), else_body)
         }
 
@@ -689,12 +1357,12 @@ fn parse_stmt(pair: Pair<Rule>, filename: Option<&str>) -> Statement {
             for _ in 0..inner_rules.clone().count() / 2 {
                 let cond = inner_rules.next().unwrap();
                 let body = inner_rules.next().unwrap();
-                elifs.push((parse_expr(cond), parse_stmt(body, filename)));
+                elifs.push((parse_expr(cond, ctx), parse_stmt(body, ctx)));
             }
 
             let mut else_body = inner_rules
                 .next()
-                .map(|x| parse_stmt(x, filename))
+                .map(|x| parse_stmt(x, ctx))
                 .unwrap_or(Statement::Block(vec![]));
 
             for (cond, body) in elifs.into_iter().rev() {
@@ -706,31 +1374,31 @@ fn parse_stmt(pair: Pair<Rule>, filename: Option<&str>) -> Statement {
 
         Rule::stmt_if_let => {
             let mut inner_rules = pair.into_inner();
-            let pat = parse_pattern(inner_rules.next().unwrap());
-            let expr = parse_expr(inner_rules.next().unwrap());
-            let body = parse_stmt(inner_rules.next().unwrap(), filename);
-            let else_body = inner_rules
-                .next()
-                .map(|x| Box::new(parse_stmt(x, filename)));
+            let pat = parse_pattern(inner_rules.next().unwrap(), ctx);
+            let expr = parse_expr(inner_rules.next().unwrap(), ctx);
+            let body = parse_stmt(
+                inner_rules.next().unwrap(),
+                &ctx.shadow(&get_pattern_names(&pat)),
+            );
+            let else_body = inner_rules.next().map(|x| Box::new(parse_stmt(x, ctx)));
             Statement::IfLet(pat, expr, Box::new(body), else_body)
         }
         Rule::stmt_if_elif_let => {
             let mut inner_rules = pair.into_inner();
             let mut elifs = vec![];
             while inner_rules.clone().count() > 3 {
-                let pat = inner_rules.next().unwrap();
-                let expr = inner_rules.next().unwrap();
-                let body = inner_rules.next().unwrap();
-                elifs.push((
-                    parse_pattern(pat),
-                    parse_expr(expr),
-                    parse_stmt(body, filename),
-                ));
+                let pat = parse_pattern(inner_rules.next().unwrap(), ctx);
+                let expr = parse_expr(inner_rules.next().unwrap(), ctx);
+                let body = parse_stmt(
+                    inner_rules.next().unwrap(),
+                    &ctx.shadow(&get_pattern_names(&pat)),
+                );
+                elifs.push((pat, expr, body));
             }
 
             let mut else_body = inner_rules
                 .next()
-                .map(|x| parse_stmt(x, filename))
+                .map(|x| parse_stmt(x, ctx))
                 .unwrap_or(Statement::Block(vec![]));
 
             for (pat, expr, body) in elifs.into_iter().rev() {
@@ -742,26 +1410,50 @@ fn parse_stmt(pair: Pair<Rule>, filename: Option<&str>) -> Statement {
 
         Rule::stmt_while => {
             let mut inner_rules = pair.into_inner();
-            let cond = parse_expr(inner_rules.next().unwrap());
-            let body = parse_stmt(inner_rules.next().unwrap(), filename);
-            Statement::While(cond, Box::new(body))
+            let label = parse_label(&mut inner_rules);
+            let cond = parse_expr(inner_rules.next().unwrap(), ctx);
+            let body = parse_stmt(inner_rules.next().unwrap(), ctx);
+            Statement::While(label, cond, Box::new(body))
         }
 
         Rule::stmt_for => {
             let mut inner_rules = pair.into_inner();
-            let pre = parse_stmt(inner_rules.next().unwrap(), filename);
-            let cond = parse_expr(inner_rules.next().unwrap());
-            let post = parse_stmt(inner_rules.next().unwrap(), filename);
-            let body = parse_stmt(inner_rules.next().unwrap(), filename);
-            Statement::For(Box::new(pre), cond, Box::new(post), Box::new(body))
+            let label = parse_label(&mut inner_rules);
+            let pre = parse_stmt(inner_rules.next().unwrap(), ctx);
+            let ctx = &ctx.shadow(&pre.get_bound_names());
+            let cond = parse_expr(inner_rules.next().unwrap(), ctx);
+            let post = parse_stmt(inner_rules.next().unwrap(), ctx);
+            let body = parse_stmt(inner_rules.next().unwrap(), ctx);
+            Statement::For(label, Box::new(pre), cond, Box::new(post), Box::new(body))
         }
 
+        Rule::stmt_for_in => {
+            let mut inner_rules = pair.into_inner();
+            let label = parse_label(&mut inner_rules);
+            let pat = parse_pattern(inner_rules.next().unwrap(), ctx);
+            let iterable = inner_rules.next().unwrap();
+            // The names bound by the pattern shadow any items with the same names.
+            let body_ctx = &ctx.shadow(&get_pattern_names(&pat));
+            let body = Box::new(parse_stmt(inner_rules.next().unwrap(), body_ctx));
+            if iterable.as_rule() == Rule::stmt_for_range {
+                let mut bounds = iterable.into_inner();
+                let start = parse_expr(bounds.next().unwrap(), ctx);
+                let end = parse_expr(bounds.next().unwrap(), ctx);
+                for_range(label, pat, start, end, body)
+            } else {
+                Statement::ForIn(label, pat, parse_expr(iterable, ctx), body)
+            }
+        }
+
+        Rule::stmt_break => Statement::Break(parse_label(&mut pair.into_inner())),
+        Rule::stmt_continue => Statement::Continue(parse_label(&mut pair.into_inner())),
+
         Rule::stmt_let_pat => {
             let mut inner_rules = pair.into_inner();
             let mut defs = vec![];
             while inner_rules.peek().is_some() {
-                let pattern = parse_pattern(inner_rules.next().unwrap());
-                let expr = parse_expr(inner_rules.next().unwrap());
+                let pattern = parse_pattern(inner_rules.next().unwrap(), ctx);
+                let expr = parse_expr(inner_rules.next().unwrap(), ctx);
                 defs.push((pattern, expr));
             }
             Statement::LetPattern(defs)
@@ -774,13 +1466,18 @@ fn parse_stmt(pair: Pair<Rule>, filename: Option<&str>) -> Statement {
                 let (mutability, symbol) = parse_symbol(inner_rules.next().unwrap());
                 let ty = inner_rules.next().unwrap();
                 if ty.as_rule() == Rule::expr {
-                    defs.push((symbol, mutability, None, parse_expr(ty)));
+                    defs.push((symbol, mutability, None, parse_expr(ty, ctx)));
                     continue;
                 }
                 if let Some(expr) = inner_rules.next() {
-                    defs.push((symbol, mutability, Some(parse_type(ty)), parse_expr(expr)));
+                    defs.push((
+                        symbol,
+                        mutability,
+                        Some(parse_type(ty, ctx)),
+                        parse_expr(expr, ctx),
+                    ));
                 } else {
-                    defs.push((symbol, mutability, None, parse_expr(ty)));
+                    defs.push((symbol, mutability, None, parse_expr(ty, ctx)));
                 }
             }
             Statement::Let(defs)
@@ -793,28 +1490,36 @@ fn parse_stmt(pair: Pair<Rule>, filename: Option<&str>) -> Statement {
                 let (mutability, symbol) = parse_symbol(inner_rules.next().unwrap());
                 let ty = inner_rules.next().unwrap();
                 if ty.as_rule() == Rule::expr {
-                    defs.push((symbol, mutability, None, parse_expr(ty)));
+                    defs.push((symbol, mutability, None, parse_expr(ty, ctx)));
                     continue;
                 }
                 if let Some(expr) = inner_rules.next() {
-                    defs.push((symbol, mutability, Some(parse_type(ty)), parse_expr(expr)));
+                    defs.push((
+                        symbol,
+                        mutability,
+                        Some(parse_type(ty, ctx)),
+                        parse_expr(expr, ctx),
+                    ));
                 } else {
-                    defs.push((symbol, mutability, None, parse_expr(ty)));
+                    defs.push((symbol, mutability, None, parse_expr(ty, ctx)));
                 }
             }
             let last = inner_rules.next().unwrap();
+            let ctx = &ctx.shadow(defs.iter().map(|(name, ..)| name));
             match last.as_rule() {
-                Rule::stmt_block => Statement::LetIn(defs, Box::new(parse_stmt(last, filename))),
-                Rule::expr => Statement::LetIn(defs, Box::new(Statement::Expr(parse_expr(last)))),
+                Rule::stmt_block => Statement::LetIn(defs, Box::new(parse_stmt(last, ctx))),
+                Rule::expr => {
+                    Statement::LetIn(defs, Box::new(Statement::Expr(parse_expr(last, ctx))))
+                }
                 other => unreachable!("Unexpected rule {:?}", other),
             }
         }
 
         Rule::stmt_assign => {
             let mut inner_rules = pair.into_inner();
-            let lhs = parse_expr(inner_rules.next().unwrap());
+            let lhs = parse_expr(inner_rules.next().unwrap(), ctx);
             let op = inner_rules.next().unwrap().as_str();
-            let rhs = parse_expr(inner_rules.next().unwrap());
+            let rhs = parse_expr(inner_rules.next().unwrap(), ctx);
             Statement::Assign(
                 lhs,
                 match op {
@@ -827,6 +1532,9 @@ fn parse_stmt(pair: Pair<Rule>, filename: Option<&str>) -> Statement {
                     "&=" => Some(Box::new(Assign::new(BitwiseAnd))),
                     "^=" => Some(Box::new(Assign::new(BitwiseXor))),
                     "|=" => Some(Box::new(Assign::new(BitwiseOr))),
+                    "<<=" => Some(Box::new(Assign::new(BitwiseShift::Left))),
+                    ">>=" => Some(Box::new(Assign::new(BitwiseShift::ArithmeticRight))),
+                    ">>>=" => Some(Box::new(Assign::new(BitwiseShift::LogicalRight))),
                     _ => unreachable!(),
                 },
                 rhs,
@@ -835,17 +1543,57 @@ fn parse_stmt(pair: Pair<Rule>, filename: Option<&str>) -> Statement {
 
         Rule::stmt_return => {
             let mut inner_rules = pair.into_inner();
-            let expr = inner_rules.next().map(parse_expr);
+            let expr = inner_rules.next().map(|x| parse_expr(x, ctx));
             Statement::Return(expr.unwrap_or(Expr::ConstExpr(ConstExpr::None)))
         }
 
-        Rule::expr => Statement::Expr(parse_expr(pair)),
+        Rule::expr => Statement::Expr(parse_expr(pair, ctx)),
 
         other => panic!("Unexpected rule: {:?}: {:?}", other, pair),
     }
     .with_loc(loc)
 }
 
+/// Desugar a loop over a range into a counting `for` loop.
+fn for_range(
+    label: Option<String>,
+    pat: Pattern,
+    start: Expr,
+    end: Expr,
+    body: Box<Statement>,
+) -> Statement {
+    // Count up from the start of the range to its end, which is only
+    // evaluated once. The counter's name can't be written in the source,
+    // so it can't be shadowed.
+    let i = Expr::var("#i");
+    let init = Statement::Let(vec![
+        (
+            "#i".to_string(),
+            Mutability::Mutable,
+            Some(Type::Int),
+            start,
+        ),
+        (
+            "#end".to_string(),
+            Mutability::Immutable,
+            Some(Type::Int),
+            end,
+        ),
+    ]);
+    let step = Statement::Assign(i.clone(), None, i.clone().add(ConstExpr::Int(1)));
+    let body = Statement::Block(vec![
+        Declaration::Statement(Statement::LetPattern(vec![(pat, i.clone())])),
+        Declaration::Statement(*body),
+    ]);
+    Statement::For(
+        label,
+        Box::new(init),
+        i.lt(Expr::var("#end")),
+        Box::new(step),
+        Box::new(body),
+    )
+}
+
 // fn parse_let(pair: Pair<Rule>) -> Statement {
 //     match pair.as_rule() {
 
@@ -870,14 +1618,14 @@ fn parse_stmt(pair: Pair<Rule>, filename: Option<&str>) -> Statement {
 //     }
 // }
 
-pub fn parse_expr(pair: Pair<Rule>) -> Expr {
+pub fn parse_expr(pair: Pair<Rule>, ctx: &Context) -> Expr {
     let span = pair.as_span();
     let (line, column) = span.start_pos().line_col();
     let length = span.end_pos().pos() - span.start_pos().pos();
     let offset = span.start_pos().pos();
 
-    let _loc = SourceCodeLocation {
-        filename: None,
+    let loc = SourceCodeLocation {
+        filename: ctx.filename.clone(),
         line,
         column,
         length: Some(length),
@@ -885,42 +1633,92 @@ pub fn parse_expr(pair: Pair<Rule>) -> Expr {
     };
 
     let result = match pair.as_rule() {
-        Rule::expr | Rule::expr_atom | Rule::expr_group => {
-            pair.into_inner().map(parse_expr).next().unwrap()
-        }
-        Rule::stmt_match => parse_match(pair),
+        Rule::expr | Rule::expr_atom | Rule::expr_group => pair
+            .into_inner()
+            .map(|x| parse_expr(x, ctx))
+            .next()
+            .unwrap(),
+        Rule::stmt_match => parse_match(pair, ctx),
         Rule::expr_logic_factor
         | Rule::expr_logic_term
         | Rule::expr_comparison
+        | Rule::expr_shift
         | Rule::expr_sum
         | Rule::expr_index
         | Rule::expr_factor
         | Rule::expr_bitwise_factor
         | Rule::expr_bitwise_term
-        | Rule::expr_bitwise_atom => parse_binop(pair),
+        | Rule::expr_bitwise_atom => parse_binop(pair, ctx),
         Rule::expr_ternary => {
             let mut inner_rules = pair.into_inner();
-            let cond = parse_expr(inner_rules.next().unwrap());
-            let if_true = parse_expr(inner_rules.next().unwrap());
-            let if_false = parse_expr(inner_rules.next().unwrap());
+            let cond = parse_expr(inner_rules.next().unwrap(), ctx);
+            let if_true = parse_expr(inner_rules.next().unwrap(), ctx);
+            let if_false = parse_expr(inner_rules.next().unwrap(), ctx);
             Expr::If(Box::new(cond), Box::new(if_true), Box::new(if_false))
         }
         Rule::expr_variant => {
             let mut inner_rules = pair.into_inner();
-            let ty = parse_type(inner_rules.next().unwrap());
+            let ty = parse_type(inner_rules.next().unwrap(), ctx);
             let variant = inner_rules.next().unwrap().as_str();
             if let Some(expr) = inner_rules.next() {
-                Expr::EnumUnion(ty, variant.to_string(), Box::new(parse_expr(expr)))
+                Expr::EnumUnion(ty, variant.to_string(), Box::new(parse_expr(expr, ctx)))
             } else {
                 Expr::ConstExpr(ConstExpr::Of(ty, variant.to_string()))
             }
         }
-        Rule::expr_term_non_keyword => parse_expr_term(pair),
+        Rule::expr_term_non_keyword => parse_expr_term(pair, ctx),
+        Rule::expr_closure => {
+            let mut capture = Capture::ByReference;
+            let mut params = vec![];
+            let mut ret = None;
+            let mut body = Expr::NONE;
+            let mut symbols = vec![];
+            for pair in pair.into_inner() {
+                match pair.as_rule() {
+                    Rule::closure_move => capture = Capture::ByValue,
+                    Rule::closure_param => {
+                        let mut inner_rules = pair.into_inner();
+                        let (mutability, name) = parse_symbol(inner_rules.next().unwrap());
+                        // A parameter without a type gets its type inferred from its uses.
+                        let ty = match inner_rules.next() {
+                            Some(ty) => parse_type(ty, ctx),
+                            None => Type::fresh_var(),
+                        };
+                        params.push((name, mutability, ty));
+                    }
+                    Rule::r#type => {
+                        ret = Some(parse_type(pair, ctx));
+                    }
+                    _ => {
+                        // The parameters shadow any items with the same names.
+                        let ctx = ctx.shadow(params.iter().map(|(name, _, _)| name));
+                        // Remember where each symbol is first used in the body, so errors
+                        // about the variables the closure captures can point at them.
+                        for symbol in pair.clone().into_inner().flatten() {
+                            if symbol.as_rule() != Rule::const_symbol {
+                                continue;
+                            }
+                            let name = ctx.resolve(symbol.as_str());
+                            if !symbols.iter().any(|(other, _)| other == &name) {
+                                let location = ctx.location(symbol.as_span());
+                                symbols.push((name, Annotation::Location(location)));
+                            }
+                        }
+                        body = parse_expr(pair, &ctx);
+                    }
+                }
+            }
+            Expr::Closure(
+                Closure::new(capture, params, ret, body)
+                    .with_annotation(Annotation::Location(loc))
+                    .with_symbol_annotations(symbols),
+            )
+        }
         Rule::expr_tuple => {
             let inner_rules = pair.into_inner();
             let mut result = vec![];
             for x in inner_rules {
-                result.push(parse_expr(x));
+                result.push(parse_expr(x, ctx));
             }
             Expr::Tuple(result)
         }
@@ -928,7 +1726,7 @@ pub fn parse_expr(pair: Pair<Rule>) -> Expr {
             let inner_rules = pair.into_inner();
             let mut result = vec![];
             for x in inner_rules {
-                result.push(parse_expr(x));
+                result.push(parse_expr(x, ctx));
             }
             Expr::Array(result)
         }
@@ -937,7 +1735,7 @@ pub fn parse_expr(pair: Pair<Rule>) -> Expr {
             let mut result = vec![];
             while inner_rules.peek().is_some() {
                 let field = inner_rules.next().unwrap().as_str().to_string();
-                let val = parse_expr(inner_rules.next().unwrap());
+                let val = parse_expr(inner_rules.next().unwrap(), ctx);
                 result.push((field, val));
             }
             Expr::Struct(result.into_iter().collect())
@@ -959,16 +1757,16 @@ pub fn parse_expr(pair: Pair<Rule>) -> Expr {
                         "*" => result.deref(),
                         _ => panic!("Unexpected unary op: {}", x.as_str()),
                     },
-                    _ => parse_expr(x),
+                    _ => parse_expr(x, ctx),
                 }
             }
             result
         }
 
         Rule::r#const | Rule::const_term | Rule::const_monomorph | Rule::const_atom => {
-            Expr::ConstExpr(parse_const(pair))
+            Expr::ConstExpr(parse_const(pair, ctx))
         }
-        Rule::stmt_block => parse_stmt(pair, None).to_expr(None),
+        Rule::stmt_block => parse_stmt(pair, ctx).to_expr(None),
         other => panic!("Unexpected rule: {:?}: {:?}", other, pair),
     };
     // result
@@ -976,9 +1774,9 @@ pub fn parse_expr(pair: Pair<Rule>) -> Expr {
     result
 }
 
-fn parse_expr_term(pair: Pair<Rule>) -> Expr {
+fn parse_expr_term(pair: Pair<Rule>, ctx: &Context) -> Expr {
     let mut inner_rules = pair.into_inner();
-    let mut head = parse_expr(inner_rules.next().unwrap());
+    let mut head = parse_expr(inner_rules.next().unwrap(), ctx);
     for suffix in inner_rules {
         head = match suffix.as_rule() {
             Rule::expr_int_field => head.field(ConstExpr::Int(
@@ -993,12 +1791,14 @@ fn parse_expr_term(pair: Pair<Rule>) -> Expr {
             Rule::expr_symbol_field => head.field(ConstExpr::Symbol(
                 suffix.into_inner().next().unwrap().as_str().to_string(),
             )),
-            Rule::expr_index => head.idx(parse_expr(suffix)),
+            Rule::expr_index => head.idx(parse_expr(suffix, ctx)),
             Rule::expr_call => {
                 let inner_rules = suffix.into_inner();
                 let mut args = Vec::new();
                 for arg in inner_rules {
-                    args.push(parse_expr(arg));
+                    // Errors about an argument point at it, rather than the whole call.
+                    let loc = ctx.location(arg.as_span());
+                    args.push(parse_expr(arg, ctx).annotate(Annotation::Location(loc)));
                 }
                 if head == Expr::ConstExpr(ConstExpr::Symbol("print".to_string())) {
                     let mut exprs: Vec<Expr> =
@@ -1030,22 +1830,25 @@ fn parse_expr_term(pair: Pair<Rule>) -> Expr {
                     head.app(args)
                 }
             }
-            Rule::expr_as_type => head.as_type(parse_type(suffix.into_inner().next().unwrap())),
+            Rule::expr_as_type => {
+                head.as_type(parse_type(suffix.into_inner().next().unwrap(), ctx))
+            }
+            Rule::expr_try => Expr::Try(Box::new(head)),
             _ => unreachable!(),
         }
     }
     head
 }
 
-fn parse_binop(pair: Pair<Rule>) -> Expr {
+fn parse_binop(pair: Pair<Rule>, ctx: &Context) -> Expr {
     let mut inner_rules = pair.into_inner().peekable();
-    let mut head = parse_expr(inner_rules.next().unwrap());
+    let mut head = parse_expr(inner_rules.next().unwrap(), ctx);
     // let count = inner_rules.clone().count() / 2;
     for pair in inner_rules {
         let mut inner_rules = pair.clone().into_inner();
         let next_pair = inner_rules.next().unwrap();
         let op = pair.as_str()[..pair.as_str().len() - next_pair.as_str().len()].trim();
-        let tail = parse_expr(next_pair);
+        let tail = parse_expr(next_pair, ctx);
         head = match op {
             "&&" => head.and(tail),
             "||" => head.or(tail),
@@ -1065,20 +1868,25 @@ fn parse_binop(pair: Pair<Rule>) -> Expr {
             "^" => head.bitxor(tail),
             "~&" => head.bitnand(tail),
             "~|" => head.bitnor(tail),
+            "<<" => head.shl(tail),
+            ">>" => head.shr(tail),
+            ">>>" => head.lshr(tail),
             _ => unreachable!(),
         };
     }
     head
 }
 
-fn parse_const(pair: Pair<Rule>) -> ConstExpr {
+fn parse_const(pair: Pair<Rule>, ctx: &Context) -> ConstExpr {
     match pair.as_rule() {
-        Rule::r#const | Rule::const_atom | Rule::const_group => {
-            pair.into_inner().map(parse_const).next().unwrap()
-        }
+        Rule::r#const | Rule::const_atom | Rule::const_group => pair
+            .into_inner()
+            .map(|x| parse_const(x, ctx))
+            .next()
+            .unwrap(),
         Rule::const_term => {
             let mut inner_rules = pair.into_inner();
-            let mut head = parse_const(inner_rules.next().unwrap());
+            let mut head = parse_const(inner_rules.next().unwrap(), ctx);
             for suffix in inner_rules {
                 head = match suffix.as_rule() {
                     Rule::expr_int_field => head.field(ConstExpr::Int(
@@ -1112,10 +1920,10 @@ fn parse_const(pair: Pair<Rule>) -> ConstExpr {
         }
         Rule::const_monomorph => {
             let mut inner_rules = pair.into_inner();
-            let c = parse_const(inner_rules.next().unwrap());
+            let c = parse_const(inner_rules.next().unwrap(), ctx);
             let mut args = Vec::new();
             for arg in inner_rules.next().unwrap().into_inner() {
-                args.push(parse_type(arg));
+                args.push(parse_type(arg, ctx));
             }
             ConstExpr::Monomorphize(Box::new(c), args)
         }
@@ -1123,7 +1931,7 @@ fn parse_const(pair: Pair<Rule>) -> ConstExpr {
             let inner_rules = pair.into_inner();
             let mut exprs = Vec::new();
             for pair in inner_rules {
-                exprs.push(parse_const(pair));
+                exprs.push(parse_const(pair, ctx));
             }
             ConstExpr::Tuple(exprs)
         }
@@ -1131,7 +1939,7 @@ fn parse_const(pair: Pair<Rule>) -> ConstExpr {
             let inner_rules = pair.into_inner();
             let mut exprs = Vec::new();
             for pair in inner_rules {
-                exprs.push(parse_const(pair));
+                exprs.push(parse_const(pair, ctx));
             }
             ConstExpr::Array(exprs)
         }
@@ -1140,24 +1948,24 @@ fn parse_const(pair: Pair<Rule>) -> ConstExpr {
             let mut fields = Vec::new();
             while inner_rules.peek().is_some() {
                 let field = inner_rules.next().unwrap().as_str().to_string();
-                let val = parse_const(inner_rules.next().unwrap());
+                let val = parse_const(inner_rules.next().unwrap(), ctx);
                 fields.push((field, val));
             }
             ConstExpr::Struct(fields.into_iter().collect())
         }
         Rule::const_variant => {
             let mut inner_rules = pair.into_inner();
-            let ty = parse_type(inner_rules.next().unwrap());
+            let ty = parse_type(inner_rules.next().unwrap(), ctx);
             let symbol = inner_rules.next().unwrap().as_str().to_string();
             if let Some(inner_rules) = inner_rules.next() {
-                let expr = parse_const(inner_rules);
+                let expr = parse_const(inner_rules, ctx);
                 // ConstExpr::Variant(ty, symbol, Some(Box::new(expr)))
                 ConstExpr::EnumUnion(ty, symbol, Box::new(expr))
             } else {
                 ConstExpr::Of(ty, symbol)
             }
         }
-        Rule::const_symbol => ConstExpr::Symbol(pair.as_str().to_string()),
+        Rule::const_symbol => ConstExpr::Symbol(ctx.resolve(pair.as_str())),
         Rule::const_int => {
             let s = pair.as_str();
             ConstExpr::Int(if s.len() > 2 && &s[..2] == "0b" {
@@ -1238,33 +2046,35 @@ fn parse_const(pair: Pair<Rule>) -> ConstExpr {
         Rule::const_none => ConstExpr::None,
         Rule::const_null => ConstExpr::Null,
         Rule::const_size_of_type => {
-            ConstExpr::SizeOfType(parse_type(pair.into_inner().next().unwrap()))
+            ConstExpr::SizeOfType(parse_type(pair.into_inner().next().unwrap(), ctx))
         }
         Rule::const_size_of_expr => {
-            ConstExpr::SizeOfExpr(parse_expr(pair.into_inner().next().unwrap()).into())
+            ConstExpr::SizeOfExpr(parse_expr(pair.into_inner().next().unwrap(), ctx).into())
         }
         other => panic!("Unexpected rule: {:?}: {:?}", other, pair),
     }
 }
 
-fn parse_type(pair: Pair<Rule>) -> Type {
+fn parse_type(pair: Pair<Rule>, ctx: &Context) -> Type {
     // todo!()
     match pair.as_rule() {
-        Rule::r#type | Rule::type_atom | Rule::type_term => {
-            pair.into_inner().map(parse_type).next().unwrap()
-        }
+        Rule::r#type | Rule::type_atom | Rule::type_term => pair
+            .into_inner()
+            .map(|x| parse_type(x, ctx))
+            .next()
+            .unwrap(),
 
         Rule::type_apply => {
             let mut inner_rules = pair.into_inner();
-            let mut head = parse_type(inner_rules.next().unwrap());
+            let mut head = parse_type(inner_rules.next().unwrap(), ctx);
 
             while inner_rules.peek().is_some() {
                 for parsed_args in inner_rules.by_ref() {
                     let mut ty_args = vec![];
                     // type_application_suffix
-                    // args.push(parse_type(arg));
+                    // args.push(parse_type(arg, ctx));
                     for parsed_arg in parsed_args.into_inner() {
-                        ty_args.push(parse_type(parsed_arg));
+                        ty_args.push(parse_type(parsed_arg, ctx));
                     }
                     head = Type::Apply(Box::new(head), ty_args);
                 }
@@ -1282,7 +2092,7 @@ fn parse_type(pair: Pair<Rule>) -> Type {
                         params.push(pair.as_str().to_string());
                     }
                     Rule::r#type => {
-                        let ty = parse_type(pair);
+                        let ty = parse_type(pair, ctx);
                         head = Type::Poly(params.clone(), Box::new(ty));
                     }
                     _ => unreachable!(),
@@ -1297,17 +2107,17 @@ fn parse_type(pair: Pair<Rule>) -> Type {
             let mut result = vec![];
             while inner_rules.clone().count() > 2 {
                 let name = inner_rules.next().unwrap().as_str().to_string();
-                let ty = parse_type(inner_rules.next().unwrap());
+                let ty = parse_type(inner_rules.next().unwrap(), ctx);
                 result.push((name, ty));
             }
-            let mut ty = parse_type(inner_rules.next().unwrap());
+            let mut ty = parse_type(inner_rules.next().unwrap(), ctx);
             for (name, var) in result.into_iter().rev() {
                 ty = Type::Let(name, Box::new(var), Box::new(ty));
             }
             ty
         }
 
-        Rule::type_symbol => Type::Symbol(pair.as_str().to_string()),
+        Rule::type_symbol => Type::Symbol(ctx.resolve(pair.as_str())),
         Rule::type_int => Type::Int,
         Rule::type_cell => Type::Cell,
         Rule::type_float => Type::Float,
@@ -1320,14 +2130,14 @@ fn parse_type(pair: Pair<Rule>) -> Type {
             let inner_rules = pair.into_inner();
             let mut tys = Vec::new();
             for pair in inner_rules {
-                tys.push(parse_type(pair));
+                tys.push(parse_type(pair, ctx));
             }
             Type::Tuple(tys)
         }
         Rule::type_array => {
             let mut inner_rules = pair.into_inner();
-            let ty = parse_type(inner_rules.next().unwrap());
-            let len = parse_const(inner_rules.next().unwrap());
+            let ty = parse_type(inner_rules.next().unwrap(), ctx);
+            let len = parse_const(inner_rules.next().unwrap(), ctx);
             Type::Array(Box::new(ty), Box::new(len))
         }
         Rule::type_struct => {
@@ -1335,7 +2145,7 @@ fn parse_type(pair: Pair<Rule>) -> Type {
             let mut fields = Vec::new();
             while inner_rules.peek().is_some() {
                 let name = inner_rules.next().unwrap().as_str().to_string();
-                let ty = parse_type(inner_rules.next().unwrap());
+                let ty = parse_type(inner_rules.next().unwrap(), ctx);
                 fields.push((name, ty));
             }
             Type::Struct(fields.into_iter().collect())
@@ -1356,7 +2166,8 @@ fn parse_type(pair: Pair<Rule>) -> Type {
 
                 if let Some(ty) = ty {
                     if ty.as_rule() == Rule::r#type {
-                        variants.push((variant_name.as_str().to_string(), Some(parse_type(ty))));
+                        variants
+                            .push((variant_name.as_str().to_string(), Some(parse_type(ty, ctx))));
                     } else {
                         variants.push((variant_name.as_str().to_string(), None));
                     }
@@ -1384,12 +2195,12 @@ fn parse_type(pair: Pair<Rule>) -> Type {
         }
         Rule::type_ptr => {
             let mut inner_rules = pair.into_inner();
-            let ty = parse_type(inner_rules.next().unwrap());
+            let ty = parse_type(inner_rules.next().unwrap(), ctx);
             Type::Pointer(Mutability::Immutable, Box::new(ty))
         }
         Rule::type_mut_ptr => {
             let mut inner_rules = pair.into_inner();
-            let ty = parse_type(inner_rules.next().unwrap());
+            let ty = parse_type(inner_rules.next().unwrap(), ctx);
             Type::Pointer(Mutability::Mutable, Box::new(ty))
         }
         Rule::type_proc => {
@@ -1397,10 +2208,10 @@ fn parse_type(pair: Pair<Rule>) -> Type {
             let mut args_rules = inner_rules.next().unwrap().into_inner();
             let mut args = Vec::new();
             while args_rules.peek().is_some() {
-                let ty = parse_type(args_rules.next().unwrap());
+                let ty = parse_type(args_rules.next().unwrap(), ctx);
                 args.push(ty);
             }
-            let ret = parse_type(inner_rules.next().unwrap());
+            let ret = parse_type(inner_rules.next().unwrap(), ctx);
             Type::Proc(args, Box::new(ret))
         }
 
@@ -1408,15 +2219,20 @@ fn parse_type(pair: Pair<Rule>) -> Type {
     }
 }
 
-fn parse_match(pair: Pair<Rule>) -> Expr {
+fn parse_match(pair: Pair<Rule>, ctx: &Context) -> Expr {
     let mut inner_rules = pair.into_inner();
-    let expr = parse_expr(inner_rules.next().unwrap());
+    let expr = parse_expr(inner_rules.next().unwrap(), ctx);
     let mut patterns = Vec::new();
     let mut stmts = Vec::new();
     for pair in inner_rules {
         let mut inner_rules = pair.into_inner();
-        let pattern = parse_pattern(inner_rules.next().unwrap());
-        let stmt = parse_expr(inner_rules.next().unwrap());
+        let mut pattern = parse_pattern(inner_rules.next().unwrap(), ctx);
+        let body_ctx = ctx.shadow(&get_pattern_names(&pattern));
+        if inner_rules.peek().unwrap().as_rule() == Rule::stmt_match_guard {
+            let guard = inner_rules.next().unwrap().into_inner().next().unwrap();
+            pattern = Pattern::guard(pattern, parse_expr(guard, &body_ctx));
+        }
+        let stmt = parse_expr(inner_rules.next().unwrap(), &body_ctx);
         patterns.push(pattern);
         stmts.push(stmt);
     }
@@ -1426,23 +2242,27 @@ fn parse_match(pair: Pair<Rule>) -> Expr {
     )
 }
 
-fn parse_pattern(pair: Pair<Rule>) -> Pattern {
+fn parse_pattern(pair: Pair<Rule>, ctx: &Context) -> Pattern {
     match pair.as_rule() {
-        Rule::pattern | Rule::pattern_term | Rule::pattern_atom | Rule::pattern_group => {
-            pair.into_inner().map(parse_pattern).next().unwrap()
+        Rule::pattern | Rule::pattern_term | Rule::pattern_atom | Rule::pattern_group => pair
+            .into_inner()
+            .map(|x| parse_pattern(x, ctx))
+            .next()
+            .unwrap(),
+        Rule::pattern_const => {
+            Pattern::ConstExpr(parse_const(pair.into_inner().next().unwrap(), ctx))
         }
-        Rule::pattern_const => Pattern::ConstExpr(parse_const(pair.into_inner().next().unwrap())),
         Rule::pattern_variant => {
             let mut inner_rules = pair.into_inner();
             let symbol = inner_rules.next().unwrap().as_str().to_string();
-            let pattern = inner_rules.next().map(parse_pattern);
+            let pattern = inner_rules.next().map(|x| parse_pattern(x, ctx));
             Pattern::Variant(symbol, pattern.map(Box::new))
         }
         Rule::pattern_tuple => {
             let inner_rules = pair.into_inner();
             let mut patterns = Vec::new();
             for pair in inner_rules {
-                let pattern = parse_pattern(pair);
+                let pattern = parse_pattern(pair, ctx);
                 patterns.push(pattern);
             }
             Pattern::Tuple(patterns)
@@ -1460,17 +2280,24 @@ fn parse_pattern(pair: Pair<Rule>) -> Pattern {
                     ));
                     continue;
                 }
-                // let pattern = parse_pattern(inner_rules.next().unwrap());
-                let pattern = inner_rules.next().map(parse_pattern).unwrap();
+                // let pattern = parse_pattern(inner_rules.next().unwrap(), ctx);
+                let pattern = inner_rules.next().map(|x| parse_pattern(x, ctx)).unwrap();
                 fields.push((symbol, pattern));
             }
             Pattern::Struct(fields.into_iter().collect())
         }
         Rule::pattern_ptr => {
             let mut inner_rules = pair.into_inner();
-            let pattern = parse_pattern(inner_rules.next().unwrap());
+            let pattern = parse_pattern(inner_rules.next().unwrap(), ctx);
             Pattern::Pointer(Box::new(pattern))
         }
+        Rule::pattern_range => {
+            let mut inner_rules = pair.into_inner();
+            let start = parse_const(inner_rules.next().unwrap(), ctx);
+            let inclusive = inner_rules.next().unwrap().as_str() == "..=";
+            let end = parse_const(inner_rules.next().unwrap(), ctx);
+            Pattern::range(start, end, inclusive)
+        }
         Rule::pattern_wildcard => Pattern::Wildcard,
         Rule::pattern_mut_symbol => {
             let mut inner_rules = pair.into_inner();
@@ -1493,7 +2320,7 @@ fn parse_pattern(pair: Pair<Rule>) -> Pattern {
             let inner_rules = pair.into_inner();
             let mut patterns = Vec::new();
             for pair in inner_rules {
-                let pattern = parse_pattern(pair);
+                let pattern = parse_pattern(pair, ctx);
                 patterns.push(pattern);
             }
             Pattern::Alt(patterns)
@@ -1501,3 +2328,21 @@ fn parse_pattern(pair: Pair<Rule>) -> Pattern {
         other => panic!("Unexpected rule: {:?}: {:?}", other, pair),
     }
 }
+
+/// Get the names of the variables bound by a pattern.
+fn get_pattern_names(pattern: &Pattern) -> Vec<String> {
+    match pattern {
+        Pattern::Symbol(_, name) => vec![name.clone()],
+        Pattern::Tuple(patterns) | Pattern::Alt(patterns) => {
+            patterns.iter().flat_map(get_pattern_names).collect()
+        }
+        Pattern::Struct(fields) => fields.values().flat_map(get_pattern_names).collect(),
+        Pattern::Variant(_, Some(pattern))
+        | Pattern::Pointer(pattern)
+        | Pattern::Guard(pattern, _) => get_pattern_names(pattern),
+        Pattern::Variant(_, None)
+        | Pattern::ConstExpr(_)
+        | Pattern::Range(_, _, _)
+        | Pattern::Wildcard => vec![],
+    }
+}
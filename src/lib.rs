@@ -49,6 +49,7 @@
 //! 2. [The Assembly Language](./asm/index.html)
 //! 3. [The Virtual Machine](./vm/index.html)
 //! 4. [Target Backends](./targets/index.html)
+//! 5. [The Compiler Pipeline](./compiler/index.html)
 //!
 //! ## Stages of IR
 //!
@@ -88,6 +89,7 @@
 //! | Arithmetic             | `IsNonNegative?` | `Add`     | `Subtract`      | `Multiply` | `Divide`    | `Remainder`  |
 //! | Worldly                | `GetChar`        | `PutChar` | `GetInt`        | `PutInt`   | `GetFloat`  | `PutFloat`   |
 pub mod asm;
+pub mod compiler;
 pub mod frontend;
 pub mod lir;
 pub mod parse;
@@ -95,6 +97,8 @@ pub mod side_effects;
 pub mod targets;
 pub mod vm;
 
+pub use compiler::{Artifacts, Compiler, SourceType, Target};
+
 /// The value of the NULL pointer constant.
 ///
 /// I've chosen to use the smallest value that can be expressed by an 8-bit signed integer.
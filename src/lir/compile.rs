@@ -13,6 +13,7 @@ use super::*;
 use crate::asm::{
     AssemblyProgram, CoreOp, CoreProgram, StandardOp, StandardProgram, A, B, C, FP, SP,
 };
+use crate::parse::SourceCodeLocation;
 use crate::NULL;
 use log::*;
 use std::sync::Mutex;
@@ -32,39 +33,207 @@ pub trait Compile: TypeCheck + std::fmt::Debug + std::fmt::Display {
     where
         Self: Sized + Clone,
     {
-        // eprintln!("Compiling LIR expression {self}");
-        info!("Type checking...");
-        // First, type check the expression.
-        self.type_check(&Env::default())?;
-        info!("Type checked successfully.");
-        // Then, attempt to compile the expression into a core assembly program.
-        let mut core_asm = CoreProgram::default();
-
-        info!("Compiling...");
-        // If the expression cannot be compiled into a core assembly program,
-        // then compile it into a standard assembly program.
-        if let Err(err) = self
-            .clone()
-            // Compile the expression into the core assembly program.
-            .compile_expr(&mut Env::default(), &mut core_asm)
-        {
-            warn!("Failed to compile into core assembly program: {err}, falling back on standard assembly");
-            let mut std_asm = StandardProgram::default();
-            // Compile the expression into the standard assembly program.
-            self.compile_expr(&mut Env::default(), &mut std_asm)?;
-            info!("Compiled to standard assembly successfully.");
-            // Return the fallback standard assembly program.
-            Ok(Err(std_asm))
-        } else {
-            info!("Compiled to core assembly successfully.");
-            // Return the successfully compiled core assembly program.
-            Ok(Ok(core_asm))
-        }
+        self.compile_with_warnings().0
+    }
+
+    /// Compile the expression into an assembly program, like `compile`, and also
+    /// return the warnings reported while type checking the expression.
+    ///
+    /// The warnings are returned even if the compilation fails.
+    #[allow(clippy::type_complexity)]
+    fn compile_with_warnings(
+        self,
+    ) -> (
+        Result<Result<CoreProgram, StandardProgram>, Error>,
+        Vec<Warning>,
+    )
+    where
+        Self: Sized + Clone,
+    {
+        let env = Env::default();
+        let result = self.compile_checked(&env);
+        (result, env.get_warnings())
+    }
+
+    /// Compile the expression into an assembly program, like `compile_with_warnings`,
+    /// and write the source code location of each annotated expression in a comment
+    /// before its code, and the name of each function at its start. Tools like the
+    /// memory checker and the debugger use these to point back to the source code.
+    #[allow(clippy::type_complexity)]
+    fn compile_with_source_locations(
+        self,
+    ) -> (
+        Result<Result<CoreProgram, StandardProgram>, Error>,
+        Vec<Warning>,
+    )
+    where
+        Self: Sized + Clone,
+    {
+        let env = Env::default();
+        let result = compile_program(self, &env, true);
+        (result, env.get_warnings())
+    }
+
+    /// Type check the expression in the given environment, and then compile it.
+    fn compile_checked(self, env: &Env) -> Result<Result<CoreProgram, StandardProgram>, Error>
+    where
+        Self: Sized + Clone,
+    {
+        compile_program(self, env, false)
     }
+
     // Compile a specific expression into an assembly program.
     fn compile_expr(self, env: &mut Env, output: &mut dyn AssemblyProgram) -> Result<(), Error>;
 }
 
+/// Type check an expression in the given environment, and then compile it,
+/// optionally recording the source code locations of its code in comments.
+fn compile_program<T: Compile + Clone>(
+    expr: T,
+    env: &Env,
+    source_locations: bool,
+) -> Result<Result<CoreProgram, StandardProgram>, Error> {
+    // eprintln!("Compiling LIR expression {expr}");
+    info!("Type checking...");
+    // First, type check the expression.
+    expr.type_check(env)?;
+    info!("Type checked successfully.");
+    // Then, attempt to compile the expression into a core assembly program.
+    let mut core_asm = CoreProgram::default();
+
+    info!("Compiling...");
+    // If the expression cannot be compiled into a core assembly program,
+    // then compile it into a standard assembly program.
+    if let Err(err) = expr
+        .clone()
+        // Compile the expression into the core assembly program.
+        .compile_expr(
+            &mut Env::default(),
+            &mut SourceLocations::new(&mut core_asm, source_locations),
+        )
+    {
+        warn!("Failed to compile into core assembly program: {err}, falling back on standard assembly");
+        let mut std_asm = StandardProgram::default();
+        // Compile the expression into the standard assembly program.
+        expr.compile_expr(
+            &mut Env::default(),
+            &mut SourceLocations::new(&mut std_asm, source_locations),
+        )?;
+        info!("Compiled to standard assembly successfully.");
+        // Return the fallback standard assembly program.
+        Ok(Err(std_asm))
+    } else {
+        info!("Compiled to core assembly successfully.");
+        // Return the successfully compiled core assembly program.
+        Ok(Ok(core_asm))
+    }
+}
+
+/// An assembly program which writes the source code locations entered by
+/// the compiler, and the names of the functions it defines, in comments if
+/// they're being recorded.
+struct SourceLocations<'a> {
+    /// The program the code is compiled into.
+    program: &'a mut dyn AssemblyProgram,
+    /// Are the source code locations being recorded?
+    enabled: bool,
+    /// The locations of the expressions being compiled, innermost last.
+    locations: Vec<SourceCodeLocation>,
+    /// The open blocks, and the locations around each function block. A
+    /// function's code doesn't belong to the expression it's compiled in.
+    blocks: Vec<Option<Vec<SourceCodeLocation>>>,
+}
+
+impl<'a> SourceLocations<'a> {
+    fn new(program: &'a mut dyn AssemblyProgram, enabled: bool) -> Self {
+        Self {
+            program,
+            enabled,
+            locations: vec![],
+            blocks: vec![],
+        }
+    }
+
+    /// Keep track of the blocks opened and closed by an operation.
+    fn track_blocks(&mut self, op: &CoreOp) {
+        match op {
+            CoreOp::Fn(_) => self.blocks.push(Some(std::mem::take(&mut self.locations))),
+            CoreOp::If(_) | CoreOp::While(_) => self.blocks.push(None),
+            CoreOp::End => {
+                if let Some(Some(locations)) = self.blocks.pop() {
+                    self.locations = locations;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl AssemblyProgram for SourceLocations<'_> {
+    fn op(&mut self, op: CoreOp) {
+        self.track_blocks(&op);
+        self.program.op(op)
+    }
+
+    fn std_op(&mut self, op: StandardOp) -> Result<(), crate::asm::Error> {
+        if let StandardOp::CoreOp(core_op) = &op {
+            self.track_blocks(core_op);
+        }
+        self.program.std_op(op)
+    }
+
+    fn comment(&mut self, comment: String) {
+        self.program.comment(comment)
+    }
+
+    fn enter_location(&mut self, loc: &SourceCodeLocation) {
+        if !self.enabled {
+            return;
+        }
+        // Nested annotations often share a location, so it's only written once.
+        if self.locations.last() != Some(loc) {
+            self.program.comment(loc.to_comment());
+        }
+        self.locations.push(loc.clone());
+    }
+
+    fn name_function(&mut self, mangled_name: &str, common_name: Option<&str>) {
+        if !self.enabled {
+            return;
+        }
+        // Either name could have spaces in it, so they're written separately.
+        self.program.comment(format!("function {mangled_name}"));
+        if let Some(common_name) = common_name {
+            self.program.comment(format!("declared as {common_name}"));
+        }
+    }
+
+    fn exit_location(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        // The code after the expression belongs to the expression around it.
+        let exited = self.locations.pop();
+        if let Some(loc) = self.locations.last() {
+            if exited.as_ref() != Some(loc) {
+                self.program.comment(loc.to_comment());
+            }
+        }
+    }
+
+    fn is_defined(&self, label: &str) -> bool {
+        self.program.is_defined(label)
+    }
+
+    fn current_instruction(&self) -> usize {
+        self.program.current_instruction()
+    }
+
+    fn get_op(&self, start: usize) -> Option<Result<CoreOp, StandardOp>> {
+        self.program.get_op(start)
+    }
+}
+
 /// Compile an LIR expression into several core assembly instructions.
 impl Compile for Expr {
     fn compile_expr(self, env: &mut Env, output: &mut dyn AssemblyProgram) -> Result<(), Error> {
@@ -78,12 +247,29 @@ impl Compile for Expr {
             comment.truncate(70);
         }
 
+        // Fill in the types of variables and closure parameters which are inferred from their uses.
+        if let Some(expr) = self.infer_local_types(env)? {
+            return expr.compile_expr(env, output);
+        }
+
+        // An operator overloaded by a user-defined type is a call to the overloading method.
+        if let Some((call, _)) = self.get_overloaded_op(env)? {
+            return call.compile_expr(env, output);
+        }
+
         // Compile the expression.
         match self {
             Self::Annotated(expr, metdata) => {
+                let loc = metdata.location();
+                if let Some(loc) = loc {
+                    output.enter_location(loc);
+                }
                 // Compile the expression.
                 expr.compile_expr(env, output)
-                    .map_err(|e| e.annotate(metdata))?;
+                    .map_err(|e| e.annotate(metdata.clone()))?;
+                if loc.is_some() {
+                    output.exit_location();
+                }
             }
 
             Self::Match(expr, branches) => {
@@ -165,9 +351,26 @@ impl Compile for Expr {
             Self::ConstExpr(expr) => expr.compile_expr(env, output)?,
             // Compile a block of expressions.
             Self::Many(exprs) => {
-                for expr in exprs {
+                let mut exprs = exprs.into_iter();
+                while let Some(expr) = exprs.next() {
+                    let can_exit_loop = expr.can_exit_loop();
+                    // The values of all but the last expression are discarded.
+                    let discarded_size = if exprs.len() > 0 {
+                        expr.get_size(env)?
+                    } else {
+                        0
+                    };
                     // Compile the expression in the block.
                     expr.compile_expr(env, output)?;
+                    if discarded_size > 0 {
+                        output.op(CoreOp::Pop(None, discarded_size));
+                    }
+                    // If the expression can `break` or `continue`, the rest
+                    // of the block must be skipped when it does.
+                    if can_exit_loop && exprs.len() > 0 {
+                        Self::Many(exprs.collect()).compile_unless_exited(env, output)?;
+                        break;
+                    }
                 }
             }
 
@@ -192,7 +395,7 @@ impl Compile for Expr {
                     (a, b) if a.get_size(env)? == b.get_size(env)? => {}
                     // Otherwise, the cast is invalid.
                     (a, b) => {
-                        return Err(Error::InvalidAs(self, a, b));
+                        return Err(Error::InvalidAs(Box::new(self), a, b));
                     }
                 }
             }
@@ -210,6 +413,16 @@ impl Compile for Expr {
                         });
                 }
 
+                // Call the monomorph of a polymorphic procedure with the inferred template arguments.
+                if let Some(call) = self_clone.infer_template_args(env)? {
+                    return call.compile_expr(env, output);
+                }
+
+                // Closures which capture by reference are passed with their records on the stack.
+                if let Some(call) = self_clone.bind_closure_arg() {
+                    return call.compile_expr(env, output);
+                }
+
                 // if !matches!(*f, Expr::Member(_, _)) {
                 //     // Push the arguments to the procedure on the stack.
                 //     for arg in &args {
@@ -272,12 +485,9 @@ impl Compile for Expr {
                             }
                             // Otherwise, it must be a procedure.
                             _ => {
-                                // Push the procedure on the stack.
-                                ConstExpr::Symbol(name).compile_expr(env, output)?;
-                                // Pop the "function pointer" from the stack.
-                                output.op(CoreOp::Pop(Some(A), 1));
                                 // Call the procedure on the arguments.
-                                output.op(CoreOp::Call(A));
+                                Expr::ConstExpr(ConstExpr::Symbol(name))
+                                    .compile_call(env, output)?;
                             }
                         }
                     }
@@ -295,13 +505,10 @@ impl Compile for Expr {
                             }
 
                             // Compile it normally:
-                            // Push the procedure on the stack.
-                            warn!("Method: Monomorphizing {template} with {ty_args:?}");
-                            ConstExpr::Monomorphize(template, ty_args).compile_expr(env, output)?;
-                            // Pop the "function pointer" from the stack.
-                            output.op(CoreOp::Pop(Some(A), 1));
                             // Call the procedure on the arguments.
-                            output.op(CoreOp::Call(A));
+                            warn!("Method: Monomorphizing {template} with {ty_args:?}");
+                            Expr::ConstExpr(ConstExpr::Monomorphize(template, ty_args))
+                                .compile_call(env, output)?;
                         }
                     }
 
@@ -319,12 +526,8 @@ impl Compile for Expr {
                             }
 
                             // Compile it normally:
-                            // Push the procedure on the stack.
-                            val.field(name).compile_expr(env, output)?;
-                            // Pop the "function pointer" from the stack.
-                            output.op(CoreOp::Pop(Some(A), 1));
                             // Call the procedure on the arguments.
-                            output.op(CoreOp::Call(A));
+                            val.field(name).compile_call(env, output)?;
                         }
                     }
                     // Otherwise, it must be a procedure.
@@ -334,15 +537,13 @@ impl Compile for Expr {
                             // Compile the argument (push it on the stack)
                             arg.clone().compile_expr(env, output)?;
                         }
-                        // Push the procedure on the stack.
-                        proc.compile_expr(env, output)?;
-                        // Pop the "function pointer" from the stack.
-                        output.op(CoreOp::Pop(Some(A), 1));
                         // Call the procedure on the arguments.
-                        output.op(CoreOp::Call(A));
+                        proc.compile_call(env, output)?;
                     }
                 }
             }
+            // Compile a closure.
+            Self::Closure(closure) => closure.compile_expr(env, output)?,
             // Compile a return statement.
             Self::Return(e) => {
                 // Get the size of the arguments and return value.
@@ -375,6 +576,69 @@ impl Compile for Expr {
                 output.op(CoreOp::Return);
             }
 
+            // Compile a `?` expression.
+            Self::Try(e) => {
+                let (_, data_ty) = Self::get_try_success(&e, env)?;
+                let early_returns = Self::get_try_early_returns(&e, env)?;
+                let ty = e.get_type(env)?;
+                let size = ty.get_size(env)?;
+                let data_size = data_ty.get_size(env)?;
+                let ret = env
+                    .get_expected_return_type()
+                    .cloned()
+                    .unwrap_or(Type::None);
+                let ret_size = ret.get_size(env)?;
+                let args_size = env.get_args_size();
+
+                // The tag values of the variants, in the matched type and the return type.
+                let variants = match ty.simplify_until_concrete(env)? {
+                    Type::EnumUnion(variants) => variants.into_keys().collect::<Vec<_>>(),
+                    _ => return Err(Error::NotTryable(ty, e)),
+                };
+                let ret_variants = match ret.simplify_until_concrete(env)? {
+                    Type::EnumUnion(variants) => variants.into_keys().collect::<Vec<_>>(),
+                    _ => vec![],
+                };
+
+                // Evaluate the tagged union: its data is followed by its tag.
+                e.compile_expr(env, output)?;
+                for (name, early_ty) in early_returns {
+                    let tag = Type::variant_index(&variants, &name).unwrap();
+                    let ret_tag = Type::variant_index(&ret_variants, &name).unwrap();
+                    output.op(CoreOp::Set(A, tag as i64));
+                    output.op(CoreOp::IsEqual {
+                        a: SP.deref(),
+                        b: A,
+                        dst: A,
+                    });
+                    output.op(CoreOp::If(A));
+                    // Overwrite the arguments with the variant, as a value of the return type.
+                    let dst = FP.deref().offset(1 - args_size as isize);
+                    output.op(CoreOp::Copy {
+                        dst: dst.clone(),
+                        src: SP.deref().offset(1 - size as isize),
+                        size: early_ty.get_size(env)?,
+                    });
+                    output.op(CoreOp::Set(
+                        dst.offset(ret_size as isize - 1),
+                        ret_tag as i64,
+                    ));
+                    // Leave the return value on the stack, and return from the procedure.
+                    output.op(CoreOp::GetAddress {
+                        addr: FP.deref(),
+                        dst: SP,
+                    });
+                    output.op(CoreOp::Prev(
+                        SP,
+                        Some(args_size as isize - ret_size as isize),
+                    ));
+                    output.op(CoreOp::Return);
+                    output.op(CoreOp::End);
+                }
+                // Otherwise, leave the success variant's data on the stack.
+                output.op(CoreOp::Pop(None, size - data_size));
+            }
+
             // Compile a declaration statement.
             Self::Declare(declaration, body) => {
                 // Create a new scope
@@ -382,23 +646,71 @@ impl Compile for Expr {
             }
 
             // Compile a while loop.
-            Self::While(cond, body) => {
-                // Eval the condition
-                cond.clone().compile_expr(env, output)?;
-                output.op(CoreOp::Pop(Some(A), 1));
-                // While the condition
-                output.op(CoreOp::While(A));
-                // Compile the body
-                body.compile_expr(env, output)?;
-                // Eval the condition again
-                cond.compile_expr(env, output)?;
-                output.op(CoreOp::Pop(Some(A), 1));
-                // Label the end of the loop
-                output.op(CoreOp::End);
+            Self::While(cond, body) => Self::compile_loop(None, *cond, *body, env, output)?,
+
+            // Compile a `for` loop as the `while` loop it's lowered to.
+            Self::ForIn(pat, iterable, body) => {
+                Self::desugar_for_in(&pat, &iterable, &body, None, env)?
+                    .compile_expr(env, output)?
+            }
+
+            // Compile a labeled loop.
+            Self::Labeled(label, body) => {
+                let mut inner = *body;
+                while let Self::Annotated(expr, _) = inner {
+                    inner = *expr;
+                }
+                match inner {
+                    Self::While(cond, body) => {
+                        Self::compile_loop(Some(label), *cond, *body, env, output)?
+                    }
+                    Self::ForIn(pat, iterable, body) => {
+                        Self::desugar_for_in(&pat, &iterable, &body, Some(&label), env)?
+                            .compile_expr(env, output)?
+                    }
+                    // A label on anything but a loop has no effect.
+                    other => other.compile_expr(env, output)?,
+                }
+            }
+
+            // Compile a `break` or `continue`.
+            Self::Break(ref label) | Self::Continue(ref label) => {
+                // The control cells of the loops we're leaving, innermost first.
+                let loops = env
+                    .get_exited_loops(label.as_deref())
+                    .ok_or_else(|| match label {
+                        Some(label) => Error::LabelNotDefined(label.clone()),
+                        None => Error::LoopControlOutsideLoop(Box::new(self.clone())),
+                    })?;
+                let is_continue = matches!(self, Self::Continue(_));
+                for (i, control) in loops.iter().enumerate() {
+                    // Every loop is broken, except for the target loop of a `continue`.
+                    let state = if is_continue && i == loops.len() - 1 {
+                        LOOP_CONTINUED
+                    } else {
+                        LOOP_BROKEN
+                    };
+                    output.op(CoreOp::Set(FP.deref().offset(*control), state));
+                }
+                // The rest of the loops' bodies are skipped by the code
+                // that follows, so we don't touch the stack here.
             }
 
             // Compile an if statement.
             Self::If(c, t, e) => {
+                // A branch that is cut short by `break` or `continue` still has
+                // to leave a result of the right size on the stack.
+                let t_padding = if t.can_exit_loop() && matches!(t.get_type(env)?, Type::Never) {
+                    e.get_size(env)?
+                } else {
+                    0
+                };
+                let e_padding = if e.can_exit_loop() && matches!(e.get_type(env)?, Type::Never) {
+                    t.get_size(env)?
+                } else {
+                    0
+                };
+
                 // Compile the condition
                 c.compile_expr(env, output)?;
                 output.op(CoreOp::Pop(Some(A), 1));
@@ -406,10 +718,16 @@ impl Compile for Expr {
                 output.op(CoreOp::If(A));
                 // Compile the true branch
                 t.compile_expr(env, output)?;
+                if t_padding > 0 {
+                    output.op(CoreOp::Next(SP, Some(t_padding as isize)));
+                }
                 // If the condition is false
                 output.op(CoreOp::Else);
                 // Compile the false branch
                 e.compile_expr(env, output)?;
+                if e_padding > 0 {
+                    output.op(CoreOp::Next(SP, Some(e_padding as isize)));
+                }
                 // Label the end of the if statement
                 output.op(CoreOp::End);
             }
@@ -429,7 +747,7 @@ impl Compile for Expr {
                     // Push all of the data at the address onto the stack.
                     output.op(CoreOp::Push(A.deref(), inner.get_size(env)?));
                 } else {
-                    return Err(Error::DerefNonPointer(*ptr));
+                    return Err(Error::DerefNonPointer(ptr));
                 }
             }
 
@@ -689,7 +1007,10 @@ impl Compile for Expr {
                                 .get_associated_const(&val_type, &name)
                                 .ok_or_else(|| {
                                     // If we could not find the member return an error.
-                                    Error::MemberNotFound(self.clone(), member.clone())
+                                    Error::MemberNotFound(
+                                        Box::new(self.clone()),
+                                        Box::new(member.clone()),
+                                    )
                                 })
                                 .and_then(|(c, _)| c.compile_expr(env, output));
                         }
@@ -718,7 +1039,7 @@ impl Compile for Expr {
                             return Err(Error::MismatchedMutability {
                                 found: *found_mutability,
                                 expected: expected_mutability,
-                                expr: Expr::ConstExpr(ConstExpr::Symbol(name)),
+                                expr: Box::new(Expr::ConstExpr(ConstExpr::Symbol(name))),
                             });
                         }
 
@@ -736,14 +1057,15 @@ impl Compile for Expr {
                             // Push the address of the variable onto the stack.
                             CoreOp::Push(C, 1),
                         ]))
-                    } else if let Some((found_mutability, _ty, location)) =
-                        env.get_static_var(&name)
+                    } else if let Some((found_mutability, _ty, location)) = env
+                        .get_captured_var(&name)
+                        .or_else(|| env.get_static_var(&name))
                     {
                         if !found_mutability.can_decay_to(&expected_mutability) {
                             return Err(Error::MismatchedMutability {
                                 found: *found_mutability,
                                 expected: expected_mutability,
-                                expr: Expr::ConstExpr(ConstExpr::Symbol(name)),
+                                expr: Box::new(Expr::ConstExpr(ConstExpr::Symbol(name))),
                             });
                         }
 
@@ -815,7 +1137,7 @@ impl Compile for Expr {
                                 return Err(Error::MismatchedMutability {
                                     found: found_mutability,
                                     expected: expected_mutability,
-                                    expr: Expr::Member(val, name),
+                                    expr: Box::new(Expr::Member(val, name)),
                                 });
                             }
                             // Compile the pointer to get the address of the value.
@@ -823,7 +1145,7 @@ impl Compile for Expr {
                         }
                         other => {
                             error!("Tried to get a member {name} of a non-struct, non-tuple, non-union, non-pointer type: {other} of value {val} in environment {env}");
-                            return Err(Error::InvalidRefer(Expr::Member(val, name)));
+                            return Err(Error::InvalidRefer(Box::new(Expr::Member(val, name))));
                         }
                     }
 
@@ -884,7 +1206,7 @@ impl Compile for Expr {
                                 return Err(Error::MismatchedMutability {
                                     found: found_mutability,
                                     expected: expected_mutability,
-                                    expr: Expr::Index(val, idx),
+                                    expr: Box::new(Expr::Index(val, idx)),
                                 });
                             }
 
@@ -914,11 +1236,11 @@ impl Compile for Expr {
                             output.op(CoreOp::Push(C, 1));
                         }
                         // Otherwise, return an error.
-                        _ => return Err(Error::InvalidIndex(Expr::Index(val, idx))),
+                        _ => return Err(Error::InvalidIndex(Box::new(Expr::Index(val, idx)))),
                     }
                 }
                 // Otherwise, return an error.
-                other => return Err(Error::InvalidRefer(other)),
+                other => return Err(Error::InvalidRefer(Box::new(other))),
             },
         }
 
@@ -927,6 +1249,240 @@ impl Compile for Expr {
     }
 }
 
+/// The state of a loop's control cell when the loop's body is running normally.
+const LOOP_RUNNING: i64 = 0;
+/// The state of a loop's control cell after a `break` exits the loop.
+const LOOP_BROKEN: i64 = 1;
+/// The state of a loop's control cell after a `continue` skips the rest of the loop's body.
+const LOOP_CONTINUED: i64 = 2;
+
+impl Expr {
+    /// Compile a program which is compiled and run one piece at a time, like the lines
+    /// entered into the REPL. The declarations at the top level of the program are kept:
+    /// they're added to the environment, and their variables are left on the stack,
+    /// so the pieces compiled after it can use them.
+    ///
+    /// The value of the program's last statement is left on the stack above the variables,
+    /// and its type is returned.
+    pub fn compile_top_level(
+        self,
+        env: &mut Env,
+        output: &mut dyn AssemblyProgram,
+    ) -> Result<Type, Error> {
+        match self {
+            Self::Annotated(expr, metadata) => expr
+                .compile_top_level(env, output)
+                .map_err(|e| e.annotate(metadata)),
+            Self::Declare(declaration, body) => {
+                declaration.compile_helper(None, env, output)?;
+                body.compile_top_level(env, output)
+            }
+            Self::Many(mut exprs) => {
+                // A program ends with an empty statement, which doesn't count.
+                if exprs.len() > 1 && matches!(exprs.last(), Some(Self::ConstExpr(ConstExpr::None)))
+                {
+                    exprs.pop();
+                }
+                let last = exprs.pop().unwrap_or(Self::ConstExpr(ConstExpr::None));
+                for expr in exprs {
+                    // The values of all but the last statement are discarded.
+                    let size = expr.get_size(env)?;
+                    expr.compile_expr(env, output)?;
+                    if size > 0 {
+                        output.op(CoreOp::Pop(None, size));
+                    }
+                }
+                last.compile_top_level(env, output)
+            }
+            expr => {
+                let ty = expr.get_type(env)?;
+                expr.compile_expr(env, output)?;
+                Ok(ty)
+            }
+        }
+    }
+
+    /// Call this procedure on the arguments on the stack.
+    ///
+    /// A procedure known at compile time is called directly by its label. Otherwise,
+    /// the procedure's value is either a label or a closure, which is checked when
+    /// it's called.
+    fn compile_call(self, env: &mut Env, output: &mut dyn AssemblyProgram) -> Result<(), Error> {
+        if let Some(proc) = self.get_static_proc(env) {
+            let label = proc.get_mangled_name().to_string();
+            proc.define_label(env, output)?;
+            output.op(CoreOp::CallLabel(label));
+        } else {
+            // Push the procedure on the stack.
+            self.compile_expr(env, output)?;
+            // Pop the "function pointer" from the stack.
+            output.op(CoreOp::Pop(Some(A), 1));
+            // Call the procedure on the arguments.
+            Closure::call_value(output);
+        }
+        Ok(())
+    }
+
+    /// Bind the first closure passed to this call which captures by reference to a variable,
+    /// so that its environment record is allocated on the stack for the duration of the call.
+    fn bind_closure_arg(&self) -> Option<Self> {
+        let Self::Apply(f, args) = self else {
+            return None;
+        };
+        let i = args.iter().position(|arg| {
+            matches!(arg.without_annotations(), Self::Closure(closure) if closure.captures_by_reference() && !closure.has_inferred_args())
+        })?;
+        let Self::Closure(closure) = args[i].without_annotations() else {
+            return None;
+        };
+        let name = format!("#{}", closure.get_mangled_name());
+        let mut args = args.clone();
+        let closure = std::mem::replace(&mut args[i], Self::var(&name));
+        Some(Self::let_var(
+            name,
+            Mutability::Immutable,
+            None,
+            closure,
+            Self::Apply(f.clone(), args),
+        ))
+    }
+
+    /// Get the procedure this expression refers to, if it's known at compile time.
+    fn get_static_proc(&self, env: &Env) -> Option<Procedure> {
+        match self {
+            Self::Annotated(expr, _) => expr.get_static_proc(env),
+            // Variables can hold any procedure at runtime.
+            Self::ConstExpr(ConstExpr::Symbol(name))
+                if env.get_var(name).is_some()
+                    || env.get_captured_var(name).is_some()
+                    || env.get_static_var(name).is_some() =>
+            {
+                None
+            }
+            Self::ConstExpr(cexpr) => match cexpr.clone().eval(env).ok()? {
+                ConstExpr::Proc(proc) => Some(proc),
+                ConstExpr::Symbol(name) => env.get_proc(&name).cloned(),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Compile a while loop, with an optional label for `break` and `continue`.
+    ///
+    /// If the body can be cut short by a `break` or `continue`, the loop gets a control cell
+    /// on the stack. Loop control writes to the control cells of the loops it exits, and
+    /// the code following it in the loop body is skipped while the control cell is set.
+    /// This way, the loop always exits through the `While`/`End` block with the stack
+    /// and frame pointer as they were when the loop started.
+    fn compile_loop(
+        label: Option<String>,
+        cond: Self,
+        body: Self,
+        env: &mut Env,
+        output: &mut dyn AssemblyProgram,
+    ) -> Result<(), Error> {
+        if !body.can_exit_loop() {
+            // Eval the condition
+            cond.clone().compile_expr(env, output)?;
+            output.op(CoreOp::Pop(Some(A), 1));
+            // While the condition
+            output.op(CoreOp::While(A));
+            // Compile the body
+            body.compile_expr(env, output)?;
+            // Eval the condition again
+            cond.compile_expr(env, output)?;
+            output.op(CoreOp::Pop(Some(A), 1));
+            // Label the end of the loop
+            output.op(CoreOp::End);
+            return Ok(());
+        }
+
+        // Allocate the control cell for the loop.
+        let mut loop_env = env.clone();
+        let control = FP.deref().offset(loop_env.push_loop(label));
+        output.op(CoreOp::Set(A, LOOP_RUNNING));
+        output.op(CoreOp::Push(A, 1));
+
+        // Eval the condition
+        cond.clone().compile_expr(&mut loop_env, output)?;
+        output.op(CoreOp::Pop(Some(A), 1));
+        // While the condition
+        output.op(CoreOp::While(A));
+        // Compile the body
+        body.compile_expr(&mut loop_env, output)?;
+        // If the body was cut short by a `continue`, keep running the loop.
+        output.op(CoreOp::Set(B, LOOP_CONTINUED));
+        output.op(CoreOp::IsEqual {
+            a: control.clone(),
+            b: B,
+            dst: B,
+        });
+        output.op(CoreOp::If(B));
+        output.op(CoreOp::Set(control.clone(), LOOP_RUNNING));
+        output.op(CoreOp::End);
+        // Eval the condition again, unless the loop was exited by a `break`.
+        output.op(CoreOp::Move {
+            src: control,
+            dst: A,
+        });
+        output.op(CoreOp::Not(A));
+        output.op(CoreOp::If(A));
+        cond.compile_expr(&mut loop_env, output)?;
+        output.op(CoreOp::Pop(Some(A), 1));
+        output.op(CoreOp::End);
+        // Label the end of the loop
+        output.op(CoreOp::End);
+
+        // Pop the control cell.
+        output.op(CoreOp::Pop(None, 1));
+        Ok(())
+    }
+
+    /// Compile this expression so that it only runs if the innermost loop's body
+    /// hasn't been cut short by a `break` or `continue`.
+    fn compile_unless_exited(
+        self,
+        env: &mut Env,
+        output: &mut dyn AssemblyProgram,
+    ) -> Result<(), Error> {
+        let size = self.get_size(env)?;
+        compile_unless_exited(env, output, |env, output| {
+            self.compile_expr(env, output)?;
+            Ok(size)
+        })
+        .map(|_| ())
+    }
+}
+
+/// Compile some code so that it only runs if the innermost loop's body hasn't been cut short
+/// by a `break` or `continue`. The code returns how many cells it leaves on the stack; if it's
+/// skipped, the stack pointer is moved past that many cells instead, so the stack stays balanced.
+pub(crate) fn compile_unless_exited(
+    env: &mut Env,
+    output: &mut dyn AssemblyProgram,
+    compile: impl FnOnce(&mut Env, &mut dyn AssemblyProgram) -> Result<usize, Error>,
+) -> Result<usize, Error> {
+    let Some(control) = env.get_loop_control() else {
+        // Outside of a loop, there's nothing to skip.
+        return compile(env, output);
+    };
+    // Check that the control cell hasn't been set.
+    output.op(CoreOp::Move {
+        src: FP.deref().offset(control),
+        dst: A,
+    });
+    output.op(CoreOp::Not(A));
+    output.op(CoreOp::If(A));
+    let size = compile(env, output)?;
+    output.op(CoreOp::Else);
+    if size > 0 {
+        output.op(CoreOp::Next(SP, Some(size as isize)));
+    }
+    output.op(CoreOp::End);
+    Ok(size)
+}
+
 /// Compile a constant expression.
 impl Compile for ConstExpr {
     fn compile_expr(self, env: &mut Env, output: &mut dyn AssemblyProgram) -> Result<(), Error> {
@@ -951,14 +1507,20 @@ impl Compile for ConstExpr {
                     (Self::Tuple(tuple), Self::Int(n)) => {
                         // If the index is out of bounds, return an error.
                         if n >= tuple.len() as i64 || n < 0 {
-                            return Err(Error::MemberNotFound((*container).into(), *member));
+                            return Err(Error::MemberNotFound(
+                                Box::new((*container).into()),
+                                member,
+                            ));
                         }
                         tuple[n as usize].clone().compile_expr(env, output)?
                     }
                     (Self::Struct(fields), Self::Symbol(name)) => {
                         // If the field is not in the struct, return an error.
                         if !fields.contains_key(&name) {
-                            return Err(Error::MemberNotFound((*container).into(), *member));
+                            return Err(Error::MemberNotFound(
+                                Box::new((*container).into()),
+                                member,
+                            ));
                         }
                         fields[&name].clone().compile_expr(env, output)?
                     }
@@ -971,7 +1533,7 @@ impl Compile for ConstExpr {
                         }
                     }
                     _ => {
-                        return Err(Error::MemberNotFound((*container).into(), *member));
+                        return Err(Error::MemberNotFound(Box::new((*container).into()), member));
                     }
                 }
             }
@@ -1011,7 +1573,9 @@ impl Compile for ConstExpr {
                 }
                 Self::Template(params, result) => {
                     if params.len() != ty_args.len() {
-                        return Err(Error::InvalidMonomorphize(Self::Template(params, result)));
+                        return Err(Error::InvalidMonomorphize(Box::new(Self::Template(
+                            params, result,
+                        ))));
                     }
 
                     let mut result = *result.clone();
@@ -1025,7 +1589,7 @@ impl Compile for ConstExpr {
                 }
 
                 val => {
-                    return Err(Error::InvalidMonomorphize(val));
+                    return Err(Error::InvalidMonomorphize(Box::new(val)));
                 }
             },
 
@@ -1221,6 +1785,10 @@ impl Compile for ConstExpr {
             Self::FFIProcedure(ffi_proc) => {
                 ffi_proc.compile_expr(env, output)?;
             }
+            // A trait method has no implementation until its type is monomorphized.
+            Self::TraitMethod(..) => {
+                return Err(Error::InvalidConstExpr(Box::new(self)));
+            }
             // Compile a procedure.
             Self::Proc(proc) => {
                 // Get the mangled name of the procedure.
@@ -1236,7 +1804,7 @@ impl Compile for ConstExpr {
             }
 
             Self::PolyProc(poly_proc) => {
-                return Err(Error::CompilePolyProc(poly_proc));
+                return Err(Error::CompilePolyProc(Box::new(poly_proc)));
             }
 
             Self::TypeOf(expr) => {
@@ -1297,6 +1865,9 @@ impl Compile for ConstExpr {
                 if let Some((_, t, offset)) = env.get_var(&name) {
                     // If the symbol is a variable, push it onto the stack.
                     output.op(CoreOp::Push(FP.deref().offset(*offset), t.get_size(env)?))
+                } else if let Some((_, t, location)) = env.get_captured_var(&name) {
+                    // If the symbol is a captured variable, push it onto the stack.
+                    output.op(CoreOp::Push(location.clone(), t.get_size(env)?))
                 } else if let Some((_, t, location)) = env.get_static_var(&name) {
                     // If the symbol is a static variable, push it onto the stack.
                     output.op(CoreOp::Push(location.clone(), t.get_size(env)?))
@@ -5,8 +5,8 @@
 //! with respect to the frame pointer.
 
 use super::{
-    Compile, ConstExpr, Declaration, Error, Expr, FFIProcedure, GetSize, GetType, Mutability,
-    PolyProcedure, Procedure, Type,
+    Annotation, Closure, Compile, ConstExpr, Declaration, Error, Expr, FFIProcedure, GetSize,
+    GetType, Mutability, PolyProcedure, Procedure, Type, Warning,
 };
 use crate::asm::{AssemblyProgram, Globals, Location};
 use core::fmt::{Debug, Display, Formatter, Result as FmtResult};
@@ -18,6 +18,10 @@ use std::{
 
 use log::*;
 
+/// A type which implements a trait, with the annotation of the implementation.
+/// Bounded type parameters have no implementation, so they have no annotation.
+type TraitImpl = (Type, Option<Annotation>);
+
 /// An environment under which expressions and types are compiled and typechecked.
 /// This is essentially the scope of an expression.
 #[derive(Clone, Debug)]
@@ -28,16 +32,31 @@ pub struct Env {
     consts: Rc<HashMap<String, ConstExpr>>,
     /// The procedures defined under the environment.
     procs: Rc<HashMap<String, Procedure>>,
+    /// The traits defined under the environment, with the signatures of their methods.
+    traits: Rc<HashMap<String, Vec<(String, Type)>>>,
+    /// The types which implement each trait.
+    trait_impls: Rc<HashMap<String, Vec<TraitImpl>>>,
+    /// The methods the bounded type parameters of a polymorphic procedure get from their traits,
+    /// with the signature of each method.
+    trait_methods: Rc<HashMap<Type, HashMap<String, Type>>>,
     /// The variables defined under the environment.
     vars: Rc<HashMap<String, (Mutability, Type, isize)>>,
     /// The static variables defined under the environment.
     static_vars: Rc<HashMap<String, (Mutability, Type, Location)>>,
+    /// The variables captured by the closure whose body is being compiled.
+    captured_vars: Rc<HashMap<String, (Mutability, Type, Location)>>,
+    /// The variables bound to closures which capture variables by reference, with the first
+    /// variable each of them captures. These can't outlive the scope they're declared in.
+    scoped_closures: Rc<HashMap<String, (Closure, String)>>,
     /// A lookup for the offsets of global variables.
     globals: Rc<RwLock<Globals>>,
 
     processed_monomorphizations: Rc<RwLock<HashMap<Type, Vec<Type>>>>,
     /// Associated constants for types.
     associated_constants: Rc<RwLock<HashMap<Type, HashMap<String, (ConstExpr, Type)>>>>,
+    /// The names of all the associated constants, so operators can quickly tell
+    /// whether any type might overload them.
+    associated_const_names: Rc<RwLock<HashSet<String>>>,
     type_checked_consts: Rc<RwLock<HashSet<ConstExpr>>>,
 
     /// The current offset of the frame pointer to assign to the next variable.
@@ -50,9 +69,18 @@ pub struct Env {
     /// Expected return type of the current function.
     /// This is `None` if we are not currently compiling a function.
     expected_ret: Option<Type>,
+    /// Where the expected return type of the current function was declared.
+    expected_ret_annotation: Annotation,
+    /// The loops around the current expression in the current function, innermost last.
+    /// Each loop has an optional label, and the offset of its control cell from the frame pointer.
+    /// The control cell records whether a `break` or `continue` has cut the loop's body short.
+    loops: Vec<(Option<String>, isize)>,
 
     /// Memoized type sizes.
     type_sizes: Rc<HashMap<Type, usize>>,
+
+    /// The warnings reported so far. These are shared by every scope.
+    warnings: Rc<RwLock<Vec<Warning>>>,
 }
 
 impl Default for Env {
@@ -64,10 +92,16 @@ impl Default for Env {
             type_sizes: Rc::new(HashMap::new()),
             consts: Rc::new(HashMap::new()),
             procs: Rc::new(HashMap::new()),
+            traits: Rc::new(HashMap::new()),
+            trait_impls: Rc::new(HashMap::new()),
+            trait_methods: Rc::new(HashMap::new()),
             vars: Rc::new(HashMap::new()),
             static_vars: Rc::new(HashMap::new()),
+            captured_vars: Rc::new(HashMap::new()),
+            scoped_closures: Rc::new(HashMap::new()),
             globals: Rc::new(RwLock::new(Globals::new())),
             associated_constants: Rc::new(RwLock::new(HashMap::new())),
+            associated_const_names: Rc::new(RwLock::new(HashSet::new())),
             processed_monomorphizations: Rc::new(RwLock::new(HashMap::new())),
             type_checked_consts: Rc::new(RwLock::new(HashSet::new())),
 
@@ -75,6 +109,10 @@ impl Default for Env {
             fp_offset: 1,
             args_size: 0,
             expected_ret: None,
+            expected_ret_annotation: Annotation::None,
+            loops: vec![],
+
+            warnings: Rc::new(RwLock::new(vec![])),
         }
     }
 }
@@ -87,6 +125,9 @@ impl Env {
             types: self.types.clone(),
             consts: self.consts.clone(),
             procs: self.procs.clone(),
+            traits: self.traits.clone(),
+            trait_impls: self.trait_impls.clone(),
+            trait_methods: self.trait_methods.clone(),
             static_vars: self.static_vars.clone(),
             type_sizes: {
                 // Copy the data but not the lock.
@@ -102,11 +143,16 @@ impl Env {
                 let associated_constants = self.associated_constants.read().unwrap().clone();
                 Rc::new(RwLock::new(associated_constants))
             },
+            associated_const_names: {
+                let associated_const_names = self.associated_const_names.read().unwrap().clone();
+                Rc::new(RwLock::new(associated_const_names))
+            },
             type_checked_consts: {
                 // let type_checked_consts = self.type_checked_consts.read().unwrap().clone();
                 // Rc::new(RwLock::new(type_checked_consts))
                 self.type_checked_consts.clone()
             },
+            warnings: self.warnings.clone(),
 
             // The rest are the same as a new environment.
             ..Env::default()
@@ -143,6 +189,9 @@ impl Env {
     /// Get the type of an associated constant of a type.
     pub fn get_type_of_associated_const(&self, ty: &Type, name: &str) -> Option<Type> {
         trace!("Getting type of associated const {name} of type {ty} in {self}");
+        if let Some(signature) = self.get_trait_method(ty, name) {
+            return Some(signature.clone());
+        }
         let associated_constants = self.associated_constants.read().unwrap();
 
         if let Some((_, expr_ty)) = associated_constants
@@ -256,6 +305,12 @@ impl Env {
 
     pub fn get_associated_const(&self, ty: &Type, name: &str) -> Option<(ConstExpr, Type)> {
         trace!("Getting associated const {name} of type {ty} in {self}");
+        if let Some(signature) = self.get_trait_method(ty, name) {
+            return Some((
+                ConstExpr::TraitMethod(ty.clone(), name.to_string()),
+                signature.clone(),
+            ));
+        }
         let associated_constants = self.associated_constants.read().unwrap();
 
         if let Some((constant, const_ty)) = associated_constants
@@ -399,6 +454,11 @@ impl Env {
             return Ok(());
         }
         consts.insert(name.to_owned(), (constant, expr_ty));
+        drop(associated_constants);
+        self.associated_const_names
+            .write()
+            .unwrap()
+            .insert(name.to_owned());
         Ok(())
     }
 
@@ -406,6 +466,15 @@ impl Env {
         self.get_associated_const(ty, name).is_some()
     }
 
+    /// Does any type have an associated constant with the given name?
+    pub(crate) fn has_associated_const_named(&self, name: &str) -> bool {
+        self.associated_const_names.read().unwrap().contains(name)
+            || self
+                .trait_methods
+                .values()
+                .any(|methods| methods.contains_key(name))
+    }
+
     pub fn get_all_associated_consts(&self, ty: &Type) -> Vec<(String, ConstExpr)> {
         trace!("Getting all associated constants of type {ty}");
         let associated_constants = self.associated_constants.read().unwrap();
@@ -608,7 +677,12 @@ impl Env {
         associated_constants
             .entry(ty)
             .or_default()
-            .insert(associated_const_name, (expr, expr_ty));
+            .insert(associated_const_name.clone(), (expr, expr_ty));
+        drop(associated_constants);
+        self.associated_const_names
+            .write()
+            .unwrap()
+            .insert(associated_const_name);
 
         Ok(())
     }
@@ -620,6 +694,54 @@ impl Env {
         Ok(())
     }
 
+    /// Add all the declarations to this environment, like `add_declaration`, but keep
+    /// going when a variable can't be declared. The variables a failed declaration
+    /// would define are bound with the `Any` type instead, so the rest of the scope
+    /// can still be checked without reporting more errors about them.
+    /// The first error found is returned.
+    pub(super) fn add_declaration_or_poison(
+        &mut self,
+        declaration: &Declaration,
+    ) -> Result<(), Error> {
+        let result = self.add_compile_time_declaration(declaration);
+        result.and(self.add_local_variable_declaration_or_poison(declaration))
+    }
+
+    /// Add the runtime declarations to this environment, binding the variables
+    /// of any declaration that fails with the `Any` type.
+    fn add_local_variable_declaration_or_poison(
+        &mut self,
+        declaration: &Declaration,
+    ) -> Result<(), Error> {
+        match declaration {
+            Declaration::Many(decls) => {
+                let mut result = Ok(());
+                for decl in decls {
+                    let decl_result = self.add_local_variable_declaration_or_poison(decl);
+                    result = result.and(decl_result);
+                }
+                result
+            }
+            Declaration::Var(name, mutability, _, _) => {
+                let result = self.add_local_variable_declaration(declaration);
+                if result.is_err() {
+                    self.define_poisoned_var(name, *mutability);
+                }
+                result
+            }
+            Declaration::VarPat(pat, _) => {
+                let result = self.add_local_variable_declaration(declaration);
+                if result.is_err() {
+                    for (mutability, name) in pat.bound_symbols() {
+                        self.define_poisoned_var(name, mutability);
+                    }
+                }
+                result
+            }
+            _ => self.add_local_variable_declaration(declaration),
+        }
+    }
+
     /// Add all the compile-time declarations to this environment. These are declarations
     /// for types, constants, and procedures that are defined at compile-time. Variables
     /// are not included because they are defined at runtime.
@@ -647,6 +769,13 @@ impl Env {
             Declaration::StaticVar(name, mutability, ty, _expr) => {
                 self.define_static_var(name, *mutability, ty.clone())?;
             }
+            Declaration::Trait(name, methods) => {
+                self.define_trait(name, methods.clone());
+            }
+            Declaration::ImplTrait(trait_name, ty, impls, annotation) => {
+                self.add_compile_time_declaration(&Declaration::Impl(ty.clone(), impls.clone()))?;
+                self.define_trait_impl(trait_name, ty.clone(), annotation)?;
+            }
             Declaration::Impl(ty, impls) => {
                 if let Type::Apply(template, supplied_params) = ty {
                     // If this is an implementation for a template type, we need to
@@ -657,7 +786,7 @@ impl Env {
                         return Err(Error::MismatchedTypes {
                             expected: *template.clone(),
                             found: Type::Apply(template.clone(), supplied_params.clone()),
-                            expr: Expr::NONE.with(declaration.clone()),
+                            expr: Box::new(Expr::NONE.with(declaration.clone())),
                         });
                     }
 
@@ -672,7 +801,7 @@ impl Env {
                                         "A symbol, not a concrete type".to_owned(),
                                     ),
                                     found: ty.clone(),
-                                    expr: Expr::NONE.with(declaration.clone()),
+                                    expr: Box::new(Expr::NONE.with(declaration.clone())),
                                 })
                             }
                         })
@@ -753,16 +882,25 @@ impl Env {
             Declaration::StaticVar(_, _, _, _) => {
                 // Static variables are not defined at runtime.
             }
-            Declaration::Impl(_, _) => {
+            Declaration::Impl(_, _) | Declaration::ImplTrait(_, _, _, _) => {
                 // Implementations are not defined at runtime.
             }
+            Declaration::Trait(_, _) => {
+                // Traits are not defined at runtime.
+            }
             Declaration::Var(name, mutability, ty, expr) => {
                 let ty = match ty {
                     Some(ty) => ty.clone(),
                     None => expr.get_type(self)?,
                 };
+                let scoped_closure = expr.get_scoped_closure(self);
                 // ty.add_monomorphized_associated_consts(self)?;
                 self.define_var(name, *mutability, ty)?;
+                // A variable bound to a closure which captures by reference
+                // can't outlive the variables the closure captures either.
+                if let Some(scoped_closure) = scoped_closure {
+                    Rc::make_mut(&mut self.scoped_closures).insert(name.clone(), scoped_closure);
+                }
             }
             Declaration::VarPat(pat, expr) => {
                 let ty = expr.get_type(self)?;
@@ -805,6 +943,29 @@ impl Env {
         self.static_vars.get(name)
     }
 
+    /// Define a variable captured by a closure, stored at the given location
+    /// in the closure's environment.
+    pub(super) fn define_captured_var(
+        &mut self,
+        name: impl ToString,
+        mutability: Mutability,
+        ty: Type,
+        location: Location,
+    ) {
+        let name = name.to_string();
+        trace!("Defining captured variable {name} of type {ty} at {location}");
+        Rc::make_mut(&mut self.captured_vars).insert(name, (mutability, ty, location));
+    }
+
+    /// Get a captured variable definition from this environment.
+    /// This contains:
+    /// 1. The mutability of the variable.
+    /// 2. The type of the variable.
+    /// 3. The location of the variable in memory.
+    pub(super) fn get_captured_var(&self, name: &str) -> Option<&(Mutability, Type, Location)> {
+        self.captured_vars.get(name)
+    }
+
     /// Define a type with a given name under this environment.
     pub(super) fn define_type(&mut self, name: impl ToString, ty: Type) {
         let name = name.to_string();
@@ -889,6 +1050,103 @@ impl Env {
         Rc::make_mut(&mut self.consts).insert(name, ConstExpr::PolyProc(proc));
     }
 
+    /// Define a trait with the given method signatures under this environment.
+    pub(super) fn define_trait(&mut self, name: impl ToString, methods: Vec<(String, Type)>) {
+        let name = name.to_string();
+        trace!("Defining trait {name}");
+        Rc::make_mut(&mut self.traits).insert(name, methods);
+    }
+
+    /// Get the method signatures of a trait defined under this environment.
+    pub(super) fn get_trait(&self, name: &str) -> Option<&Vec<(String, Type)>> {
+        self.traits.get(name)
+    }
+
+    /// Define a method a bounded type parameter gets from one of its traits, with the
+    /// method's signature. The method can be type checked like an associated constant,
+    /// but it has no implementation until the type parameter is monomorphized.
+    pub(super) fn define_trait_method(&mut self, ty: Type, method: impl ToString, signature: Type) {
+        let method = method.to_string();
+        trace!("Defining trait method {method} for {ty} as {signature}");
+        Rc::make_mut(&mut self.trait_methods)
+            .entry(ty)
+            .or_default()
+            .insert(method, signature);
+    }
+
+    /// Get the signature of a method a bounded type parameter gets from a trait.
+    pub(super) fn get_trait_method(&self, ty: &Type, method: &str) -> Option<&Type> {
+        self.trait_methods
+            .get(ty)
+            .or_else(|| {
+                // The type may refer to the type parameter by name.
+                self.trait_methods
+                    .iter()
+                    .find(|(param, _)| ty.equals(param, self).unwrap_or(false))
+                    .map(|(_, methods)| methods)
+            })
+            .and_then(|methods| methods.get(method))
+    }
+
+    /// Record that a type implements a trait under this environment. Adding the
+    /// same implementation again does nothing, but a different implementation
+    /// of the trait for the same type conflicts with it.
+    pub(super) fn define_trait_impl(
+        &mut self,
+        trait_name: impl ToString,
+        ty: Type,
+        annotation: &Annotation,
+    ) -> Result<(), Error> {
+        let trait_name = trait_name.to_string();
+        trace!("Defining implementation of trait {trait_name} for {ty}");
+        let impls = self.trait_impls.get(&trait_name).into_iter().flatten();
+        for (impl_ty, impl_annotation) in impls {
+            match impl_annotation {
+                Some(impl_annotation) if impl_ty == &ty => {
+                    if impl_annotation == annotation {
+                        return Ok(());
+                    }
+                    return Err(Error::ConflictingTraitImpl { trait_name, ty }
+                        .with_label(impl_annotation, "first implemented here")
+                        .annotate(annotation.clone()));
+                }
+                _ => {}
+            }
+        }
+        Rc::make_mut(&mut self.trait_impls)
+            .entry(trait_name)
+            .or_default()
+            .push((ty, Some(annotation.clone())));
+        Ok(())
+    }
+
+    /// Record that a bounded type parameter implements a trait in the body of
+    /// a polymorphic procedure.
+    pub(super) fn define_trait_bound(&mut self, trait_name: impl ToString, ty: Type) {
+        let trait_name = trait_name.to_string();
+        trace!("Defining bound of {ty} on trait {trait_name}");
+        Rc::make_mut(&mut self.trait_impls)
+            .entry(trait_name)
+            .or_default()
+            .push((ty, None));
+    }
+
+    /// Does a type (or the template it is a monomorph of) implement a trait?
+    pub(super) fn implements_trait(&self, ty: &Type, trait_name: &str) -> Result<bool, Error> {
+        for (impl_ty, _) in self.trait_impls.get(trait_name).into_iter().flatten() {
+            // An implementation for a template type is an implementation
+            // for all of the template's monomorphs.
+            let impl_ty = match impl_ty {
+                Type::Apply(template, _) => template,
+                impl_ty => impl_ty,
+            };
+            if ty.equals(impl_ty, self)? || ty.is_monomorph_of(impl_ty, self)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
     /// Define an FFI procedure with a given name under this environment.
     pub(super) fn define_ffi_proc(&mut self, name: impl ToString, proc: FFIProcedure) {
         let name = name.to_string();
@@ -934,10 +1192,18 @@ impl Env {
         self.vars.get(var)
     }
 
+    /// Get the closure which captures by reference that a variable is bound to,
+    /// and the first variable the closure captures.
+    pub(super) fn get_scoped_closure(&self, var: &str) -> Option<&(Closure, String)> {
+        self.scoped_closures.get(var)
+    }
+
     /// Is the variable defined in scope as mutable?
     pub(super) fn is_defined_as_mutable(&self, var: &str) -> bool {
         if let Some((mutability, _, _)) = self.vars.get(var) {
             mutability.is_mutable()
+        } else if let Some((mutability, _, _)) = self.captured_vars.get(var) {
+            mutability.is_mutable()
         } else if let Some((mutability, _, _)) = self.static_vars.get(var) {
             mutability.is_mutable()
         } else {
@@ -996,12 +1262,69 @@ impl Env {
         // so that the next variable is allocated directly after this variable.
         debug!("Defining variable {var} of type {ty} at {offset} in\n{self}");
         self.fp_offset += size;
+        // The variable shadows any closure bound to the same name.
+        if self.scoped_closures.contains_key(&var) {
+            Rc::make_mut(&mut self.scoped_closures).remove(&var);
+        }
         // Store the variable's type and offset in the environment.
         Rc::make_mut(&mut self.vars).insert(var, (mutability, ty, offset));
         // Return the offset of the variable from the frame pointer.
         Ok(offset)
     }
 
+    /// Bind a variable whose declaration failed to typecheck with the `Any` type.
+    /// The variable is given no space in the frame, since code which uses it
+    /// is never compiled.
+    fn define_poisoned_var(&mut self, var: impl ToString, mutability: Mutability) {
+        let var = var.to_string();
+        debug!("Poisoning variable {var}");
+        if self.scoped_closures.contains_key(&var) {
+            Rc::make_mut(&mut self.scoped_closures).remove(&var);
+        }
+        Rc::make_mut(&mut self.vars).insert(var, (mutability, Type::Any, self.fp_offset));
+    }
+
+    /// Report a warning about the code being compiled.
+    pub fn warn(&self, warning: Warning) {
+        debug!("Warning: {warning}");
+        self.warnings.write().unwrap().push(warning);
+    }
+
+    /// Get the warnings reported so far, in the order they were reported.
+    /// Warnings reported more than once (like in a polymorphic procedure which
+    /// is type checked for each of its monomorphs) are only included once.
+    pub fn get_warnings(&self) -> Vec<Warning> {
+        let mut result: Vec<Warning> = vec![];
+        for warning in self.warnings.read().unwrap().iter() {
+            if !result.contains(warning) {
+                result.push(warning.clone());
+            }
+        }
+        result
+    }
+
+    /// Take the warnings reported so far, like `get_warnings`. The warnings
+    /// reported after this are about the code checked after it.
+    pub fn take_warnings(&self) -> Vec<Warning> {
+        let result = self.get_warnings();
+        self.warnings.write().unwrap().clear();
+        result
+    }
+
+    /// Get the number of warnings reported so far.
+    pub(super) fn warning_count(&self) -> usize {
+        self.warnings.read().unwrap().len()
+    }
+
+    /// Annotate the warnings reported since there were `start` warnings which don't
+    /// have a location yet.
+    pub(super) fn annotate_warnings(&self, start: usize, annotation: &Annotation) {
+        let mut warnings = self.warnings.write().unwrap();
+        for warning in warnings.iter_mut().skip(start) {
+            *warning = warning.clone().annotate(annotation.clone());
+        }
+    }
+
     /// Get the expected return type of the current function.
     /// This is used to check if the returned value of a function matches the expected return type.
     /// This method returns `None` if the current scope is not a function.
@@ -1014,6 +1337,53 @@ impl Env {
     /// If we're not in a function, this will be `None`.
     pub(super) fn set_expected_return_type(&mut self, t: Type) {
         self.expected_ret = Some(t);
+        self.expected_ret_annotation = Annotation::None;
+    }
+
+    /// Get where the expected return type of the current function was declared.
+    /// This is used to point at the return type when a returned value doesn't match it.
+    pub(super) fn get_return_annotation(&self) -> &Annotation {
+        &self.expected_ret_annotation
+    }
+
+    /// Set where the expected return type of the current function was declared.
+    pub(super) fn set_return_annotation(&mut self, annotation: Annotation) {
+        self.expected_ret_annotation = annotation;
+    }
+
+    /// Enter a loop, and allocate a control cell for it as the next variable on the stack.
+    /// This returns the offset of the control cell from the frame pointer.
+    pub(super) fn push_loop(&mut self, label: Option<String>) -> isize {
+        let offset = self.fp_offset;
+        self.fp_offset += 1;
+        self.loops.push((label, offset));
+        offset
+    }
+
+    /// Get the offset of the innermost loop's control cell from the frame pointer.
+    /// This method returns `None` if the current scope is not in a loop.
+    pub(super) fn get_loop_control(&self) -> Option<isize> {
+        self.loops.last().map(|(_, offset)| *offset)
+    }
+
+    /// Get the control cells of all the loops a `break` or `continue` exits, from the
+    /// innermost loop to the loop with the given label (or just the innermost loop,
+    /// if there is no label). This method returns `None` if there's no such loop.
+    pub(super) fn get_exited_loops(&self, label: Option<&str>) -> Option<Vec<isize>> {
+        let target = match label {
+            None => self.loops.len().checked_sub(1)?,
+            Some(label) => self
+                .loops
+                .iter()
+                .rposition(|(name, _)| name.as_deref() == Some(label))?,
+        };
+        Some(
+            self.loops[target..]
+                .iter()
+                .rev()
+                .map(|(_, offset)| *offset)
+                .collect(),
+        )
     }
 
     /// Does the environment have some precalculated size for the given type?
@@ -1,10 +1,14 @@
 use super::{
-    Annotation, AssignOp, BinaryOp, ConstExpr, Expr, Mutability, Pattern, PolyProcedure, TernaryOp,
-    Type, UnaryOp,
+    Annotation, AssignOp, BinaryOp, ConstExpr, Constraint, Expr, Mutability, Pattern,
+    PolyProcedure, TernaryOp, Type, UnaryOp,
 };
+use crate::parse::SourceCodeLocation;
 use core::fmt::{Debug, Display, Formatter, Result as FmtResult};
 
 /// An LIR compilation error.
+///
+/// The expressions and patterns an error refers to are boxed, so that results
+/// returned throughout the compiler stay small.
 #[derive(Clone, Debug)]
 pub enum Error {
     /// An error with some annotation about the source code that caused the error.
@@ -15,6 +19,14 @@ pub enum Error {
         /// This is used for error reporting.
         Annotation,
     ),
+    /// An error with a secondary location in the source code which is related to it,
+    /// like the declaration an expected type came from, and a message about that location.
+    Labeled(Box<Self>, Annotation, String),
+    /// An error with a note or suggestion about how to fix it.
+    Noted(Box<Self>, String),
+    /// Several independent errors. The type checker recovers from an error in
+    /// a statement or declaration, and reports the errors in the rest of the code too.
+    Many(Vec<Self>),
 
     /// An error caused by trying to assemble invalid code generated by the compiler.
     /// This should be taken seriously, unless the error is due to an invalid handwritten builtin.
@@ -23,42 +35,42 @@ pub enum Error {
     /// The variant of an enum is not defined.
     VariantNotFound(Type, String),
     /// Tried to access an undefined member of a tuple, struct, or union.
-    MemberNotFound(Expr, ConstExpr),
+    MemberNotFound(Box<Expr>, Box<ConstExpr>),
     /// Recursion depth exceeded when trying to evaluate a constant expression.
-    RecursionDepthConst(ConstExpr),
+    RecursionDepthConst(Box<ConstExpr>),
     /// Recursion depth exceeded when trying to confirm a type's equality to another type.
     CouldntSimplify(Type, Type),
     /// Recursion depth exceeded when trying to confirm a type's equality to another type.
     RecursionDepthTypeEquality(Type, Type),
     /// Got another type when expecting an integer, bool, or char.
-    NonIntegralConst(ConstExpr),
+    NonIntegralConst(Box<ConstExpr>),
     /// Tried to instantiate a type that cannot be sized.
     /// This is a problem because we cannot manage the stack if we cannot know the size of the type.
     UnsizedType(Type),
     /// Tried to dereference a non-pointer.
-    DerefNonPointer(Expr),
+    DerefNonPointer(Box<Expr>),
     /// Tried to apply a non-procedure to some arguments.
-    ApplyNonProc(Expr),
+    ApplyNonProc(Box<Expr>),
     /// Expected a symbol, but got something else.
-    NonSymbol(ConstExpr),
+    NonSymbol(Box<ConstExpr>),
     /// Invalid `Index` expression (incorrect types).
-    InvalidIndex(Expr),
+    InvalidIndex(Box<Expr>),
     /// Invalid `Refer` expression. The compiler was not able to calculate the address of the expression.
-    InvalidRefer(Expr),
+    InvalidRefer(Box<Expr>),
     /// Invalid unary operation (negate, not) expression (incorrect types).
-    InvalidUnaryOp(Box<dyn UnaryOp>, Expr),
+    InvalidUnaryOp(Box<dyn UnaryOp>, Box<Expr>),
     /// Invalid unary op types (incorrect types).
     InvalidUnaryOpTypes(Box<dyn UnaryOp>, Type),
     /// Invalid binary operation (add, subtract, and, or) expression (incorrect types).
-    InvalidBinaryOp(Box<dyn BinaryOp>, Expr, Expr),
+    InvalidBinaryOp(Box<dyn BinaryOp>, Box<Expr>, Box<Expr>),
     /// Invalid binary op types (incorrect types).
     InvalidBinaryOpTypes(Box<dyn BinaryOp>, Type, Type),
     /// Invalid ternary operation (if) expression (incorrect types).
-    InvalidTernaryOp(Box<dyn TernaryOp>, Expr, Expr, Expr),
+    InvalidTernaryOp(Box<dyn TernaryOp>, Box<Expr>, Box<Expr>, Box<Expr>),
     /// Invalid ternary op types (incorrect types).
-    InvalidTernaryOpTypes(Box<dyn TernaryOp>, Type, Type, Type),
+    InvalidTernaryOpTypes(Box<dyn TernaryOp>, Box<Type>, Box<Type>, Box<Type>),
     /// Invalid assignment operation (assign, add_assign, subtract_assign, and_assign, or_assign) expression (incorrect types).
-    InvalidAssignOp(Box<dyn AssignOp>, Expr, Expr),
+    InvalidAssignOp(Box<dyn AssignOp>, Box<Expr>, Box<Expr>),
     /// Invalid assign op types (incorrect types).
     InvalidAssignOpTypes(Box<dyn AssignOp>, Type, Type),
 
@@ -66,47 +78,64 @@ pub enum Error {
     MismatchedTypes {
         expected: Type,
         found: Type,
-        expr: Expr,
+        expr: Box<Expr>,
     },
     /// Mismatched mutability
     MismatchedMutability {
         expected: Mutability,
         found: Mutability,
-        expr: Expr,
+        expr: Box<Expr>,
     },
 
     /// A symbol was used, but not defined.
     SymbolNotDefined(String),
     /// A type was used, but not defined.
     TypeNotDefined(String),
+    /// A `break` or `continue` referred to a loop label that isn't defined.
+    LabelNotDefined(String),
+    /// Tried to `break` or `continue` outside of a loop.
+    LoopControlOutsideLoop(Box<Expr>),
+    /// Tried to loop over a value which isn't an array and has no `next` or `iter` method.
+    NotIterable(Type, Box<Expr>),
+    /// Tried to use `?` on a value which isn't a tagged union with an `Ok` or `Some` variant.
+    NotTryable(Type, Box<Expr>),
+    /// Tried to use `?` in a procedure which can't return the other variants of the value.
+    IncompatibleTryReturn {
+        expr: Box<Expr>,
+        ty: Type,
+        expected_ret: Type,
+    },
     /// Tried to create an array with a negative length.
-    NegativeArrayLength(Expr),
+    NegativeArrayLength(Box<Expr>),
 
     /// Tried to use a pattern that is not valid for the given type.
-    InvalidPatternForType(Type, Pattern),
+    InvalidPatternForType(Type, Box<Pattern>),
     /// Tried to use a pattern that is not valid for the given expression.
-    InvalidPatternForExpr(Expr, Pattern),
+    InvalidPatternForExpr(Box<Expr>, Box<Pattern>),
 
     /// Tried to match over an expression that cannot be matched over.
-    InvalidMatchExpr(Expr),
+    InvalidMatchExpr(Box<Expr>),
 
     /// Invalid pattern for a match expression.
-    NonExhaustivePatterns { patterns: Vec<Pattern>, expr: Expr },
+    NonExhaustivePatterns {
+        patterns: Vec<Pattern>,
+        expr: Box<Expr>,
+    },
 
     /// Invalid type casting expression.
-    InvalidAs(Expr, Type, Type),
+    InvalidAs(Box<Expr>, Type, Type),
 
     /// Invalid constant expression.
-    InvalidConstExpr(ConstExpr),
+    InvalidConstExpr(Box<ConstExpr>),
 
     /// Expression uses an operation unsupported by the target.
-    UnsupportedOperation(Expr),
+    UnsupportedOperation(Box<Expr>),
 
     /// Tried to define a type that already exists.
     TypeRedefined(String),
 
     /// Unused expression returned a non-None value.
-    UnusedExpr(Expr, Type),
+    UnusedExpr(Box<Expr>, Type),
 
     /// Invalid number of template arguments to a type.
     InvalidTemplateArgs(Type),
@@ -114,14 +143,48 @@ pub enum Error {
     /// Tried to apply a non-template type to some arguments.
     ApplyNonTemplate(Type),
 
+    /// Couldn't infer some template arguments of a polymorphic procedure call
+    /// from the types of the supplied arguments.
+    AmbiguousTemplateArgs {
+        params: Vec<String>,
+        expr: Box<Expr>,
+    },
+
+    /// Two uses of a variable whose type is inferred require it to have different types.
+    ConflictingConstraints(Box<Constraint>, Box<Constraint>),
+    /// Couldn't infer the type of a closure parameter from its uses.
+    CannotInferType(String, Box<Expr>),
+    /// A closure which captures a variable by reference is returned or stored,
+    /// so it may outlive the variable.
+    EscapingCapture(String, Box<Expr>),
+
+    /// A trait was used, but not defined.
+    TraitNotDefined(String),
+    /// A type argument of a polymorphic procedure doesn't implement a trait its parameter is bounded by.
+    UnsatisfiedBound {
+        ty: Type,
+        trait_name: String,
+        expr: Box<ConstExpr>,
+    },
+    /// An implementation of a trait doesn't define one of the trait's methods.
+    MissingTraitMethod {
+        trait_name: String,
+        ty: Type,
+        method: String,
+    },
+    /// An implementation of a trait defines a method that isn't part of the trait.
+    NotTraitMethod { trait_name: String, method: String },
+    /// A trait is implemented more than once for the same type.
+    ConflictingTraitImpl { trait_name: String, ty: Type },
+
     /// Tried to get the size of a template type.
     SizeOfTemplate(Type),
 
     /// Tried to  compile a polymorphic procedure without monomorphing it.
-    CompilePolyProc(PolyProcedure),
+    CompilePolyProc(Box<PolyProcedure>),
 
     /// Cannot monomorphize a constant expression.
-    InvalidMonomorphize(ConstExpr),
+    InvalidMonomorphize(Box<ConstExpr>),
 }
 
 impl Error {
@@ -135,9 +198,187 @@ impl Error {
                 *previous_annotation = result;
                 self
             }
+            Self::Many(errs) => Self::Many(
+                errs.drain(..)
+                    .map(|err| err.annotate(annotation.clone()))
+                    .collect(),
+            ),
             _ => Self::Annotated(Box::new(self), annotation),
         }
     }
+
+    /// Get the stable code identifying the kind of this error, like `E0024`.
+    /// The codes of existing errors never change, so tools can rely on them.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Annotated(err, _) | Self::Labeled(err, ..) | Self::Noted(err, _) => err.code(),
+            // Lists of errors are reported as each of their errors.
+            Self::Many(_) => "E0000",
+            Self::AssemblyError { .. } => "E0001",
+            Self::VariantNotFound { .. } => "E0002",
+            Self::MemberNotFound { .. } => "E0003",
+            Self::RecursionDepthConst { .. } => "E0004",
+            Self::CouldntSimplify { .. } => "E0005",
+            Self::RecursionDepthTypeEquality { .. } => "E0006",
+            Self::NonIntegralConst { .. } => "E0007",
+            Self::UnsizedType { .. } => "E0008",
+            Self::DerefNonPointer { .. } => "E0009",
+            Self::ApplyNonProc { .. } => "E0010",
+            Self::NonSymbol { .. } => "E0011",
+            Self::InvalidIndex { .. } => "E0012",
+            Self::InvalidRefer { .. } => "E0013",
+            Self::InvalidUnaryOp { .. } => "E0014",
+            Self::InvalidUnaryOpTypes { .. } => "E0015",
+            Self::InvalidBinaryOp { .. } => "E0016",
+            Self::InvalidBinaryOpTypes { .. } => "E0017",
+            Self::InvalidTernaryOp { .. } => "E0018",
+            Self::InvalidTernaryOpTypes { .. } => "E0019",
+            Self::InvalidAssignOp { .. } => "E0020",
+            Self::InvalidAssignOpTypes { .. } => "E0021",
+            Self::MismatchedTypes { .. } => "E0022",
+            Self::MismatchedMutability { .. } => "E0023",
+            Self::SymbolNotDefined { .. } => "E0024",
+            Self::TypeNotDefined { .. } => "E0025",
+            Self::LabelNotDefined { .. } => "E0026",
+            Self::LoopControlOutsideLoop { .. } => "E0027",
+            Self::NotIterable { .. } => "E0028",
+            Self::NotTryable { .. } => "E0029",
+            Self::IncompatibleTryReturn { .. } => "E0030",
+            Self::NegativeArrayLength { .. } => "E0031",
+            Self::InvalidPatternForType { .. } => "E0032",
+            Self::InvalidPatternForExpr { .. } => "E0033",
+            Self::InvalidMatchExpr { .. } => "E0034",
+            Self::NonExhaustivePatterns { .. } => "E0035",
+            Self::InvalidAs { .. } => "E0036",
+            Self::InvalidConstExpr { .. } => "E0037",
+            Self::UnsupportedOperation { .. } => "E0038",
+            Self::TypeRedefined { .. } => "E0039",
+            Self::UnusedExpr { .. } => "E0040",
+            Self::InvalidTemplateArgs { .. } => "E0041",
+            Self::ApplyNonTemplate { .. } => "E0042",
+            Self::AmbiguousTemplateArgs { .. } => "E0043",
+            Self::ConflictingConstraints { .. } => "E0044",
+            Self::CannotInferType { .. } => "E0045",
+            Self::TraitNotDefined { .. } => "E0046",
+            Self::UnsatisfiedBound { .. } => "E0047",
+            Self::MissingTraitMethod { .. } => "E0048",
+            Self::NotTraitMethod { .. } => "E0049",
+            Self::SizeOfTemplate { .. } => "E0050",
+            Self::CompilePolyProc { .. } => "E0051",
+            Self::InvalidMonomorphize { .. } => "E0052",
+            Self::EscapingCapture { .. } => "E0053",
+            Self::ConflictingTraitImpl { .. } => "E0054",
+        }
+    }
+
+    /// Get the source code location of this error, if it has one.
+    pub fn location(&self) -> Option<&SourceCodeLocation> {
+        match self {
+            Self::Annotated(err, annotation) => annotation.location().or_else(|| err.location()),
+            Self::Labeled(err, ..) | Self::Noted(err, _) => err.location(),
+            _ => None,
+        }
+    }
+
+    /// Point at a secondary location in the source code which is related to this error.
+    /// If the annotation has no location, the error is returned unchanged.
+    pub fn with_label(self, annotation: &Annotation, message: impl ToString) -> Self {
+        if !annotation.has_location() {
+            return self;
+        }
+        Self::Labeled(Box::new(self), annotation.clone(), message.to_string())
+    }
+
+    /// Attach a note or suggestion about how to fix this error.
+    pub fn with_note(self, note: impl ToString) -> Self {
+        Self::Noted(Box::new(self), note.to_string())
+    }
+
+    /// Attach a note suggesting how to fix this error, if there is a common fix for it.
+    pub fn with_suggestion(self) -> Self {
+        let note = match &self {
+            Self::MismatchedTypes {
+                expected: expected @ (Type::Int | Type::Float),
+                found: found @ (Type::Int | Type::Float),
+                ..
+            } if expected != found => {
+                format!("convert the {found} to {expected} with `as {expected}`")
+            }
+            _ => return self,
+        };
+        self.with_note(note)
+    }
+
+    /// Get the secondary locations related to this error, and their messages.
+    pub fn labels(&self) -> Vec<(&SourceCodeLocation, &str)> {
+        match self {
+            Self::Annotated(err, _) | Self::Noted(err, _) => err.labels(),
+            Self::Labeled(err, annotation, message) => {
+                let mut labels = err.labels();
+                if let Some(loc) = annotation.location() {
+                    labels.insert(0, (loc, message.as_str()));
+                }
+                labels
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Get the notes attached to this error.
+    pub fn notes(&self) -> Vec<&str> {
+        match self {
+            Self::Annotated(err, _) | Self::Labeled(err, ..) => err.notes(),
+            Self::Noted(err, note) => {
+                let mut notes = err.notes();
+                notes.insert(0, note.as_str());
+                notes
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Combine several errors into one. Nested lists of errors are flattened,
+    /// errors with the same message at the same location are only kept once,
+    /// and a single error is returned as-is.
+    pub fn many(errs: impl IntoIterator<Item = Self>) -> Self {
+        let mut result: Vec<Self> = vec![];
+        let flattened = errs.into_iter().flat_map(|err| match err {
+            Self::Many(errs) => errs,
+            err => vec![err],
+        });
+        for err in flattened {
+            // The same error can be found by checking the same code more than once.
+            let message = err.to_string();
+            if !result
+                .iter()
+                .any(|found| found.location() == err.location() && found.to_string() == message)
+            {
+                result.push(err);
+            }
+        }
+        if result.len() == 1 {
+            result.remove(0)
+        } else {
+            Self::Many(result)
+        }
+    }
+
+    /// Return all the errors found, or success if there were none.
+    pub fn check_all(errs: Vec<Self>) -> Result<(), Self> {
+        if errs.is_empty() {
+            Ok(())
+        } else {
+            Err(Self::many(errs))
+        }
+    }
+
+    /// Get the independent errors this error consists of.
+    pub fn errors(&self) -> Vec<&Self> {
+        match self {
+            Self::Many(errs) => errs.iter().flat_map(Self::errors).collect(),
+            err => vec![err],
+        }
+    }
 }
 
 /// Create an IR error from an assembly error.
@@ -150,9 +391,18 @@ impl From<crate::asm::Error> for Error {
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter) -> FmtResult {
         match self {
-            Self::Annotated(err, _) => {
+            Self::Annotated(err, _) | Self::Labeled(err, ..) | Self::Noted(err, _) => {
                 write!(f, "{err}")
             }
+            Self::Many(errs) => {
+                for (i, err) in errs.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{err}")?;
+                }
+                Ok(())
+            }
             Self::MismatchedTypes {
                 expected,
                 found,
@@ -265,6 +515,29 @@ impl Display for Error {
             Self::TypeNotDefined(ty) => {
                 write!(f, "type {} not defined", ty)
             }
+            Self::LabelNotDefined(label) => {
+                write!(f, "loop label '{} not defined", label)
+            }
+            Self::LoopControlOutsideLoop(expr) => {
+                write!(f, "{} used outside of a loop", expr)
+            }
+            Self::NotIterable(ty, expr) => {
+                write!(f, "cannot iterate over {} of type {}", expr, ty)
+            }
+            Self::NotTryable(ty, expr) => {
+                write!(f, "cannot use `?` on {} of type {}", expr, ty)
+            }
+            Self::IncompatibleTryReturn {
+                expr,
+                ty,
+                expected_ret,
+            } => {
+                write!(
+                    f,
+                    "cannot use `?` on {} of type {} in a procedure returning {}",
+                    expr, ty, expected_ret
+                )
+            }
             Self::NegativeArrayLength(expr) => {
                 write!(f, "negative array length {}", expr)
             }
@@ -309,6 +582,68 @@ impl Display for Error {
             Self::ApplyNonTemplate(ty) => {
                 write!(f, "tried to apply non-template type {}", ty)
             }
+            Self::AmbiguousTemplateArgs { params, expr } => {
+                write!(
+                    f,
+                    "couldn't infer template arguments {} for {}, they must be supplied explicitly",
+                    params.join(", "),
+                    expr
+                )
+            }
+            Self::ConflictingConstraints(first, second) => {
+                write!(f, "conflicting constraints: {first}, but {second}")
+            }
+            Self::CannotInferType(name, expr) => {
+                write!(
+                    f,
+                    "couldn't infer the type of {name} in {expr}, it must be supplied explicitly"
+                )
+            }
+            Self::EscapingCapture(name, expr) => {
+                write!(
+                    f,
+                    "closure {expr} captures {name} by reference, but it may outlive {name}"
+                )
+            }
+            Self::TraitNotDefined(name) => {
+                write!(f, "trait {} not defined", name)
+            }
+            Self::UnsatisfiedBound {
+                ty,
+                trait_name,
+                expr,
+            } => {
+                write!(
+                    f,
+                    "type {} does not implement trait {}, which is required by {}",
+                    ty, trait_name, expr
+                )
+            }
+            Self::MissingTraitMethod {
+                trait_name,
+                ty,
+                method,
+            } => {
+                write!(
+                    f,
+                    "implementation of trait {} for {} is missing method {}",
+                    trait_name, ty, method
+                )
+            }
+            Self::NotTraitMethod { trait_name, method } => {
+                write!(
+                    f,
+                    "method {} is not a member of trait {}",
+                    method, trait_name
+                )
+            }
+            Self::ConflictingTraitImpl { trait_name, ty } => {
+                write!(
+                    f,
+                    "conflicting implementations of trait {} for {}",
+                    trait_name, ty
+                )
+            }
             Self::SizeOfTemplate(ty) => {
                 write!(f, "tried to get size of template type {}", ty)
             }
@@ -16,7 +16,7 @@ use crate::lir::{
 use log::*;
 
 use core::fmt;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::hash::{Hash, Hasher};
 
 /// A compiletime expression.
@@ -78,6 +78,10 @@ pub enum ConstExpr {
     StandardBuiltin(StandardBuiltin),
     /// A foreign function interface binding.
     FFIProcedure(FFIProcedure),
+    /// A method of a trait, on a type parameter bounded by the trait.
+    /// This is only used to type check the body of a polymorphic procedure,
+    /// and it is replaced by the implementation when the procedure is monomorphized.
+    TraitMethod(Type, String),
     /// A procedure.
     Proc(Procedure),
     /// A polymorphic procedure.
@@ -187,7 +191,7 @@ impl ConstExpr {
         let i = i + 1;
         if i > 10 {
             error!("Recursion depth exceeded while evaluating: {self}");
-            Err(Error::RecursionDepthConst(self))
+            Err(Error::RecursionDepthConst(Box::new(self)))
         } else {
             trace!("Evaluating constexpr: {self}");
             match self {
@@ -217,7 +221,10 @@ impl ConstExpr {
                         (Self::Tuple(tuple), Self::Int(n)) => {
                             // If the index is out of bounds, return an error.
                             if n >= tuple.len() as i64 || n < 0 {
-                                return Err(Error::MemberNotFound((*container).into(), *member));
+                                return Err(Error::MemberNotFound(
+                                    Box::new((*container).into()),
+                                    member,
+                                ));
                             }
                             tuple[n as usize].clone().eval_checked(env, i)?
                         }
@@ -230,7 +237,10 @@ impl ConstExpr {
                                     return constant.eval_checked(env, i);
                                     // return Ok(constant.clone());
                                 }
-                                return Err(Error::MemberNotFound((*container).into(), *member));
+                                return Err(Error::MemberNotFound(
+                                    Box::new((*container).into()),
+                                    member,
+                                ));
                             }
                             fields[&name].clone().eval_checked(env, i)?
                         }
@@ -261,7 +271,10 @@ impl ConstExpr {
                                 error!(
                                     "Member access not implemented for: {container_ty} . {member}"
                                 );
-                                return Err(Error::MemberNotFound((*container).into(), *member));
+                                return Err(Error::MemberNotFound(
+                                    Box::new((*container).into()),
+                                    member,
+                                ));
                             }
                         }
                         _ => {
@@ -274,7 +287,10 @@ impl ConstExpr {
                                 // return Ok(constant.clone());
                             }
                             error!("Member access not implemented for: {container_ty} . {member}");
-                            return Err(Error::MemberNotFound((*container).into(), *member));
+                            return Err(Error::MemberNotFound(
+                                Box::new((*container).into()),
+                                member,
+                            ));
                         }
                     })
                 }
@@ -289,6 +305,7 @@ impl ConstExpr {
                 | Self::CoreBuiltin(_)
                 | Self::StandardBuiltin(_)
                 | Self::FFIProcedure(_)
+                | Self::TraitMethod(..)
                 | Self::Proc(_)
                 | Self::PolyProc(_)
                 | Self::Type(_) => Ok(self),
@@ -321,7 +338,7 @@ impl ConstExpr {
                     let result = match expr.clone().eval(env)? {
                         Self::Template(params, ret) => {
                             if params.len() != ty_args.len() {
-                                return Err(Error::InvalidMonomorphize(*expr));
+                                return Err(Error::InvalidMonomorphize(expr));
                             }
                             let mut ret = ret.clone();
 
@@ -359,7 +376,7 @@ impl ConstExpr {
                     let found = expr.get_type_checked(env, i)?;
                     if !found.can_cast_to(&cast_ty, env)? {
                         return Err(Error::InvalidAs(
-                            Expr::ConstExpr(*expr.clone()),
+                            Box::new(Expr::ConstExpr(*expr.clone())),
                             found,
                             cast_ty,
                         ));
@@ -413,6 +430,41 @@ impl ConstExpr {
         }
     }
 
+    /// Collect the symbols this constant expression refers to.
+    /// Procedures are compiled in their own scope, so the symbols inside of them aren't collected.
+    pub(crate) fn collect_symbols(&self, symbols: &mut BTreeSet<String>) {
+        match self {
+            Self::Symbol(name) => {
+                symbols.insert(name.clone());
+            }
+            Self::Annotated(expr, _)
+            | Self::Monomorphize(expr, _)
+            | Self::As(expr, _)
+            | Self::Union(_, _, expr)
+            | Self::EnumUnion(_, _, expr)
+            | Self::Member(expr, _) => expr.collect_symbols(symbols),
+            Self::TypeOf(expr) | Self::SizeOfExpr(expr) => expr.collect_symbols(symbols),
+            Self::Tuple(items) | Self::Array(items) => {
+                for item in items {
+                    item.collect_symbols(symbols);
+                }
+            }
+            Self::Struct(fields) => {
+                for item in fields.values() {
+                    item.collect_symbols(symbols);
+                }
+            }
+            Self::Declare(decl, expr) => {
+                // Uses of the variables declared refer to them, not to any outside.
+                let mut scope = BTreeSet::new();
+                expr.collect_symbols(&mut scope);
+                decl.collect_symbols_in_scope(&mut scope);
+                symbols.extend(scope);
+            }
+            _ => {}
+        }
+    }
+
     /// Try to get this constant expression as an integer.
     pub fn as_int(self, env: &Env) -> Result<i64, Error> {
         trace!("Getting int from constexpr: {self}");
@@ -425,7 +477,7 @@ impl ConstExpr {
             Self::Int(n) => Ok(n),
             other => match other.eval(env)? {
                 Self::Int(n) => Ok(n),
-                other => Err(Error::NonIntegralConst(other)),
+                other => Err(Error::NonIntegralConst(Box::new(other))),
             },
         }
     }
@@ -442,7 +494,7 @@ impl ConstExpr {
             Self::Float(n) => Ok(n),
             other => match other.eval(env)? {
                 Self::Float(n) => Ok(n),
-                other => Err(Error::NonIntegralConst(other)),
+                other => Err(Error::NonIntegralConst(Box::new(other))),
             },
         }
     }
@@ -459,7 +511,7 @@ impl ConstExpr {
             Self::Bool(n) => Ok(n),
             other => match other.eval(env)? {
                 Self::Bool(n) => Ok(n),
-                other => Err(Error::NonIntegralConst(other)),
+                other => Err(Error::NonIntegralConst(Box::new(other))),
             },
         }
     }
@@ -473,7 +525,7 @@ impl ConstExpr {
             // If not, evaluate it and see if it's a symbol.
             other => match other.eval(env)? {
                 Self::Symbol(name) => Ok(name),
-                other => Err(Error::NonSymbol(other)),
+                other => Err(Error::NonSymbol(Box::new(other))),
             },
         }
     }
@@ -516,13 +568,17 @@ impl GetType for ConstExpr {
                     Type::Unit(_unit_name, inner_ty) => {
                         // Get the type of the field.
                         env.get_type_of_associated_const(inner_ty, &as_symbol?)
-                            .ok_or(Error::MemberNotFound((*val.clone()).into(), *field.clone()))?
+                            .ok_or(Error::MemberNotFound(
+                                Box::new((*val.clone()).into()),
+                                field.clone(),
+                            ))?
                     }
 
                     Type::Type(ty) => {
                         // Get the associated constant expression's type.
-                        env.get_type_of_associated_const(ty, &as_symbol?)
-                            .ok_or(Error::MemberNotFound((*val.clone()).into(), *field.clone()))?
+                        env.get_type_of_associated_const(ty, &as_symbol?).ok_or(
+                            Error::MemberNotFound(Box::new((*val.clone()).into()), field.clone()),
+                        )?
                     }
                     // If we're accessing a member of a tuple,
                     // we use the `as_int` interpretation of the field.
@@ -533,8 +589,8 @@ impl GetType for ConstExpr {
                             return env
                                 .get_type_of_associated_const(&val_type, &as_symbol?)
                                 .ok_or(Error::MemberNotFound(
-                                    (*val.clone()).into(),
-                                    *field.clone(),
+                                    Box::new((*val.clone()).into()),
+                                    field.clone(),
                                 ));
                         }
 
@@ -551,7 +607,10 @@ impl GetType for ConstExpr {
                             )
                             .get_type(env)
                             .map_err(|_e| {
-                                Error::MemberNotFound((*val.clone()).into(), *field.clone())
+                                Error::MemberNotFound(
+                                    Box::new((*val.clone()).into()),
+                                    field.clone(),
+                                )
                             });
                         }
                     }
@@ -571,7 +630,10 @@ impl GetType for ConstExpr {
                             )
                             .get_type(env)
                             .map_err(|_e| {
-                                Error::MemberNotFound((*val.clone()).into(), *field.clone())
+                                Error::MemberNotFound(
+                                    Box::new((*val.clone()).into()),
+                                    field.clone(),
+                                )
                             });
                         }
                     }
@@ -593,7 +655,10 @@ impl GetType for ConstExpr {
                             )
                             .get_type(env)
                             .map_err(|_e| {
-                                Error::MemberNotFound((*val.clone()).into(), *field.clone())
+                                Error::MemberNotFound(
+                                    Box::new((*val.clone()).into()),
+                                    field.clone(),
+                                )
                             });
                         }
                     }
@@ -604,7 +669,10 @@ impl GetType for ConstExpr {
                         return ConstExpr::Member(ConstExpr::Type(val_type).into(), field.clone())
                             .get_type(env)
                             .map_err(|_e| {
-                                Error::MemberNotFound((*val.clone()).into(), *field.clone())
+                                Error::MemberNotFound(
+                                    Box::new((*val.clone()).into()),
+                                    field.clone(),
+                                )
                             });
                     }
                 }
@@ -618,7 +686,7 @@ impl GetType for ConstExpr {
                 let found = expr.get_type_checked(env, i)?;
                 if !found.can_cast_to(&cast_ty, env)? {
                     return Err(Error::InvalidAs(
-                        Expr::ConstExpr(self.clone()),
+                        Box::new(Expr::ConstExpr(self.clone())),
                         found,
                         cast_ty,
                     ));
@@ -639,7 +707,7 @@ impl GetType for ConstExpr {
                 // }
                 let mono_ty = if let Self::Template(params, ret) = *expr.clone() {
                     if params.len() != ty_args.len() {
-                        return Err(Error::InvalidMonomorphize(*expr));
+                        return Err(Error::InvalidMonomorphize(expr));
                     }
 
                     let mut ret = ret.clone();
@@ -712,11 +780,18 @@ impl GetType for ConstExpr {
             Self::CoreBuiltin(builtin) => builtin.get_type_checked(env, i)?,
             Self::StandardBuiltin(builtin) => builtin.get_type_checked(env, i)?,
             Self::FFIProcedure(ffi_proc) => ffi_proc.get_type_checked(env, i)?,
+            Self::TraitMethod(ty, method) => env
+                .get_trait_method(&ty, &method)
+                .cloned()
+                .ok_or(Error::SymbolNotDefined(method))?,
 
             Self::Symbol(name) => {
                 if let Some((_, ty, _)) = env.get_var(&name) {
                     // If the symbol is a variable, get the variables type.
                     ty.clone()
+                } else if let Some((_, t, _)) = env.get_captured_var(&name) {
+                    // If the symbol is a captured variable, get the variables type.
+                    t.clone()
                 } else if let Some((_, t, _)) = env.get_static_var(&name) {
                     // If the symbol is a static variable, push it onto the stack.
                     t.clone()
@@ -835,6 +910,9 @@ impl GetType for ConstExpr {
             Self::FFIProcedure(ffi_proc) => {
                 ffi_proc.substitute(name, subsitution);
             }
+            Self::TraitMethod(ty, _) => {
+                *ty = ty.substitute(name, subsitution);
+            }
             Self::Symbol(_) => {
                 // A constant symbol cannot be substituted for a type variable.
             }
@@ -844,6 +922,16 @@ impl GetType for ConstExpr {
 
 impl fmt::Display for ConstExpr {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // The alternate format spreads procedures and declarations over several lines.
+        if f.alternate() {
+            match self {
+                Self::Annotated(expr, _) => return write!(f, "{expr:#}"),
+                Self::Proc(proc) => return write!(f, "{proc:#}"),
+                Self::PolyProc(proc) => return write!(f, "{proc:#}"),
+                Self::Declare(bindings, expr) => return write!(f, "let {bindings:#} in\n{expr:#}"),
+                _ => {}
+            }
+        }
         match self {
             Self::Template(params, expr) => {
                 write!(f, "<")?;
@@ -868,6 +956,9 @@ impl fmt::Display for ConstExpr {
             Self::FFIProcedure(ffi_proc) => {
                 write!(f, "{ffi_proc}")
             }
+            Self::TraitMethod(ty, method) => {
+                write!(f, "{ty}.{method}")
+            }
             Self::CoreBuiltin(builtin) => {
                 write!(f, "{builtin}")
             }
@@ -1079,6 +1170,11 @@ impl Hash for ConstExpr {
                 state.write_u8(28);
                 ty.hash(state);
             }
+            Self::TraitMethod(ty, method) => {
+                state.write_u8(29);
+                ty.hash(state);
+                method.hash(state);
+            }
         }
     }
 }
@@ -1,9 +1,9 @@
-use super::{PolyProcedure, Procedure};
+use super::{expression::Indented, PolyProcedure, Procedure};
 use crate::{
     asm::{AssemblyProgram, CoreOp, Location, SP},
     lir::{
-        Compile, ConstExpr, Env, Error, Expr, FFIProcedure, GetSize, GetType, Mutability, Pattern,
-        Type, TypeCheck,
+        compile_unless_exited, Annotation, Compile, ConstExpr, Env, Error, Expr, FFIProcedure,
+        GetSize, GetType, Mutability, Pattern, Type, TypeCheck, Warning,
     },
 };
 use core::{
@@ -11,7 +11,7 @@ use core::{
     ops::{Add, AddAssign},
 };
 use log::*;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::hash::{Hash, Hasher};
 
 /// A declaration of a variable, function, type, etc.
@@ -35,6 +35,12 @@ pub enum Declaration {
     ExternProc(String, FFIProcedure),
     /// Declare associated constants and procedures for a type.
     Impl(Type, Vec<(String, ConstExpr)>),
+    /// A trait declaration, with the signatures of the methods its implementations must define.
+    /// The signatures refer to the implementing type as `Self`.
+    Trait(String, Vec<(String, Type)>),
+    /// Implement a trait for a type with associated procedures,
+    /// annotated with the location of the implementation.
+    ImplTrait(String, Type, Vec<(String, ConstExpr)>, Annotation),
     /// Many declarations.
     Many(Vec<Declaration>),
 }
@@ -75,6 +81,96 @@ impl Declaration {
         }
     }
 
+    /// Can evaluating this declaration's variables be cut short by a `break` or `continue`?
+    pub(crate) fn can_exit_loop(&self) -> bool {
+        let mut exits = vec![];
+        self.collect_loop_exits(&mut vec![], &mut exits);
+        !exits.is_empty()
+    }
+
+    /// Collect the `break` and `continue` expressions which jump out of the initial
+    /// values of the variables in this declaration.
+    pub(crate) fn collect_loop_exits(
+        &self,
+        loops: &mut Vec<Option<String>>,
+        exits: &mut Vec<Expr>,
+    ) {
+        match self {
+            Self::Var(_, _, _, expr) | Self::VarPat(_, expr) => {
+                expr.collect_loop_exits(loops, exits)
+            }
+            Self::Many(decls) => {
+                for decl in decls {
+                    decl.collect_loop_exits(loops, exits);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Collect the symbols referred to by this declaration and the scope it's in, given
+    /// the symbols referred to by the scope after it. Uses of the variables it declares
+    /// refer to those variables, so they're removed, and the symbols referred to by the
+    /// initial values of the variables are added.
+    pub(crate) fn collect_symbols_in_scope(&self, symbols: &mut BTreeSet<String>) {
+        match self {
+            Self::Var(name, _, _, expr) => {
+                symbols.remove(name);
+                expr.collect_symbols(symbols);
+            }
+            Self::VarPat(pattern, expr) => {
+                symbols.retain(|name| !pattern.binds(name));
+                expr.collect_symbols(symbols);
+            }
+            // Each declaration is in scope for the ones after it.
+            Self::Many(decls) => {
+                for decl in decls.iter().rev() {
+                    decl.collect_symbols_in_scope(symbols);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Warn about the variables in this declaration which are never used in the body,
+    /// or which shadow another variable with the same name. Variables whose names
+    /// start with an underscore are exempt.
+    ///
+    /// Only variables declared in the source code are checked: the bindings generated
+    /// for patterns are declared several times over, and never point at the source code.
+    pub(crate) fn warn_unused_or_shadowed(&self, body: &Expr, env: &Env) {
+        let decls = match self {
+            Self::Many(decls) => decls.as_slice(),
+            decl => std::slice::from_ref(decl),
+        };
+        for (i, decl) in decls.iter().enumerate() {
+            let Self::Var(name, _, _, expr @ Expr::Annotated(_, metadata)) = decl else {
+                continue;
+            };
+            if !metadata.has_location() || name.starts_with('_') {
+                continue;
+            }
+
+            let shadows_earlier = decls[..i]
+                .iter()
+                .any(|decl| matches!(decl, Self::Var(earlier, ..) if earlier == name));
+            if shadows_earlier || env.get_var(name).is_some() {
+                env.warn(expr.annotate_warning(Warning::ShadowedVariable(name.clone())));
+            }
+
+            // The variable is in scope for the rest of the declarations and the body,
+            // until another variable with the same name shadows it.
+            let mut symbols = BTreeSet::new();
+            body.collect_symbols(&mut symbols);
+            for decl in decls[i + 1..].iter().rev() {
+                decl.collect_symbols_in_scope(&mut symbols);
+            }
+            if !symbols.contains(name) {
+                env.warn(expr.annotate_warning(Warning::UnusedVariable(name.clone())));
+            }
+        }
+    }
+
     /// Compile a declaration with a body in a new scope. This will copy the old environment,
     /// and add the declaration to the new environment.
     pub(crate) fn compile(
@@ -91,7 +187,7 @@ impl Declaration {
     /// This will modify the environment to add the declaration. If there is a body,
     /// it will be compiled under a new scope, and will be popped off the stack when
     /// the declaration is finished.
-    fn compile_helper(
+    pub(crate) fn compile_helper(
         &self,
         body: Option<Expr>,
         env: &mut Env,
@@ -119,8 +215,25 @@ impl Declaration {
                 };
                 // Get the size of the variables for the body of the declaration.
                 var_size = var_ty.get_size(env)?;
-                // Compile the expression to leave the value on the stack.
-                expr.clone().compile_expr(env, output)?;
+                match expr.without_annotations() {
+                    // A closure which captures by reference can't outlive the variables
+                    // it captures, so its environment is allocated on the stack, just
+                    // below the variable.
+                    Expr::Closure(closure) if closure.captures_by_reference() => {
+                        let record_size = closure.clone().compile_on_stack(env, output)?;
+                        env.define_var(
+                            format!("#{name}"),
+                            Mutability::Immutable,
+                            Type::Array(
+                                Box::new(Type::Cell),
+                                Box::new(ConstExpr::Int(record_size as i64)),
+                            ),
+                        )?;
+                        var_size += record_size;
+                    }
+                    // Compile the expression to leave the value on the stack.
+                    _ => expr.clone().compile_expr(env, output)?,
+                }
 
                 // Add the variable to the environment, so that it can be used in the body.
                 env.add_local_variable_declaration(self)?;
@@ -166,12 +279,22 @@ impl Declaration {
                 output.log_instructions_after(&name, &log_message, current_instruction);
             }
             Declaration::Many(decls) => {
+                let mut can_exit_loop = false;
                 for decl in decls {
                     // Compile all the sub-declarations,
                     // and leave their variables on the stack.
                     // Add their variable sizes to the total variable size.
                     // This will be used to pop the stack when we're done.
-                    var_size += decl.compile_helper(None, env, output)?;
+                    var_size += if can_exit_loop {
+                        // Skip the declaration if an earlier one was cut short
+                        // by a `break` or `continue`.
+                        compile_unless_exited(env, output, |env, output| {
+                            decl.compile_helper(None, env, output)
+                        })?
+                    } else {
+                        decl.compile_helper(None, env, output)?
+                    };
+                    can_exit_loop = can_exit_loop || decl.can_exit_loop();
                 }
             }
             _ => {}
@@ -187,7 +310,16 @@ impl Declaration {
             let result_size = result_type.get_size(env)?;
 
             // Compile the body under the new scope
-            body.compile_expr(env, output)?;
+            if self.can_exit_loop() {
+                // Skip the body if a variable's initial value was cut short
+                // by a `break` or `continue`.
+                compile_unless_exited(env, output, |env, output| {
+                    body.compile_expr(env, output)?;
+                    Ok(result_size)
+                })?;
+            } else {
+                body.compile_expr(env, output)?;
+            }
             if var_size != 0 {
                 // Copy the return value over where the arguments were stored,
                 // so that when we pop the stack, it's as if we popped the variables
@@ -270,11 +402,16 @@ impl Declaration {
             Self::ExternProc(_name, proc) => {
                 proc.substitute(substitution_name, substitution_ty);
             }
-            Self::Impl(_name, impls) => {
+            Self::Impl(_name, impls) | Self::ImplTrait(_, _name, impls, _) => {
                 for (_name, expr) in impls {
                     expr.substitute(substitution_name, substitution_ty);
                 }
             }
+            Self::Trait(_name, methods) => {
+                for (_name, ty) in methods {
+                    *ty = ty.substitute(substitution_name, substitution_ty);
+                }
+            }
             Self::Many(decls) => {
                 for decl in decls {
                     decl.substitute(substitution_name, substitution_ty);
@@ -287,6 +424,17 @@ impl Declaration {
 impl TypeCheck for Declaration {
     fn type_check(&self, env: &Env) -> Result<(), Error> {
         match self {
+            // Point the errors in a variable declaration at its source code.
+            Self::Var(name, mutability, expected_ty, Expr::Annotated(expr, metadata)) => {
+                Self::Var(
+                    name.clone(),
+                    *mutability,
+                    expected_ty.clone(),
+                    *expr.clone(),
+                )
+                .type_check(env)
+                .map_err(|e| e.annotate(metadata.clone()))?;
+            }
             // Typecheck a variable declaration.
             Self::Var(_name, _mutability, expected_ty, expr) => {
                 // Get the type of the expression.
@@ -305,8 +453,9 @@ impl TypeCheck for Declaration {
                         return Err(Error::MismatchedTypes {
                             expected: expected_ty.clone(),
                             found: found_ty.clone(),
-                            expr: Expr::NONE.with(self.clone()),
-                        });
+                            expr: Box::new(Expr::NONE.with(self.clone())),
+                        }
+                        .with_suggestion());
                     }
                 }
 
@@ -355,7 +504,7 @@ impl TypeCheck for Declaration {
                     return Err(Error::MismatchedTypes {
                         expected: expected_ty.clone(),
                         found: found_ty.clone(),
-                        expr: Expr::NONE.with(self.clone()),
+                        expr: Box::new(Expr::NONE.with(self.clone())),
                     });
                 }
             }
@@ -376,7 +525,7 @@ impl TypeCheck for Declaration {
                     // If it is not, then we throw an error.
                     return Err(Error::NonExhaustivePatterns {
                         patterns: vec![pat.clone()],
-                        expr: Expr::NONE.with(self.clone()),
+                        expr: Box::new(Expr::NONE.with(self.clone())),
                     });
                 }
                 // Get the bindings of the variables under the pattern
@@ -389,8 +538,8 @@ impl TypeCheck for Declaration {
                 // Make sure the size of the bindings is the same as the size of the expression.
                 if size_of_bindings != size {
                     return Err(Error::InvalidPatternForExpr(
-                        Expr::NONE.with(self.clone()),
-                        pat.clone(),
+                        Box::new(Expr::NONE.with(self.clone())),
+                        Box::new(pat.clone()),
                     ));
                 }
             }
@@ -412,7 +561,7 @@ impl TypeCheck for Declaration {
                         return Err(Error::MismatchedTypes {
                             expected: *template.clone(),
                             found: Type::Apply(template.clone(), supplied_params.clone()),
-                            expr: Expr::NONE.with(self.clone()),
+                            expr: Box::new(Expr::NONE.with(self.clone())),
                         });
                     }
 
@@ -427,7 +576,7 @@ impl TypeCheck for Declaration {
                                         "A symbol, not a concrete type".to_owned(),
                                     ),
                                     found: ty.clone(),
-                                    expr: Expr::NONE.with(self.clone()),
+                                    expr: Box::new(Expr::NONE.with(self.clone())),
                                 })
                             }
                         })
@@ -461,33 +610,157 @@ impl TypeCheck for Declaration {
                         new_env.add_associated_const(ty.clone(), name, associated_const.clone())?;
                     }
 
-                    for (_name, associated_const) in impls {
-                        associated_const.type_check(&new_env)?;
-                    }
+                    // Check every method, even if one of them isn't sound.
+                    Error::check_all(
+                        impls
+                            .iter()
+                            .filter_map(|(_name, associated_const)| {
+                                associated_const.type_check(&new_env).err()
+                            })
+                            .collect(),
+                    )?;
                 }
                 // for (_name, expr) in impls {
                 //     expr.type_check(&new_env)?;
                 // }
             }
+            Self::Trait(_name, methods) => {
+                let mut new_env = env.clone();
+                // The method signatures refer to the implementing type as `Self`.
+                new_env.define_type("Self", Type::Unit("Self".to_string(), Box::new(Type::None)));
+                for (_name, ty) in methods {
+                    ty.type_check(&new_env)?;
+                }
+            }
+            Self::ImplTrait(trait_name, ty, impls, annotation) => {
+                // Typecheck the associated constants like any other implementation.
+                Self::Impl(ty.clone(), impls.clone()).type_check(env)?;
+
+                // Errors about the implementation as a whole point at its header.
+                let methods = env.get_trait(trait_name).ok_or_else(|| {
+                    Error::TraitNotDefined(trait_name.clone()).annotate(annotation.clone())
+                })?;
+                for (name, _) in impls {
+                    if !methods.iter().any(|(method, _)| method == name) {
+                        return Err(Error::NotTraitMethod {
+                            trait_name: trait_name.clone(),
+                            method: name.clone(),
+                        }
+                        .annotate(annotation.clone()));
+                    }
+                }
+                for (method, signature) in methods {
+                    let (_, associated_const) = impls
+                        .iter()
+                        .find(|(name, _)| name == method)
+                        .ok_or_else(|| {
+                            Error::MissingTraitMethod {
+                                trait_name: trait_name.clone(),
+                                ty: ty.clone(),
+                                method: method.clone(),
+                            }
+                            .annotate(annotation.clone())
+                        })?;
+                    // The methods of an implementation for a template type are templates
+                    // themselves, so they are only checked against the trait when monomorphized.
+                    if matches!(ty, Type::Apply(..)) {
+                        continue;
+                    }
+                    let expected = signature.substitute("Self", ty);
+                    let found = associated_const.get_type(env)?;
+                    if !found.equals(&expected, env)? {
+                        let err = Error::MismatchedTypes {
+                            expected,
+                            found,
+                            expr: Box::new(Expr::ConstExpr(associated_const.clone())),
+                        };
+                        // Point at the method's declaration in the implementation.
+                        return Err(match associated_const {
+                            ConstExpr::Annotated(_, annotation) => err.annotate(annotation.clone()),
+                            ConstExpr::Proc(proc) => err.annotate(proc.get_annotation().clone()),
+                            _ => err,
+                        });
+                    }
+                }
+            }
             // Typecheck a multi-declaration.
             Self::Many(decls) => {
                 let mut new_env = env.clone();
                 // Add all the compile-time declarations to the environment.
                 new_env.add_compile_time_declaration(&self.clone())?;
+                let mut errors = vec![];
                 for decl in decls {
-                    // Typecheck any variable declarations in the old scope
-                    decl.type_check(&new_env)?;
-                    // Add them to the new scope.
-                    new_env.add_declaration(decl)?;
+                    // Typecheck any variable declarations in the old scope,
+                    // and keep checking the rest of the declarations if there's an error.
+                    let result = decl.type_check(&new_env);
+                    // Add them to the new scope. If a variable can't be declared,
+                    // it's bound with the `Any` type so the rest of the declarations
+                    // which depend on it can still be checked.
+                    if let Err(err) = new_env.add_declaration_or_poison(decl) {
+                        errors.push(result.err().unwrap_or(err));
+                    } else if let Err(err) = result {
+                        errors.push(err);
+                    }
                 }
+                return Error::check_all(errors);
             }
         }
         Ok(())
     }
 }
 
+impl Declaration {
+    /// Display the declaration in the alternate format, which puts each of several
+    /// declarations on its own line, and spreads the expressions they declare over
+    /// several lines like `Expr` does.
+    fn fmt_alternate(&self, f: &mut Formatter) -> FmtResult {
+        use core::fmt::Write;
+        match self {
+            Self::StaticVar(name, mutability, ty, expr) => {
+                write!(f, "static {mutability} {name}: {ty} = {expr:#}")
+            }
+            Self::Var(name, _mutability, ty, expr) => {
+                write!(f, "{name} = {expr:#}")?;
+                if let Some(ty) = ty {
+                    write!(f, ": {ty}")?;
+                }
+                Ok(())
+            }
+            Self::Proc(name, proc) => write!(f, "{proc:#} {name}"),
+            Self::PolyProc(name, proc) => write!(f, "{proc:#} {name}"),
+            Self::Const(name, expr) => write!(f, "const {name}: {expr:#}"),
+            Self::VarPat(pat, expr) => write!(f, "{pat} = {expr:#}"),
+            Self::Impl(name, impls) | Self::ImplTrait(_, name, impls, _) if !impls.is_empty() => {
+                if let Self::ImplTrait(trait_name, ..) = self {
+                    writeln!(f, "impl {trait_name} for {name} {{")?;
+                } else {
+                    writeln!(f, "impl {name} {{")?;
+                }
+                let mut block = Indented::new(f);
+                for (name, expr) in impls {
+                    writeln!(block, "{name} = {expr:#}")?;
+                }
+                write!(f, "}}")
+            }
+            Self::Many(decls) => {
+                for (i, decl) in decls.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{decl:#}")?;
+                }
+                Ok(())
+            }
+            _ => write!(f, "{self}"),
+        }
+    }
+}
+
 impl Display for Declaration {
     fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        if f.alternate() {
+            return self.fmt_alternate(f);
+        }
         match self {
             Self::StaticVar(name, mutability, ty, expr) => {
                 write!(f, "static {mutability} {name}: {ty} = {expr}")?;
@@ -528,6 +801,22 @@ impl Display for Declaration {
                 }
                 write!(f, "}}")?;
             }
+            Self::Trait(name, methods) => {
+                write!(f, "trait {}", name)?;
+                write!(f, " {{")?;
+                for (name, ty) in methods {
+                    write!(f, "{}: {}", name, ty)?;
+                }
+                write!(f, "}}")?;
+            }
+            Self::ImplTrait(trait_name, name, impls, _) => {
+                write!(f, "impl {} for {}", trait_name, name)?;
+                write!(f, " {{")?;
+                for (name, expr) in impls {
+                    write!(f, "{} = {}", name, expr)?;
+                }
+                write!(f, "}}")?;
+            }
             Self::Many(decls) => {
                 for decl in decls {
                     writeln!(f, "{}", decl)?;
@@ -752,6 +1041,17 @@ impl Hash for Declaration {
                 state.write_u8(9);
                 decls.hash(state);
             }
+            Self::Trait(name, methods) => {
+                state.write_u8(10);
+                name.hash(state);
+                methods.hash(state);
+            }
+            Self::ImplTrait(trait_name, name, impls, _) => {
+                state.write_u8(11);
+                trait_name.hash(state);
+                name.hash(state);
+                impls.hash(state);
+            }
         }
     }
 }
@@ -7,10 +7,11 @@
 
 use super::ops::*;
 use crate::lir::{
-    Annotation, ConstExpr, Declaration, Env, Error, GetType, Mutability, Pattern, Procedure, Type,
+    Annotation, Closure, ConstExpr, Declaration, Env, Error, GetType, Mutability, Pattern,
+    Procedure, Type, Warning,
 };
 use core::fmt;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::hash::{Hash, Hasher};
 
 use log::*;
@@ -35,6 +36,17 @@ pub enum Expr {
 
     /// Create a while loop: while the first expression evaluates to true, evaluate the second expression.
     While(Box<Self>, Box<Self>),
+    /// A labeled loop: `break` and `continue` can refer to the loop by its label
+    /// to exit or continue it from inside of nested loops.
+    Labeled(String, Box<Self>),
+    /// Exit the innermost loop, or the loop with the given label.
+    Break(Option<String>),
+    /// Skip the rest of the innermost loop's body (or the body of the loop
+    /// with the given label), and check the loop's condition again.
+    Continue(Option<String>),
+    /// Loop over the elements of an array, or the values produced by an iterator.
+    /// Each value is bound to the pattern, and then the body is evaluated.
+    ForIn(Pattern, Box<Self>, Box<Self>),
     /// An if-then-else expression.
     ///
     /// Evaluate a condition.
@@ -79,6 +91,12 @@ pub enum Expr {
 
     /// Return a value from a function.
     Return(Box<Self>),
+    /// Unwrap the success variant (`Ok` or `Some`) of a tagged union, or return
+    /// any other variant early from the enclosing procedure.
+    Try(Box<Self>),
+
+    /// A procedure which captures variables from the scope it is created in.
+    Closure(Closure),
 
     /// An array of expressions.
     Array(Vec<Self>),
@@ -198,6 +216,220 @@ impl Expr {
         Ok(result)
     }
 
+    /// If this is an operator applied to a value of a user-defined type which overloads
+    /// the operator with an associated method (like `add` for `+`, or `index` for indexing),
+    /// get the equivalent call to the method, along with the type of the call.
+    ///
+    /// `a != b` uses the `eq` method if the type has no `ne` method. If the `index` method
+    /// returns a pointer, the pointer is dereferenced, so the element can be assigned to.
+    ///
+    /// This is checked for every expression, so operators whose methods no type defines
+    /// are rejected without getting the types of their operands.
+    pub(crate) fn get_overloaded_op(&self, env: &Env) -> Result<Option<(Self, Type)>, Error> {
+        Ok(match self {
+            Self::UnaryOp(op, val) => match op.overload_name() {
+                Some(name) => Self::call_operator_method(val, name, vec![], env)?,
+                None => None,
+            },
+            Self::BinaryOp(op, lhs, rhs) => match op.overload_name() {
+                Some("ne") => match Self::call_operator_method(lhs, "ne", vec![*rhs.clone()], env)?
+                {
+                    Some(call) => Some(call),
+                    None => Self::call_operator_method(lhs, "eq", vec![*rhs.clone()], env)?
+                        .map(|(call, ty)| (call.not(), ty)),
+                },
+                Some(name) => Self::call_operator_method(lhs, name, vec![*rhs.clone()], env)?,
+                None => None,
+            },
+            Self::Index(val, idx) => {
+                match Self::call_operator_method(val, "index", vec![*idx.clone()], env)? {
+                    Some((call, ty)) => match ty.simplify_until_concrete(env)? {
+                        Type::Pointer(_, elem) => Some((call.deref(), *elem)),
+                        _ => Some((call, ty)),
+                    },
+                    None => None,
+                }
+            }
+            Self::Refer(_, val) => match val.without_annotations() {
+                Self::Index(val, idx) => {
+                    match Self::call_operator_method(val, "index", vec![*idx.clone()], env)? {
+                        Some((call, ty))
+                            if matches!(ty.simplify_until_concrete(env)?, Type::Pointer(..)) =>
+                        {
+                            Some((call, ty))
+                        }
+                        _ => None,
+                    }
+                }
+                _ => None,
+            },
+            _ => None,
+        })
+    }
+
+    /// Get the type of the associated method which overloads an operator for the given type.
+    /// Only user-defined types (structures, tuples, unions and enums) can overload operators.
+    pub(crate) fn get_operator_method(
+        ty: &Type,
+        name: &str,
+        env: &Env,
+    ) -> Result<Option<Type>, Error> {
+        match ty.simplify_until_concrete(env)? {
+            Type::Struct(_)
+            | Type::Tuple(_)
+            | Type::Union(_)
+            | Type::EnumUnion(_)
+            | Type::Enum(_) => Ok(env
+                .get_associated_const(ty, name)
+                .map(|(_, method_ty)| method_ty)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Call the associated method which overloads an operator on a value, if the value's type
+    /// has one, and get the type of the call. The other operands are passed by reference if
+    /// the method expects pointers.
+    fn call_operator_method(
+        val: &Self,
+        name: &str,
+        args: Vec<Self>,
+        env: &Env,
+    ) -> Result<Option<(Self, Type)>, Error> {
+        if !env.has_associated_const_named(name) {
+            return Ok(None);
+        }
+        let Some(method_ty) = Self::get_operator_method(&val.get_type(env)?, name, env)? else {
+            return Ok(None);
+        };
+        let (params, ret) = match method_ty.simplify_until_concrete(env)? {
+            Type::Proc(params, ret) => (params, Some(*ret)),
+            _ => (vec![], None),
+        };
+        let mut new_args = vec![];
+        for (i, arg) in args.into_iter().enumerate() {
+            // The first parameter is the value the method is called on.
+            let param = params.get(i + 1);
+            match param
+                .map(|param| param.simplify_until_concrete(env))
+                .transpose()?
+            {
+                Some(Type::Pointer(mutability, _))
+                    if !matches!(
+                        arg.get_type(env)?.simplify_until_concrete(env)?,
+                        Type::Pointer(..)
+                    ) =>
+                {
+                    new_args.push(arg.refer(mutability))
+                }
+                _ => new_args.push(arg),
+            }
+        }
+        let call = val
+            .clone()
+            .field(ConstExpr::Symbol(name.to_string()))
+            .app(new_args);
+        // Only polymorphic methods need the call's type to be inferred.
+        let ty = match ret {
+            Some(ret) => ret,
+            None => call.get_type(env)?,
+        };
+        Ok(Some((call, ty)))
+    }
+
+    /// If this is a call to a polymorphic procedure without any template arguments,
+    /// infer the template arguments from the types of the supplied arguments, and
+    /// return the call to the monomorphized procedure.
+    ///
+    /// This returns `None` if the expression isn't a call to a polymorphic procedure.
+    pub fn infer_template_args(&self, env: &Env) -> Result<Option<Self>, Error> {
+        let (f, args) = match self {
+            Self::Annotated(inner, annotation) => {
+                return inner
+                    .infer_template_args(env)
+                    .map(|call| call.map(|call| call.annotate(annotation.clone())))
+                    .map_err(|e| e.annotate(annotation.clone()))
+            }
+            Self::Apply(f, args) => (f, args),
+            _ => return Ok(None),
+        };
+
+        // Only constant procedures can be polymorphic.
+        let template = match f.without_annotations() {
+            Self::ConstExpr(ConstExpr::Monomorphize(..)) => return Ok(None),
+            Self::ConstExpr(template) => template.clone(),
+            // An associated procedure of a type, like `Option.unwrap`.
+            Self::Member(val, name) => match val.without_annotations() {
+                Self::ConstExpr(val) if matches!(val.get_type(env)?, Type::Type(_)) => {
+                    ConstExpr::Member(val.clone().into(), name.clone().into())
+                }
+                _ => return Ok(None),
+            },
+            _ => return Ok(None),
+        };
+        let (ty_params, param_tys) = match f.get_type(env)? {
+            Type::Poly(ty_params, proc_ty) => match *proc_ty {
+                Type::Proc(param_tys, _) if param_tys.len() == args.len() => (ty_params, param_tys),
+                // Let the typechecker report the wrong number of arguments.
+                _ => return Ok(None),
+            },
+            _ => return Ok(None),
+        };
+        debug!("Inferring template arguments {ty_params:?} for {self}");
+
+        // Match the type of each argument against the type of its parameter.
+        let ty_param_set = ty_params.iter().cloned().collect();
+        let mut inferred: HashMap<String, Type> = HashMap::new();
+        for (arg, param_ty) in args.iter().zip(param_tys.iter()) {
+            let arg_ty = arg.get_type(env)?;
+            let mut matched = HashMap::new();
+            arg_ty.get_monomorph_template_args(param_ty, &mut matched, &ty_param_set, env)?;
+
+            for (name, ty) in matched {
+                // `Any` and `Never` don't say anything about the template argument.
+                if matches!(ty, Type::Any | Type::Never) {
+                    continue;
+                }
+                match inferred.get(&name) {
+                    // Two arguments disagree about the template argument.
+                    Some(expected) if !ty.equals(expected, env)? => {
+                        return Err(Error::MismatchedTypes {
+                            expected: expected.clone(),
+                            found: ty,
+                            expr: Box::new(self.clone()),
+                        });
+                    }
+                    Some(_) => {}
+                    None => {
+                        inferred.insert(name, ty);
+                    }
+                }
+            }
+        }
+
+        // Every template parameter must be determined by the arguments.
+        let missing = ty_params
+            .iter()
+            .filter(|name| !inferred.contains_key(*name))
+            .cloned()
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            return Err(Error::AmbiguousTemplateArgs {
+                params: missing,
+                expr: Box::new(self.clone()),
+            });
+        }
+
+        let ty_args = ty_params
+            .iter()
+            .map(|name| inferred[name].clone())
+            .collect::<Vec<_>>();
+        debug!("Inferred template arguments {ty_args:?} for {self}");
+        Ok(Some(Self::Apply(
+            Box::new(template.monomorphize(ty_args).into()),
+            args.clone(),
+        )))
+    }
+
     pub fn transform_method_call(&self, env: &Env) -> Result<Self, Error> {
         debug!("transform_method_call: {self} -- {self:?}");
 
@@ -415,7 +647,10 @@ impl Expr {
                             Ok(self.clone())
                         }
                     }
-                    _ => Err(Error::MemberNotFound(self.clone(), ConstExpr::None)),
+                    _ => Err(Error::MemberNotFound(
+                        Box::new(self.clone()),
+                        Box::new(ConstExpr::None),
+                    )),
                 }
             }
             _ => Ok(self.clone()),
@@ -446,12 +681,27 @@ impl Expr {
                         // let val_type = val.get_type(env)?;
                         Ok(fun_type.get_self_param_mutability(env))
                     }
-                    _ => Err(Error::MemberNotFound(self.clone(), ConstExpr::None)),
+                    _ => Err(Error::MemberNotFound(
+                        Box::new(self.clone()),
+                        Box::new(ConstExpr::None),
+                    )),
                 }
             }
 
-            _ => Err(Error::MemberNotFound(self.clone(), ConstExpr::None)),
+            _ => Err(Error::MemberNotFound(
+                Box::new(self.clone()),
+                Box::new(ConstExpr::None),
+            )),
+        }
+    }
+
+    /// Get the expression underneath any source code annotations.
+    pub(crate) fn without_annotations(&self) -> &Self {
+        let mut expr = self;
+        while let Self::Annotated(inner, _) = expr {
+            expr = inner;
         }
+        expr
     }
 
     /// An annotated expression with some metadata.
@@ -466,11 +716,19 @@ impl Expr {
         }
     }
 
+    /// Point a warning about this expression at its source code, if it has a location.
+    pub(crate) fn annotate_warning(&self, warning: Warning) -> Warning {
+        match self {
+            Self::Annotated(_, metadata) => warning.annotate(metadata.clone()),
+            _ => warning,
+        }
+    }
+
     /// Return this expression, but with a given declaration in scope.
     pub fn with(&self, older_decls: impl Into<Declaration>) -> Self {
         match self {
-            // If the expression is an annotated expression, we need to unwrap it.
-            Self::Annotated(expr, annotation) => {
+            // If the expression is an annotated declaration, we need to unwrap it.
+            Self::Annotated(expr, annotation) if matches!(**expr, Self::Declare(..)) => {
                 // Just unwrap the expression and recurse.
                 expr.with(older_decls).annotate(annotation.clone())
             }
@@ -485,11 +743,406 @@ impl Expr {
                 Self::Declare(Box::new(result), expr.clone())
             }
 
-            // Return the expression with the declaration in scope.
+            // Return the expression with the declaration in scope. Any other annotated
+            // expression keeps its annotation, so the declaration doesn't belong to it.
             _ => Self::Declare(Box::new(older_decls.into()), Box::new(self.clone())),
         }
     }
 
+    /// Lower a `for` loop over a collection into a `while` loop, with an optional label.
+    ///
+    /// Arrays (and pointers to arrays) are looped over by index. Any other value is looped
+    /// over with the iterator protocol: its associated `next` method is called on every
+    /// iteration, until it returns a value which doesn't match `of Some(..)`. If the value
+    /// has an associated `iter` method instead, the iterator it returns is used.
+    pub(crate) fn desugar_for_in(
+        pat: &Pattern,
+        iterable: &Self,
+        body: &Self,
+        label: Option<&str>,
+        env: &Env,
+    ) -> Result<Self, Error> {
+        let label_loop = |result: Self| match label {
+            Some(label) => result.labeled(label),
+            None => result,
+        };
+
+        let ty = iterable.get_type(env)?;
+        let concrete_ty = ty.simplify_until_concrete(env)?;
+        let pointee_ty = match &concrete_ty {
+            Type::Pointer(_, inner) => Some(inner.simplify_until_concrete(env)?),
+            _ => None,
+        };
+
+        // Loop over an array by index.
+        let xs = Self::var("#xs");
+        let i = Self::var("#i");
+        let array = match (&concrete_ty, &pointee_ty) {
+            (Type::Array(_, len), _) => Some((xs.idx(i.clone()), len)),
+            (_, Some(Type::Array(_, len))) => Some((xs.deref().idx(i.clone()), len)),
+            _ => None,
+        };
+        if let Some((element, len)) = array {
+            let step = i
+                .clone()
+                .refer(Mutability::Mutable)
+                .deref_mut(i.clone().add(ConstExpr::Int(1)));
+            return Ok(Self::let_vars(
+                vec![
+                    ("#xs", Mutability::Immutable, Some(ty), iterable.clone()),
+                    (
+                        "#i",
+                        Mutability::Mutable,
+                        Some(Type::Int),
+                        ConstExpr::Int(0).into(),
+                    ),
+                ],
+                label_loop(i.lt(Self::ConstExpr(*len.clone())).while_loop(
+                    Self::Many(vec![step, body.clone()]).with(vec![(pat.clone(), element)]),
+                )),
+            ));
+        }
+
+        // Otherwise, use the iterator protocol.
+        let has_method = |name: &str| {
+            env.has_associated_const(&ty, name)
+                || matches!(&concrete_ty, Type::Pointer(_, inner) if env.has_associated_const(inner, name))
+        };
+        let iterator = if has_method("next") {
+            iterable.clone()
+        } else if has_method("iter") {
+            iterable
+                .clone()
+                .field(ConstExpr::Symbol("iter".to_string()))
+                .app(vec![])
+        } else {
+            return Err(Error::NotIterable(ty, Box::new(iterable.clone())));
+        };
+        let more = Self::var("#more");
+        Ok(Self::let_vars(
+            vec![
+                ("#it", Mutability::Mutable, None, iterator),
+                (
+                    "#more",
+                    Mutability::Mutable,
+                    Some(Type::Bool),
+                    ConstExpr::Bool(true).into(),
+                ),
+            ],
+            label_loop(
+                more.clone().while_loop(Self::IfLet(
+                    Pattern::Variant("Some".to_string(), Some(Box::new(pat.clone()))),
+                    Box::new(
+                        Self::var("#it")
+                            .field(ConstExpr::Symbol("next".to_string()))
+                            .app(vec![]),
+                    ),
+                    Box::new(Self::Many(vec![body.clone(), Self::NONE])),
+                    Box::new(
+                        more.refer(Mutability::Mutable)
+                            .deref_mut(ConstExpr::Bool(false)),
+                    ),
+                )),
+            ),
+        ))
+    }
+
+    /// Get the success variant of the tagged union that a `?` expression unwraps,
+    /// and the type of the variant's data.
+    pub(crate) fn get_try_success(expr: &Self, env: &Env) -> Result<(String, Type), Error> {
+        let ty = expr.get_type(env)?;
+        if let Type::EnumUnion(variants) = ty.simplify_until_concrete(env)? {
+            for name in ["Ok", "Some"] {
+                if let Some(data_ty) = variants.get(name) {
+                    if variants.len() > 1 {
+                        return Ok((name.to_string(), data_ty.clone()));
+                    }
+                }
+            }
+        }
+        Err(Error::NotTryable(ty, Box::new(expr.clone())))
+    }
+
+    /// Get the variants which a `?` expression returns early from the enclosing procedure,
+    /// along with the type of their data.
+    ///
+    /// Every variant other than the success variant (`Ok` or `Some`) is returned early,
+    /// so the procedure's return type must be a tagged union with the same variants,
+    /// holding data of compatible types.
+    pub(crate) fn get_try_early_returns(
+        expr: &Self,
+        env: &Env,
+    ) -> Result<BTreeMap<String, Type>, Error> {
+        let (success, _) = Self::get_try_success(expr, env)?;
+        let ty = expr.get_type(env)?;
+        let Type::EnumUnion(mut variants) = ty.simplify_until_concrete(env)? else {
+            return Err(Error::NotTryable(ty, Box::new(expr.clone())));
+        };
+        variants.remove(&success);
+
+        let ret = env
+            .get_expected_return_type()
+            .cloned()
+            .unwrap_or(Type::None);
+        let ret_variants = match ret.simplify_until_concrete(env)? {
+            Type::EnumUnion(ret_variants) => ret_variants,
+            _ => BTreeMap::new(),
+        };
+        for (name, data_ty) in &variants {
+            match ret_variants.get(name) {
+                Some(ret_data_ty) if data_ty.can_decay_to(ret_data_ty, env)? => {}
+                _ => {
+                    return Err(Error::IncompatibleTryReturn {
+                        expr: Box::new(expr.clone()),
+                        ty,
+                        expected_ret: ret,
+                    })
+                }
+            }
+        }
+        Ok(variants)
+    }
+
+    /// Can this expression be left early by a `break` or `continue`?
+    ///
+    /// Loop control that only exits loops *inside* of this expression doesn't count.
+    pub(crate) fn can_exit_loop(&self) -> bool {
+        !self.get_loop_exits().is_empty()
+    }
+
+    /// Does this loop body contain a `break` which exits the loop with the given label?
+    pub(crate) fn can_break_loop(&self, label: Option<&str>) -> bool {
+        self.get_loop_exits().iter().any(|exit| match exit {
+            Self::Break(None) => true,
+            Self::Break(Some(name)) => Some(name.as_str()) == label,
+            _ => false,
+        })
+    }
+
+    /// Get all the `break` and `continue` expressions which exit this expression early.
+    pub(crate) fn get_loop_exits(&self) -> Vec<Self> {
+        let mut exits = vec![];
+        self.collect_loop_exits(&mut vec![], &mut exits);
+        exits
+    }
+
+    /// Collect the `break` and `continue` expressions which jump out of this expression.
+    /// `loops` are the labels of the loops we've entered inside of the expression.
+    pub(crate) fn collect_loop_exits(
+        &self,
+        loops: &mut Vec<Option<String>>,
+        exits: &mut Vec<Self>,
+    ) {
+        match self {
+            Self::Break(label) | Self::Continue(label) => {
+                let exits_expr = match label {
+                    None => loops.is_empty(),
+                    Some(_) => !loops.contains(label),
+                };
+                if exits_expr {
+                    exits.push(self.clone());
+                }
+            }
+
+            Self::While(cond, body) => {
+                cond.collect_loop_exits(loops, exits);
+                loops.push(None);
+                body.collect_loop_exits(loops, exits);
+                loops.pop();
+            }
+            Self::ForIn(_, iterable, body) => {
+                iterable.collect_loop_exits(loops, exits);
+                loops.push(None);
+                body.collect_loop_exits(loops, exits);
+                loops.pop();
+            }
+            Self::Labeled(label, body) => {
+                let mut body = &**body;
+                while let Self::Annotated(inner, _) = body {
+                    body = inner;
+                }
+                if let Self::While(cond, body) | Self::ForIn(_, cond, body) = body {
+                    cond.collect_loop_exits(loops, exits);
+                    loops.push(Some(label.clone()));
+                    body.collect_loop_exits(loops, exits);
+                    loops.pop();
+                } else {
+                    body.collect_loop_exits(loops, exits);
+                }
+            }
+
+            Self::Annotated(expr, _)
+            | Self::UnaryOp(_, expr)
+            | Self::Refer(_, expr)
+            | Self::Deref(expr)
+            | Self::Return(expr)
+            | Self::Try(expr)
+            | Self::Union(_, _, expr)
+            | Self::EnumUnion(_, _, expr)
+            | Self::As(expr, _)
+            | Self::Member(expr, _) => expr.collect_loop_exits(loops, exits),
+
+            Self::BinaryOp(_, a, b)
+            | Self::AssignOp(_, a, b)
+            | Self::DerefMut(a, b)
+            | Self::Index(a, b)
+            | Self::When(_, a, b) => {
+                a.collect_loop_exits(loops, exits);
+                b.collect_loop_exits(loops, exits);
+            }
+            Self::If(a, b, c) | Self::TernaryOp(_, a, b, c) | Self::IfLet(_, a, b, c) => {
+                a.collect_loop_exits(loops, exits);
+                b.collect_loop_exits(loops, exits);
+                c.collect_loop_exits(loops, exits);
+            }
+
+            Self::Many(exprs) | Self::Array(exprs) | Self::Tuple(exprs) => {
+                for expr in exprs {
+                    expr.collect_loop_exits(loops, exits);
+                }
+            }
+            Self::Struct(fields) => {
+                for expr in fields.values() {
+                    expr.collect_loop_exits(loops, exits);
+                }
+            }
+            Self::Apply(f, args) => {
+                f.collect_loop_exits(loops, exits);
+                for arg in args {
+                    arg.collect_loop_exits(loops, exits);
+                }
+            }
+            Self::Match(expr, branches) => {
+                expr.collect_loop_exits(loops, exits);
+                for (_, branch) in branches {
+                    branch.collect_loop_exits(loops, exits);
+                }
+            }
+            Self::Declare(decl, body) => {
+                decl.collect_loop_exits(loops, exits);
+                body.collect_loop_exits(loops, exits);
+            }
+
+            // Procedures are compiled in their own scope, so loop control
+            // inside of them can never exit a loop outside of them.
+            Self::ConstExpr(_) | Self::Closure(_) => {}
+        }
+    }
+
+    /// Get the closure this expression may evaluate to which captures a variable
+    /// by reference, along with the first variable it captures. Such a closure
+    /// can't outlive the scope the variable is declared in.
+    pub(crate) fn get_scoped_closure(&self, env: &Env) -> Option<(Closure, String)> {
+        match self {
+            Self::Annotated(expr, _) => expr.get_scoped_closure(env),
+            Self::Closure(closure) => closure
+                .get_reference_capture(env)
+                .map(|capture| (closure.clone(), capture)),
+            // A variable bound to such a closure.
+            Self::ConstExpr(ConstExpr::Symbol(name)) => env.get_scoped_closure(name).cloned(),
+
+            // The value of a block is the value of its last expression.
+            Self::Many(exprs) => exprs.last()?.get_scoped_closure(env),
+            Self::Declare(declaration, body) => {
+                let mut new_env = env.clone();
+                new_env.add_declaration(declaration).ok()?;
+                body.get_scoped_closure(&new_env)
+            }
+            Self::If(_, a, b) | Self::When(_, a, b) | Self::IfLet(_, _, a, b) => a
+                .get_scoped_closure(env)
+                .or_else(|| b.get_scoped_closure(env)),
+            Self::Match(_, branches) => branches
+                .iter()
+                .find_map(|(_, branch)| branch.get_scoped_closure(env)),
+            _ => None,
+        }
+    }
+
+    /// Check that the value of this expression can outlive the scope it's evaluated in,
+    /// because it's returned or stored. A closure which captures variables by reference
+    /// can't, because it would refer to them after they're gone.
+    pub(crate) fn check_escape(&self, env: &Env) -> Result<(), Error> {
+        match self.get_scoped_closure(env) {
+            Some((closure, capture)) => Err(closure.escape_error(&capture)),
+            None => Ok(()),
+        }
+    }
+
+    /// Collect the symbols this expression refers to.
+    /// This is used to find the variables a closure captures.
+    pub(crate) fn collect_symbols(&self, symbols: &mut BTreeSet<String>) {
+        match self {
+            Self::ConstExpr(expr) => expr.collect_symbols(symbols),
+            Self::Closure(closure) => closure.collect_symbols(symbols),
+            Self::Break(_) | Self::Continue(_) => {}
+
+            Self::Annotated(expr, _)
+            | Self::UnaryOp(_, expr)
+            | Self::Refer(_, expr)
+            | Self::Deref(expr)
+            | Self::Return(expr)
+            | Self::Try(expr)
+            | Self::Union(_, _, expr)
+            | Self::EnumUnion(_, _, expr)
+            | Self::As(expr, _)
+            | Self::Member(expr, _)
+            | Self::Labeled(_, expr) => expr.collect_symbols(symbols),
+
+            Self::While(a, b)
+            | Self::ForIn(_, a, b)
+            | Self::BinaryOp(_, a, b)
+            | Self::AssignOp(_, a, b)
+            | Self::DerefMut(a, b)
+            | Self::Index(a, b) => {
+                a.collect_symbols(symbols);
+                b.collect_symbols(symbols);
+            }
+            Self::When(cond, a, b) => {
+                cond.collect_symbols(symbols);
+                a.collect_symbols(symbols);
+                b.collect_symbols(symbols);
+            }
+            Self::If(a, b, c) | Self::TernaryOp(_, a, b, c) | Self::IfLet(_, a, b, c) => {
+                a.collect_symbols(symbols);
+                b.collect_symbols(symbols);
+                c.collect_symbols(symbols);
+            }
+
+            Self::Many(exprs) | Self::Array(exprs) | Self::Tuple(exprs) => {
+                for expr in exprs {
+                    expr.collect_symbols(symbols);
+                }
+            }
+            Self::Struct(fields) => {
+                for expr in fields.values() {
+                    expr.collect_symbols(symbols);
+                }
+            }
+            Self::Apply(f, args) => {
+                f.collect_symbols(symbols);
+                for arg in args {
+                    arg.collect_symbols(symbols);
+                }
+            }
+            Self::Match(expr, branches) => {
+                expr.collect_symbols(symbols);
+                for (pattern, branch) in branches {
+                    if let Pattern::Guard(_, guard) = pattern {
+                        guard.collect_symbols(symbols);
+                    }
+                    branch.collect_symbols(symbols);
+                }
+            }
+            Self::Declare(decl, body) => {
+                // Uses of the variables declared refer to them, not to any outside.
+                let mut scope = BTreeSet::new();
+                body.collect_symbols(&mut scope);
+                decl.collect_symbols_in_scope(&mut scope);
+                symbols.extend(scope);
+            }
+        }
+    }
+
     /// Get the size of an expression.
     pub fn size_of(self) -> Self {
         Self::ConstExpr(ConstExpr::SizeOfExpr(Box::new(self)))
@@ -536,6 +1189,23 @@ impl Expr {
         self.binop(BitwiseNor, other)
     }
 
+    /// Shift this expression left by another number of bits.
+    #[allow(clippy::should_implement_trait)]
+    pub fn shl(self, other: impl Into<Self>) -> Self {
+        self.binop(BitwiseShift::Left, other)
+    }
+
+    /// Shift this expression right by another number of bits, copying the sign bit.
+    #[allow(clippy::should_implement_trait)]
+    pub fn shr(self, other: impl Into<Self>) -> Self {
+        self.binop(BitwiseShift::ArithmeticRight, other)
+    }
+
+    /// Shift this expression right by another number of bits, filling with zeros.
+    pub fn lshr(self, other: impl Into<Self>) -> Self {
+        self.binop(BitwiseShift::LogicalRight, other)
+    }
+
     /// BitwiseAnd this expression with another.
     pub fn bitnot(self) -> Self {
         self.unop(BitwiseNot)
@@ -741,6 +1411,11 @@ impl Expr {
         Expr::While(Box::new(self), Box::new(body.into()))
     }
 
+    /// Label this loop, so that `break` and `continue` can refer to it by name.
+    pub fn labeled(self, label: impl ToString) -> Self {
+        Expr::Labeled(label.to_string(), Box::new(self))
+    }
+
     /// Reference this expression (i.e. get a pointer to it).
     pub fn refer(self, mutability: impl Into<Mutability>) -> Self {
         Expr::Refer(mutability.into(), Box::new(self))
@@ -768,8 +1443,85 @@ impl Expr {
     }
 }
 
+/// A writer which indents the lines written to it, to display the contents of
+/// a block under it in the alternate format.
+pub(super) struct Indented<'a, 'b> {
+    f: &'a mut fmt::Formatter<'b>,
+    /// Is the writer at the start of a line which hasn't been indented yet?
+    at_line_start: bool,
+}
+
+impl<'a, 'b> Indented<'a, 'b> {
+    pub(super) fn new(f: &'a mut fmt::Formatter<'b>) -> Self {
+        Self {
+            f,
+            at_line_start: true,
+        }
+    }
+}
+
+impl fmt::Write for Indented<'_, '_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for line in s.split_inclusive('\n') {
+            if self.at_line_start && line != "\n" {
+                self.f.write_str("    ")?;
+            }
+            self.f.write_str(line)?;
+            self.at_line_start = line.ends_with('\n');
+        }
+        Ok(())
+    }
+}
+
+impl Expr {
+    /// Display the expression in the alternate format, which is spread over several lines
+    /// to be read by people. The statements of each block are on their own lines, indented
+    /// under it, and the body of each declaration is on the line after it. Expressions
+    /// without blocks in them are displayed on one line.
+    fn fmt_alternate(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use fmt::Write;
+        match self {
+            Self::Declare(declaration, result) => {
+                write!(f, "let {declaration:#} in\n{result:#}")
+            }
+            Self::Annotated(expr, _) => write!(f, "{expr:#}"),
+            Self::ConstExpr(expr) => write!(f, "{expr:#}"),
+            Self::Many(exprs) if !exprs.is_empty() => {
+                writeln!(f, "{{")?;
+                let mut block = Indented::new(f);
+                for (i, item) in exprs.iter().enumerate() {
+                    write!(block, "{item:#}")?;
+                    writeln!(block, "{}", if i < exprs.len() - 1 { ";" } else { "" })?;
+                }
+                write!(f, "}}")
+            }
+            Self::While(cond, body) => write!(f, "while ({cond}) {body:#}"),
+            Self::Labeled(label, body) => write!(f, "'{label}: {body:#}"),
+            Self::ForIn(pat, iterable, body) => write!(f, "for {pat} in {iterable} {body:#}"),
+            Self::If(cond, t, e) => write!(f, "if ({cond}) {t:#} else {e:#}"),
+            Self::IfLet(pat, expr, t, e) => write!(f, "if let {pat} = {expr} {t:#} else {e:#}"),
+            Self::When(cond, t, e) => write!(f, "when ({cond}) {t:#} else {e:#}"),
+            Self::Match(expr, branches) if !branches.is_empty() => {
+                writeln!(f, "match {expr} {{")?;
+                let mut block = Indented::new(f);
+                for (i, (pat, val)) in branches.iter().enumerate() {
+                    write!(block, "{pat} => {val:#}")?;
+                    writeln!(block, "{}", if i < branches.len() - 1 { "," } else { "" })?;
+                }
+                write!(f, "}}")
+            }
+            Self::Return(val) => write!(f, "return {val:#}"),
+            Self::Closure(closure) => write!(f, "{closure:#}"),
+            _ => write!(f, "{self}"),
+        }
+    }
+}
+
 impl fmt::Display for Expr {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if f.alternate() {
+            return self.fmt_alternate(f);
+        }
         match self {
             Self::Declare(declaration, result) => {
                 write!(f, "let {declaration} in {result}")
@@ -810,6 +1562,12 @@ impl fmt::Display for Expr {
             Self::While(cond, body) => {
                 write!(f, "while ({cond}) {body}")
             }
+            Self::Labeled(label, body) => write!(f, "'{label}: {body}"),
+            Self::Break(None) => write!(f, "break"),
+            Self::Break(Some(label)) => write!(f, "break '{label}"),
+            Self::Continue(None) => write!(f, "continue"),
+            Self::Continue(Some(label)) => write!(f, "continue '{label}"),
+            Self::ForIn(pat, iterable, body) => write!(f, "for {pat} in {iterable} {body}"),
             Self::If(cond, t, e) => {
                 write!(f, "if ({cond}) {t} else {e}")
             }
@@ -857,6 +1615,8 @@ impl fmt::Display for Expr {
             Self::Index(val, idx) => write!(f, "{val}[{idx}]"),
 
             Self::Return(val) => write!(f, "return {val}"),
+            Self::Try(val) => write!(f, "{val}?"),
+            Self::Closure(closure) => write!(f, "{closure}"),
             Self::Refer(mutability, val) => {
                 write!(f, "&")?;
                 if mutability.is_mutable() {
@@ -894,6 +1654,14 @@ impl PartialEq for Expr {
 
             // Create a while loop: while the first expression evaluates to true, evaluate the second expression.
             (While(cond1, body1), While(cond2, body2)) => cond1 == cond2 && body1 == body2,
+            // A labeled loop.
+            (Labeled(label1, body1), Labeled(label2, body2)) => label1 == label2 && body1 == body2,
+            // Exit or continue a loop.
+            (Break(label1), Break(label2)) => label1 == label2,
+            (Continue(label1), Continue(label2)) => label1 == label2,
+            (ForIn(pat1, iterable1, body1), ForIn(pat2, iterable2, body2)) => {
+                pat1 == pat2 && iterable1 == iterable2 && body1 == body2
+            }
 
             // An if-then-else expression.
             //
@@ -938,6 +1706,8 @@ impl PartialEq for Expr {
             (Apply(func1, args1), Apply(func2, args2)) => func1 == func2 && args1 == args2,
             // Return a value from a function.
             (Return(val1), Return(val2)) => val1 == val2,
+            (Try(val1), Try(val2)) => val1 == val2,
+            (Closure(closure1), Closure(closure2)) => closure1 == closure2,
 
             // An array of expressions.
             (Array(vals1), Array(vals2)) => vals1 == vals2,
@@ -975,6 +1745,8 @@ impl PartialEq for Expr {
     }
 }
 
+impl Eq for Expr {}
+
 impl Hash for Expr {
     fn hash<H: Hasher>(&self, state: &mut H) {
         use Expr::*;
@@ -1076,6 +1848,11 @@ impl Hash for Expr {
                 val.hash(state);
             }
 
+            Closure(closure) => {
+                state.write_u8(26);
+                closure.hash(state);
+            }
+
             Array(vals) => {
                 state.write_u8(14);
                 vals.hash(state);
@@ -1132,6 +1909,34 @@ impl Hash for Expr {
                 decl.hash(state);
                 expr.hash(state);
             }
+
+            Labeled(label, body) => {
+                state.write_u8(23);
+                label.hash(state);
+                body.hash(state);
+            }
+
+            Break(label) => {
+                state.write_u8(24);
+                label.hash(state);
+            }
+
+            Continue(label) => {
+                state.write_u8(25);
+                label.hash(state);
+            }
+
+            ForIn(pat, iterable, body) => {
+                state.write_u8(27);
+                pat.hash(state);
+                iterable.hash(state);
+                body.hash(state);
+            }
+
+            Try(expr) => {
+                state.write_u8(28);
+                expr.hash(state);
+            }
         }
     }
 }
@@ -96,8 +96,8 @@ impl BinaryOp for Add {
             _ => {
                 return Err(Error::InvalidBinaryOp(
                     self.clone_box(),
-                    Expr::ConstExpr(lhs.clone()),
-                    Expr::ConstExpr(rhs.clone()),
+                    Box::new(Expr::ConstExpr(lhs.clone())),
+                    Box::new(Expr::ConstExpr(rhs.clone())),
                 ))
             }
         })
@@ -340,6 +340,10 @@ impl BinaryOp for Add {
     fn clone_box(&self) -> Box<dyn BinaryOp> {
         Box::new(*self)
     }
+
+    fn overload_name(&self) -> Option<&'static str> {
+        Some("add")
+    }
 }
 
 impl Display for Add {
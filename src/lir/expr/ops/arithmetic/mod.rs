@@ -92,8 +92,8 @@ impl BinaryOp for Arithmetic {
                         error!("Cannot multiply array {lhs} by {n} (not positive)");
                         return Err(Error::InvalidBinaryOp(
                             Box::new(*self),
-                            lhs.clone(),
-                            rhs.clone(),
+                            Box::new(lhs.clone()),
+                            Box::new(rhs.clone()),
                         ));
                     }
                     Type::Array(elem, Box::new(ConstExpr::Int(size * n)))
@@ -101,8 +101,8 @@ impl BinaryOp for Arithmetic {
                     error!("Cannot multiply array {lhs} by {rhs} (not constant = {rhs:?})");
                     return Err(Error::InvalidBinaryOp(
                         Box::new(*self),
-                        lhs.clone(),
-                        rhs.clone(),
+                        Box::new(lhs.clone()),
+                        Box::new(rhs.clone()),
                     ));
                 }
             }
@@ -122,8 +122,8 @@ impl BinaryOp for Arithmetic {
                 if name1 != name2 {
                     return Err(Error::InvalidBinaryOp(
                         Box::new(*self),
-                        lhs.clone(),
-                        rhs.clone(),
+                        Box::new(lhs.clone()),
+                        Box::new(rhs.clone()),
                     ));
                 }
 
@@ -131,8 +131,8 @@ impl BinaryOp for Arithmetic {
                 if !a_type.equals(&b_type, env)? {
                     return Err(Error::InvalidBinaryOp(
                         Box::new(*self),
-                        lhs.clone(),
-                        rhs.clone(),
+                        Box::new(lhs.clone()),
+                        Box::new(rhs.clone()),
                     ));
                 }
 
@@ -141,8 +141,8 @@ impl BinaryOp for Arithmetic {
             _ => {
                 return Err(Error::InvalidBinaryOp(
                     Box::new(*self),
-                    lhs.clone(),
-                    rhs.clone(),
+                    Box::new(lhs.clone()),
+                    Box::new(rhs.clone()),
                 ))
             }
         })
@@ -243,8 +243,8 @@ impl BinaryOp for Arithmetic {
             }
             _ => Err(Error::InvalidBinaryOp(
                 self.clone_box(),
-                Expr::ConstExpr(lhs.clone()),
-                Expr::ConstExpr(rhs.clone()),
+                Box::new(Expr::ConstExpr(lhs.clone())),
+                Box::new(Expr::ConstExpr(rhs.clone())),
             )),
         }
     }
@@ -374,6 +374,17 @@ impl BinaryOp for Arithmetic {
     fn clone_box(&self) -> Box<dyn BinaryOp> {
         Box::new(*self)
     }
+
+    fn overload_name(&self) -> Option<&'static str> {
+        Some(match self {
+            Self::Add => "add",
+            Self::Subtract => "sub",
+            Self::Multiply => "mul",
+            Self::Divide => "div",
+            Self::Remainder => "rem",
+            Self::Power => "pow",
+        })
+    }
 }
 
 impl Debug for Arithmetic {
@@ -23,7 +23,7 @@ impl UnaryOp for Negate {
             Err(Error::MismatchedTypes {
                 expected: Type::Int,
                 found: ty,
-                expr: x.clone(),
+                expr: Box::new(x.clone()),
             })
         }
     }
@@ -38,7 +38,7 @@ impl UnaryOp for Negate {
                 return Err(Error::MismatchedTypes {
                     expected: Type::Int,
                     found: ty,
-                    expr: Expr::ConstExpr(x.clone()),
+                    expr: Box::new(Expr::ConstExpr(x.clone())),
                 })
             }
         })
@@ -81,6 +81,10 @@ impl UnaryOp for Negate {
     fn clone_box(&self) -> Box<dyn UnaryOp> {
         Box::new(*self)
     }
+
+    fn overload_name(&self) -> Option<&'static str> {
+        Some("neg")
+    }
 }
 
 impl Display for Negate {
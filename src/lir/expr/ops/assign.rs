@@ -23,6 +23,12 @@ impl AssignOp for Assign {
     fn can_apply(&self, lhs: &Type, rhs: &Type, env: &Env) -> Result<bool, Error> {
         if let Type::Pointer(mutability, t) = lhs.clone().simplify(env)? {
             if mutability.is_mutable() {
+                // Operators overloaded by user-defined types can also be assigned with.
+                if let Some(name) = self.0.overload_name() {
+                    if Expr::get_operator_method(&t, name, env)?.is_some() {
+                        return Ok(true);
+                    }
+                }
                 return self.0.can_apply(&t, rhs, env);
             }
         }
@@ -38,8 +38,8 @@ impl BinaryOp for BitwiseAnd {
             }
             _ => Err(Error::InvalidBinaryOp(
                 self.clone_box(),
-                Expr::ConstExpr(lhs.clone()),
-                Expr::ConstExpr(rhs.clone()),
+                Box::new(Expr::ConstExpr(lhs.clone())),
+                Box::new(Expr::ConstExpr(rhs.clone())),
             )),
         }
     }
@@ -64,6 +64,11 @@ impl BinaryOp for BitwiseAnd {
     fn clone_box(&self) -> Box<dyn BinaryOp> {
         Box::new(*self)
     }
+
+    /// The name of the associated method which overloads this operation.
+    fn overload_name(&self) -> Option<&'static str> {
+        Some("bitand")
+    }
 }
 
 impl Debug for BitwiseAnd {
@@ -6,11 +6,13 @@
 //! - `And`
 //! - `Nand`
 //! - `Xor`
+//! - `Shift`
 mod and;
 mod nand;
 mod nor;
 mod not;
 mod or;
+mod shift;
 mod xor;
 
 pub use and::*;
@@ -18,4 +20,5 @@ pub use nand::*;
 pub use nor::*;
 pub use not::*;
 pub use or::*;
+pub use shift::*;
 pub use xor::*;
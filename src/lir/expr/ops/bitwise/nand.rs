@@ -38,8 +38,8 @@ impl BinaryOp for BitwiseNand {
             }
             _ => Err(Error::InvalidBinaryOp(
                 self.clone_box(),
-                Expr::ConstExpr(lhs.clone()),
-                Expr::ConstExpr(rhs.clone()),
+                Box::new(Expr::ConstExpr(lhs.clone())),
+                Box::new(Expr::ConstExpr(rhs.clone())),
             )),
         }
     }
@@ -23,7 +23,7 @@ impl UnaryOp for BitwiseNot {
             Err(Error::MismatchedTypes {
                 expected: Type::Cell,
                 found: ty,
-                expr: x.clone(),
+                expr: Box::new(x.clone()),
             })
         }
     }
@@ -38,7 +38,7 @@ impl UnaryOp for BitwiseNot {
                 return Err(Error::MismatchedTypes {
                     expected: Type::Cell,
                     found: ty,
-                    expr: Expr::ConstExpr(x.clone()),
+                    expr: Box::new(Expr::ConstExpr(x.clone())),
                 })
             }
         })
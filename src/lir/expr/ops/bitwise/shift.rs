@@ -0,0 +1,249 @@
+//! # Bitwise Shift Operations
+use crate::{
+    asm::{AssemblyProgram, CoreOp, StandardOp, A, B, SP},
+    lir::*,
+};
+use ::core::fmt::{Debug, Display, Formatter, Result as FmtResult};
+
+/// A bitwise shift of an integer by a number of bits.
+///
+/// Shifting by a negative number of bits leaves the value unchanged, and shifting
+/// by 64 bits or more shifts every bit out of the value.
+#[derive(Clone, Copy, PartialOrd, Ord, PartialEq, Eq, Hash)]
+pub enum BitwiseShift {
+    /// Shift left, filling the vacated bits with zeros (`<<`).
+    Left,
+    /// Shift right, copying the sign bit into the vacated bits (`>>`).
+    ArithmeticRight,
+    /// Shift right, filling the vacated bits with zeros (`>>>`).
+    LogicalRight,
+}
+
+impl BitwiseShift {
+    /// Shift a value by a number of bits.
+    fn shift(&self, val: i64, bits: i64) -> i64 {
+        if bits <= 0 {
+            return val;
+        }
+        match self {
+            Self::Left => val.checked_shl(bits.min(64) as u32).unwrap_or(0),
+            Self::ArithmeticRight => val >> bits.min(63),
+            Self::LogicalRight => (val as u64).checked_shr(bits.min(64) as u32).unwrap_or(0) as i64,
+        }
+    }
+
+    /// Shift the value below the top of the stack by the number of bits on the top of the stack,
+    /// using only core instructions. The value is shifted one bit at a time.
+    fn compile_core(&self) -> CoreOp {
+        let bits = SP.deref();
+        let val = SP.deref().offset(-1);
+        let tmp = SP.deref().offset(1);
+
+        // Shift the value by a single bit.
+        let step = match self {
+            // Multiplying by two could overflow, so clear the top two bits first,
+            // and then set the sign bit if the second highest bit was set.
+            Self::Left => vec![
+                CoreOp::Move {
+                    src: val.clone(),
+                    dst: tmp.clone(),
+                },
+                CoreOp::Set(A, 1 << 62),
+                CoreOp::BitwiseAnd {
+                    src: A,
+                    dst: tmp.clone(),
+                },
+                CoreOp::Set(A, (1 << 62) - 1),
+                CoreOp::BitwiseAnd {
+                    src: A,
+                    dst: val.clone(),
+                },
+                CoreOp::Set(A, 2),
+                CoreOp::Mul {
+                    src: A,
+                    dst: val.clone(),
+                },
+                CoreOp::If(tmp),
+                CoreOp::Set(A, i64::MIN),
+                CoreOp::Add { src: A, dst: val },
+                CoreOp::End,
+            ],
+            // Dividing rounds towards zero, so subtract the lowest bit first
+            // to round towards negative infinity instead.
+            Self::ArithmeticRight | Self::LogicalRight => {
+                let mut step = vec![
+                    CoreOp::Move {
+                        src: val.clone(),
+                        dst: tmp.clone(),
+                    },
+                    CoreOp::Set(A, 1),
+                    CoreOp::BitwiseAnd {
+                        src: A,
+                        dst: tmp.clone(),
+                    },
+                    CoreOp::Sub {
+                        src: tmp,
+                        dst: val.clone(),
+                    },
+                    CoreOp::Set(A, 2),
+                    CoreOp::Div {
+                        src: A,
+                        dst: val.clone(),
+                    },
+                ];
+                if let Self::LogicalRight = self {
+                    // Clear the sign bit.
+                    step.push(CoreOp::Set(A, i64::MAX));
+                    step.push(CoreOp::BitwiseAnd { src: A, dst: val });
+                }
+                step
+            }
+        };
+
+        let mut code = vec![
+            // Shifting by more than 64 bits is the same as shifting by 64 bits.
+            CoreOp::Set(A, 64),
+            CoreOp::IsGreater {
+                a: bits.clone(),
+                b: A,
+                dst: B,
+            },
+            CoreOp::If(B),
+            CoreOp::Move {
+                src: A,
+                dst: bits.clone(),
+            },
+            CoreOp::End,
+            // While there are bits left to shift by, shift by one bit.
+            CoreOp::Set(A, 0),
+            CoreOp::IsGreater {
+                a: bits.clone(),
+                b: A,
+                dst: B,
+            },
+            CoreOp::While(B),
+        ];
+        code.extend(step);
+        code.extend([
+            CoreOp::Dec(bits.clone()),
+            CoreOp::Set(A, 0),
+            CoreOp::IsGreater {
+                a: bits,
+                b: A,
+                dst: B,
+            },
+            CoreOp::End,
+        ]);
+        CoreOp::Many(code)
+    }
+}
+
+impl BinaryOp for BitwiseShift {
+    /// Can this binary operation be applied to the given types?
+    fn can_apply(&self, lhs: &Type, rhs: &Type, env: &Env) -> Result<bool, Error> {
+        Ok(
+            (lhs.equals(&Type::Cell, env)? || lhs.equals(&Type::Int, env)?)
+                && (rhs.equals(&Type::Cell, env)? || rhs.equals(&Type::Int, env)?),
+        )
+    }
+
+    /// Get the type of the result of applying this binary operation to the given types.
+    fn return_type(&self, lhs: &Expr, _rhs: &Expr, env: &Env) -> Result<Type, Error> {
+        if lhs.get_type(env)?.equals(&Type::Cell, env)? {
+            Ok(Type::Cell)
+        } else {
+            Ok(Type::Int)
+        }
+    }
+
+    /// Evaluate this binary operation on the given constant values.
+    fn eval(&self, lhs: &ConstExpr, rhs: &ConstExpr, env: &mut Env) -> Result<ConstExpr, Error> {
+        match (lhs.clone().eval(env)?, rhs.clone().eval(env)?) {
+            (ConstExpr::Int(a), ConstExpr::Int(b) | ConstExpr::Cell(b)) => {
+                Ok(ConstExpr::Int(self.shift(a, b)))
+            }
+            (ConstExpr::Cell(a), ConstExpr::Int(b) | ConstExpr::Cell(b)) => {
+                Ok(ConstExpr::Cell(self.shift(a, b)))
+            }
+            _ => Err(Error::InvalidBinaryOp(
+                self.clone_box(),
+                Box::new(Expr::ConstExpr(lhs.clone())),
+                Box::new(Expr::ConstExpr(rhs.clone())),
+            )),
+        }
+    }
+
+    /// Compile the binary operation. Shifts of constants are folded at compile time.
+    fn compile(
+        &self,
+        lhs: &Expr,
+        rhs: &Expr,
+        env: &mut Env,
+        output: &mut dyn AssemblyProgram,
+    ) -> Result<(), Error> {
+        if let (Expr::ConstExpr(a), Expr::ConstExpr(b)) =
+            (lhs.without_annotations(), rhs.without_annotations())
+        {
+            if let Ok(result) = self.eval(a, b, env) {
+                return result.compile_expr(env, output);
+            }
+        }
+
+        lhs.clone().compile_expr(env, output)?;
+        rhs.clone().compile_expr(env, output)?;
+        self.compile_types(&lhs.get_type(env)?, &rhs.get_type(env)?, env, output)
+    }
+
+    /// Compile the binary operation.
+    fn compile_types(
+        &self,
+        _lhs: &Type,
+        _rhs: &Type,
+        _env: &mut Env,
+        output: &mut dyn AssemblyProgram,
+    ) -> Result<(), Error> {
+        let src = SP.deref();
+        let dst = SP.deref().offset(-1);
+        let std_op = match self {
+            Self::Left => StandardOp::ShiftLeft { src, dst },
+            Self::ArithmeticRight => StandardOp::ShiftRight { src, dst },
+            Self::LogicalRight => StandardOp::LogicalShiftRight { src, dst },
+        };
+        // If the target doesn't support shifting natively, shift one bit at a time.
+        if output.std_op(std_op).is_err() {
+            output.op(self.compile_core());
+        }
+        output.op(CoreOp::Pop(None, 1));
+        Ok(())
+    }
+
+    /// Clone this binary operation into a box.
+    fn clone_box(&self) -> Box<dyn BinaryOp> {
+        Box::new(*self)
+    }
+
+    /// The name of the associated method which overloads this operation.
+    fn overload_name(&self) -> Option<&'static str> {
+        Some(match self {
+            Self::Left => "shl",
+            Self::ArithmeticRight => "shr",
+            Self::LogicalRight => "lshr",
+        })
+    }
+}
+
+impl Debug for BitwiseShift {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "{self}")
+    }
+}
+
+impl Display for BitwiseShift {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            Self::Left => write!(f, "<<"),
+            Self::ArithmeticRight => write!(f, ">>"),
+            Self::LogicalRight => write!(f, ">>>"),
+        }
+    }
+}
@@ -33,6 +33,11 @@ impl BinaryOp for Comparison {
             | (Type::Cell, Self::GreaterThan, Type::Cell)
             | (Type::Cell, Self::GreaterThanOrEqual, Type::Cell) => Ok(true),
 
+            (Type::Char, Self::LessThan, Type::Char)
+            | (Type::Char, Self::LessThanOrEqual, Type::Char)
+            | (Type::Char, Self::GreaterThan, Type::Char)
+            | (Type::Char, Self::GreaterThanOrEqual, Type::Char) => Ok(true),
+
             (Type::Float, Self::LessThan, Type::Float)
             | (Type::Float, Self::LessThan, Type::Int)
             | (Type::Int, Self::LessThan, Type::Float)
@@ -78,6 +83,16 @@ impl BinaryOp for Comparison {
             (ConstExpr::Int(a), Self::GreaterThanOrEqual, ConstExpr::Int(b)) => {
                 Ok(ConstExpr::Bool(a >= b))
             }
+            (ConstExpr::Char(a), Self::LessThan, ConstExpr::Char(b)) => Ok(ConstExpr::Bool(a < b)),
+            (ConstExpr::Char(a), Self::LessThanOrEqual, ConstExpr::Char(b)) => {
+                Ok(ConstExpr::Bool(a <= b))
+            }
+            (ConstExpr::Char(a), Self::GreaterThan, ConstExpr::Char(b)) => {
+                Ok(ConstExpr::Bool(a > b))
+            }
+            (ConstExpr::Char(a), Self::GreaterThanOrEqual, ConstExpr::Char(b)) => {
+                Ok(ConstExpr::Bool(a >= b))
+            }
             (ConstExpr::Float(a), Self::LessThan, ConstExpr::Float(b)) => {
                 Ok(ConstExpr::Bool(a < b))
             }
@@ -99,8 +114,8 @@ impl BinaryOp for Comparison {
 
             _ => Err(Error::InvalidBinaryOp(
                 self.clone_box(),
-                Expr::ConstExpr(lhs.clone()),
-                Expr::ConstExpr(rhs.clone()),
+                Box::new(Expr::ConstExpr(lhs.clone())),
+                Box::new(Expr::ConstExpr(rhs.clone())),
             )),
         }
     }
@@ -211,8 +226,9 @@ impl BinaryOp for Comparison {
                 output.std_op(std_op)?;
             }
 
-            // If cells and/or ints are used, we just use them as integers.
+            // If cells, chars and/or ints are used, we just use them as integers.
             (Type::Int, _, Type::Int)
+            | (Type::Char, _, Type::Char)
             | (Type::Cell, _, Type::Cell)
             | (Type::Cell, _, Type::Int)
             | (Type::Int, _, Type::Cell) => {
@@ -251,6 +267,17 @@ impl BinaryOp for Comparison {
     fn clone_box(&self) -> Box<dyn BinaryOp> {
         Box::new(*self)
     }
+
+    fn overload_name(&self) -> Option<&'static str> {
+        Some(match self {
+            Self::Equal => "eq",
+            Self::NotEqual => "ne",
+            Self::LessThan => "lt",
+            Self::LessThanOrEqual => "le",
+            Self::GreaterThan => "gt",
+            Self::GreaterThanOrEqual => "ge",
+        })
+    }
 }
 
 impl Debug for Comparison {
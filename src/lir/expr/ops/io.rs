@@ -32,7 +32,7 @@ impl UnaryOp for Get {
 
     /// Evaluate this unary operation on the given constant values.
     fn eval(&self, expr: &ConstExpr, _env: &mut Env) -> Result<ConstExpr, Error> {
-        Err(Error::InvalidConstExpr(expr.clone()))
+        Err(Error::InvalidConstExpr(Box::new(expr.clone())))
     }
 
     /// Compile the unary operation.
@@ -58,10 +58,10 @@ impl UnaryOp for Get {
         )? {
             output.op(CoreOp::Get(SP.deref().deref(), Input::stdin_float()));
         } else {
-            return Err(Error::UnsupportedOperation(Expr::UnaryOp(
+            return Err(Error::UnsupportedOperation(Box::new(Expr::UnaryOp(
                 self.clone_box(),
                 Box::new(Expr::ConstExpr(ConstExpr::None)),
-            )));
+            ))));
         }
 
         output.op(CoreOp::Pop(None, 1));
@@ -30,8 +30,8 @@ impl BinaryOp for And {
             (ConstExpr::Bool(a), ConstExpr::Bool(b)) => Ok(ConstExpr::Bool(a && b)),
             _ => Err(Error::InvalidBinaryOp(
                 self.clone_box(),
-                Expr::ConstExpr(lhs.clone()),
-                Expr::ConstExpr(rhs.clone()),
+                Box::new(Expr::ConstExpr(lhs.clone())),
+                Box::new(Expr::ConstExpr(rhs.clone())),
             )),
         }
     }
@@ -79,8 +79,8 @@ impl BinaryOp for Or {
             (ConstExpr::Bool(a), ConstExpr::Bool(b)) => Ok(ConstExpr::Bool(a || b)),
             _ => Err(Error::InvalidBinaryOp(
                 self.clone_box(),
-                Expr::ConstExpr(lhs.clone()),
-                Expr::ConstExpr(rhs.clone()),
+                Box::new(Expr::ConstExpr(lhs.clone())),
+                Box::new(Expr::ConstExpr(rhs.clone())),
             )),
         }
     }
@@ -129,7 +129,7 @@ impl UnaryOp for Not {
         } else {
             Err(Error::InvalidUnaryOp(
                 self.clone_box(),
-                Expr::ConstExpr(expr.clone()),
+                Box::new(Expr::ConstExpr(expr.clone())),
             ))
         }
     }
@@ -19,7 +19,7 @@ impl UnaryOp for New {
 
     /// Evaluate this unary operation on the given constant values.
     fn eval(&self, expr: &ConstExpr, _env: &mut Env) -> Result<ConstExpr, Error> {
-        Err(Error::InvalidConstExpr(expr.clone()))
+        Err(Error::InvalidConstExpr(Box::new(expr.clone())))
     }
 
     /// Compile the unary operation.
@@ -34,10 +34,10 @@ impl UnaryOp for New {
         // output.op(CoreOp::Next(SP, None));
         output.op(CoreOp::Set(A, size as i64));
         output.std_op(StandardOp::Alloc(A)).map_err(|_| {
-            Error::UnsupportedOperation(Expr::UnaryOp(
+            Error::UnsupportedOperation(Box::new(Expr::UnaryOp(
                 self.clone_box(),
                 Box::new(Expr::ConstExpr(ConstExpr::None)),
-            ))
+            )))
         })?;
         output.op(CoreOp::Copy {
             dst: A.deref(),
@@ -85,7 +85,7 @@ impl UnaryOp for Delete {
 
     /// Evaluate this unary operation on the given constant values.
     fn eval(&self, expr: &ConstExpr, _env: &mut Env) -> Result<ConstExpr, Error> {
-        Err(Error::InvalidConstExpr(expr.clone()))
+        Err(Error::InvalidConstExpr(Box::new(expr.clone())))
     }
 
     /// Compile the unary operation.
@@ -96,10 +96,10 @@ impl UnaryOp for Delete {
         output: &mut dyn AssemblyProgram,
     ) -> Result<(), Error> {
         output.std_op(StandardOp::Free(SP.deref())).map_err(|_| {
-            Error::UnsupportedOperation(Expr::UnaryOp(
+            Error::UnsupportedOperation(Box::new(Expr::UnaryOp(
                 self.clone_box(),
                 Box::new(Expr::ConstExpr(ConstExpr::None)),
-            ))
+            )))
         })?;
         output.op(CoreOp::Pop(None, 1));
         Ok(())
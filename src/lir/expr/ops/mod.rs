@@ -37,8 +37,8 @@ pub trait AssignOp: std::fmt::Debug + std::fmt::Display {
             error!("Invalid assign op: {dst} {self} {src} ({self:?}) in environment {env}");
             Err(Error::InvalidAssignOp(
                 self.clone_box(),
-                dst.clone(),
-                src.clone(),
+                Box::new(dst.clone()),
+                Box::new(src.clone()),
             ))
         }
     }
@@ -62,8 +62,8 @@ pub trait AssignOp: std::fmt::Debug + std::fmt::Display {
             error!("Invalid assign op: {dst} {self} {src} ({self:?}) in environment {env}");
             Err(Error::InvalidAssignOp(
                 self.clone_box(),
-                dst.clone(),
-                src.clone(),
+                Box::new(dst.clone()),
+                Box::new(src.clone()),
             ))
         }
     }
@@ -144,7 +144,10 @@ pub trait UnaryOp: std::fmt::Debug + std::fmt::Display {
             expr.type_check(env)
         } else {
             error!("Invalid unary op: {self} {expr} ({self:?}) in environment {env}");
-            Err(Error::InvalidUnaryOp(self.clone_box(), expr.clone()))
+            Err(Error::InvalidUnaryOp(
+                self.clone_box(),
+                Box::new(expr.clone()),
+            ))
         }
     }
     /// Gets the type of the operation on the given expression.
@@ -159,11 +162,19 @@ pub trait UnaryOp: std::fmt::Debug + std::fmt::Display {
             Ok(expr.get_type(env)?)
         } else {
             error!("Invalid unary op: {self} {expr} ({self:?}) in environment {env}");
-            Err(Error::InvalidUnaryOp(self.clone_box(), expr.clone()))
+            Err(Error::InvalidUnaryOp(
+                self.clone_box(),
+                Box::new(expr.clone()),
+            ))
         }
     }
     /// Clones the operation into a boxed trait object.
     fn clone_box(&self) -> Box<dyn UnaryOp>;
+    /// The name of the associated method which overloads this operation
+    /// for user-defined types, if it can be overloaded.
+    fn overload_name(&self) -> Option<&'static str> {
+        None
+    }
     /// Formats the operation for display.
     fn display(&self, expr: &Expr) -> String {
         format!("{} {}", self, expr)
@@ -229,8 +240,8 @@ pub trait BinaryOp: std::fmt::Debug + std::fmt::Display {
             error!("Invalid binary op: {lhs} {self} {rhs} ({self:?}) in environment {env}");
             Err(Error::InvalidBinaryOp(
                 self.clone_box(),
-                lhs.clone(),
-                rhs.clone(),
+                Box::new(lhs.clone()),
+                Box::new(rhs.clone()),
             ))
         }
     }
@@ -253,13 +264,18 @@ pub trait BinaryOp: std::fmt::Debug + std::fmt::Display {
             error!("Invalid binary op: {lhs} {self} {rhs} ({self:?}) in environment {env}");
             Err(Error::InvalidBinaryOp(
                 self.clone_box(),
-                lhs.clone(),
-                rhs.clone(),
+                Box::new(lhs.clone()),
+                Box::new(rhs.clone()),
             ))
         }
     }
     /// Clones the operation into a boxed trait object.
     fn clone_box(&self) -> Box<dyn BinaryOp>;
+    /// The name of the associated method which overloads this operation
+    /// for user-defined types, if it can be overloaded.
+    fn overload_name(&self) -> Option<&'static str> {
+        None
+    }
     /// Formats the operation for display.
     fn display(&self, lhs: &Expr, rhs: &Expr) -> String {
         format!("{} {} {}", lhs, self, rhs)
@@ -340,9 +356,9 @@ pub trait TernaryOp: std::fmt::Debug + std::fmt::Display {
         } else {
             Err(Error::InvalidTernaryOp(
                 self.clone_box(),
-                a.clone(),
-                b.clone(),
-                c.clone(),
+                Box::new(a.clone()),
+                Box::new(b.clone()),
+                Box::new(c.clone()),
             ))
         }
     }
@@ -369,9 +385,9 @@ pub trait TernaryOp: std::fmt::Debug + std::fmt::Display {
         } else {
             Err(Error::InvalidTernaryOp(
                 self.clone_box(),
-                a.clone(),
-                b.clone(),
-                c.clone(),
+                Box::new(a.clone()),
+                Box::new(b.clone()),
+                Box::new(c.clone()),
             ))
         }
     }
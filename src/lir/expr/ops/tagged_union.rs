@@ -24,7 +24,7 @@ impl UnaryOp for Tag {
             found => Err(Error::MismatchedTypes {
                 expected: Type::EnumUnion(BTreeMap::new()),
                 found,
-                expr: expr.clone(),
+                expr: Box::new(expr.clone()),
             }),
         }
     }
@@ -43,14 +43,14 @@ impl UnaryOp for Tag {
                     Err(Error::MismatchedTypes {
                         expected: Type::EnumUnion(BTreeMap::new()),
                         found: t,
-                        expr: Expr::ConstExpr(expr),
+                        expr: Box::new(Expr::ConstExpr(expr)),
                     })
                 }
             }
             found => Err(Error::MismatchedTypes {
                 expected: Type::EnumUnion(BTreeMap::new()),
                 found: found.get_type(env)?,
-                expr: Expr::ConstExpr(expr),
+                expr: Box::new(Expr::ConstExpr(expr)),
             }),
         }
     }
@@ -113,7 +113,7 @@ impl UnaryOp for Data {
             found => Err(Error::MismatchedTypes {
                 expected: Type::EnumUnion(BTreeMap::new()),
                 found,
-                expr: expr.clone(),
+                expr: Box::new(expr.clone()),
             }),
         }
     }
@@ -129,14 +129,14 @@ impl UnaryOp for Data {
                     Err(Error::MismatchedTypes {
                         expected: Type::EnumUnion(BTreeMap::new()),
                         found: t.clone(),
-                        expr: Expr::ConstExpr(expr),
+                        expr: Box::new(Expr::ConstExpr(expr)),
                     })
                 }
             }
             found => Err(Error::MismatchedTypes {
                 expected: Type::EnumUnion(BTreeMap::new()),
                 found: found.get_type(env)?,
-                expr: Expr::ConstExpr(expr),
+                expr: Box::new(Expr::ConstExpr(expr)),
             }),
         }
     }
@@ -20,6 +20,11 @@ pub enum Pattern {
     ConstExpr(ConstExpr),
     Alt(Vec<Pattern>),
     Pointer(Box<Pattern>),
+    /// Matches a value between two constants. The `bool` is whether the end is inclusive.
+    Range(Box<ConstExpr>, Box<ConstExpr>, bool),
+    /// Matches a pattern, but only if the guard expression evaluates to true
+    /// with the pattern's bindings defined.
+    Guard(Box<Pattern>, Box<Expr>),
     Wildcard,
 }
 
@@ -60,6 +65,76 @@ impl Pattern {
     pub fn pointer(pattern: Pattern) -> Self {
         Self::Pointer(Box::new(pattern))
     }
+    /// Construct a new pattern which matches a value between two constants.
+    pub fn range(start: ConstExpr, end: ConstExpr, inclusive: bool) -> Self {
+        Self::Range(Box::new(start), Box::new(end), inclusive)
+    }
+    /// Construct a new pattern which only matches if the guard evaluates to true.
+    pub fn guard(pattern: Pattern, guard: impl Into<Expr>) -> Self {
+        Self::Guard(Box::new(pattern), Box::new(guard.into()))
+    }
+
+    /// Does this pattern bind a variable with the given name?
+    pub(crate) fn binds(&self, name: &str) -> bool {
+        match self {
+            Self::Symbol(_, symbol) => symbol == name,
+            Self::Tuple(patterns) | Self::Alt(patterns) => {
+                patterns.iter().any(|pattern| pattern.binds(name))
+            }
+            Self::Struct(patterns) => patterns.values().any(|pattern| pattern.binds(name)),
+            Self::Variant(_, Some(pattern)) | Self::Pointer(pattern) | Self::Guard(pattern, _) => {
+                pattern.binds(name)
+            }
+            Self::Variant(_, None) | Self::ConstExpr(_) | Self::Range(_, _, _) | Self::Wildcard => {
+                false
+            }
+        }
+    }
+
+    /// Get the variables this pattern binds, with their mutability.
+    pub(crate) fn bound_symbols(&self) -> Vec<(Mutability, &String)> {
+        match self {
+            Self::Symbol(mutability, symbol) => vec![(*mutability, symbol)],
+            Self::Tuple(patterns) | Self::Alt(patterns) => {
+                patterns.iter().flat_map(Self::bound_symbols).collect()
+            }
+            Self::Struct(patterns) => patterns.values().flat_map(Self::bound_symbols).collect(),
+            Self::Variant(_, Some(pattern)) | Self::Pointer(pattern) | Self::Guard(pattern, _) => {
+                pattern.bound_symbols()
+            }
+            Self::Variant(_, None) | Self::ConstExpr(_) | Self::Range(_, _, _) | Self::Wildcard => {
+                vec![]
+            }
+        }
+    }
+
+    /// Substitute a type in the guard expressions of this pattern.
+    pub(crate) fn substitute(&mut self, name: &str, ty: &Type) {
+        match self {
+            Self::Guard(pattern, guard) => {
+                pattern.substitute(name, ty);
+                guard.substitute(name, ty);
+            }
+            Self::Tuple(patterns) | Self::Alt(patterns) => {
+                for pattern in patterns {
+                    pattern.substitute(name, ty);
+                }
+            }
+            Self::Struct(patterns) => {
+                for pattern in patterns.values_mut() {
+                    pattern.substitute(name, ty);
+                }
+            }
+            Self::Variant(_, Some(pattern)) | Self::Pointer(pattern) => {
+                pattern.substitute(name, ty);
+            }
+            Self::Variant(_, None)
+            | Self::Symbol(_, _)
+            | Self::ConstExpr(_)
+            | Self::Range(_, _, _)
+            | Self::Wildcard => {}
+        }
+    }
 
     /// Get the type of a branch with a given expression matched to this pattern.
     pub fn get_branch_result_type(
@@ -91,6 +166,9 @@ impl Pattern {
     /// This associated function returns whether or not a set of patterns is exhaustive,
     /// that is, whether or not it matches all possible values of a given type.
     /// This is used to check if a `match` expression is exhaustive.
+    ///
+    /// Guarded patterns and range patterns are never considered to cover any values,
+    /// since whether they match can't be known until runtime.
     pub fn are_patterns_exhaustive(
         expr: &Expr,
         patterns: &[Pattern],
@@ -213,7 +291,7 @@ impl Pattern {
                                 return Err(Error::MismatchedTypes {
                                     expected: matching_expr_ty.clone(),
                                     found: ty.clone(),
-                                    expr: expr.clone(),
+                                    expr: Box::new(expr.clone()),
                                 });
                             }
                         }
@@ -389,6 +467,23 @@ impl Pattern {
         trace!("Type checking pattern match: {} => {}", self, branch);
         // Get the type of the expression being matched.
         let matching_ty = matching_expr.get_type(env)?.simplify_until_concrete(env)?;
+        // If the pattern is guarded, the guard must be a boolean expression
+        // under the bindings of the pattern.
+        if let Self::Guard(pattern, guard) = self {
+            let mut guard_env = env.clone();
+            for (var, (mutability, ty)) in pattern.get_bindings(matching_expr, &matching_ty, env)? {
+                guard_env.define_var(var, mutability, ty)?;
+            }
+            guard.type_check(&guard_env)?;
+            let found = guard.get_type(&guard_env)?;
+            if !found.equals(&Type::Bool, &guard_env)? {
+                return Err(Error::MismatchedTypes {
+                    expected: Type::Bool,
+                    found,
+                    expr: guard.clone(),
+                });
+            }
+        }
         // Get the type of the branch as a result of the match.
         let expected = self.get_branch_result_type(matching_expr, branch, env)?;
         // Type-check the expression generated to match the pattern.
@@ -403,37 +498,42 @@ impl Pattern {
                 return Err(Error::MismatchedTypes {
                     expected,
                     found,
-                    expr: Expr::Match(
+                    expr: Box::new(Expr::Match(
                         Box::new(matching_expr.clone()),
                         vec![(self.clone(), branch.clone())],
-                    ),
+                    )),
                 });
             }
             Err(Error::InvalidBinaryOpTypes(_, expected, found)) => {
                 return Err(Error::MismatchedTypes {
                     expected,
                     found,
-                    expr: Expr::Match(
+                    expr: Box::new(Expr::Match(
                         Box::new(matching_expr.clone()),
                         vec![(self.clone(), branch.clone())],
-                    ),
+                    )),
                 })
             }
             _ => {
                 return Err(Error::MismatchedTypes {
                     expected,
                     found: matching_ty,
-                    expr: Expr::Match(
+                    expr: Box::new(Expr::Match(
                         Box::new(matching_expr.clone()),
                         vec![(self.clone(), branch.clone())],
-                    ),
+                    )),
                 })
             } // Err(e) => return Err(Error::InvalidPatternForExpr(matching_expr.clone(), self.clone())),
         }
         // Generate an expression with the bindings defined for the branch.
         let result_expr = self
             .bind(matching_expr, &matching_ty, branch, env)
-            .map_err(|_| Error::InvalidPatternForExpr(matching_expr.clone(), self.clone()))?;
+            .map_err(|_| {
+                Error::InvalidPatternForExpr(
+                    Box::new(matching_expr.clone()),
+                    Box::new(self.clone()),
+                )
+            })?;
         // Type-check the expression generated to bind the pattern.
         result_expr.type_check(env)?;
         // Get the type of the expression generated to bind the pattern.
@@ -444,7 +544,7 @@ impl Pattern {
             return Err(Error::MismatchedTypes {
                 expected,
                 found,
-                expr: matching_expr.clone(),
+                expr: Box::new(matching_expr.clone()),
             });
         }
 
@@ -642,7 +742,10 @@ impl Pattern {
                     } else {
                         // If the struct does not have a field with the given name, then
                         // return an error.
-                        return Err(Error::InvalidPatternForExpr(expr.clone(), self.clone()));
+                        return Err(Error::InvalidPatternForExpr(
+                            Box::new(expr.clone()),
+                            Box::new(self.clone()),
+                        ));
                     }
                 }
                 result.into_iter().collect()
@@ -660,7 +763,13 @@ impl Pattern {
             (Self::Variant(_, None), Type::Enum(_))
             | (Self::Variant(_, None), Type::EnumUnion(_))
             | (Self::Wildcard, _)
-            | (Self::ConstExpr(_), _) => HashMap::new(),
+            | (Self::ConstExpr(_), _)
+            | (Self::Range(_, _, _), _) => HashMap::new(),
+
+            // A guarded pattern binds the same variables as the pattern it guards.
+            (Self::Guard(pattern, _), ty) => {
+                pattern.get_bindings_with_offset(expr, ty, env, origin)?
+            }
 
             (Self::Pointer(pattern), Type::Pointer(_, item_type)) => {
                 pattern.get_bindings_with_offset(&expr.clone().deref(), item_type, env, origin)?
@@ -682,8 +791,8 @@ impl Pattern {
                             // If the variable is not in the result, then add it.
                             if !result.contains_key(&var) {
                                 return Err(Error::InvalidPatternForExpr(
-                                    expr.clone(),
-                                    self.clone(),
+                                    Box::new(expr.clone()),
+                                    Box::new(self.clone()),
                                 ));
                             } else {
                                 // If the variable is in the result, then check if the mutability and type match.
@@ -692,8 +801,8 @@ impl Pattern {
                                     // If the bindings for this pattern are different to the previous patterns,
                                     // then return an error.
                                     return Err(Error::InvalidPatternForExpr(
-                                        expr.clone(),
-                                        self.clone(),
+                                        Box::new(expr.clone()),
+                                        Box::new(self.clone()),
                                     ));
                                 }
                             }
@@ -710,7 +819,10 @@ impl Pattern {
                 error!("Pattern: {}", self);
                 error!("Type: {}", ty);
                 // If the pattern does not match the type, then return an error.
-                return Err(Error::InvalidPatternForExpr(expr.clone(), self.clone()));
+                return Err(Error::InvalidPatternForExpr(
+                    Box::new(expr.clone()),
+                    Box::new(self.clone()),
+                ));
             }
         })
     }
@@ -779,7 +891,10 @@ impl Pattern {
                 // If the number of patterns does not match the number of members,
                 // then return an error.
                 if patterns.len() != item_types.len() {
-                    return Err(Error::InvalidPatternForExpr(expr.clone(), self.clone()));
+                    return Err(Error::InvalidPatternForExpr(
+                        Box::new(expr.clone()),
+                        Box::new(self.clone()),
+                    ));
                 }
 
                 // Iterate over the patterns and types of the tuple.
@@ -810,7 +925,10 @@ impl Pattern {
                 // If the number of patterns does not match the number of members,
                 // then return an error.
                 if patterns.len() != item_types.len() {
-                    return Err(Error::InvalidPatternForExpr(expr.clone(), self.clone()));
+                    return Err(Error::InvalidPatternForExpr(
+                        Box::new(expr.clone()),
+                        Box::new(self.clone()),
+                    ));
                 }
 
                 // Iterate over the field names and patterns of the struct.
@@ -827,7 +945,10 @@ impl Pattern {
                     } else {
                         // If the struct does not have a field with the given name, then
                         // return an error.
-                        return Err(Error::InvalidPatternForExpr(expr.clone(), self.clone()));
+                        return Err(Error::InvalidPatternForExpr(
+                            Box::new(expr.clone()),
+                            Box::new(self.clone()),
+                        ));
                     }
                 }
 
@@ -849,6 +970,25 @@ impl Pattern {
                 expr.clone().eq(Expr::ConstExpr(const_expr.clone()))
             }
 
+            // If the pattern is a range, check the expression is between the bounds.
+            (Self::Range(start, end, inclusive), _) => {
+                let lower = expr.clone().ge(Expr::ConstExpr(*start.clone()));
+                let upper = if *inclusive {
+                    expr.clone().le(Expr::ConstExpr(*end.clone()))
+                } else {
+                    expr.clone().lt(Expr::ConstExpr(*end.clone()))
+                };
+                lower.and(upper)
+            }
+
+            // If the pattern is guarded, check that the pattern matches first,
+            // and only then evaluate the guard with the pattern's bindings.
+            (Self::Guard(pattern, guard), _) => Expr::If(
+                Box::new(pattern.matches(expr, ty, env)?),
+                Box::new(pattern.bind(expr, ty, guard, env)?),
+                Box::new(Expr::ConstExpr(ConstExpr::Bool(false))),
+            ),
+
             // If the pattern is an alternative, then check if any of the patterns match.
             (Self::Alt(patterns), _) => {
                 // The result of the match expression.
@@ -865,7 +1005,12 @@ impl Pattern {
                 result
             }
 
-            _ => return Err(Error::InvalidPatternForExpr(expr.clone(), self.clone())),
+            _ => {
+                return Err(Error::InvalidPatternForExpr(
+                    Box::new(expr.clone()),
+                    Box::new(self.clone()),
+                ))
+            }
         })
     }
 
@@ -986,7 +1131,10 @@ impl Pattern {
                     } else {
                         // If the struct does not have a field with the given name, then
                         // return an error.
-                        return Err(Error::InvalidPatternForExpr(expr.clone(), self.clone()));
+                        return Err(Error::InvalidPatternForExpr(
+                            Box::new(expr.clone()),
+                            Box::new(self.clone()),
+                        ));
                     }
                 }
 
@@ -1008,7 +1156,12 @@ impl Pattern {
             }
 
             // If the pattern is a wildcard, then it will not add any bindings.
-            (Self::Wildcard, _) | (Self::ConstExpr(_), _) => ret.clone(),
+            (Self::Wildcard, _) | (Self::ConstExpr(_), _) | (Self::Range(_, _, _), _) => {
+                ret.clone()
+            }
+
+            // If the pattern is guarded, bind the pattern it guards.
+            (Self::Guard(pattern, _), _) => pattern.bind(expr, ty, ret, env)?,
 
             // If the pattern is an alternative, then bind the first pattern.
             // All their bindings will be the same type, so it doesn't matter which one
@@ -1019,12 +1172,17 @@ impl Pattern {
                     .first()
                     .map(|x| x.bind(expr, ty, ret, env))
                     .unwrap_or(Err(Error::InvalidPatternForExpr(
-                        expr.clone(),
-                        self.clone(),
+                        Box::new(expr.clone()),
+                        Box::new(self.clone()),
                     )))?
             }
 
-            _ => return Err(Error::InvalidPatternForExpr(expr.clone(), self.clone())),
+            _ => {
+                return Err(Error::InvalidPatternForExpr(
+                    Box::new(expr.clone()),
+                    Box::new(self.clone()),
+                ))
+            }
         })
     }
 }
@@ -1080,6 +1238,16 @@ impl Display for Pattern {
                 write!(f, ")")
             }
 
+            Self::Range(start, end, inclusive) => {
+                if *inclusive {
+                    write!(f, "{start}..={end}")
+                } else {
+                    write!(f, "{start}..{end}")
+                }
+            }
+
+            Self::Guard(pattern, guard) => write!(f, "{pattern} if {guard}"),
+
             Self::Wildcard => write!(f, "_"),
         }
     }
@@ -0,0 +1,552 @@
+//! # Closure
+//!
+//! A procedure which captures variables from the scope it is created in.
+//!
+//! A closure is compiled down to a label like any other procedure, along with an
+//! environment record: a cell holding the label, followed by the captured variables.
+//! A procedure value is a single cell. For a procedure, it's the procedure's label, and
+//! for a closure, it's the negated address of the closure's record (minus one, so that
+//! it's always negative). A call through a procedure value checks its sign, so closures
+//! can be used anywhere a procedure can be used.
+//!
+//! A closure either copies the values of the variables it captures into its record,
+//! or stores pointers to them. A closure which captures by reference can't outlive
+//! the variables it captures, so its record is allocated on the stack: next to the
+//! variable it's bound to, or below the arguments of the call it's passed to.
+//! The type checker rejects a closure which captures by reference, or a variable bound
+//! to one, when it's returned or stored somewhere that may outlive the variables.
+//! Every other closure may escape the scope it's created in, so its record is
+//! allocated on the heap. Nothing owns a heap record, so it is never freed: each
+//! evaluation of an escaping closure leaks its record. A closure which captures
+//! nothing doesn't need a record, so its value is its label.
+use crate::asm::{AssemblyProgram, CoreOp, StandardOp, A, B, C, FP, SP};
+use crate::lir::{
+    Annotation, Compile, Constraint, Env, Error, Expr, GetSize, GetType, Mutability, Type,
+    TypeCheck,
+};
+use core::fmt;
+use std::collections::BTreeSet;
+use std::hash::Hash;
+use std::{rc::Rc, sync::Mutex};
+
+use log::trace;
+
+// TODO: Do this without lazy_static. This is used to create unique mangled IDs for each compiled closure.
+use lazy_static::lazy_static;
+lazy_static! {
+    // The number of closures created so far.
+    static ref CLOSURE_COUNT: Mutex<usize> = Mutex::new(0);
+}
+
+/// How a closure captures the variables it refers to from the scope it is created in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Capture {
+    /// Copy the values of the captured variables into the closure's environment.
+    ByValue,
+    /// Store pointers to the captured variables in the closure's environment.
+    ByReference,
+}
+
+/// A procedure which captures variables from the scope it is created in.
+/// The body is compiled down to a label, and the value of the closure is
+/// the tagged address of its environment record.
+#[derive(Clone, Debug)]
+pub struct Closure {
+    /// How the closure captures variables.
+    capture: Capture,
+    /// The generated name of the closure's label.
+    mangled_name: String,
+    /// The arguments of the closure, and their types.
+    args: Vec<(String, Mutability, Type)>,
+    /// The return type of the closure. If this isn't given,
+    /// it's the type of the closure's body.
+    ret: Option<Type>,
+    /// The closure's body expression.
+    body: Box<Expr>,
+    /// The location of the closure in the source code, and the location of the first
+    /// use of each symbol in its body, if any. Errors about a variable the closure
+    /// captures point at its use.
+    annotations: Rc<(Annotation, Vec<(String, Annotation)>)>,
+}
+
+impl PartialEq for Closure {
+    fn eq(&self, other: &Self) -> bool {
+        self.capture == other.capture
+            && self.mangled_name == other.mangled_name
+            && self.args == other.args
+            && self.ret == other.ret
+            && self.body == other.body
+    }
+}
+
+impl Closure {
+    /// Construct a new closure with a given list of arguments and their types,
+    /// an optional return type, and the body of the closure.
+    pub fn new(
+        capture: Capture,
+        args: Vec<(String, Mutability, Type)>,
+        ret: Option<Type>,
+        body: impl Into<Expr>,
+    ) -> Self {
+        let mut closure_count = CLOSURE_COUNT.lock().unwrap();
+        *closure_count += 1;
+        Self {
+            capture,
+            mangled_name: format!("__CLOSURE_{closure_count}"),
+            args,
+            ret,
+            body: Box::new(body.into()),
+            annotations: Rc::new((Annotation::None, vec![])),
+        }
+    }
+
+    /// Annotate the closure with its location.
+    pub fn with_annotation(mut self, annotation: Annotation) -> Self {
+        Rc::make_mut(&mut self.annotations).0 = annotation;
+        self
+    }
+
+    /// Annotate the closure with the locations of the uses of the symbols in its body.
+    pub fn with_symbol_annotations(mut self, annotations: Vec<(String, Annotation)>) -> Self {
+        Rc::make_mut(&mut self.annotations).1 = annotations;
+        self
+    }
+
+    /// Get the annotation of the closure.
+    pub fn get_annotation(&self) -> &Annotation {
+        &self.annotations.0
+    }
+
+    /// Get the annotation of the first use of a symbol in the closure's body.
+    /// If the use has no location, this is the annotation of the closure.
+    fn get_symbol_annotation(&self, name: &str) -> &Annotation {
+        self.annotations
+            .1
+            .iter()
+            .find(|(symbol, annotation)| symbol == name && annotation.has_location())
+            .map(|(_, annotation)| annotation)
+            .unwrap_or(&self.annotations.0)
+    }
+
+    /// Does this closure capture variables by reference?
+    pub fn captures_by_reference(&self) -> bool {
+        self.capture == Capture::ByReference
+    }
+
+    /// Get the generated name of the closure's label.
+    pub fn get_mangled_name(&self) -> &str {
+        &self.mangled_name
+    }
+
+    /// Call the procedure value in `A` on the arguments on the stack.
+    ///
+    /// If the value is a closure, the address of its environment record
+    /// is left in `A` for the callee, so it can find the variables it captures.
+    pub(crate) fn call_value(output: &mut dyn AssemblyProgram) {
+        output.op(CoreOp::Set(C, 0));
+        output.op(CoreOp::IsLess { dst: B, a: A, b: C });
+        output.op(CoreOp::If(B));
+        // Untag the address of the record, and call the label it holds.
+        output.op(CoreOp::Neg(A));
+        output.op(CoreOp::Dec(A));
+        output.op(CoreOp::Call(A.deref()));
+        output.op(CoreOp::Else);
+        output.op(CoreOp::Call(A));
+        output.op(CoreOp::End);
+    }
+
+    /// Push the label of a closure which doesn't capture anything. It doesn't
+    /// need an environment record, so its value is a label like a procedure's.
+    fn push_label(&self, output: &mut dyn AssemblyProgram) {
+        output.op(CoreOp::SetLabel(A, self.mangled_name.clone()));
+        output.op(CoreOp::Push(A, 1));
+    }
+
+    /// Push the value of the closure whose environment record is at the address in `A`.
+    fn push_value(output: &mut dyn AssemblyProgram) {
+        output.op(CoreOp::Inc(A));
+        output.op(CoreOp::Neg(A));
+        output.op(CoreOp::Push(A, 1));
+    }
+
+    /// Collect the symbols the closure's body refers to, other than its arguments.
+    pub(crate) fn collect_symbols(&self, symbols: &mut BTreeSet<String>) {
+        let mut body_symbols = BTreeSet::new();
+        self.body.collect_symbols(&mut body_symbols);
+        for name in body_symbols {
+            if !self.args.iter().any(|(arg, _, _)| arg == &name) {
+                symbols.insert(name);
+            }
+        }
+    }
+
+    /// Does the closure have an argument with the given name?
+    pub(crate) fn has_arg(&self, name: &str) -> bool {
+        self.args.iter().any(|(arg, _, _)| arg == name)
+    }
+
+    /// Does the closure have arguments whose types must be inferred?
+    pub(crate) fn has_inferred_args(&self) -> bool {
+        self.args.iter().any(|(_, _, ty)| ty.is_type_var())
+    }
+
+    /// Get the type of the closure, with type variables for the types which must be inferred.
+    pub(crate) fn get_template_type(&self) -> Type {
+        Type::Proc(
+            self.args.iter().map(|(_, _, t)| t.clone()).collect(),
+            Box::new(self.ret.clone().unwrap_or_else(Type::fresh_var)),
+        )
+    }
+
+    /// Call a function with the closure's body, and an approximation of the scope it's
+    /// evaluated in, which is used to infer the types of the arguments. The arguments
+    /// whose types aren't known yet aren't defined in the scope.
+    pub(crate) fn visit_body(&self, f: impl FnOnce(&Expr, &Env), env: &Env) {
+        let mut new_env = env.clone();
+        for (name, mutability, ty) in &self.args {
+            if !ty.is_type_var() {
+                let _ = new_env.define_var(name, *mutability, ty.clone());
+            }
+        }
+        if let Some(ret) = &self.ret {
+            new_env.set_expected_return_type(ret.clone());
+        }
+        f(&self.body, &new_env)
+    }
+
+    /// Infer the types of the arguments which weren't given, from their uses in
+    /// the closure's body, and the given constraints on the closure's type.
+    pub(crate) fn infer_arg_types(
+        &self,
+        constraints: Vec<Constraint>,
+        env: &Env,
+    ) -> Result<Self, Error> {
+        // The uses in the body come first, so conflicting uses outside of the
+        // closure are reported against them.
+        let mut all_constraints = vec![];
+        for (name, _, ty) in &self.args {
+            if ty.is_type_var() {
+                self.visit_body(
+                    |body, scope| {
+                        all_constraints.extend(Constraint::collect(
+                            name,
+                            ty,
+                            body,
+                            self.ret.clone(),
+                            scope,
+                        ))
+                    },
+                    env,
+                );
+            }
+        }
+        all_constraints.extend(constraints);
+        let substitution = Constraint::solve(&all_constraints, env)?;
+
+        let mut result = self.clone();
+        for (name, _, ty) in &self.args {
+            if let Type::Symbol(var) = ty {
+                let inferred = substitution.apply(ty);
+                if inferred.has_type_vars() {
+                    // Point at the closure, rather than wherever it's first used.
+                    return Err(Error::CannotInferType(
+                        name.clone(),
+                        Box::new(Expr::Closure(self.clone())),
+                    )
+                    .annotate(self.get_annotation().clone()));
+                }
+                result.substitute(var, &inferred);
+            }
+        }
+        Ok(result)
+    }
+
+    /// Get the variables from the given scope which the closure's body refers to.
+    fn get_captures(&self, env: &Env) -> Vec<(String, Mutability, Type)> {
+        let mut symbols = BTreeSet::new();
+        self.collect_symbols(&mut symbols);
+        symbols
+            .into_iter()
+            .filter_map(|name| {
+                let (mutability, ty) = match (env.get_var(&name), env.get_captured_var(&name)) {
+                    (Some((mutability, ty, _)), _) | (None, Some((mutability, ty, _))) => {
+                        (*mutability, ty.clone())
+                    }
+                    (None, None) => return None,
+                };
+                Some((name, mutability, ty))
+            })
+            .collect()
+    }
+
+    /// Get the first variable this closure captures by reference, if any.
+    /// A closure which does can't outlive the scope the variable is declared in.
+    pub(crate) fn get_reference_capture(&self, env: &Env) -> Option<String> {
+        if self.capture == Capture::ByValue {
+            return None;
+        }
+        self.get_captures(env)
+            .into_iter()
+            .next()
+            .map(|(name, _, _)| name)
+    }
+
+    /// Get the error for this closure outliving the variable it captures by reference.
+    /// The error points at the first use of the variable in the closure's body.
+    pub(crate) fn escape_error(&self, capture: &str) -> Error {
+        Error::EscapingCapture(capture.to_string(), Box::new(Expr::Closure(self.clone())))
+            .with_note(format!(
+                "use a `move` closure to copy {capture} into the closure instead"
+            ))
+            .annotate(self.get_symbol_annotation(capture).clone())
+    }
+
+    /// Get the body of the closure.
+    pub fn get_body(&self) -> &Expr {
+        &self.body
+    }
+
+    /// Create the scope the closure's body is evaluated in: the arguments are defined
+    /// on the stack frame, and the captured variables are defined in the environment record.
+    /// The pointer to the environment record is stored in the first cell of the stack frame.
+    pub(crate) fn get_scope(&self, env: &Env) -> Result<Env, Error> {
+        let mut new_env = env.new_scope();
+        new_env.define_args(self.args.clone())?;
+        let record = new_env.define_var(
+            format!("#{}", self.mangled_name),
+            Mutability::Immutable,
+            Type::Pointer(Mutability::Any, Box::new(Type::Cell)),
+        )?;
+
+        // The first cell of the record holds the label.
+        let mut offset = 1;
+        for (name, mutability, ty) in self.get_captures(env) {
+            let location = FP.deref().offset(record).deref().offset(offset);
+            match self.capture {
+                Capture::ByValue => {
+                    new_env.define_captured_var(&name, mutability, ty.clone(), location);
+                    offset += ty.get_size(env)? as isize;
+                }
+                Capture::ByReference => {
+                    new_env.define_captured_var(&name, mutability, ty, location.deref());
+                    offset += 1;
+                }
+            }
+        }
+
+        let ret = match &self.ret {
+            Some(ret) => ret.clone(),
+            None => self.body.get_type(&new_env)?,
+        };
+        new_env.set_expected_return_type(ret);
+        Ok(new_env)
+    }
+
+    /// Get the return type of the closure.
+    fn get_ret(&self, env: &Env) -> Result<Type, Error> {
+        match &self.ret {
+            Some(ret) => Ok(ret.clone()),
+            None => {
+                let new_env = self.get_scope(env)?;
+                self.body.get_type(&new_env)
+            }
+        }
+    }
+
+    /// Compile the closure's body under its label, if it hasn't been compiled yet.
+    fn compile_body(&self, env: &Env, output: &mut dyn AssemblyProgram) -> Result<(), Error> {
+        if output.is_defined(&self.mangled_name) {
+            return Ok(());
+        }
+        let mut new_env = self.get_scope(env)?;
+        let args_size = new_env.get_args_size();
+        let ret_size = self.get_ret(env)?.get_size(env)?;
+
+        output.op(CoreOp::Fn(self.mangled_name.clone()));
+        output.name_function(&self.mangled_name, None);
+        // The caller leaves the pointer to the environment record in `A`.
+        output.op(CoreOp::Push(A, 1));
+        // Execute the body to leave the return value
+        self.body.clone().compile_expr(&mut new_env, output)?;
+        // Overwrite the arguments with the return value
+        output.op(CoreOp::Copy {
+            dst: FP.deref().offset(1 - args_size as isize),
+            src: SP.deref().offset(1 - ret_size as isize),
+            size: ret_size,
+        });
+        // Pop the arguments and the pointer to the environment record,
+        // to leave the return value on the stack.
+        output.op(CoreOp::Pop(None, args_size + 1));
+        output.op(CoreOp::End);
+        Ok(())
+    }
+
+    /// Push the closure's environment record onto the stack,
+    /// and return the size of the record.
+    fn push_record(&self, env: &mut Env, output: &mut dyn AssemblyProgram) -> Result<usize, Error> {
+        output.op(CoreOp::SetLabel(A, self.mangled_name.clone()));
+        output.op(CoreOp::Push(A, 1));
+        let mut size = 1;
+        for (name, mutability, _) in self.get_captures(env) {
+            let var = Expr::var(&name);
+            match self.capture {
+                Capture::ByValue => {
+                    size += var.get_size(env)?;
+                    var.compile_expr(env, output)?;
+                }
+                Capture::ByReference => {
+                    let mutability = if mutability.is_mutable() {
+                        Mutability::Mutable
+                    } else {
+                        Mutability::Immutable
+                    };
+                    size += 1;
+                    var.refer(mutability).compile_expr(env, output)?;
+                }
+            }
+        }
+        Ok(size)
+    }
+
+    /// Compile the closure with its environment record allocated on the stack,
+    /// directly below the value pointing to it. This returns the size of the record.
+    pub(crate) fn compile_on_stack(
+        self,
+        env: &mut Env,
+        output: &mut dyn AssemblyProgram,
+    ) -> Result<usize, Error> {
+        self.compile_body(env, output)?;
+        if self.get_captures(env).is_empty() {
+            self.push_label(output);
+            return Ok(0);
+        }
+        let size = self.push_record(env, output)?;
+        // Push the value pointing to the record.
+        output.op(CoreOp::GetAddress {
+            addr: SP.deref().offset(1 - size as isize),
+            dst: A,
+        });
+        Self::push_value(output);
+        Ok(size)
+    }
+}
+
+impl TypeCheck for Closure {
+    fn type_check(&self, env: &Env) -> Result<(), Error> {
+        trace!("type checking closure: {}", self);
+        // Typecheck the types of the arguments and return value
+        for (_, _, t) in &self.args {
+            t.type_check(env)?;
+        }
+        if let Some(ret) = &self.ret {
+            ret.type_check(env)?;
+        }
+
+        // Get the type of the closure's body, and confirm that it matches the return type.
+        let new_env = self.get_scope(env)?;
+        let ret = self.get_ret(env)?;
+        let body_type = self.body.get_type(&new_env)?;
+        if !body_type.can_decay_to(&ret, env)? {
+            Err(Error::MismatchedTypes {
+                expected: ret,
+                found: body_type,
+                expr: Box::new(Expr::Closure(self.clone())),
+            })
+        } else {
+            // Typecheck the closure's body.
+            self.body.type_check(&new_env)?;
+            // The value of the body is returned, so it must be able to outlive the body.
+            self.body.check_escape(&new_env)
+        }
+    }
+}
+
+impl GetType for Closure {
+    fn get_type_checked(&self, env: &Env, _i: usize) -> Result<Type, Error> {
+        Ok(Type::Proc(
+            self.args.iter().map(|(_, _, t)| t.clone()).collect(),
+            Box::new(self.get_ret(env)?),
+        ))
+    }
+
+    fn substitute(&mut self, name: &str, ty: &Type) {
+        for (_, _, t) in &mut self.args {
+            *t = t.substitute(name, ty);
+        }
+        if let Some(ret) = &mut self.ret {
+            *ret = ret.substitute(name, ty);
+        }
+        self.body.substitute(name, ty);
+    }
+}
+
+impl Compile for Closure {
+    /// Compile a closure which may escape the scope it's created in.
+    /// Its record is allocated on the heap, and is never freed.
+    fn compile_expr(self, env: &mut Env, output: &mut dyn AssemblyProgram) -> Result<(), Error> {
+        self.compile_body(env, output)?;
+        if self.get_captures(env).is_empty() {
+            self.push_label(output);
+            return Ok(());
+        }
+        let size = self.push_record(env, output)?;
+
+        // Move the record to the heap, and push the value pointing to it.
+        // Escaping closures need the heap, so they're unsupported by the core variant.
+        output.op(CoreOp::Set(A, size as i64));
+        output
+            .std_op(StandardOp::Alloc(A))
+            .map_err(|_| Error::UnsupportedOperation(Box::new(Expr::Closure(self.clone()))))?;
+        output.op(CoreOp::Copy {
+            dst: A.deref(),
+            src: SP.deref().offset(1 - size as isize),
+            size,
+        });
+        output.op(CoreOp::Pop(None, size));
+        Self::push_value(output);
+        Ok(())
+    }
+}
+
+impl fmt::Display for Closure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.capture == Capture::ByValue {
+            write!(f, "move ")?;
+        }
+        write!(f, "|")?;
+        for (i, (name, mutability, ty)) in self.args.iter().enumerate() {
+            if mutability.is_mutable() {
+                write!(f, "mut ")?;
+            }
+            if ty.is_type_var() {
+                write!(f, "{name}")?;
+            } else {
+                write!(f, "{name}: {ty}")?;
+            }
+            if i < self.args.len() - 1 {
+                write!(f, ", ")?
+            }
+        }
+        write!(f, "|")?;
+        if let Some(ret) = &self.ret {
+            write!(f, " -> {ret}")?;
+        }
+        write!(f, " ")?;
+        // The body is displayed in the same format, so the alternate format spreads it over several lines.
+        fmt::Display::fmt(&self.body, f)
+    }
+}
+
+impl Eq for Closure {}
+
+impl Hash for Closure {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.mangled_name.hash(state);
+    }
+}
+
+impl From<Closure> for Expr {
+    fn from(closure: Closure) -> Self {
+        Self::Closure(closure)
+    }
+}
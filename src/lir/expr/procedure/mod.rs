@@ -15,12 +15,36 @@
 //!
 //! Polymorphic procedures take a list of type arguments, and produce a monomorphized
 //! version of the procedure. This can then be compiled directly to assembly.
+//!
+//! ## Closure
+//!
+//! A procedure which captures variables from the scope it is created in,
+//! either by value or by reference.
 mod builtin;
+mod closure;
 mod ffi;
 mod mono;
 mod poly;
 
 pub use builtin::*;
+pub use closure::*;
 pub use ffi::*;
 pub use mono::*;
 pub use poly::*;
+
+use crate::lir::{Annotation, Error, Expr};
+
+/// Point an error about the type of a procedure's body at the body, if we know
+/// where it is, with a label at the procedure's signature. Otherwise, point at the signature.
+fn annotate_body_error(err: Error, body: &Expr, signature: &Annotation) -> Error {
+    match body {
+        Expr::Annotated(_, annotation) if annotation.has_location() => err
+            .with_label(
+                signature,
+                "expected because of the return type of this procedure",
+            )
+            .annotate(annotation.clone()),
+        _ if signature.has_location() => err.annotate(signature.clone()),
+        _ => err,
+    }
+}
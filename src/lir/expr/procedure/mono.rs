@@ -10,7 +10,7 @@
 //! Procedures are created by the `proc` keyword.
 use crate::asm::{AssemblyProgram, CoreOp, A, FP, SP};
 use crate::lir::{
-    Compile, ConstExpr, Env, Error, Expr, GetSize, GetType, Mutability, Type, TypeCheck,
+    Annotation, Compile, ConstExpr, Env, Error, Expr, GetSize, GetType, Mutability, Type, TypeCheck,
 };
 use core::fmt;
 use std::hash::Hash;
@@ -43,6 +43,10 @@ pub struct Procedure {
     ret: Type,
     /// The procedure's body expression
     body: Box<Expr>,
+    /// The locations of the procedure's declaration and of each of its parameters
+    /// in the source code, if any. Errors about its return type point at the declaration,
+    /// and errors about the arguments passed to it point at their parameters.
+    annotations: Rc<(Annotation, Vec<Annotation>)>,
     has_type_checked: Rc<RwLock<bool>>,
 }
 
@@ -73,10 +77,42 @@ impl Procedure {
             args,
             ret,
             body: Box::new(body.into()),
+            annotations: Rc::new((Annotation::None, vec![])),
             has_type_checked: Rc::new(RwLock::new(false)),
         }
     }
 
+    /// Annotate the procedure with the location of its declaration.
+    pub fn with_annotation(mut self, annotation: Annotation) -> Self {
+        Rc::make_mut(&mut self.annotations).0 = annotation;
+        self
+    }
+
+    /// Annotate the procedure's parameters with their locations.
+    pub fn with_param_annotations(mut self, annotations: Vec<Annotation>) -> Self {
+        Rc::make_mut(&mut self.annotations).1 = annotations;
+        self
+    }
+
+    /// Get the annotation of the procedure's declaration.
+    pub fn get_annotation(&self) -> &Annotation {
+        &self.annotations.0
+    }
+
+    /// Get the annotation of one of the procedure's parameters.
+    /// If the parameter has no location, this is the annotation of the declaration.
+    pub fn get_param_annotation(&self, i: usize) -> &Annotation {
+        match self.annotations.1.get(i) {
+            Some(annotation) if annotation.has_location() => annotation,
+            _ => &self.annotations.0,
+        }
+    }
+
+    /// Get the annotations of the procedure's parameters.
+    pub(crate) fn get_param_annotations(&self) -> &[Annotation] {
+        &self.annotations.1
+    }
+
     /// Get the arguments of the procedure.
     pub fn get_args(&self) -> &[(String, Mutability, Type)] {
         &self.args
@@ -92,6 +128,16 @@ impl Procedure {
         &self.body
     }
 
+    /// Create the scope the procedure's body is type checked in,
+    /// with the arguments and the expected return type defined.
+    pub(crate) fn get_scope(&self, env: &Env) -> Result<Env, Error> {
+        let mut new_env = env.new_scope();
+        new_env.define_args(self.args.clone())?;
+        new_env.set_expected_return_type(self.ret.clone());
+        new_env.set_return_annotation(self.annotations.0.clone());
+        Ok(new_env)
+    }
+
     /// Get the mangled name of the procedure.
     /// The procedure's mangled name is used to store the procedure in the environment.
     pub fn get_mangled_name(&self) -> &str {
@@ -117,6 +163,62 @@ impl Procedure {
         // Push the register to the stack.
         output.op(CoreOp::Push(A, 1));
     }
+
+    /// Compile the procedure's body under its label, so that it can be called.
+    /// Procedures are only compiled once, so this does nothing if the label is already defined.
+    pub fn define_label(self, env: &Env, output: &mut dyn AssemblyProgram) -> Result<(), Error> {
+        // Check to see if the procedure has already been defined
+        if output.is_defined(&self.mangled_name) {
+            // If the procedure has already been defined, then we don't need to
+            // compile it again.
+            return Ok(());
+        }
+
+        // Compile the contents of the procedure under a new environment
+        let mut new_env = env.new_scope();
+
+        // Declare the arguments and get their size
+        let args_size = new_env.define_args(self.args)?;
+        new_env.set_expected_return_type(self.ret.clone());
+        new_env.set_return_annotation(self.annotations.0.clone());
+        // Get the size of the return value to leave on the stack
+        let ret_size = self.ret.get_size(env)?;
+
+        // Declare the function body
+        output.op(CoreOp::Fn(self.mangled_name.clone()));
+        output.name_function(&self.mangled_name, self.common_name.as_deref());
+        if let Some(common_name) = &self.common_name {
+            output.comment(format!("{}({})", common_name, args_size));
+        }
+        let current_instruction = output.current_instruction();
+
+        // Execute the body to leave the return value
+        self.body.compile_expr(&mut new_env, output)?;
+
+        // Overwrite the arguments with the return value
+        output.op(CoreOp::Copy {
+            dst: FP.deref().offset(1 - args_size as isize),
+            src: SP.deref().offset(1 - ret_size as isize),
+            size: ret_size,
+        });
+        // Decrement the stack pointer by the difference between the size of the
+        // arguments and return value, to leave the return value on the stack.
+        output.op(CoreOp::Pop(None, args_size));
+        // End the function body
+        output.op(CoreOp::End);
+
+        let name = self.common_name.as_deref().unwrap_or("<anonymous>");
+        // Log the compiled procedure
+        let message = format!("Compiled procedure {common_name} to {mangled_name} with args of size {args_size} and return value of size {ret_size}",
+            common_name = name,
+            mangled_name = self.mangled_name,
+            args_size = args_size,
+            ret_size = ret_size,
+        );
+        output.log_instructions_after(name, &message, current_instruction);
+
+        Ok(())
+    }
 }
 
 impl TypeCheck for Procedure {
@@ -138,22 +240,28 @@ impl TypeCheck for Procedure {
         // self.ret.simplify_until_simple(env)?.add_monomorphized_associated_consts(env)?;
         self.ret.type_check(env)?;
 
-        // Create a new scope for the procedure's body, and define the arguments for the scope.
-        let mut new_env = env.new_scope();
-        new_env.define_args(self.args.clone())?;
-        new_env.set_expected_return_type(self.ret.clone());
+        // Create a new scope for the procedure's body.
+        let new_env = self.get_scope(env)?;
 
         // Get the type of the procedure's body, and confirm that it matches the return type.
         let body_type = self.body.get_type(&new_env)?;
         if !body_type.can_decay_to(&self.ret, env)? {
-            Err(Error::MismatchedTypes {
+            let err = Error::MismatchedTypes {
                 expected: self.ret.clone(),
                 found: body_type,
-                expr: ConstExpr::Proc(self.clone()).into(),
-            })
+                expr: Box::new(ConstExpr::Proc(self.clone()).into()),
+            }
+            .with_suggestion();
+            Err(super::annotate_body_error(
+                err,
+                &self.body,
+                &self.annotations.0,
+            ))
         } else {
             // Typecheck the procedure's body.
-            self.body.type_check(&new_env)
+            self.body.type_check(&new_env)?;
+            // The value of the body is returned, so it must be able to outlive the body.
+            self.body.check_escape(&new_env)
         }
     }
 }
@@ -178,61 +286,13 @@ impl GetType for Procedure {
 
 impl Compile for Procedure {
     fn compile_expr(self, env: &mut Env, output: &mut dyn AssemblyProgram) -> Result<(), Error> {
-        // Compile the contents of the procedure under a new environment
-        let mut new_env = env.new_scope();
-
-        // Declare the arguments and get their size
-        let args_size = new_env.define_args(self.args)?;
-        // Get the size of the return value to leave on the stack
-        let ret_size = self.ret.get_size(env)?;
-
-        // Check to see if the procedure has already been defined
-        if output.is_defined(&self.mangled_name) {
-            // If the procedure has already been defined, then we don't need to
-            // compile it again.
-            // Push the procedure label address onto the stack
-            output.op(CoreOp::Next(SP, None));
-            output.op(CoreOp::SetLabel(SP.deref(), self.mangled_name));
-            return Ok(());
-        }
-
-        // Declare the function body
-        output.op(CoreOp::Fn(self.mangled_name.clone()));
-        if let Some(common_name) = &self.common_name {
-            output.comment(format!("{}({})", common_name, args_size));
-        }
-        let current_instruction = output.current_instruction();
-
-        // Execute the body to leave the return value
-        self.body.compile_expr(&mut new_env, output)?;
-
-        // Overwrite the arguments with the return value
-        output.op(CoreOp::Copy {
-            dst: FP.deref().offset(1 - args_size as isize),
-            src: SP.deref().offset(1 - ret_size as isize),
-            size: ret_size,
-        });
-        // Decrement the stack pointer by the difference between the size of the
-        // arguments and return value, to leave the return value on the stack.
-        output.op(CoreOp::Pop(None, args_size));
-        // End the function body
-        output.op(CoreOp::End);
-
-        output.comment(format!("push {} onto the stack", self.mangled_name));
+        let mangled_name = self.mangled_name.clone();
+        // Compile the procedure's label, if it hasn't been compiled yet.
+        self.define_label(env, output)?;
+        output.comment(format!("push {mangled_name} onto the stack"));
         // Push the procedure label address onto the stack
-        output.op(CoreOp::SetLabel(A, self.mangled_name.clone()));
+        output.op(CoreOp::SetLabel(A, mangled_name));
         output.op(CoreOp::Push(A, 1));
-
-        let name = self.common_name.as_deref().unwrap_or("<anonymous>");
-        // Log the compiled procedure
-        let message = format!("Compiled procedure {common_name} to {mangled_name} with args of size {args_size} and return value of size {ret_size}",
-            common_name = name,
-            mangled_name = self.mangled_name,
-            args_size = args_size,
-            ret_size = ret_size,
-        );
-        output.log_instructions_after(name, &message, current_instruction);
-
         Ok(())
     }
 }
@@ -249,7 +309,9 @@ impl fmt::Display for Procedure {
                 write!(f, ", ")?
             }
         }
-        write!(f, ") -> {} = {}", self.ret, self.body)
+        write!(f, ") -> {} = ", self.ret)?;
+        // The body is displayed in the same format, so the alternate format spreads it over several lines.
+        fmt::Display::fmt(&self.body, f)
     }
 }
 
@@ -3,7 +3,7 @@
 //! A polymorphic procedure of LIR code which can be applied to a list of arguments with type arguments.
 //! This is mono-morphed into a `Procedure` when it is called with a list of type arguments.
 //! A procedure is compiled down to a label in the assembly code.
-use crate::lir::{ConstExpr, Env, Error, Expr, GetType, Mutability, Type, TypeCheck};
+use crate::lir::{Annotation, ConstExpr, Env, Error, Expr, GetType, Mutability, Type, TypeCheck};
 use core::fmt;
 use log::{debug, error, trace};
 use std::{collections::HashMap, rc::Rc, sync::RwLock};
@@ -20,6 +20,9 @@ pub struct PolyProcedure {
     name: String,
     /// The type parameters of the procedure.
     ty_params: Vec<String>,
+    /// The traits the type parameters are bounded by.
+    /// Each type argument must implement the traits its parameter is bounded by.
+    bounds: Vec<(String, String)>,
     /// The arguments of the procedure.
     args: Vec<(String, Mutability, Type)>,
     /// The return type of the procedure.
@@ -28,6 +31,9 @@ pub struct PolyProcedure {
     body: Box<Expr>,
     /// The monomorphs of the procedure.
     monomorphs: Rc<RwLock<HashMap<String, Procedure>>>,
+    /// The locations of the procedure's declaration and of each of its parameters
+    /// in the source code, if any.
+    annotations: Rc<(Annotation, Vec<Annotation>)>,
     has_type_checked: Rc<RwLock<bool>>,
 }
 
@@ -35,6 +41,7 @@ impl PartialEq for PolyProcedure {
     fn eq(&self, other: &Self) -> bool {
         self.name == other.name
             && self.ty_params == other.ty_params
+            && self.bounds == other.bounds
             && self.args == other.args
             && self.ret == other.ret
             && self.body == other.body
@@ -54,10 +61,12 @@ impl PolyProcedure {
         Self {
             name,
             ty_params,
+            bounds: vec![],
             args,
             ret,
             body: Box::new(body.into()),
             monomorphs: Rc::new(RwLock::new(HashMap::new())),
+            annotations: Rc::new((Annotation::None, vec![])),
             has_type_checked: Rc::new(RwLock::new(false)),
         }
     }
@@ -72,14 +81,111 @@ impl PolyProcedure {
         Self {
             name,
             ty_params,
+            bounds: vec![],
             args: mono.get_args().to_vec(),
             ret: mono.get_ret().clone(),
             body: mono.get_body().clone().into(),
             monomorphs: Rc::new(RwLock::new(HashMap::new())),
+            annotations: Rc::new((
+                mono.get_annotation().clone(),
+                mono.get_param_annotations().to_vec(),
+            )),
             has_type_checked: Rc::new(RwLock::new(false)),
         }
     }
 
+    /// Bound the type parameters of the procedure by some traits.
+    /// Each bound is a pair of a type parameter and the name of a trait it must implement.
+    pub fn with_bounds(mut self, bounds: Vec<(String, String)>) -> Self {
+        self.bounds = bounds;
+        self
+    }
+
+    /// Annotate the procedure with the location of its declaration.
+    /// Its monomorphs are annotated with the same location.
+    pub fn with_annotation(mut self, annotation: Annotation) -> Self {
+        Rc::make_mut(&mut self.annotations).0 = annotation;
+        self
+    }
+
+    /// Annotate the procedure's parameters with their locations.
+    pub fn with_param_annotations(mut self, annotations: Vec<Annotation>) -> Self {
+        Rc::make_mut(&mut self.annotations).1 = annotations;
+        self
+    }
+
+    /// Get the body of the procedure.
+    pub fn get_body(&self) -> &Expr {
+        &self.body
+    }
+
+    /// Create the scope the procedure's body is type checked in. The type parameters are
+    /// defined as opaque types which implement the methods of their bounds, and the
+    /// arguments and the expected return type are defined.
+    pub(crate) fn get_scope(&self, env: &Env) -> Result<Env, Error> {
+        let mut new_env = env.new_scope();
+        // Define the type parameters of the procedure.
+        new_env.define_types(
+            self.ty_params
+                .clone()
+                .into_iter()
+                .map(|ty_param| (ty_param.clone(), Type::Unit(ty_param, Box::new(Type::None))))
+                .collect(),
+        );
+        // Each bounded type parameter implements the methods of its traits.
+        // The body is only type checked against the signatures of the methods here;
+        // the implementations are resolved when the procedure is monomorphized.
+        for (ty_param, trait_name) in &self.bounds {
+            let methods = env
+                .get_trait(trait_name)
+                .ok_or_else(|| {
+                    // Point at the signature which declares the bound.
+                    Error::TraitNotDefined(trait_name.clone()).annotate(self.annotations.0.clone())
+                })?
+                .clone();
+            let ty = Type::Unit(ty_param.clone(), Box::new(Type::None));
+            for (method, signature) in methods {
+                new_env.define_trait_method(ty.clone(), method, signature.substitute("Self", &ty));
+            }
+            new_env.define_trait_bound(trait_name, ty);
+        }
+        // Define the arguments of the procedure.
+        new_env.define_args(self.args.clone())?;
+        new_env.set_expected_return_type(self.ret.clone());
+        new_env.set_return_annotation(self.annotations.0.clone());
+        Ok(new_env)
+    }
+
+    /// Confirm that the type arguments supplied to this procedure
+    /// implement all the traits their parameters are bounded by.
+    pub fn check_bounds(&self, ty_args: &[Type], env: &Env) -> Result<(), Error> {
+        for (param, trait_name) in &self.bounds {
+            // An unknown trait is reported where the procedure is declared.
+            if env.get_trait(trait_name).is_none() {
+                continue;
+            }
+            let Some(ty_arg) = self
+                .ty_params
+                .iter()
+                .position(|ty_param| ty_param == param)
+                .and_then(|i| ty_args.get(i))
+            else {
+                continue;
+            };
+
+            if !env.implements_trait(&ty_arg.simplify_until_concrete(env)?, trait_name)? {
+                return Err(Error::UnsatisfiedBound {
+                    ty: ty_arg.clone(),
+                    trait_name: trait_name.clone(),
+                    expr: Box::new(
+                        ConstExpr::Symbol(self.name.clone()).monomorphize(ty_args.to_vec()),
+                    ),
+                });
+            }
+        }
+        Ok(())
+    }
+
     /// Get the name of this polymorphic procedure.
     /// This is not the mangled name, but the name known to the LIR front-end.
     /// The mangled name is unique for each monomorph of the procedure.
@@ -169,6 +275,8 @@ impl PolyProcedure {
                 );
 
                 Procedure::new(Some(mangled_name.clone()), args, ret, body)
+                    .with_annotation(self.annotations.0.clone())
+                    .with_param_annotations(self.annotations.1.clone())
             })
             .clone();
 
@@ -209,19 +317,8 @@ impl TypeCheck for PolyProcedure {
 
         *self.has_type_checked.write().unwrap() = true;
         trace!("Type checking {self}");
-        // Create a new scope for the procedure's body, and define the arguments for the scope.
-        let mut new_env = env.new_scope();
-        // Define the type parameters of the procedure.
-        new_env.define_types(
-            self.ty_params
-                .clone()
-                .into_iter()
-                .map(|ty_param| (ty_param.clone(), Type::Unit(ty_param, Box::new(Type::None))))
-                .collect(),
-        );
-        // Define the arguments of the procedure.
-        new_env.define_args(self.args.clone())?;
-        new_env.set_expected_return_type(self.ret.clone());
+        // Create a new scope for the procedure's body.
+        let new_env = self.get_scope(env)?;
 
         // Typecheck the types of the arguments and return value
         for (_, _, t) in &self.args {
@@ -238,14 +335,22 @@ impl TypeCheck for PolyProcedure {
                 self.ret, body_type
             );
 
-            Err(Error::MismatchedTypes {
+            let err = Error::MismatchedTypes {
                 expected: self.ret.clone(),
                 found: body_type,
-                expr: ConstExpr::PolyProc(self.clone()).into(),
-            })
+                expr: Box::new(ConstExpr::PolyProc(self.clone()).into()),
+            }
+            .with_suggestion();
+            Err(super::annotate_body_error(
+                err,
+                &self.body,
+                &self.annotations.0,
+            ))
         } else {
             // Typecheck the procedure's body.
-            self.body.type_check(&new_env)
+            self.body.type_check(&new_env)?;
+            // The value of the body is returned, so it must be able to outlive the body.
+            self.body.check_escape(&new_env)
         }
     }
 }
@@ -255,6 +360,14 @@ impl fmt::Display for PolyProcedure {
         write!(f, "proc[")?;
         for (i, ty_param) in self.ty_params.iter().enumerate() {
             write!(f, "{}", ty_param)?;
+            for (j, (_, trait_name)) in self
+                .bounds
+                .iter()
+                .filter(|(param, _)| param == ty_param)
+                .enumerate()
+            {
+                write!(f, "{}{}", if j == 0 { ": " } else { " + " }, trait_name)?;
+            }
             if i < self.ty_params.len() - 1 {
                 write!(f, ", ")?;
             }
@@ -269,7 +382,9 @@ impl fmt::Display for PolyProcedure {
                 write!(f, ", ")?
             }
         }
-        write!(f, ") -> {} = {}", self.ret, self.body)
+        write!(f, ") -> {} = ", self.ret)?;
+        // The body is displayed in the same format, so the alternate format spreads it over several lines.
+        fmt::Display::fmt(&self.body, f)
     }
 }
 
@@ -279,6 +394,7 @@ impl Hash for PolyProcedure {
     fn hash<H: Hasher>(&self, state: &mut H) {
         self.name.hash(state);
         self.ty_params.hash(state);
+        self.bounds.hash(state);
         self.args.hash(state);
         self.ret.hash(state);
         self.body.hash(state);
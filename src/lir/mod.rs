@@ -10,7 +10,7 @@
 //! 1. [Types](./enum.Type.html)
 //! 2. [Constants](./enum.ConstExpr.html) and [Expressions](./enum.Expr.html)
 //! 3. [Core Builtins](struct.CoreBuiltin.html) and [Standard Builtins](struct.StandardBuiltin.html)
-//! 4. [Compilation Errors](./enum.Error.html)
+//! 4. [Compilation Errors](./enum.Error.html) and [Warnings](./enum.Warning.html)
 
 //! ## Purpose
 //!
@@ -70,14 +70,18 @@ mod compile;
 mod env;
 mod error;
 mod expr;
+mod query;
 mod types;
+mod warning;
 
 pub use annotate::*;
 pub use compile::*;
 pub use env::*;
 pub use error::*;
 pub use expr::*;
+pub use query::*;
 pub use types::*;
+pub use warning::*;
 
 /// Simplify an expression while maintaining structural equality.
 pub trait Simplify: Sized {
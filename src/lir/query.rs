@@ -0,0 +1,326 @@
+//! # Queries
+//!
+//! This module answers questions about a program for interactive tools, like the language server:
+//! which scope a point in the source code is in, the type of a symbol in that scope, and the
+//! members of a value of a given type.
+//!
+//! The queries are error-tolerant: declarations which fail to type check are skipped,
+//! so they still give useful answers about the rest of an ill-typed program.
+
+use super::{ConstExpr, Declaration, Env, Expr, GetType, Type};
+use crate::parse::SourceCodeLocation;
+
+/// An annotated expression in a program, and the environment it is type checked in.
+#[derive(Clone, Debug)]
+pub struct Scope {
+    /// The expression.
+    pub expr: Expr,
+    /// The location of the expression in the source code.
+    pub location: SourceCodeLocation,
+    /// The environment the expression is type checked in.
+    pub env: Env,
+}
+
+impl Scope {
+    /// Get the type of a variable, constant, or procedure visible in this scope,
+    /// including the ones declared by the expression itself.
+    pub fn get_symbol_type(&self, name: &str) -> Option<Type> {
+        if let Some(ty) = self.env.get_symbol_type(name) {
+            return Some(ty);
+        }
+        let mut expr = &self.expr;
+        while let Expr::Annotated(inner, _) = expr {
+            expr = inner;
+        }
+        match expr {
+            Expr::Declare(declaration, _) => {
+                let mut env = self.env.clone();
+                let _ = env.add_declaration(declaration);
+                env.get_symbol_type(name)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Expr {
+    /// Find the innermost annotated expression containing the given offset into a file,
+    /// and the environment it is type checked in.
+    pub fn scope_at(&self, filename: Option<&str>, offset: usize) -> Option<Scope> {
+        let mut result = None;
+        self.find_scope(filename, offset, &Env::default(), &mut result);
+        result
+    }
+
+    /// Find the last statement of a program checked in the given environment, and the
+    /// environment it's type checked in, which has every declaration in scope at the end
+    /// of the program.
+    pub fn last_statement(&self, env: &Env) -> (&Self, Env) {
+        let mut env = env.clone();
+        let mut expr = self;
+        loop {
+            match expr {
+                Self::Annotated(inner, _) => expr = inner,
+                Self::Declare(declaration, body) => {
+                    // If the declaration is ill-typed, keep going with what could be declared.
+                    let _ = env.add_declaration(declaration);
+                    expr = body;
+                }
+                // A program ends with an empty statement, which doesn't count.
+                Self::Many(exprs) => match exprs.as_slice() {
+                    [.., last, Self::ConstExpr(ConstExpr::None)] => return (last, env),
+                    [.., last] => expr = last,
+                    [] => return (expr, env),
+                },
+                _ => return (expr, env),
+            }
+        }
+    }
+
+    /// Search this expression for the innermost annotated expression containing the offset.
+    fn find_scope(
+        &self,
+        filename: Option<&str>,
+        offset: usize,
+        env: &Env,
+        result: &mut Option<Scope>,
+    ) {
+        match self {
+            Self::Annotated(expr, annotation) => {
+                if let Some(loc) = annotation.location() {
+                    let length = loc.length.unwrap_or(0);
+                    let contains = loc.filename.as_deref() == filename
+                        && loc.offset <= offset
+                        && offset <= loc.offset + length;
+                    // Prefer the smallest expression containing the offset.
+                    let is_smaller = result
+                        .as_ref()
+                        .map(|scope| length <= scope.location.length.unwrap_or(0))
+                        .unwrap_or(true);
+                    if contains && is_smaller {
+                        *result = Some(Scope {
+                            expr: *expr.clone(),
+                            location: loc.clone(),
+                            env: env.clone(),
+                        });
+                    }
+                }
+                expr.find_scope(filename, offset, env, result)
+            }
+            Self::Declare(declaration, body) => {
+                let mut new_env = env.clone();
+                // If the declaration is ill-typed, keep going with what could be declared.
+                let _ = new_env.add_declaration(declaration);
+                declaration.find_scope(filename, offset, &new_env, result);
+                body.find_scope(filename, offset, &new_env, result)
+            }
+            Self::ConstExpr(expr) => expr.find_scope(filename, offset, env, result),
+            Self::Closure(closure) => {
+                if let Ok(new_env) = closure.get_scope(env) {
+                    closure
+                        .get_body()
+                        .find_scope(filename, offset, &new_env, result)
+                }
+            }
+            Self::ForIn(pat, iterable, body) => {
+                match Self::desugar_for_in(pat, iterable, body, None, env) {
+                    Ok(expr) => expr.find_scope(filename, offset, env, result),
+                    Err(_) => {
+                        iterable.find_scope(filename, offset, env, result);
+                        body.find_scope(filename, offset, env, result)
+                    }
+                }
+            }
+            Self::Match(expr, branches) => {
+                expr.find_scope(filename, offset, env, result);
+                let ty = expr.get_type(env).unwrap_or(Type::Any);
+                for (pat, branch) in branches {
+                    // Define the variables bound by the pattern in the branch.
+                    let mut new_env = env.clone();
+                    let _ = pat.declare_let_bind(expr, &ty, &mut new_env);
+                    branch.find_scope(filename, offset, &new_env, result)
+                }
+            }
+            Self::IfLet(pat, expr, then, otherwise) => {
+                expr.find_scope(filename, offset, env, result);
+                let ty = expr.get_type(env).unwrap_or(Type::Any);
+                let mut new_env = env.clone();
+                let _ = pat.declare_let_bind(expr, &ty, &mut new_env);
+                then.find_scope(filename, offset, &new_env, result);
+                otherwise.find_scope(filename, offset, env, result)
+            }
+
+            Self::Many(exprs) | Self::Array(exprs) | Self::Tuple(exprs) => {
+                for expr in exprs {
+                    expr.find_scope(filename, offset, env, result)
+                }
+            }
+            Self::Struct(fields) => {
+                for expr in fields.values() {
+                    expr.find_scope(filename, offset, env, result)
+                }
+            }
+            Self::Apply(f, args) => {
+                f.find_scope(filename, offset, env, result);
+                for arg in args {
+                    arg.find_scope(filename, offset, env, result)
+                }
+            }
+            Self::If(cond, then, otherwise) | Self::TernaryOp(_, cond, then, otherwise) => {
+                cond.find_scope(filename, offset, env, result);
+                then.find_scope(filename, offset, env, result);
+                otherwise.find_scope(filename, offset, env, result)
+            }
+            Self::While(a, b)
+            | Self::When(_, a, b)
+            | Self::BinaryOp(_, a, b)
+            | Self::AssignOp(_, a, b)
+            | Self::DerefMut(a, b)
+            | Self::Index(a, b) => {
+                a.find_scope(filename, offset, env, result);
+                b.find_scope(filename, offset, env, result)
+            }
+            Self::Labeled(_, expr)
+            | Self::UnaryOp(_, expr)
+            | Self::Refer(_, expr)
+            | Self::Deref(expr)
+            | Self::Return(expr)
+            | Self::Try(expr)
+            | Self::Union(_, _, expr)
+            | Self::EnumUnion(_, _, expr)
+            | Self::As(expr, _)
+            | Self::Member(expr, _) => expr.find_scope(filename, offset, env, result),
+            Self::Break(_) | Self::Continue(_) => {}
+        }
+    }
+}
+
+impl ConstExpr {
+    /// Search the bodies of the procedures in this constant for the offset.
+    fn find_scope(
+        &self,
+        filename: Option<&str>,
+        offset: usize,
+        env: &Env,
+        result: &mut Option<Scope>,
+    ) {
+        match self {
+            Self::Annotated(expr, _) => expr.find_scope(filename, offset, env, result),
+            Self::Proc(proc) => {
+                if let Ok(new_env) = proc.get_scope(env) {
+                    proc.get_body()
+                        .find_scope(filename, offset, &new_env, result)
+                }
+            }
+            Self::PolyProc(proc) => {
+                if let Ok(new_env) = proc.get_scope(env) {
+                    proc.get_body()
+                        .find_scope(filename, offset, &new_env, result)
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Declaration {
+    /// Search the expressions in this declaration for the offset.
+    /// The environment already contains the declaration.
+    fn find_scope(
+        &self,
+        filename: Option<&str>,
+        offset: usize,
+        env: &Env,
+        result: &mut Option<Scope>,
+    ) {
+        match self {
+            Self::Var(_, _, _, expr) | Self::VarPat(_, expr) => {
+                expr.find_scope(filename, offset, env, result)
+            }
+            Self::Proc(_, proc) => {
+                ConstExpr::Proc(proc.clone()).find_scope(filename, offset, env, result)
+            }
+            Self::PolyProc(_, proc) => {
+                ConstExpr::PolyProc(proc.clone()).find_scope(filename, offset, env, result)
+            }
+            Self::Const(_, expr) => expr.find_scope(filename, offset, env, result),
+            Self::Impl(_, consts) | Self::ImplTrait(_, _, consts, _) => {
+                for (_, expr) in consts {
+                    expr.find_scope(filename, offset, env, result)
+                }
+            }
+            Self::Many(declarations) => {
+                for declaration in declarations.iter() {
+                    declaration.find_scope(filename, offset, env, result)
+                }
+            }
+            Self::StaticVar(..) | Self::Type(..) | Self::ExternProc(..) | Self::Trait(..) => {}
+        }
+    }
+}
+
+impl Env {
+    /// Get the type of a variable, constant, or procedure defined in this environment.
+    pub fn get_symbol_type(&self, name: &str) -> Option<Type> {
+        if self.get_var(name).is_none()
+            && self.get_static_var(name).is_none()
+            && self.get_const(name).is_none()
+            && self.get_proc(name).is_none()
+        {
+            return None;
+        }
+        Expr::ConstExpr(ConstExpr::Symbol(name.to_string()))
+            .get_type(self)
+            .ok()
+    }
+
+    /// Get the type defined under the given name in this environment.
+    pub fn get_type_definition(&self, name: &str) -> Option<&Type> {
+        self.get_type(name)
+    }
+
+    /// Get the location of the declaration of a procedure defined in this environment.
+    pub fn get_proc_location(&self, name: &str) -> Option<&SourceCodeLocation> {
+        match self.get_const(name) {
+            Some(ConstExpr::Proc(proc)) => proc.get_annotation().location(),
+            _ => self
+                .get_proc(name)
+                .and_then(|proc| proc.get_annotation().location()),
+        }
+    }
+
+    /// Get the members of a value of the given type: its fields, and its associated
+    /// constants and methods, along with their types. Pointers are looked through,
+    /// since members can be accessed through them too.
+    pub fn get_members(&self, ty: &Type) -> Vec<(String, Type)> {
+        let mut ty = ty.clone();
+        if let Ok(Type::Pointer(_, inner)) = ty.simplify_until_concrete(self) {
+            ty = *inner;
+        }
+
+        let mut members = vec![];
+        match ty.simplify_until_concrete(self) {
+            Ok(Type::Struct(fields)) | Ok(Type::Union(fields)) => {
+                members.extend(fields);
+            }
+            Ok(Type::Tuple(items)) => {
+                members.extend(
+                    items
+                        .into_iter()
+                        .enumerate()
+                        .map(|(i, ty)| (i.to_string(), ty)),
+                );
+            }
+            _ => {}
+        }
+        for (name, expr) in self.get_all_associated_consts(&ty) {
+            if members.iter().any(|(member, _)| *member == name) {
+                continue;
+            }
+            if let Ok(member_ty) = expr.get_type(self) {
+                members.push((name, member_ty));
+            }
+        }
+        members
+    }
+}
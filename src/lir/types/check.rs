@@ -10,7 +10,7 @@
 //! - Ensuring that all array lengths are non-negative.
 //! - Ensuring that you don't attempt to access a variable that is out of scope.
 use super::*;
-use crate::lir::Pattern;
+use crate::lir::{Pattern, Procedure, Warning};
 
 use log::{error, trace};
 
@@ -72,7 +72,9 @@ impl TypeCheck for Type {
                 if len.clone().as_int(env)? < 0 {
                     // If it is negative, return an error.
                     error!("Negative array length detected in type {self} in environment {env}");
-                    return Err(Error::NegativeArrayLength(Expr::ConstExpr(*len.clone())));
+                    return Err(Error::NegativeArrayLength(Box::new(Expr::ConstExpr(
+                        *len.clone(),
+                    ))));
                 }
                 // Otherwise, return success.
                 Ok(())
@@ -179,25 +181,35 @@ impl TypeCheck for Type {
 impl TypeCheck for Expr {
     fn type_check(&self, env: &Env) -> Result<(), Error> {
         trace!("Type checking expression: {self}");
-        let ty = self.get_type(env)?;
+        let ty = match self.get_type(env) {
+            Ok(ty) => ty,
+            // Blocks and declarations recover from errors in their statements, so they
+            // can report the errors in each of their statements instead of just the first.
+            Err(err)
+                if matches!(
+                    self.without_annotations(),
+                    Self::Many(_) | Self::Declare(..)
+                ) =>
+            {
+                return self.type_check_statements(env).and(Err(err));
+            }
+            Err(err) => return Err(err),
+        };
         ty.type_check(env)?;
 
-        match self {
-            Self::Annotated(expr, metadata) => {
-                // Check the inner expression.
-                expr.type_check(env)
-                    .map_err(|e| e.annotate(metadata.clone()))
-            }
+        // Fill in the types of variables and closure parameters which are inferred from their uses.
+        if let Some(expr) = self.infer_local_types(env)? {
+            return expr.type_check(env);
+        }
 
-            Self::Declare(declaration, body) => {
-                // Create a new environment with the declarations defined.
-                let mut new_env = env.clone();
-                // Check the declaration.
-                declaration.type_check(&new_env)?;
-                // Add the declarations to the environment.
-                new_env.add_declaration(declaration)?;
-                // Check the body with the declarations defined.
-                body.type_check(&new_env)
+        // An operator overloaded by a user-defined type is a call to the overloading method.
+        if let Some((call, _)) = self.get_overloaded_op(env)? {
+            return call.type_check(env);
+        }
+
+        match self {
+            Self::Annotated(..) | Self::Declare(..) | Self::Many(_) => {
+                self.type_check_statements(env)
             }
 
             Self::UnaryOp(unop, expr) => {
@@ -241,6 +253,8 @@ impl TypeCheck for Expr {
                 ternop.type_check(a, b, c, env)
             }
             Self::AssignOp(op, dst, src) => {
+                // The assigned value may outlive the scope it's created in.
+                src.check_escape(env)?;
                 if let Self::Annotated(src, metadata) = &**src {
                     return op
                         .type_check(dst, src, env)
@@ -267,7 +281,7 @@ impl TypeCheck for Expr {
                     // throw an `InvalidMatchExpr` error. We do this
                     // because `Never` is an opaque type, and we can't
                     // match on it.
-                    return Err(Error::InvalidMatchExpr(*expr.clone()));
+                    return Err(Error::InvalidMatchExpr(expr.clone()));
                 }
 
                 let mut result_ty: Option<Type> = None;
@@ -296,7 +310,7 @@ impl TypeCheck for Expr {
                             return Err(Error::MismatchedTypes {
                                 found: branch_ty,
                                 expected: result_ty.clone(),
-                                expr: branch.clone(),
+                                expr: Box::new(branch.clone()),
                             });
                         }
                     } else {
@@ -314,8 +328,9 @@ impl TypeCheck for Expr {
                 if !Pattern::are_patterns_exhaustive(self, &patterns, &ty, env)? {
                     return Err(Error::NonExhaustivePatterns {
                         patterns,
-                        expr: self.clone(),
-                    });
+                        expr: Box::new(self.clone()),
+                    }
+                    .with_note("add a `_` pattern to match the remaining cases"));
                 }
 
                 // Return success if all the branches are sound.
@@ -337,7 +352,7 @@ impl TypeCheck for Expr {
                     return Err(Error::MismatchedTypes {
                         expected: then_type,
                         found: otherwise_type,
-                        expr: self.clone(),
+                        expr: Box::new(self.clone()),
                     });
                 }
                 // Return success if all the branches are sound.
@@ -347,32 +362,51 @@ impl TypeCheck for Expr {
             // Typecheck the inner constant expression.
             Self::ConstExpr(c) => c.type_check(env),
 
-            // Typecheck a block of expressions.
-            Self::Many(exprs) => {
-                // Typecheck each expression.
-                for (i, expr) in exprs.iter().enumerate() {
-                    // Check the inner expression.
-                    expr.type_check(env)?;
-                    if i < exprs.len() - 1 {
-                        // If it's not the last expression, confirm that it's of type `None`.
-                        // Otherwise, return an error.
-                        let ty = expr.get_type(env)?;
-                        if !ty.can_decay_to(&Type::None, env)? {
-                            error!("Expected type {} for expression {expr}, but found type {ty} in environment {env}", Type::None);
-                            // If it's not, return an error.
-                            return Err(Error::UnusedExpr(expr.clone(), ty));
-                        }
-                    }
-                }
-                // Return success if all the expressions are sound.
-                Ok(())
-            }
-
             Self::While(cond, body) => {
                 // Typecheck the condition.
                 cond.type_check(env)?;
-                // Typecheck the body.
-                body.type_check(env)
+                // Typecheck the body inside of the loop.
+                let mut new_env = env.clone();
+                new_env.push_loop(None);
+                body.type_check(&new_env)
+            }
+
+            // Typecheck the `while` loop that a `for` loop is lowered to.
+            Self::ForIn(pat, iterable, body) => {
+                iterable.type_check(env)?;
+                Self::desugar_for_in(pat, iterable, body, None, env)?.type_check(env)
+            }
+
+            Self::Labeled(label, body) => {
+                let mut inner = &**body;
+                while let Self::Annotated(expr, _) = inner {
+                    inner = expr;
+                }
+                if let Self::While(cond, body) = inner {
+                    // Typecheck the condition.
+                    cond.type_check(env)?;
+                    // Typecheck the body inside of the labeled loop.
+                    let mut new_env = env.clone();
+                    new_env.push_loop(Some(label.clone()));
+                    body.type_check(&new_env)
+                } else if let Self::ForIn(pat, iterable, body) = inner {
+                    iterable.type_check(env)?;
+                    Self::desugar_for_in(pat, iterable, body, Some(label), env)?.type_check(env)
+                } else {
+                    // A label on anything but a loop has no effect.
+                    body.type_check(env)
+                }
+            }
+
+            // Confirm that there's a loop for the `break` or `continue` to exit.
+            Self::Break(label) | Self::Continue(label) => {
+                if env.get_exited_loops(label.as_deref()).is_some() {
+                    Ok(())
+                } else if let Some(label) = label {
+                    Err(Error::LabelNotDefined(label.clone()))
+                } else {
+                    Err(Error::LoopControlOutsideLoop(Box::new(self.clone())))
+                }
             }
 
             Self::If(cond, t, e) => {
@@ -391,7 +425,7 @@ impl TypeCheck for Expr {
                     return Err(Error::MismatchedTypes {
                         expected: t_type,
                         found: e_type,
-                        expr: self.clone(),
+                        expr: Box::new(self.clone()),
                     });
                 }
                 Ok(())
@@ -429,7 +463,7 @@ impl TypeCheck for Expr {
                     } else {
                         error!("Expected mutability {expected_mutability} for expression {self}, but found incompatible mutability in environment {env}");
                         // If it isn't, then return an error.
-                        Err(Error::InvalidRefer(self.clone()))
+                        Err(Error::InvalidRefer(Box::new(self.clone())))
                     }
                 }
                 Expr::ConstExpr(_cexpr) => Ok(()),
@@ -445,7 +479,7 @@ impl TypeCheck for Expr {
                                 return Err(Error::MismatchedMutability {
                                     expected: *expected_mutability,
                                     found: found_mutability,
-                                    expr: self.clone(),
+                                    expr: Box::new(self.clone()),
                                 });
                             }
                         }
@@ -477,7 +511,7 @@ impl TypeCheck for Expr {
                                 return Err(Error::MismatchedMutability {
                                     expected: *expected_mutability,
                                     found: found_mutability,
-                                    expr: self.clone(),
+                                    expr: Box::new(self.clone()),
                                 });
                             }
                         }
@@ -487,7 +521,7 @@ impl TypeCheck for Expr {
                     // If so, then make sure the expression being accessed is also sound.
                     e.type_check(env)
                 }
-                other => Err(Error::InvalidRefer(other)),
+                other => Err(Error::InvalidRefer(Box::new(other))),
             },
             // Typecheck a dereference of a pointer.
             Self::Deref(e) => {
@@ -506,7 +540,7 @@ impl TypeCheck for Expr {
                         // The expected type is a pointer.
                         expected: Type::Pointer(Mutability::Any, Box::new(Type::Any)),
                         found: t,
-                        expr: self.clone(),
+                        expr: Box::new(self.clone()),
                     })
                 }
             }
@@ -517,6 +551,8 @@ impl TypeCheck for Expr {
                 // Typecheck the pointer and the value we want to assign.
                 ptr.type_check(env)?;
                 val.type_check(env)?;
+                // The stored value may outlive the scope it's created in.
+                val.check_escape(env)?;
                 // Get the types of the pointer and the value.
                 let ptr_type = ptr.get_type(env)?;
                 let val_type = val.get_type(env)?;
@@ -533,7 +569,7 @@ impl TypeCheck for Expr {
                             Err(Error::MismatchedMutability {
                                 expected: Mutability::Mutable,
                                 found: mutability,
-                                expr: self.clone(),
+                                expr: Box::new(self.clone()),
                             })
                         }
                     } else {
@@ -541,7 +577,7 @@ impl TypeCheck for Expr {
                         Err(Error::MismatchedTypes {
                             expected: val_type,
                             found: *ptr_elem_ty,
-                            expr: self.clone(),
+                            expr: Box::new(self.clone()),
                         })
                     }
                 } else {
@@ -549,13 +585,19 @@ impl TypeCheck for Expr {
                     Err(Error::MismatchedTypes {
                         expected: Type::Pointer(Mutability::Mutable, Box::new(Type::Any)),
                         found: ptr_type,
-                        expr: self.clone(),
+                        expr: Box::new(self.clone()),
                     })
                 }
             }
 
             // Typecheck a function application.
             Self::Apply(f, args) => {
+                // If we're calling a polymorphic procedure without template arguments,
+                // typecheck the call to the monomorph with the inferred template arguments.
+                if let Some(call) = self.infer_template_args(env)? {
+                    return call.type_check(env);
+                }
+
                 if self.is_method_call(env)? {
                     // Get the type of the object we're calling the method on.
                     let method_call = self.transform_method_call(env)?;
@@ -573,31 +615,43 @@ impl TypeCheck for Expr {
                         let f_type = f.get_type(env)?.simplify_until_concrete(env)?;
                         // Infer the types of the supplied arguments.
                         let mut found_arg_tys = vec![];
-                        for arg in args {
+                        for arg in &args {
                             found_arg_tys.push(arg.get_type(env)?);
                         }
                         match f_type {
                             Type::Proc(expected_arg_tys, ret_ty) => {
                                 // If the number of arguments is incorrect, then return an error.
                                 if expected_arg_tys.len() != found_arg_tys.len() {
-                                    return Err(Error::MismatchedTypes {
+                                    let err = Error::MismatchedTypes {
                                         expected: Type::Proc(expected_arg_tys, ret_ty.clone()),
                                         found: Type::Proc(found_arg_tys, ret_ty),
-                                        expr: self.clone(),
+                                        expr: Box::new(self.clone()),
+                                    };
+                                    return Err(match f.get_callee(env) {
+                                        Some(proc) => err.with_label(
+                                            proc.get_annotation(),
+                                            format!(
+                                                "procedure is declared here with {} parameter(s)",
+                                                proc.get_args().len()
+                                            ),
+                                        ),
+                                        None => err,
                                     });
                                 }
                                 // If the function is a procedure, confirm that the type of each
                                 // argument matches the the type of the supplied value.
-                                for (expected, found) in
-                                    expected_arg_tys.into_iter().zip(found_arg_tys.into_iter())
+                                for (i, (expected, found)) in
+                                    expected_arg_tys.into_iter().zip(found_arg_tys).enumerate()
                                 {
                                     // If the types don't match, return an error.
                                     if !found.can_decay_to(&expected, env)? {
-                                        return Err(Error::MismatchedTypes {
+                                        let err = Error::MismatchedTypes {
                                             expected,
                                             found,
-                                            expr: self.clone(),
-                                        });
+                                            expr: Box::new(self.clone()),
+                                        }
+                                        .with_suggestion();
+                                        return Err(f.argument_error(err, &args[i], i, env));
                                     }
                                 }
                                 return Ok(());
@@ -607,7 +661,7 @@ impl TypeCheck for Expr {
                                 return Err(Error::MismatchedTypes {
                                     expected: Type::Proc(found_arg_tys, Box::new(Type::Any)),
                                     found: f_type,
-                                    expr: self.clone(),
+                                    expr: Box::new(self.clone()),
                                 })
                             }
                         }
@@ -632,24 +686,36 @@ impl TypeCheck for Expr {
                     Type::Proc(expected_arg_tys, ret_ty) => {
                         // If the number of arguments is incorrect, then return an error.
                         if expected_arg_tys.len() != found_arg_tys.len() {
-                            return Err(Error::MismatchedTypes {
+                            let err = Error::MismatchedTypes {
                                 expected: Type::Proc(expected_arg_tys, ret_ty.clone()),
                                 found: Type::Proc(found_arg_tys, ret_ty),
-                                expr: self.clone(),
+                                expr: Box::new(self.clone()),
+                            };
+                            return Err(match f.get_callee(env) {
+                                Some(proc) => err.with_label(
+                                    proc.get_annotation(),
+                                    format!(
+                                        "procedure is declared here with {} parameter(s)",
+                                        proc.get_args().len()
+                                    ),
+                                ),
+                                None => err,
                             });
                         }
                         // If the function is a procedure, confirm that the type of each
                         // argument matches the the type of the supplied value.
-                        for (expected, found) in
-                            expected_arg_tys.into_iter().zip(found_arg_tys.into_iter())
+                        for (i, (expected, found)) in
+                            expected_arg_tys.into_iter().zip(found_arg_tys).enumerate()
                         {
                             // If the types don't match, return an error.
                             if !found.can_decay_to(&expected, env)? {
-                                return Err(Error::MismatchedTypes {
+                                let err = Error::MismatchedTypes {
                                     expected,
                                     found,
-                                    expr: self.clone(),
-                                });
+                                    expr: Box::new(self.clone()),
+                                }
+                                .with_suggestion();
+                                return Err(f.argument_error(err, &args[i], i, env));
                             }
                         }
                         Ok(())
@@ -658,14 +724,19 @@ impl TypeCheck for Expr {
                     _ => Err(Error::MismatchedTypes {
                         expected: Type::Proc(found_arg_tys, Box::new(Type::Any)),
                         found: f_type,
-                        expr: self.clone(),
+                        expr: Box::new(self.clone()),
                     }),
                 }
             }
 
+            // Typecheck a closure.
+            Self::Closure(closure) => closure.type_check(env),
+
             // Typecheck a return statement.
             Self::Return(e) => {
                 e.type_check(env)?;
+                // The returned value outlives the procedure's scope.
+                e.check_escape(env)?;
                 let found = e.get_type(env)?;
                 let expected = env
                     .get_expected_return_type()
@@ -675,12 +746,25 @@ impl TypeCheck for Expr {
                     return Err(Error::MismatchedTypes {
                         expected,
                         found,
-                        expr: self.clone(),
-                    });
+                        expr: Box::new(self.clone()),
+                    }
+                    .with_suggestion()
+                    .with_label(
+                        env.get_return_annotation(),
+                        "expected because of the return type of this procedure",
+                    ));
                 }
                 Ok(())
             }
 
+            // Typecheck a `?` expression: the enclosing procedure must be able to
+            // return every variant other than the success variant.
+            Self::Try(e) => {
+                e.type_check(env)?;
+                Self::get_try_early_returns(e, env)?;
+                Ok(())
+            }
+
             // Typecheck an array or tuple literal.
             Self::Array(items) => {
                 let mut last_type: Option<Type> = None;
@@ -688,6 +772,8 @@ impl TypeCheck for Expr {
                 for item in items {
                     // Typecheck the item.
                     item.type_check(env)?;
+                    // The item is stored in the array, which may outlive its scope.
+                    item.check_escape(env)?;
                     // Get the type of the item.
                     let item_type = item.get_type(env)?;
                     // If the type of the item is different from the last item,
@@ -699,7 +785,7 @@ impl TypeCheck for Expr {
                             return Err(Error::MismatchedTypes {
                                 expected: last_type,
                                 found: item_type,
-                                expr: self.clone(),
+                                expr: Box::new(self.clone()),
                             });
                         }
                     }
@@ -710,6 +796,7 @@ impl TypeCheck for Expr {
             Self::Tuple(elems) => {
                 for elem in elems {
                     elem.type_check(env)?;
+                    elem.check_escape(env)?;
                 }
                 Ok(())
             }
@@ -718,6 +805,7 @@ impl TypeCheck for Expr {
             Self::Struct(fields) => {
                 for field_expr in fields.values() {
                     field_expr.type_check(env)?;
+                    field_expr.check_escape(env)?;
                 }
                 Ok(())
             }
@@ -733,25 +821,26 @@ impl TypeCheck for Expr {
                         if let Some(expected_ty) = fields.get(field) {
                             // Typecheck the value assigned to the variant.
                             val.type_check(env)?;
+                            val.check_escape(env)?;
                             let found = val.get_type(env)?;
                             if !found.can_decay_to(expected_ty, env)? {
                                 return Err(Error::MismatchedTypes {
                                     expected: expected_ty.clone(),
                                     found,
-                                    expr: self.clone(),
+                                    expr: Box::new(self.clone()),
                                 });
                             }
                             Ok(())
                         } else {
                             Err(Error::MemberNotFound(
-                                self.clone(),
-                                ConstExpr::Symbol(field.clone()),
+                                Box::new(self.clone()),
+                                Box::new(ConstExpr::Symbol(field.clone())),
                             ))
                         }
                     }
                     _ => Err(Error::MemberNotFound(
-                        self.clone(),
-                        ConstExpr::Symbol(field.clone()),
+                        Box::new(self.clone()),
+                        Box::new(ConstExpr::Symbol(field.clone())),
                     )),
                 }
             }
@@ -768,12 +857,13 @@ impl TypeCheck for Expr {
                         if let Some(expected_ty) = fields.get(variant) {
                             // Typecheck the value assigned to the variant.
                             val.type_check(env)?;
+                            val.check_escape(env)?;
                             let found = val.get_type(env)?;
                             if !found.can_decay_to(expected_ty, env)? {
                                 return Err(Error::MismatchedTypes {
                                     expected: expected_ty.clone(),
                                     found,
-                                    expr: self.clone(),
+                                    expr: Box::new(self.clone()),
                                 });
                             }
                             Ok(())
@@ -801,7 +891,11 @@ impl TypeCheck for Expr {
                     Ok(())
                 } else {
                     // Otherwise, it isn't a valid cast, so return an error.
-                    Err(Error::InvalidAs(self.clone(), found_ty, desired_ty.clone()))
+                    Err(Error::InvalidAs(
+                        Box::new(self.clone()),
+                        found_ty,
+                        desired_ty.clone(),
+                    ))
                 }
             }
 
@@ -843,7 +937,7 @@ impl TypeCheck for Expr {
                 match val_type {
                     Type::Array(_, _) | Type::Pointer(_, _) => {}
                     // If it isn't, return an error.
-                    _ => return Err(Error::InvalidIndex(self.clone())),
+                    _ => return Err(Error::InvalidIndex(Box::new(self.clone()))),
                 }
 
                 // Confirm that the index is an integer.
@@ -852,9 +946,136 @@ impl TypeCheck for Expr {
                     Ok(())
                 } else {
                     // Otherwise, return an error.
-                    Err(Error::InvalidIndex(self.clone()))
+                    Err(Error::InvalidIndex(Box::new(self.clone())))
+                }
+            }
+        }
+    }
+}
+
+impl Expr {
+    /// Get the procedure this expression refers to, if it names a known procedure.
+    /// This is used to point at a procedure's declaration when a call to it is ill-typed.
+    fn get_callee(&self, env: &Env) -> Option<Procedure> {
+        let mut expr = match self {
+            Self::Annotated(expr, _) => return expr.get_callee(env),
+            Self::ConstExpr(expr) => expr.clone(),
+            _ => return None,
+        };
+        // Look through annotations, and follow a symbol to the constant or procedure it names.
+        let mut followed_symbol = false;
+        loop {
+            match expr {
+                ConstExpr::Annotated(inner, _) => expr = *inner,
+                ConstExpr::Proc(proc) => return Some(proc),
+                ConstExpr::Symbol(name) if !followed_symbol => {
+                    if let Some(proc) = env.get_proc(&name) {
+                        return Some(proc.clone());
+                    }
+                    expr = env.get_const(&name)?.clone();
+                    followed_symbol = true;
+                }
+                _ => return None,
+            }
+        }
+    }
+
+    /// Point the error about an argument passed to this procedure at the argument,
+    /// and label it with the parameter the argument is passed to, if the procedure is known.
+    fn argument_error(&self, err: Error, arg: &Self, i: usize, env: &Env) -> Error {
+        let err = match self.get_callee(env) {
+            Some(proc) => match proc.get_args().get(i) {
+                Some((name, _, ty)) => err.with_label(
+                    proc.get_param_annotation(i),
+                    format!("parameter `{name}` is declared here with type {ty}"),
+                ),
+                None => err,
+            },
+            None => err,
+        };
+        match arg {
+            Self::Annotated(_, annotation) => err.annotate(annotation.clone()),
+            _ => err,
+        }
+    }
+
+    /// Typecheck a block, a declaration, or an annotated expression. An error in one
+    /// statement or declaration doesn't stop the rest of them from being checked, so
+    /// all the independent errors are reported together.
+    fn type_check_statements(&self, env: &Env) -> Result<(), Error> {
+        match self {
+            Self::Annotated(expr, metadata) => {
+                let warning_count = env.warning_count();
+                // Check the inner expression.
+                let result = expr
+                    .type_check(env)
+                    .map_err(|e| e.annotate(metadata.clone()));
+                // Point the warnings about the inner expression at its source code.
+                env.annotate_warnings(warning_count, metadata);
+                result
+            }
+
+            Self::Declare(declaration, body) => {
+                // Fill in the types of variables which are inferred from their uses.
+                if let Some(expr) = self.infer_local_types(env)? {
+                    return expr.type_check(env);
+                }
+                let mut errors = vec![];
+                // Create a new environment with the declarations defined.
+                let mut new_env = env.clone();
+                // Check the declaration.
+                match declaration.type_check(&new_env) {
+                    Ok(()) => declaration.warn_unused_or_shadowed(body, env),
+                    Err(err) => errors.push(err),
                 }
+                // Add the declarations to the environment. A variable which can't be
+                // declared is bound with the `Any` type, so the body can still be
+                // checked without reporting spurious errors about it.
+                if let Err(err) = new_env.add_declaration_or_poison(declaration) {
+                    // Prefer the error found while checking the declaration, if there was one.
+                    if errors.is_empty() {
+                        errors.push(err);
+                    }
+                }
+                // Check the body with the declarations defined.
+                if let Err(err) = body.type_check(&new_env) {
+                    errors.push(err);
+                }
+                Error::check_all(errors)
             }
+
+            // Typecheck a block of expressions.
+            Self::Many(exprs) => {
+                let mut errors = vec![];
+                let mut warned_unreachable = false;
+                // Typecheck each expression.
+                for (i, expr) in exprs.iter().enumerate() {
+                    // Check the inner expression, and keep checking the rest of the block
+                    // if it's not sound.
+                    if let Err(err) = expr.type_check(env) {
+                        errors.push(err);
+                        continue;
+                    }
+                    if i < exprs.len() - 1 {
+                        // If it's not the last expression, its value is discarded.
+                        let ty = expr.get_type(env)?;
+                        if ty == Type::Never && !warned_unreachable {
+                            // The rest of the block can never be evaluated.
+                            warned_unreachable = true;
+                            env.warn(
+                                exprs[i + 1]
+                                    .annotate_warning(Warning::UnreachableCode(expr.clone())),
+                            );
+                        } else if !ty.can_decay_to(&Type::None, env)? {
+                            env.warn(expr.annotate_warning(Warning::UnusedValue(expr.clone(), ty)));
+                        }
+                    }
+                }
+                // Return success if all the expressions are sound.
+                Error::check_all(errors)
+            }
+
+            _ => self.type_check(env),
         }
     }
 }
@@ -933,7 +1154,7 @@ impl TypeCheck for ConstExpr {
                 // throw an error.
                 if bindings.has_local_variable_declaration() {
                     // Cannot declare local variables in a constant expression.
-                    return Err(Error::InvalidConstExpr(self.clone()));
+                    return Err(Error::InvalidConstExpr(Box::new(self.clone())));
                 }
                 // Add all the bindings to the environment.
                 new_env.add_compile_time_declaration(bindings)?;
@@ -946,6 +1167,10 @@ impl TypeCheck for ConstExpr {
                 debug!(
                     "Monomorphizing {expr} with type arguments {ty_args:?} in environment {env}"
                 );
+                // The type arguments must satisfy the bounds of a polymorphic procedure.
+                if let Ok(Self::PolyProc(poly)) = expr.clone().eval(env) {
+                    poly.check_bounds(ty_args, env)?;
+                }
                 match **expr {
                     Self::Template(ref ty_params, ref template) => {
                         // Create a new environment with the type parameters defined.
@@ -999,7 +1224,7 @@ impl TypeCheck for ConstExpr {
                     error!("Invalid cast: {found} as {cast_ty} in environment {env}");
                     // If it isn't, return an error.
                     return Err(Error::InvalidAs(
-                        Expr::ConstExpr(*expr.clone()),
+                        Box::new(Expr::ConstExpr(*expr.clone())),
                         found,
                         cast_ty.clone(),
                     ));
@@ -1017,6 +1242,8 @@ impl TypeCheck for ConstExpr {
             Self::StandardBuiltin(builtin) => builtin.type_check(env),
             // Typecheck a FFI procedure call
             Self::FFIProcedure(ffi) => ffi.type_check(env),
+            // Typecheck a trait method, which only needs its signature to be defined.
+            Self::TraitMethod(..) => self.get_type(env).map(|_| ()),
             // Typecheck a procedure.
             Self::Proc(proc) => proc.type_check(env),
             Self::PolyProc(proc) => proc.type_check(env),
@@ -1027,6 +1254,7 @@ impl TypeCheck for ConstExpr {
                 if env.get_const(name).is_some()
                     || env.get_proc(name).is_some()
                     || env.get_var(name).is_some()
+                    || env.get_captured_var(name).is_some()
                     || env.get_static_var(name).is_some()
                     || env.get_type(name).is_some()
                 {
@@ -1112,7 +1340,7 @@ impl TypeCheck for ConstExpr {
                             return Err(Error::MismatchedTypes {
                                 expected: last_type,
                                 found: item_type,
-                                expr: Expr::ConstExpr(self.clone()),
+                                expr: Box::new(Expr::ConstExpr(self.clone())),
                             });
                         }
                     }
@@ -1150,23 +1378,23 @@ impl TypeCheck for ConstExpr {
                                 return Err(Error::MismatchedTypes {
                                     expected: expected_ty.clone(),
                                     found,
-                                    expr: Expr::ConstExpr(self.clone()),
+                                    expr: Box::new(Expr::ConstExpr(self.clone())),
                                 });
                             }
                             Ok(())
                         } else {
                             error!("Member {field} not found in type {self} in environment {env}");
                             Err(Error::MemberNotFound(
-                                Expr::ConstExpr(self.clone()),
-                                ConstExpr::Symbol(field.clone()),
+                                Box::new(Expr::ConstExpr(self.clone())),
+                                Box::new(ConstExpr::Symbol(field.clone())),
                             ))
                         }
                     }
                     _ => {
                         error!("Member {field} not found in type {self} in environment {env}");
                         Err(Error::MemberNotFound(
-                            Expr::ConstExpr(self.clone()),
-                            ConstExpr::Symbol(field.clone()),
+                            Box::new(Expr::ConstExpr(self.clone())),
+                            Box::new(ConstExpr::Symbol(field.clone())),
                         ))
                     }
                 }
@@ -1188,7 +1416,7 @@ impl TypeCheck for ConstExpr {
                                 return Err(Error::MismatchedTypes {
                                     expected: expected_ty.clone(),
                                     found,
-                                    expr: Expr::ConstExpr(self.clone()),
+                                    expr: Box::new(Expr::ConstExpr(self.clone())),
                                 });
                             }
                             Ok(())
@@ -39,6 +39,15 @@ impl GetType for Expr {
     fn get_type_checked(&self, env: &Env, i: usize) -> Result<Type, Error> {
         trace!("Getting type of expression {}", self);
         let i = i + 1;
+        // Fill in the types of variables and closure parameters which are inferred from their uses.
+        if let Some(expr) = self.infer_local_types(env)? {
+            return expr.get_type_checked(env, i);
+        }
+
+        // An operator overloaded by a user-defined type is a call to the overloading method.
+        if let Some((_, ty)) = self.get_overloaded_op(env)? {
+            return Ok(ty);
+        }
         Ok(match self {
             Self::Annotated(expr, annotation) => {
                 // Get the type of the inner expression.
@@ -163,13 +172,15 @@ impl GetType for Expr {
             Self::As(_, t) => t.clone(),
 
             // A while loop returns the None value.
-            Self::While(cond, _) => {
+            Self::While(cond, body) => {
                 let mut cond = *cond.clone();
                 while let Expr::Annotated(expr, _) = cond {
                     cond = *expr;
                 }
 
                 match cond {
+                    // An infinite loop never finishes, unless it's exited with `break`.
+                    _ if body.can_break_loop(None) => Type::None,
                     Self::ConstExpr(ConstExpr::Bool(true)) => Type::Never,
                     Self::ConstExpr(ref c) => {
                         if let Ok(true) = c.clone().simplify(env)?.as_bool(env) {
@@ -181,6 +192,29 @@ impl GetType for Expr {
                     _ => Type::None,
                 }
             }
+            // A labeled loop has the type of the loop, unless it can be exited by its label.
+            Self::Labeled(label, body) => {
+                let mut inner = &**body;
+                while let Self::Annotated(expr, _) = inner {
+                    inner = expr;
+                }
+                match inner {
+                    Self::While(_, loop_body) if loop_body.can_break_loop(Some(label)) => {
+                        Type::None
+                    }
+                    Self::ForIn(pat, iterable, loop_body) => {
+                        Self::desugar_for_in(pat, iterable, loop_body, Some(label), env)?
+                            .get_type_checked(env, i)?
+                    }
+                    _ => body.get_type_checked(env, i)?,
+                }
+            }
+            // A `for` loop has the type of the `while` loop it's lowered to.
+            Self::ForIn(pat, iterable, body) => {
+                Self::desugar_for_in(pat, iterable, body, None, env)?.get_type_checked(env, i)?
+            }
+            // Loop control never lets the following instructions execute.
+            Self::Break(_) | Self::Continue(_) => Type::Never,
 
             // An if statement returns the type of the expression
             // that is evaluated if the condition is true (which must
@@ -217,7 +251,7 @@ impl GetType for Expr {
                     *inner
                 } else {
                     // Otherwise, we cannot prove that we can dereference this type.
-                    return Err(Error::DerefNonPointer(self.clone()));
+                    return Err(Error::DerefNonPointer(Box::new(self.clone())));
                 }
             }
             // The result of an assignment to a pointer is None.
@@ -226,15 +260,22 @@ impl GetType for Expr {
             // The type of a return is "Never" - it never allows
             // the following instructions to be executed.
             Self::Return(_) => Type::Never,
+            // A `?` expression has the type of the data in the success variant.
+            Self::Try(expr) => Self::get_try_success(expr, env)?.1,
+            Self::Closure(closure) => closure.get_type_checked(env, i)?,
             // Get the type of a procedure call.
             Self::Apply(func, _) => {
                 // Get the type of the function.
-                let ty = func
-                    .get_type_checked(env, i)?
-                    .simplify_until_concrete(env)?;
-                match ty {
+                let ty = func.get_type_checked(env, i)?;
+                if let Type::Poly(..) = ty {
+                    // Get the type of the call with the inferred template arguments.
+                    if let Some(call) = self.infer_template_args(env)? {
+                        return call.get_type_checked(env, i);
+                    }
+                }
+                match ty.simplify_until_concrete(env)? {
                     Type::Proc(_, ret) => *ret,
-                    _ => return Err(Error::ApplyNonProc(self.clone())),
+                    _ => return Err(Error::ApplyNonProc(Box::new(self.clone()))),
                 }
             }
 
@@ -299,12 +340,16 @@ impl GetType for Expr {
 
                         // Get the associated constant expression's type.
                         env.get_type_of_associated_const(&ty, &as_symbol?)
-                            .ok_or(Error::MemberNotFound(*val.clone(), field.clone()))?
+                            .ok_or(Error::MemberNotFound(val.clone(), Box::new(field.clone())))?
                     }
-                    Type::Unit(_unit_name, inner_ty) => {
+                    Type::Unit(unit_name, inner_ty) => {
                         // Get the associated constant expression's type.
-                        env.get_type_of_associated_const(&inner_ty, &as_symbol?)
-                            .ok_or(Error::MemberNotFound(*val.clone(), field.clone()))?
+                        // This falls back on the associated constants of the inner type.
+                        env.get_type_of_associated_const(
+                            &Type::Unit(unit_name, inner_ty),
+                            &as_symbol?,
+                        )
+                        .ok_or(Error::MemberNotFound(val.clone(), Box::new(field.clone())))?
                     }
                     Type::Pointer(found_mutability, t) => {
                         match t.get_member_offset(field, val, env) {
@@ -315,7 +360,10 @@ impl GetType for Expr {
                                         &Type::Pointer(found_mutability, t),
                                         &as_symbol?,
                                     )
-                                    .ok_or(Error::MemberNotFound(*val.clone(), field.clone()));
+                                    .ok_or(Error::MemberNotFound(
+                                        val.clone(),
+                                        Box::new(field.clone()),
+                                    ));
                             }
                         }
                     }
@@ -326,7 +374,10 @@ impl GetType for Expr {
                         if as_symbol.is_ok() {
                             return env
                                 .get_type_of_associated_const(&Type::Tuple(items), &as_symbol?)
-                                .ok_or(Error::MemberNotFound(*val.clone(), field.clone()));
+                                .ok_or(Error::MemberNotFound(
+                                    val.clone(),
+                                    Box::new(field.clone()),
+                                ));
                         }
                         // Get the index of the field.
                         let n = as_int? as usize;
@@ -335,7 +386,10 @@ impl GetType for Expr {
                             // Return the type of the field.
                             items[n].clone()
                         } else {
-                            return Err(Error::MemberNotFound(*val.clone(), field.clone()));
+                            return Err(Error::MemberNotFound(
+                                val.clone(),
+                                Box::new(field.clone()),
+                            ));
                         }
                     }
                     // If we're accessing a member of a struct,
@@ -351,7 +405,10 @@ impl GetType for Expr {
                             // If the field is not in the struct, return an error.
                             return env
                                 .get_type_of_associated_const(&Type::Struct(fields), &name)
-                                .ok_or(Error::MemberNotFound(*val.clone(), field.clone()));
+                                .ok_or(Error::MemberNotFound(
+                                    val.clone(),
+                                    Box::new(field.clone()),
+                                ));
                         }
                     }
                     // If we're accessing a member of a union,
@@ -367,7 +424,10 @@ impl GetType for Expr {
                             // If the field is not in the union, return an error.
                             return env
                                 .get_type_of_associated_const(&Type::Union(types), &name)
-                                .ok_or(Error::MemberNotFound(*val.clone(), field.clone()));
+                                .ok_or(Error::MemberNotFound(
+                                    val.clone(),
+                                    Box::new(field.clone()),
+                                ));
                         }
                     }
 
@@ -384,7 +444,7 @@ impl GetType for Expr {
                         // Try to get the member of the underlying type.
                         return env
                             .get_type_of_associated_const(&val_type, &as_symbol?)
-                            .ok_or(Error::MemberNotFound(*val.clone(), field.clone()));
+                            .ok_or(Error::MemberNotFound(val.clone(), Box::new(field.clone())));
                     } // Err(e) => {
                       //     // Try to get the member of the underlying type.
                       //     // return Err(e);
@@ -402,7 +462,7 @@ impl GetType for Expr {
 
                 // If we're accessing an index of a type that is not an array or pointer,
                 // we cannot access an index.
-                _ => return Err(Error::InvalidIndex(self.clone())),
+                _ => return Err(Error::InvalidIndex(Box::new(self.clone()))),
             },
         })
     }
@@ -431,6 +491,12 @@ impl GetType for Expr {
                 cond.substitute(name, ty);
                 body.substitute(name, ty)
             }
+            Self::Labeled(_, body) => body.substitute(name, ty),
+            Self::ForIn(_, iterable, body) => {
+                iterable.substitute(name, ty);
+                body.substitute(name, ty);
+            }
+            Self::Break(_) | Self::Continue(_) => {}
 
             Self::Many(exprs) => {
                 for expr in exprs.iter_mut() {
@@ -445,7 +511,8 @@ impl GetType for Expr {
 
             Self::Match(expr, arms) => {
                 expr.substitute(name, ty);
-                for (_, arm) in arms.iter_mut() {
+                for (pattern, arm) in arms.iter_mut() {
+                    pattern.substitute(name, ty);
                     arm.substitute(name, ty);
                 }
             }
@@ -490,7 +557,8 @@ impl GetType for Expr {
                 }
             }
 
-            Self::Return(expr) => expr.substitute(name, ty),
+            Self::Return(expr) | Self::Try(expr) => expr.substitute(name, ty),
+            Self::Closure(closure) => closure.substitute(name, ty),
 
             Self::Array(exprs) | Self::Tuple(exprs) => {
                 for expr in exprs.iter_mut() {
@@ -11,9 +11,11 @@ use std::sync::Mutex;
 mod check;
 mod inference;
 mod size;
+mod unify;
 pub use check::*;
 pub use inference::*;
 pub use size::*;
+pub use unify::*;
 
 use log::*;
 
@@ -1837,7 +1839,10 @@ impl Type {
                     let size = t.get_size(env)?;
                     offset += size;
                 }
-                Err(Error::MemberNotFound(expr.clone(), member.clone()))
+                Err(Error::MemberNotFound(
+                    Box::new(expr.clone()),
+                    Box::new(member.clone()),
+                ))
             }
             Type::Tuple(items) => {
                 let mut offset = 0;
@@ -1854,11 +1859,17 @@ impl Type {
                     let size = t.get_size(env)?;
                     offset += size;
                 }
-                Err(Error::MemberNotFound(expr.clone(), member.clone()))
+                Err(Error::MemberNotFound(
+                    Box::new(expr.clone()),
+                    Box::new(member.clone()),
+                ))
             }
             Type::Union(types) => match types.get(&member.clone().as_symbol(env)?) {
                 Some(t) => Ok((t.clone().simplify(env)?, 0)),
-                None => Err(Error::MemberNotFound(expr.clone(), member.clone())),
+                None => Err(Error::MemberNotFound(
+                    Box::new(expr.clone()),
+                    Box::new(member.clone()),
+                )),
             },
             Type::Let(name, t, ret) => {
                 // Create a new scope and define the new type within it
@@ -1893,7 +1904,10 @@ impl Type {
                 }
             }
 
-            _ => Err(Error::MemberNotFound(expr.clone(), member.clone())),
+            _ => Err(Error::MemberNotFound(
+                Box::new(expr.clone()),
+                Box::new(member.clone()),
+            )),
         }
     }
 
@@ -1910,7 +1924,10 @@ impl Type {
                 if env.has_associated_const(ty, &name) {
                     Ok(())
                 } else {
-                    Err(Error::MemberNotFound(expr.clone(), member.clone()))
+                    Err(Error::MemberNotFound(
+                        Box::new(expr.clone()),
+                        Box::new(member.clone()),
+                    ))
                 }
             }
 
@@ -1926,7 +1943,10 @@ impl Type {
                     }
                 }
                 // error!("{} does not have member {}", self, member);
-                Err(Error::MemberNotFound(expr.clone(), member.clone()))
+                Err(Error::MemberNotFound(
+                    Box::new(expr.clone()),
+                    Box::new(member.clone()),
+                ))
             }
             Type::Tuple(items) => {
                 for (i, _) in items.iter().enumerate() {
@@ -1942,7 +1962,10 @@ impl Type {
                 if env.has_associated_const(self, &name) {
                     Ok(())
                 } else {
-                    Err(Error::MemberNotFound(expr.clone(), member.clone()))
+                    Err(Error::MemberNotFound(
+                        Box::new(expr.clone()),
+                        Box::new(member.clone()),
+                    ))
                 }
             }
             Type::Union(types) => {
@@ -1953,7 +1976,10 @@ impl Type {
                         if env.has_associated_const(self, &name) {
                             Ok(())
                         } else {
-                            Err(Error::MemberNotFound(expr.clone(), member.clone()))
+                            Err(Error::MemberNotFound(
+                                Box::new(expr.clone()),
+                                Box::new(member.clone()),
+                            ))
                         }
                     }
                 }
@@ -2004,7 +2030,10 @@ impl Type {
                     Ok(())
                 } else {
                     // error!("Type {self} does not have member {member}");
-                    Err(Error::MemberNotFound(expr.clone(), member.clone()))
+                    Err(Error::MemberNotFound(
+                        Box::new(expr.clone()),
+                        Box::new(member.clone()),
+                    ))
                 }
             }
         }
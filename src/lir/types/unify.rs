@@ -0,0 +1,697 @@
+//! # Type Unification
+//!
+//! This module implements the local type inference for unannotated `let` bindings,
+//! closure parameters, and empty array literals.
+//!
+//! A type which isn't known yet is represented by a *type variable*: a type symbol
+//! whose name starts with `?`, which can't be written in the source code. Every use of
+//! a variable whose type contains type variables constrains its type, and unifying the
+//! constraints with the variable's type solves for the type variables.
+use crate::lir::*;
+use core::fmt;
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use log::debug;
+
+use lazy_static::lazy_static;
+lazy_static! {
+    // The number of type variables created so far.
+    static ref TYPE_VAR_COUNT: Mutex<usize> = Mutex::new(0);
+}
+
+impl Type {
+    /// Create a new type variable, which is distinct from every other type variable.
+    pub fn fresh_var() -> Self {
+        let mut type_var_count = TYPE_VAR_COUNT.lock().unwrap();
+        *type_var_count += 1;
+        Self::Symbol(format!("?{type_var_count}"))
+    }
+
+    /// Is this type a type variable?
+    pub fn is_type_var(&self) -> bool {
+        matches!(self, Self::Symbol(name) if name.starts_with('?'))
+    }
+
+    /// Does this type contain any type variables?
+    pub fn has_type_vars(&self) -> bool {
+        let found = Cell::new(false);
+        self.map_leaves(&|t| {
+            if t.is_type_var() {
+                found.set(true);
+            }
+            None
+        });
+        found.get()
+    }
+
+    /// Replace every `Any` in this type with a new type variable.
+    fn any_to_type_vars(&self) -> Self {
+        self.map_leaves(&|t| match t {
+            Self::Any => Some(Self::fresh_var()),
+            _ => None,
+        })
+    }
+
+    /// Replace every type variable in this type with another type.
+    fn replace_type_vars(&self, replacement: &Self) -> Self {
+        self.map_leaves(&|t| match t {
+            t if t.is_type_var() => Some(replacement.clone()),
+            _ => None,
+        })
+    }
+
+    /// Rebuild this type, replacing every component for which the function returns
+    /// a type with that type. The components of the replaced types aren't visited.
+    fn map_leaves(&self, f: &impl Fn(&Self) -> Option<Self>) -> Self {
+        if let Some(t) = f(self) {
+            return t;
+        }
+        let map_all = |items: &[Self]| items.iter().map(|t| t.map_leaves(f)).collect();
+        let map_fields = |fields: &BTreeMap<String, Self>| {
+            fields
+                .iter()
+                .map(|(name, t)| (name.clone(), t.map_leaves(f)))
+                .collect()
+        };
+        match self {
+            Self::Pointer(mutability, inner) => {
+                Self::Pointer(*mutability, inner.map_leaves(f).into())
+            }
+            Self::Array(inner, size) => Self::Array(inner.map_leaves(f).into(), size.clone()),
+            Self::Tuple(items) => Self::Tuple(map_all(items)),
+            Self::Struct(fields) => Self::Struct(map_fields(fields)),
+            Self::Union(fields) => Self::Union(map_fields(fields)),
+            Self::EnumUnion(fields) => Self::EnumUnion(map_fields(fields)),
+            Self::Proc(args, ret) => Self::Proc(map_all(args), ret.map_leaves(f).into()),
+            Self::Apply(poly, args) => Self::Apply(poly.clone(), map_all(args)),
+            Self::Unit(name, inner) => Self::Unit(name.clone(), inner.map_leaves(f).into()),
+            _ => self.clone(),
+        }
+    }
+}
+
+/// A type required of a variable by one of its uses.
+#[derive(Clone, Debug)]
+pub struct Constraint {
+    /// The name of the constrained variable.
+    name: String,
+    /// The type of the variable, which may contain type variables.
+    var: Type,
+    /// The type the use requires the variable to have.
+    ty: Type,
+    /// The expression which uses the variable.
+    site: Expr,
+}
+
+impl Constraint {
+    /// Create a constraint that the variable with the given (partially unknown) type
+    /// must have the type `ty`, because of its use in the expression `site`.
+    pub fn new(name: impl ToString, var: Type, ty: Type, site: Expr) -> Self {
+        Self {
+            name: name.to_string(),
+            var,
+            ty,
+            site,
+        }
+    }
+
+    /// Collect the constraints which the uses of a variable in an expression place on
+    /// its type. The expression is the scope of the variable, and it's expected to have
+    /// the type `expected`, if that is known.
+    pub fn collect(
+        name: &str,
+        var: &Type,
+        scope: &Expr,
+        expected: Option<Type>,
+        env: &Env,
+    ) -> Vec<Self> {
+        let mut collector = ConstraintCollector {
+            name,
+            var,
+            constraints: vec![],
+        };
+        collector.visit(scope, expected, scope, env);
+        collector.constraints
+    }
+
+    /// Solve a list of constraints for the type variables in them.
+    /// If two of the constraints conflict, the error names both of them.
+    pub fn solve(constraints: &[Self], env: &Env) -> Result<Substitution, Error> {
+        let mut substitution = Substitution::default();
+        for (i, constraint) in constraints.iter().enumerate() {
+            if substitution.unify(&constraint.var, &constraint.ty, env) {
+                continue;
+            }
+            // Find the earlier constraint which this one conflicts with.
+            let first = constraints[..i]
+                .iter()
+                .find(|first| {
+                    let mut substitution = Substitution::default();
+                    !(substitution.unify(&first.var, &first.ty, env)
+                        && substitution.unify(&constraint.var, &constraint.ty, env))
+                })
+                .unwrap_or(&constraints[0]);
+            debug!("Conflicting constraints {first} and {constraint}");
+            return Err(Error::ConflictingConstraints(
+                Box::new(first.clone()),
+                Box::new(constraint.clone()),
+            ));
+        }
+        Ok(substitution)
+    }
+}
+
+impl fmt::Display for Constraint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} requires {} to be {}",
+            self.site,
+            self.name,
+            self.ty.replace_type_vars(&Type::Symbol("_".to_string()))
+        )
+    }
+}
+
+/// A solution for some type variables.
+#[derive(Clone, Debug, Default)]
+pub struct Substitution(BTreeMap<String, Type>);
+
+impl Substitution {
+    /// Replace the solved type variables in a type with their solutions.
+    pub fn apply(&self, ty: &Type) -> Type {
+        ty.map_leaves(&|t| match t {
+            Type::Symbol(name) => self.0.get(name).map(|t| self.apply(t)),
+            _ => None,
+        })
+    }
+
+    /// Solve the type variables in two types so that the types are the same.
+    /// This returns false if there is no such solution.
+    pub fn unify(&mut self, a: &Type, b: &Type, env: &Env) -> bool {
+        self.unify_checked(a, b, env, 0)
+    }
+
+    fn unify_checked(&mut self, a: &Type, b: &Type, env: &Env, i: usize) -> bool {
+        let (a, b) = (self.apply(a), self.apply(b));
+        if a == b {
+            return true;
+        }
+        if i > Type::SIMPLIFY_RECURSION_LIMIT {
+            return false;
+        }
+        let i = i + 1;
+
+        match (&a, &b) {
+            (Type::Symbol(var), t) | (t, Type::Symbol(var)) if var.starts_with('?') => {
+                // A type can't contain itself.
+                if t.contains_symbol(var) {
+                    return false;
+                }
+                self.0.insert(var.clone(), t.clone());
+                true
+            }
+            (Type::Any | Type::Never, _) | (_, Type::Any | Type::Never) => true,
+            // The mutability and the length are checked when the
+            // inferred type is type checked.
+            (Type::Pointer(_, a), Type::Pointer(_, b)) | (Type::Array(a, _), Type::Array(b, _)) => {
+                self.unify_checked(a, b, env, i)
+            }
+            (Type::Tuple(a), Type::Tuple(b)) if a.len() == b.len() => a
+                .iter()
+                .zip(b)
+                .all(|(a, b)| self.unify_checked(a, b, env, i)),
+            (Type::Struct(a), Type::Struct(b))
+            | (Type::Union(a), Type::Union(b))
+            | (Type::EnumUnion(a), Type::EnumUnion(b))
+                if a.keys().eq(b.keys()) =>
+            {
+                a.values()
+                    .zip(b.values())
+                    .all(|(a, b)| self.unify_checked(a, b, env, i))
+            }
+            (Type::Proc(a_args, a_ret), Type::Proc(b_args, b_ret))
+                if a_args.len() == b_args.len() =>
+            {
+                a_args
+                    .iter()
+                    .zip(b_args)
+                    .all(|(a, b)| self.unify_checked(a, b, env, i))
+                    && self.unify_checked(a_ret, b_ret, env, i)
+            }
+            (Type::Apply(a_poly, a_args), Type::Apply(b_poly, b_args))
+                if a_poly == b_poly && a_args.len() == b_args.len() =>
+            {
+                a_args
+                    .iter()
+                    .zip(b_args)
+                    .all(|(a, b)| self.unify_checked(a, b, env, i))
+            }
+            (Type::Unit(a_name, a), Type::Unit(b_name, b)) if a_name == b_name => {
+                self.unify_checked(a, b, env, i)
+            }
+            _ if !a.has_type_vars() && !b.has_type_vars() => {
+                a.equals(&b, env).unwrap_or(false)
+                    || a.can_decay_to(&b, env).unwrap_or(false)
+                    || b.can_decay_to(&a, env).unwrap_or(false)
+            }
+            _ => {
+                // One of the types might be a named type, so look at its definition.
+                let expose = |t: &Type| {
+                    if t.is_type_var() {
+                        t.clone()
+                    } else {
+                        t.simplify_until_concrete(env).unwrap_or_else(|_| t.clone())
+                    }
+                };
+                let (exposed_a, exposed_b) = (expose(&a), expose(&b));
+                (exposed_a != a || exposed_b != b)
+                    && self.unify_checked(&exposed_a, &exposed_b, env, i)
+            }
+        }
+    }
+}
+
+/// Walks the scope of a variable to collect the constraints on its type.
+struct ConstraintCollector<'a> {
+    /// The name of the variable.
+    name: &'a str,
+    /// The type of the variable, which may contain type variables.
+    var: &'a Type,
+    /// The constraints collected so far.
+    constraints: Vec<Constraint>,
+}
+
+impl ConstraintCollector<'_> {
+    /// Is this expression the variable?
+    fn is_var(&self, expr: &Expr) -> bool {
+        matches!(expr.without_annotations(), Expr::ConstExpr(ConstExpr::Symbol(name)) if name == self.name)
+    }
+
+    /// Require the variable to have a type because of its use in `site`.
+    fn require(&mut self, ty: Type, site: &Expr) {
+        self.constraints.push(Constraint::new(
+            self.name,
+            self.var.clone(),
+            ty,
+            site.clone(),
+        ));
+    }
+
+    /// Visit an expression which is expected to have a given type. Types which can't be
+    /// determined yet just don't constrain the variable.
+    fn visit(&mut self, expr: &Expr, expected: Option<Type>, site: &Expr, env: &Env) {
+        let ty_of = |expr: &Expr| expr.get_type(env).ok();
+        let exposed = |t: Option<Type>| t.and_then(|t| t.simplify_until_concrete(env).ok());
+        match expr {
+            Expr::Annotated(inner, _) => self.visit(inner, expected, site, env),
+            Expr::ConstExpr(ConstExpr::Symbol(name)) if name == self.name => {
+                if let Some(ty) = expected {
+                    self.require(ty, site);
+                }
+            }
+            Expr::ConstExpr(_) | Expr::Break(_) | Expr::Continue(_) => {}
+            Expr::Many(exprs) => {
+                for (i, inner) in exprs.iter().enumerate() {
+                    let expected = if i + 1 == exprs.len() {
+                        expected.clone()
+                    } else {
+                        None
+                    };
+                    self.visit(inner, expected, expr, env);
+                }
+            }
+            Expr::Declare(declaration, body) => {
+                if self.visit_declaration(declaration, expr, env) {
+                    // The variables whose types can't be determined yet are left undefined.
+                    let mut new_env = env.clone();
+                    let _ = new_env.add_declaration(declaration);
+                    self.visit(body, expected, expr, &new_env);
+                }
+            }
+            Expr::While(cond, body) => {
+                self.visit(cond, Some(Type::Bool), expr, env);
+                self.visit(body, None, expr, env);
+            }
+            Expr::Labeled(_, body) => self.visit(body, expected, expr, env),
+            Expr::ForIn(pattern, iter, body) => {
+                self.visit(iter, None, expr, env);
+                if !pattern.binds(self.name) {
+                    self.visit(body, None, expr, env);
+                }
+            }
+            Expr::If(cond, then, els) => {
+                self.visit(cond, Some(Type::Bool), expr, env);
+                self.visit(then, expected.clone(), expr, env);
+                self.visit(els, expected, expr, env);
+            }
+            Expr::When(_, then, els) => {
+                self.visit(then, expected.clone(), expr, env);
+                self.visit(els, expected, expr, env);
+            }
+            Expr::Match(val, branches) => {
+                self.visit(val, None, expr, env);
+                for (pattern, branch) in branches {
+                    if !pattern.binds(self.name) {
+                        self.visit(branch, expected.clone(), expr, env);
+                    }
+                }
+            }
+            Expr::IfLet(pattern, val, then, els) => {
+                self.visit(val, None, expr, env);
+                if !pattern.binds(self.name) {
+                    self.visit(then, expected.clone(), expr, env);
+                }
+                self.visit(els, expected, expr, env);
+            }
+            Expr::UnaryOp(_, val) | Expr::Try(val) | Expr::As(val, _) | Expr::Member(val, _) => {
+                self.visit(val, None, expr, env)
+            }
+            Expr::BinaryOp(op, lhs, rhs) => {
+                // Arithmetic, bitwise and comparison operators take operands of the same type.
+                let same_types = matches!(
+                    op.overload_name(),
+                    Some(
+                        "add"
+                            | "sub"
+                            | "mul"
+                            | "div"
+                            | "rem"
+                            | "pow"
+                            | "bitand"
+                            | "bitor"
+                            | "bitxor"
+                            | "eq"
+                            | "ne"
+                            | "lt"
+                            | "le"
+                            | "gt"
+                            | "ge"
+                    )
+                );
+                let (lhs_expected, rhs_expected) = if same_types {
+                    (ty_of(rhs), ty_of(lhs))
+                } else {
+                    (None, None)
+                };
+                self.visit(lhs, lhs_expected, expr, env);
+                self.visit(rhs, rhs_expected, expr, env);
+            }
+            Expr::TernaryOp(_, a, b, c) => {
+                self.visit(a, None, expr, env);
+                self.visit(b, None, expr, env);
+                self.visit(c, None, expr, env);
+            }
+            Expr::AssignOp(_, dst, src) | Expr::DerefMut(dst, src) => {
+                // Assigning to the variable requires it to have the type of the assigned value.
+                match dst.without_annotations() {
+                    Expr::Refer(_, val) if self.is_var(val) => {
+                        if let Some(ty) = ty_of(src) {
+                            self.require(ty, expr);
+                        }
+                    }
+                    dst if self.is_var(dst) && matches!(expr, Expr::DerefMut(..)) => {
+                        if let Some(ty) = ty_of(src) {
+                            self.require(Type::Pointer(Mutability::Mutable, ty.into()), expr);
+                        }
+                    }
+                    _ => self.visit(dst, None, expr, env),
+                }
+                let src_expected = match exposed(ty_of(dst)) {
+                    Some(Type::Pointer(_, ty)) => Some(*ty),
+                    _ => None,
+                };
+                self.visit(src, src_expected, expr, env);
+            }
+            Expr::Refer(_, val) => match expected.and_then(|t| exposed(Some(t))) {
+                Some(Type::Pointer(_, ty)) => self.visit(val, Some(*ty), expr, env),
+                _ => self.visit(val, None, expr, env),
+            },
+            Expr::Deref(ptr) => {
+                let expected = expected.map(|ty| Type::Pointer(Mutability::Any, ty.into()));
+                self.visit(ptr, expected, expr, env)
+            }
+            Expr::Apply(f, args) => {
+                if self.is_var(f) {
+                    // Calling the variable requires it to be a procedure.
+                    let args = args
+                        .iter()
+                        .map(|arg| ty_of(arg).unwrap_or_else(Type::fresh_var))
+                        .collect();
+                    let ret = expected.unwrap_or_else(Type::fresh_var);
+                    self.require(Type::Proc(args, ret.into()), expr);
+                    return;
+                }
+                self.visit(f, None, expr, env);
+                // A method call's first argument is the value the method is called on.
+                let skipped = usize::from(expr.is_method_call(env).unwrap_or(false));
+                let params = match exposed(ty_of(f)) {
+                    Some(Type::Proc(params, _)) => params,
+                    _ => vec![],
+                };
+                for (i, arg) in args.iter().enumerate() {
+                    self.visit(arg, params.get(i + skipped).cloned(), expr, env);
+                }
+            }
+            Expr::Return(val) => {
+                let expected = env.get_expected_return_type().cloned();
+                self.visit(val, expected, expr, env)
+            }
+            Expr::Closure(closure) => {
+                if !closure.has_arg(self.name) {
+                    closure.visit_body(|body, scope| self.visit(body, None, expr, scope), env);
+                }
+            }
+            Expr::Array(items) => {
+                let expected = match exposed(expected) {
+                    Some(Type::Array(ty, _)) => Some(*ty),
+                    _ => None,
+                };
+                for item in items {
+                    self.visit(item, expected.clone(), expr, env);
+                }
+            }
+            Expr::Tuple(items) => {
+                let expected = match exposed(expected) {
+                    Some(Type::Tuple(types)) => types,
+                    _ => vec![],
+                };
+                for (i, item) in items.iter().enumerate() {
+                    self.visit(item, expected.get(i).cloned(), expr, env);
+                }
+            }
+            Expr::Struct(fields) => {
+                let expected = match exposed(expected) {
+                    Some(Type::Struct(types)) => types,
+                    _ => BTreeMap::new(),
+                };
+                for (name, field) in fields {
+                    self.visit(field, expected.get(name).cloned(), expr, env);
+                }
+            }
+            Expr::Union(_, _, val) | Expr::EnumUnion(_, _, val) => self.visit(val, None, expr, env),
+            Expr::Index(val, idx) => {
+                self.visit(val, None, expr, env);
+                self.visit(idx, Some(Type::Int), expr, env);
+            }
+        }
+    }
+
+    /// Visit the initial values in a declaration. This returns false if the
+    /// declaration shadows the variable.
+    fn visit_declaration(&mut self, declaration: &Declaration, site: &Expr, env: &Env) -> bool {
+        match declaration {
+            Declaration::Var(name, _, ty, val) => {
+                self.visit(val, ty.clone(), site, env);
+                name != self.name
+            }
+            Declaration::VarPat(pattern, val) => {
+                self.visit(val, None, site, env);
+                !pattern.binds(self.name)
+            }
+            Declaration::Many(declarations) => declarations
+                .iter()
+                .all(|declaration| self.visit_declaration(declaration, site, env)),
+            _ => true,
+        }
+    }
+}
+
+impl Expr {
+    /// Infer the unknown types of the variables and closure parameters declared by this
+    /// expression from their uses, and get the expression with the types filled in.
+    /// This returns `None` if there is nothing to infer.
+    pub(crate) fn infer_local_types(&self, env: &Env) -> Result<Option<Self>, Error> {
+        match self {
+            Self::Declare(declaration, body) => {
+                // Consecutive declarations are merged, so each variable's scope
+                // is the rest of the declarations, followed by the body.
+                let declarations = match &**declaration {
+                    Declaration::Many(declarations) => declarations.as_slice(),
+                    declaration => std::slice::from_ref(declaration),
+                };
+                if !declarations.iter().any(Declaration::may_infer_type) {
+                    return Ok(None);
+                }
+                let mut new_env = env.clone();
+                if new_env.add_compile_time_declaration(declaration).is_err() {
+                    return Ok(None);
+                }
+                for (i, declaration) in declarations.iter().enumerate() {
+                    let scope = || match &declarations[i + 1..] {
+                        [] => *body.clone(),
+                        rest => {
+                            Self::Declare(Box::new(Declaration::Many(rest.to_vec())), body.clone())
+                        }
+                    };
+                    if let Some(inferred) = declaration.infer_type(scope, &new_env)? {
+                        let mut declarations = declarations.to_vec();
+                        declarations[i] = inferred;
+                        let declaration = match declarations.len() {
+                            1 => declarations.remove(0),
+                            _ => Declaration::Many(declarations),
+                        };
+                        return Ok(Some(Self::Declare(Box::new(declaration), body.clone())));
+                    }
+                    if new_env.add_local_variable_declaration(declaration).is_err() {
+                        return Ok(None);
+                    }
+                }
+                Ok(None)
+            }
+
+            Self::Closure(closure) if closure.has_inferred_args() => {
+                Ok(Some(Self::Closure(closure.infer_arg_types(vec![], env)?)))
+            }
+
+            Self::Apply(f, args) => {
+                let has_inferred_args = |arg: &Self| matches!(arg.without_annotations(), Self::Closure(closure) if closure.has_inferred_args());
+                if !args.iter().any(has_inferred_args) {
+                    return Ok(None);
+                }
+                // A closure passed to a procedure gets its parameter types from the procedure.
+                // Closures passed to polymorphic procedures are inferred from their bodies alone.
+                let Ok(Type::Proc(params, _)) = f.get_type(env)?.simplify_until_concrete(env)
+                else {
+                    return Ok(None);
+                };
+                let skipped = usize::from(self.is_method_call(env)?);
+                let mut changed = false;
+                let mut new_args = vec![];
+                for (i, arg) in args.iter().enumerate() {
+                    match (arg.without_annotations(), params.get(i + skipped)) {
+                        (Self::Closure(closure), Some(param)) if has_inferred_args(arg) => {
+                            let constraint = Constraint::new(
+                                format!("argument {}", i + 1),
+                                closure.get_template_type(),
+                                param.clone(),
+                                self.clone(),
+                            );
+                            new_args.push(Self::Closure(
+                                closure.infer_arg_types(vec![constraint], env)?,
+                            ));
+                            changed = true;
+                        }
+                        _ => new_args.push(arg.clone()),
+                    }
+                }
+                Ok(changed.then(|| Self::Apply(f.clone(), new_args)))
+            }
+
+            _ => Ok(None),
+        }
+    }
+}
+
+impl Declaration {
+    /// Might this be a variable declaration whose type is inferred from its uses?
+    fn may_infer_type(&self) -> bool {
+        match self {
+            Self::Var(_, _, specifier, val) => match val.without_annotations() {
+                Expr::Closure(closure) => closure.has_inferred_args(),
+                // Only empty arrays and the results of calls can have types containing `Any`.
+                Expr::Array(items) => specifier.is_none() && items.is_empty(),
+                Expr::Apply(..) => specifier.is_none(),
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// Infer the type of a variable declaration from the uses of the variable in its scope,
+    /// and get the declaration with the type filled in. This returns `None` if there is
+    /// nothing to infer.
+    ///
+    /// A variable initialized with a value whose type contains `Any` (like the pointer
+    /// returned by `alloc`) gets a more specific type if its uses agree on one.
+    fn infer_type(&self, scope: impl FnOnce() -> Expr, env: &Env) -> Result<Option<Self>, Error> {
+        if !self.may_infer_type() {
+            return Ok(None);
+        }
+        let Self::Var(name, mutability, specifier, val) = self else {
+            return Ok(None);
+        };
+
+        if let Expr::Closure(closure) = val.without_annotations() {
+            let template = closure.get_template_type();
+            let mut constraints = vec![];
+            if let Some(specifier) = specifier {
+                constraints.push(Constraint::new(
+                    name,
+                    template.clone(),
+                    specifier.clone(),
+                    Expr::NONE.with(self.clone()),
+                ));
+            }
+            let mut new_env = env.clone();
+            let _ = new_env.define_var(name, *mutability, template.clone());
+            constraints.extend(Constraint::collect(
+                name,
+                &template,
+                &scope(),
+                None,
+                &new_env,
+            ));
+            let closure = Expr::Closure(closure.infer_arg_types(constraints, env)?);
+            let val = match val {
+                Expr::Annotated(_, annotation) => closure.annotate(annotation.clone()),
+                _ => closure,
+            };
+            return Ok(Some(Self::Var(
+                name.clone(),
+                *mutability,
+                specifier.clone(),
+                val,
+            )));
+        }
+
+        let Ok(ty) = val.get_type(env) else {
+            return Ok(None);
+        };
+        let template = ty.any_to_type_vars();
+        if !template.has_type_vars() {
+            return Ok(None);
+        }
+        let mut new_env = env.clone();
+        // An empty array of `Any` can't be defined, because its size is unknown.
+        let _ = new_env.define_var(name, *mutability, ty.clone());
+        let constraints = Constraint::collect(name, &template, &scope(), None, &new_env);
+        // If the uses don't agree on a type, the variable keeps the type of its value.
+        let Ok(substitution) = Constraint::solve(&constraints, env) else {
+            return Ok(None);
+        };
+        let inferred = substitution.apply(&template).replace_type_vars(&Type::Any);
+        if inferred == ty || !ty.can_decay_to(&inferred, env).unwrap_or(false) {
+            return Ok(None);
+        }
+        debug!("Inferred type {inferred} for {name}");
+        Ok(Some(Self::Var(
+            name.clone(),
+            *mutability,
+            Some(inferred),
+            val.clone(),
+        )))
+    }
+}
@@ -0,0 +1,94 @@
+//! # Warnings
+//!
+//! Warnings are reported for code which compiles, but is probably a mistake.
+//! Unlike errors, warnings don't stop the compilation: the type checker reports
+//! them to the environment, and they are returned alongside the compiled program.
+use super::{Annotation, Expr, Type};
+use crate::parse::SourceCodeLocation;
+use core::fmt::{Display, Formatter, Result as FmtResult};
+
+/// An LIR compilation warning.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Warning {
+    /// A warning with some annotation about the source code that caused the warning.
+    Annotated(Box<Self>, Annotation),
+
+    /// A variable was declared, but never used.
+    UnusedVariable(String),
+    /// A variable was declared with the same name as a variable already in scope.
+    ShadowedVariable(String),
+    /// An expression in a block returned a value which was discarded.
+    UnusedValue(Expr, Type),
+    /// The code after an expression which never finishes (like a `return`) can never be evaluated.
+    UnreachableCode(Expr),
+}
+
+impl Warning {
+    /// The names of the kinds of warnings, which are used to allow or deny them.
+    pub const NAMES: &'static [&'static str] = &[
+        "unused-variable",
+        "shadowed-variable",
+        "unused-value",
+        "unreachable-code",
+    ];
+
+    /// Get the name of the kind of this warning.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Annotated(warning, _) => warning.name(),
+            Self::UnusedVariable(_) => "unused-variable",
+            Self::ShadowedVariable(_) => "shadowed-variable",
+            Self::UnusedValue(..) => "unused-value",
+            Self::UnreachableCode(_) => "unreachable-code",
+        }
+    }
+
+    /// Get the stable code identifying the kind of this warning, like `W0001`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Annotated(warning, _) => warning.code(),
+            Self::UnusedVariable(_) => "W0001",
+            Self::ShadowedVariable(_) => "W0002",
+            Self::UnusedValue(..) => "W0003",
+            Self::UnreachableCode(_) => "W0004",
+        }
+    }
+
+    /// Annotate a warning with some metadata, unless it already has a location.
+    /// The innermost location is the most precise one.
+    pub fn annotate(self, annotation: Annotation) -> Self {
+        if self.location().is_some() || !annotation.has_location() {
+            return self;
+        }
+        Self::Annotated(Box::new(self), annotation)
+    }
+
+    /// Get the source code location of this warning, if it has one.
+    pub fn location(&self) -> Option<&SourceCodeLocation> {
+        match self {
+            Self::Annotated(warning, annotation) => {
+                annotation.location().or_else(|| warning.location())
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Display for Warning {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            Self::Annotated(warning, _) => write!(f, "{warning}"),
+            Self::UnusedVariable(name) => write!(f, "unused variable {name}"),
+            Self::ShadowedVariable(name) => {
+                write!(
+                    f,
+                    "variable {name} shadows another variable with the same name"
+                )
+            }
+            Self::UnusedValue(expr, ty) => {
+                write!(f, "unused value {expr} of type {ty}")
+            }
+            Self::UnreachableCode(_) => write!(f, "unreachable code"),
+        }
+    }
+}
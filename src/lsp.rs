@@ -0,0 +1,650 @@
+//! # The Sage Language Server
+//!
+//! This program implements a language server for the sage frontend language.
+//! It speaks the Language Server Protocol over stdin and stdout, so editors can
+//! show the diagnostics of a program when it's saved, the types of expressions
+//! on hover, jump to the definitions of procedures, types, and constants, and
+//! complete the fields and methods of values.
+//!
+//! Each document keeps the last version of it which parsed. While the document
+//! has syntax errors, hover, definitions, and completion use that version instead.
+
+use sage::{
+    compiler::with_stack_size,
+    frontend,
+    lir::{Env, Expr, GetType, Scope, Type, TypeCheck},
+    parse::SourceCodeLocation,
+};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, Write},
+    panic::{catch_unwind, AssertUnwindSafe},
+};
+
+/// The type checker can recurse deeply, so the server runs with a large stack.
+const STACK_SIZE_MB: usize = 512;
+
+/// The error code for a request with an unknown method.
+const METHOD_NOT_FOUND: i64 = -32601;
+
+/// An error found in a document, to be published as an LSP diagnostic.
+struct Diagnostic {
+    /// `1` for errors, and `2` for warnings.
+    severity: u8,
+    code: Option<&'static str>,
+    message: String,
+    loc: Option<SourceCodeLocation>,
+    /// The secondary locations related to the diagnostic, and their messages.
+    labels: Vec<(SourceCodeLocation, String)>,
+}
+
+/// The parsed program of a document.
+struct Analysis {
+    /// The text the program was parsed from.
+    text: String,
+    /// The program.
+    expr: Expr,
+    /// The names of the procedures, types, and constants declared in the program,
+    /// and the locations of their names.
+    definitions: Vec<(String, SourceCodeLocation)>,
+}
+
+/// A document opened by the editor.
+struct Document {
+    /// The path of the document, used as the filename of its source code locations.
+    path: String,
+    /// The current text of the document.
+    text: String,
+    /// The last version of the document which parsed.
+    analysis: Option<Analysis>,
+}
+
+impl Document {
+    fn new(uri: &str, text: String) -> Self {
+        Self {
+            path: uri_to_path(uri),
+            text,
+            analysis: None,
+        }
+    }
+
+    /// Parse the current text of the document, unless it was already parsed.
+    /// If the text doesn't parse, the last version which did is kept.
+    fn analyze(&mut self) -> Result<&Analysis, String> {
+        let is_current = self
+            .analysis
+            .as_ref()
+            .map(|analysis| analysis.text == self.text)
+            .unwrap_or(false);
+        if !is_current {
+            let expr = frontend::parse(&self.text, Some(&self.path))?;
+            let definitions = frontend::definitions(&self.text, Some(&self.path))?;
+            self.analysis = Some(Analysis {
+                text: self.text.clone(),
+                expr,
+                definitions,
+            });
+        }
+        Ok(self.analysis.as_ref().unwrap())
+    }
+
+    /// Get the last version of the document which parsed.
+    fn last_analysis(&mut self) -> Option<&Analysis> {
+        let _ = self.analyze();
+        self.analysis.as_ref()
+    }
+
+    /// Parse and type check the document, and get the errors and warnings in it.
+    fn diagnostics(&mut self) -> Vec<Diagnostic> {
+        let expr = match self.analyze() {
+            Ok(analysis) => analysis.expr.clone(),
+            Err(message) => return vec![parse_error_diagnostic(&self.path, &self.text, &message)],
+        };
+
+        let env = Env::default();
+        let result = match catch_unwind(AssertUnwindSafe(|| expr.type_check(&env))) {
+            Ok(result) => result,
+            // A bug in the type checker shouldn't look like a document without any errors.
+            Err(payload) => {
+                let message = payload
+                    .downcast_ref::<&str>()
+                    .map(|message| message.to_string())
+                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "unknown panic".to_string());
+                return vec![Diagnostic {
+                    severity: 1,
+                    code: None,
+                    message: format!("internal compiler error while type checking: {message}"),
+                    loc: None,
+                    labels: vec![],
+                }];
+            }
+        };
+
+        let mut diagnostics = vec![];
+        if let Err(err) = result {
+            for err in err.errors() {
+                diagnostics.push(Diagnostic {
+                    severity: 1,
+                    code: Some(err.code()),
+                    message: std::iter::once(err.to_string())
+                        .chain(err.notes().into_iter().map(|note| note.to_string()))
+                        .collect::<Vec<_>>()
+                        .join("\n"),
+                    loc: err.location().cloned(),
+                    labels: err
+                        .labels()
+                        .into_iter()
+                        .map(|(loc, message)| (loc.clone(), message.to_string()))
+                        .collect(),
+                });
+            }
+        }
+        for warning in env.get_warnings() {
+            diagnostics.push(Diagnostic {
+                severity: 2,
+                code: Some(warning.code()),
+                message: format!("{warning} [{}]", warning.name()),
+                loc: warning.location().cloned(),
+                labels: vec![],
+            });
+        }
+        diagnostics
+    }
+}
+
+/// Get the diagnostic for an error from the parser, which points at a line and column.
+fn parse_error_diagnostic(path: &str, text: &str, message: &str) -> Diagnostic {
+    let position = regex::Regex::new(r"(?m)-->\s*(?:.*:)?(\d+):(\d+)\s*$")
+        .unwrap()
+        .captures(message)
+        .map(|captures| {
+            (
+                captures[1].parse().unwrap_or(1),
+                captures[2].parse().unwrap_or(1),
+            )
+        });
+    // The parser explains what it expected on a line starting with `=`.
+    let expected = message
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("= "))
+        .unwrap_or(message);
+
+    Diagnostic {
+        severity: 1,
+        code: None,
+        message: expected.to_string(),
+        loc: position.map(|(line, column): (usize, usize)| SourceCodeLocation {
+            line,
+            column,
+            offset: position_to_offset(text, &json!({ "line": line - 1, "character": column - 1 })),
+            length: Some(0),
+            filename: Some(path.to_string()),
+        }),
+        labels: vec![],
+    }
+}
+
+/// Convert a `file://` URI to a path.
+fn uri_to_path(uri: &str) -> String {
+    let path = uri.strip_prefix("file://").unwrap_or(uri);
+    // Decode the percent-encoded bytes in the path.
+    let mut bytes = vec![];
+    let mut rest = path.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        match (byte, std::str::from_utf8(tail.get(..2).unwrap_or(&[]))) {
+            (b'%', Ok(hex)) if u8::from_str_radix(hex, 16).is_ok() => {
+                bytes.push(u8::from_str_radix(hex, 16).unwrap());
+                rest = &tail[2..];
+            }
+            _ => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Convert a path to a `file://` URI.
+fn path_to_uri(path: &str) -> String {
+    format!("file://{}", path.replace('%', "%25").replace(' ', "%20"))
+}
+
+/// Convert a byte offset into some text to an LSP position.
+/// LSP positions count the characters in a line in UTF-16 code units.
+fn offset_to_position(text: &str, offset: usize) -> Value {
+    let mut line = 0;
+    let mut character = 0;
+    for (i, c) in text.char_indices() {
+        if i >= offset {
+            break;
+        }
+        if c == '\n' {
+            line += 1;
+            character = 0;
+        } else {
+            character += c.len_utf16();
+        }
+    }
+    json!({ "line": line, "character": character })
+}
+
+/// Convert an LSP position in some text to a byte offset.
+fn position_to_offset(text: &str, position: &Value) -> usize {
+    let line = position["line"].as_u64().unwrap_or(0) as usize;
+    let character = position["character"].as_u64().unwrap_or(0) as usize;
+
+    let mut offset = 0;
+    for (i, text_line) in text.split_inclusive('\n').enumerate() {
+        if i == line {
+            let mut units = 0;
+            for (j, c) in text_line.char_indices() {
+                if units >= character || c == '\n' {
+                    return offset + j;
+                }
+                units += c.len_utf16();
+            }
+            return offset + text_line.len();
+        }
+        offset += text_line.len();
+    }
+    offset
+}
+
+/// Get the LSP range of a location in some text.
+fn location_to_range(text: &str, loc: &SourceCodeLocation) -> Value {
+    json!({
+        "start": offset_to_position(text, loc.offset),
+        "end": offset_to_position(text, loc.offset + loc.length.unwrap_or(0)),
+    })
+}
+
+/// Get the identifier at an offset into some text, and where it starts.
+fn word_at(text: &str, offset: usize) -> Option<(&str, usize)> {
+    let is_ident = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let offset = offset.min(text.len());
+    let start = text[..offset]
+        .rfind(|c: char| !is_ident(c))
+        .map(|i| i + 1)
+        .unwrap_or(0);
+    let end = text[offset..]
+        .find(|c: char| !is_ident(c))
+        .map(|i| offset + i)
+        .unwrap_or(text.len());
+    let word = &text[start..end];
+    if word.is_empty() || word.starts_with(|c: char| c.is_ascii_digit()) {
+        None
+    } else {
+        Some((word, start))
+    }
+}
+
+/// Get the chain of names before a `.` ending at an offset into some text,
+/// like `["point", "x"]` for `point.x.` followed by the member being typed.
+fn member_chain_at(text: &str, offset: usize) -> Option<Vec<String>> {
+    let is_ident = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let offset = offset.min(text.len());
+    // Skip the part of the member name that has already been typed.
+    let mut end = text[..offset]
+        .rfind(|c: char| !is_ident(c))
+        .map(|i| i + 1)
+        .unwrap_or(0);
+
+    let mut chain = vec![];
+    while text[..end].ends_with('.') {
+        end -= 1;
+        let start = text[..end]
+            .rfind(|c: char| !is_ident(c))
+            .map(|i| i + 1)
+            .unwrap_or(0);
+        if start == end {
+            break;
+        }
+        chain.insert(0, text[start..end].to_string());
+        end = start;
+    }
+    if chain.is_empty() {
+        None
+    } else {
+        Some(chain)
+    }
+}
+
+/// The state of the language server.
+#[derive(Default)]
+struct Server {
+    /// The documents opened by the editor, by their URIs.
+    documents: HashMap<String, Document>,
+    /// Whether the editor has asked the server to shut down.
+    is_shutting_down: bool,
+}
+
+impl Server {
+    /// Handle a request, and get its result.
+    fn request(&mut self, method: &str, params: &Value) -> Result<Value, (i64, String)> {
+        match method {
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    "textDocumentSync": {
+                        "openClose": true,
+                        // The editor sends the full text of a document when it changes.
+                        "change": 1,
+                        "save": { "includeText": true },
+                    },
+                    "hoverProvider": true,
+                    "definitionProvider": true,
+                    "completionProvider": { "triggerCharacters": ["."] },
+                },
+                "serverInfo": {
+                    "name": "sage-lsp",
+                    "version": env!("CARGO_PKG_VERSION"),
+                },
+            })),
+            "shutdown" => {
+                self.is_shutting_down = true;
+                Ok(Value::Null)
+            }
+            "textDocument/hover" => Ok(self.hover(params).unwrap_or(Value::Null)),
+            "textDocument/definition" => Ok(self.definition(params).unwrap_or(Value::Null)),
+            "textDocument/completion" => Ok(json!({
+                "isIncomplete": false,
+                "items": self.completion(params).unwrap_or_default(),
+            })),
+            _ => Err((METHOD_NOT_FOUND, format!("unknown method {method}"))),
+        }
+    }
+
+    /// Handle a notification, and get the notifications to send back.
+    fn notification(&mut self, method: &str, params: &Value) -> Vec<Value> {
+        let uri = params["textDocument"]["uri"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+        match method {
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string();
+                self.documents
+                    .insert(uri.clone(), Document::new(&uri, text));
+                self.publish_diagnostics(&uri).into_iter().collect()
+            }
+            "textDocument/didChange" => {
+                // With full document sync, the last change is the whole text.
+                let text = params["contentChanges"]
+                    .as_array()
+                    .and_then(|changes| changes.last())
+                    .and_then(|change| change["text"].as_str());
+                if let (Some(document), Some(text)) = (self.documents.get_mut(&uri), text) {
+                    document.text = text.to_string();
+                }
+                vec![]
+            }
+            "textDocument/didSave" => {
+                if let (Some(document), Some(text)) =
+                    (self.documents.get_mut(&uri), params["text"].as_str())
+                {
+                    document.text = text.to_string();
+                }
+                self.publish_diagnostics(&uri).into_iter().collect()
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                vec![json!({
+                    "method": "textDocument/publishDiagnostics",
+                    "params": { "uri": uri, "diagnostics": [] },
+                })]
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Check a document, and create the notification publishing its diagnostics.
+    fn publish_diagnostics(&mut self, uri: &str) -> Option<Value> {
+        let document = self.documents.get_mut(uri)?;
+        let (path, text) = (document.path.clone(), document.text.clone());
+
+        let mut diagnostics = vec![];
+        for diagnostic in document.diagnostics() {
+            // Diagnostics in other files, like imported modules, are shown at the top.
+            let range = match &diagnostic.loc {
+                Some(loc) if loc.filename.as_deref() == Some(&path) => {
+                    location_to_range(&text, loc)
+                }
+                _ => json!({
+                    "start": { "line": 0, "character": 0 },
+                    "end": { "line": 0, "character": 0 },
+                }),
+            };
+            let related: Vec<_> = diagnostic
+                .labels
+                .iter()
+                .filter_map(|(loc, message)| {
+                    Some(json!({
+                        "location": self.location_to_lsp(loc)?,
+                        "message": message,
+                    }))
+                })
+                .collect();
+            diagnostics.push(json!({
+                "range": range,
+                "severity": diagnostic.severity,
+                "code": diagnostic.code,
+                "source": "sage",
+                "message": diagnostic.message,
+                "relatedInformation": related,
+            }));
+        }
+        Some(json!({
+            "method": "textDocument/publishDiagnostics",
+            "params": { "uri": uri, "diagnostics": diagnostics },
+        }))
+    }
+
+    /// Get the LSP location of a location in the source code of some file.
+    fn location_to_lsp(&self, loc: &SourceCodeLocation) -> Option<Value> {
+        let path = loc.filename.as_ref()?;
+        // Prefer the text of an open document, which may not be saved yet.
+        let open = self
+            .documents
+            .iter()
+            .find(|(_, document)| document.path == *path);
+        let (uri, text) = match open {
+            Some((uri, document)) => (uri.clone(), document.text.clone()),
+            None => (path_to_uri(path), std::fs::read_to_string(path).ok()?),
+        };
+        Some(json!({ "uri": uri, "range": location_to_range(&text, loc) }))
+    }
+
+    /// Get the scope of the position in a request, in the last version of its document which parsed.
+    fn scope_at(&mut self, params: &Value) -> Option<(Scope, String, usize)> {
+        let uri = params["textDocument"]["uri"].as_str()?;
+        let document = self.documents.get_mut(uri)?;
+        let path = document.path.clone();
+        let analysis = document.last_analysis()?;
+        let offset = position_to_offset(&analysis.text, &params["position"]);
+        let scope = catch_unwind(AssertUnwindSafe(|| {
+            analysis.expr.scope_at(Some(&path), offset)
+        }))
+        .ok()??;
+        Some((scope, analysis.text.clone(), offset))
+    }
+
+    /// Show the type of the symbol or expression at a position.
+    fn hover(&mut self, params: &Value) -> Option<Value> {
+        let (scope, text, offset) = self.scope_at(params)?;
+        let contents = match word_at(&text, offset) {
+            Some((word, _)) => {
+                if let Some(ty) = scope.get_symbol_type(word) {
+                    format!("{word}: {ty}")
+                } else if let Some(ty) = scope.env.get_type_definition(word) {
+                    format!("type {word} = {ty}")
+                } else {
+                    format!("{}", scope.expr.get_type(&scope.env).ok()?)
+                }
+            }
+            None => format!("{}", scope.expr.get_type(&scope.env).ok()?),
+        };
+        Some(json!({
+            "contents": {
+                "kind": "markdown",
+                "value": format!("```sage\n{contents}\n```"),
+            },
+        }))
+    }
+
+    /// Find the declaration of the procedure, type, or constant at a position.
+    fn definition(&mut self, params: &Value) -> Option<Value> {
+        let uri = params["textDocument"]["uri"].as_str()?;
+        let document = self.documents.get_mut(uri)?;
+        let analysis = document.last_analysis()?;
+        let offset = position_to_offset(&analysis.text, &params["position"]);
+        let (word, _) = word_at(&analysis.text, offset)?;
+
+        // Prefer the closest declaration before the position, since it shadows the others.
+        let candidates: Vec<_> = analysis
+            .definitions
+            .iter()
+            .filter(|(name, _)| name == word)
+            .map(|(_, loc)| loc.clone())
+            .collect();
+        let loc = candidates
+            .iter()
+            .rev()
+            .find(|loc| loc.offset <= offset)
+            .or(candidates.first())
+            .cloned();
+
+        match loc {
+            Some(loc) => self.location_to_lsp(&loc),
+            // Procedures from imported modules are found through the environment.
+            None => {
+                let word = word.to_string();
+                let (scope, ..) = self.scope_at(params)?;
+                let loc = scope.env.get_proc_location(&word)?.clone();
+                self.location_to_lsp(&loc)
+            }
+        }
+    }
+
+    /// Complete the fields and methods of the value before a `.` at a position.
+    fn completion(&mut self, params: &Value) -> Option<Vec<Value>> {
+        let uri = params["textDocument"]["uri"].as_str()?;
+        // The member access is in the current text, which may not parse yet.
+        let text = self.documents.get(uri)?.text.clone();
+        let chain = member_chain_at(&text, position_to_offset(&text, &params["position"]))?;
+
+        let (scope, ..) = self.scope_at(params)?;
+        let mut ty = scope.get_symbol_type(&chain[0])?;
+        for name in &chain[1..] {
+            ty = scope
+                .env
+                .get_members(&ty)
+                .into_iter()
+                .find(|(member, _)| member == name)?
+                .1;
+        }
+
+        let items = scope
+            .env
+            .get_members(&ty)
+            .into_iter()
+            .map(|(name, ty)| {
+                // Methods and fields have different kinds of completion items.
+                let kind = match ty {
+                    Type::Proc(..) => 2,
+                    _ => 5,
+                };
+                json!({ "label": name, "kind": kind, "detail": ty.to_string() })
+            })
+            .collect();
+        Some(items)
+    }
+}
+
+/// Read a message from the editor. This returns `None` when the input is closed.
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+
+    let length = length.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length header")
+    })?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(io::Error::from)
+}
+
+/// Send a message to the editor.
+fn write_message(output: &mut impl Write, message: Value) -> io::Result<()> {
+    let mut message = message;
+    message["jsonrpc"] = json!("2.0");
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+/// Serve the editor until it exits. This returns whether the server shut down cleanly.
+fn serve() -> io::Result<bool> {
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let stdout = io::stdout();
+    let mut output = stdout.lock();
+    let mut server = Server::default();
+
+    while let Some(message) = read_message(&mut input)? {
+        let method = message["method"].as_str().unwrap_or_default();
+        let params = &message["params"];
+        if method == "exit" {
+            return Ok(server.is_shutting_down);
+        }
+
+        match message.get("id") {
+            // Requests have an ID, and always get a response.
+            Some(id) => {
+                let response = match server.request(method, params) {
+                    Ok(result) => json!({ "id": id, "result": result }),
+                    Err((code, message)) => json!({
+                        "id": id,
+                        "error": { "code": code, "message": message },
+                    }),
+                };
+                write_message(&mut output, response)?;
+            }
+            None => {
+                for notification in server.notification(method, params) {
+                    write_message(&mut output, notification)?;
+                }
+            }
+        }
+    }
+    Ok(server.is_shutting_down)
+}
+
+fn main() {
+    match with_stack_size(STACK_SIZE_MB, serve) {
+        Ok(true) => {}
+        Ok(false) => std::process::exit(1),
+        Err(err) => {
+            eprintln!("sage-lsp: {err}");
+            std::process::exit(1)
+        }
+    }
+}
@@ -71,6 +71,47 @@ impl SourceCodeLocation {
 
         code
     }
+
+    /// Write the location as a comment for the assembly and virtual machine code,
+    /// so that the code compiled from this location can be traced back to it.
+    pub fn to_comment(&self) -> String {
+        let length = self.length.map_or("-".to_string(), |n| n.to_string());
+        let mut comment = format!(
+            "source {}:{}:{}:{}",
+            self.line, self.column, self.offset, length
+        );
+        if let Some(filename) = &self.filename {
+            comment += " ";
+            comment += filename;
+        }
+        comment
+    }
+
+    /// Read a location from a comment written by `to_comment`.
+    pub fn from_comment(comment: &str) -> Option<Self> {
+        let (numbers, filename) = match comment.strip_prefix("source ")?.split_once(' ') {
+            Some((numbers, filename)) => (numbers, Some(filename.to_string())),
+            None => (comment.strip_prefix("source ")?, None),
+        };
+        let mut numbers = numbers.split(':');
+        let line = numbers.next()?.parse().ok()?;
+        let column = numbers.next()?.parse().ok()?;
+        let offset = numbers.next()?.parse().ok()?;
+        let length = match numbers.next()? {
+            "-" => None,
+            n => Some(n.parse().ok()?),
+        };
+        if numbers.next().is_some() {
+            return None;
+        }
+        Some(Self {
+            line,
+            column,
+            offset,
+            length,
+            filename,
+        })
+    }
 }
 
 lalrpop_mod!(
@@ -153,6 +194,11 @@ pub fn parse_frontend(input: impl ToString, filename: Option<&str>) -> Result<Ex
     Ok(result)
 }
 
+/// Format frontend sage code in the canonical style.
+pub fn format_frontend(input: impl ToString, filename: Option<&str>) -> Result<String, String> {
+    frontend::format(input, filename)
+}
+
 type SyntaxError<'a, T> = lalrpop_util::ParseError<usize, T, &'a str>;
 
 /// This formats an error properly given the line, the `unexpected` token as a string,
@@ -0,0 +1,339 @@
+//! # The Sage REPL
+//!
+//! This module implements `sage repl`, an interactive session for the frontend language.
+//! Structs, procedures, impls, and variables can be declared one line at a time, and
+//! later lines can use everything declared before them.
+//!
+//! An expression entered without a trailing semicolon has its value and type printed.
+//! Everything else is a statement or declaration, which is added to the session.
+//!
+//! Each line is compiled on its own, in the environment left by the lines before it,
+//! and run by the interpreter which ran them. The variables declared at the top level
+//! stay on the interpreter's stack, and the procedures compiled for earlier lines stay
+//! in its code, so every line is only compiled and run once.
+
+use crate::{DiagnosticOptions, Error};
+use sage::{
+    asm::{
+        AssemblyProgram, CoreOp, CoreProgram, Location, StandardAssembler, StandardOp,
+        StandardProgram, REGISTERS,
+    },
+    frontend,
+    lir::{self, Compile, Env, Expr, GetSize, GetType, Put, Type, TypeCheck, UnaryOp, Warning},
+    parse::parse_frontend,
+    vm::{self, StandardDevice, StandardInterpreter},
+};
+use std::io::{self, IsTerminal, Write};
+
+const HELP: &str = "\
+Enter declarations and statements to add them to the session,
+or an expression without a trailing semicolon to print its value and type.
+
+Commands:
+  :type <expr>  Print the type of an expression
+  :asm <expr>   Print the assembly code an expression is compiled to
+  :reset        Forget everything declared in the session
+  :help         Print this message
+  :quit         Exit the REPL";
+
+/// The number of cells reserved for the static variables declared in the session.
+const SIZE_OF_GLOBALS: usize = 4096;
+
+/// The lines entered into the REPL so far, and the interpreter running them.
+struct Session {
+    /// The declarations made by the lines run so far.
+    env: Env,
+    /// The assembly code of the lines compiled so far.
+    asm: StandardProgram,
+    /// Assembles the code of each line after the code of the lines before it.
+    assembler: StandardAssembler,
+    /// The virtual machine code of the lines assembled so far.
+    code: vm::StandardProgram,
+    /// The interpreter running the session, which keeps its tape between lines.
+    interpreter: StandardInterpreter<StandardDevice>,
+    /// The number of cells allocated for the call stack.
+    call_stack_size: usize,
+}
+
+impl Session {
+    fn new(call_stack_size: usize) -> Self {
+        let (assembler, code) = StandardAssembler::new(call_stack_size, SIZE_OF_GLOBALS);
+        let mut interpreter = StandardInterpreter::new(StandardDevice::default());
+        interpreter
+            .execute_appended(&code)
+            .expect("setting up the registers can't fail");
+        Self {
+            env: Env::default(),
+            asm: StandardProgram::default(),
+            assembler,
+            code,
+            interpreter,
+            call_stack_size,
+        }
+    }
+
+    /// Parse a line, and type check it in the session's environment.
+    /// This returns the warnings about the line along with it.
+    fn check(&self, code: &str) -> Result<(Expr, Vec<Warning>), Error> {
+        let program = parse_frontend(code, None).map_err(Error::Parse)?;
+        let result = program.type_check(&self.env);
+        let warnings = self.env.take_warnings();
+        result.map_err(|e| Error::LirError(e).annotate_with_source(code))?;
+        Ok((program, warnings))
+    }
+
+    /// Compile a line after the code of the lines before it. The value of an expression
+    /// is printed, and its type is returned along with whether it could be printed.
+    fn compile(
+        &mut self,
+        program: Expr,
+        is_expr: bool,
+        env: &mut Env,
+    ) -> Result<Option<(Type, bool)>, lir::Error> {
+        let ty = program.compile_top_level(env, &mut self.asm)?;
+        let size = ty.get_size(env)?;
+        if !is_expr || ty == Type::None {
+            if size > 0 {
+                self.asm.op(CoreOp::Pop(None, size));
+            }
+            return Ok(None);
+        }
+
+        // Values which can't be printed, like procedures, only have their type shown.
+        let start = self.asm.code.len();
+        if Put::Display
+            .compile_types(&ty, &mut env.clone(), &mut self.asm)
+            .is_ok()
+        {
+            return Ok(Some((ty, true)));
+        }
+        self.asm.truncate(start);
+        if size > 0 {
+            self.asm.op(CoreOp::Pop(None, size));
+        }
+        Ok(Some((ty, false)))
+    }
+
+    /// Get the values of the registers, to put them back if a line fails while it's running.
+    fn registers(&self) -> Vec<(Location, i64)> {
+        let tape = self.interpreter.tape();
+        REGISTERS
+            .into_iter()
+            .filter_map(|register| match register {
+                Location::Address(n) => Some((register, tape.get(n).copied().unwrap_or(0))),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Put the registers back to the values they had before a line failed, so the
+    /// stack and its variables are the way the lines before it left them.
+    fn restore_registers(&mut self, registers: Vec<(Location, i64)>) {
+        let restore = StandardProgram::new(
+            registers
+                .into_iter()
+                .map(|(register, value)| StandardOp::CoreOp(CoreOp::Set(register, value)))
+                .collect(),
+        );
+        if let Ok(part) = self.assembler.assemble(&restore, 0) {
+            self.code.0.extend(part.0);
+            let _ = self.interpreter.execute_appended(&self.code);
+        }
+    }
+
+    /// Run a line in the session. Expressions have their value and type printed too.
+    fn eval(&mut self, line: &str, diagnostics: &DiagnosticOptions) -> Result<(), Error> {
+        let is_expr = !line.ends_with(';') && frontend::is_expr(line);
+        let code = if is_expr {
+            format!("{line};")
+        } else {
+            line.to_string()
+        };
+        let (program, warnings) = self.check(&code)?;
+
+        // Variables declared at the top level are used by the lines after them,
+        // so they aren't reported as unused. The value of an expression is printed,
+        // so it isn't unused either.
+        let warnings = warnings
+            .into_iter()
+            .filter(|warning| match warning.name() {
+                "unused-variable" => false,
+                "unused-value" => !is_expr,
+                _ => true,
+            })
+            .collect::<Vec<_>>();
+        diagnostics.report(&warnings, &code)?;
+
+        // The line's declarations are only added to the session once it has run.
+        let mut env = self.env.clone();
+        let start = self.asm.code.len();
+        let compiled = self
+            .compile(program, is_expr, &mut env)
+            .map_err(|e| Error::LirError(e).annotate_with_source(&code))
+            .and_then(|shown| {
+                let part = self
+                    .assembler
+                    .assemble(&self.asm, start)
+                    .map_err(Error::AsmError)?;
+                Ok((shown, part))
+            });
+        let (shown, part) = match compiled {
+            Ok(compiled) => compiled,
+            Err(e) => {
+                self.asm.truncate(start);
+                return Err(e);
+            }
+        };
+
+        self.code.0.extend(part.0);
+        let registers = self.registers();
+        if let Err(e) = self.interpreter.execute_appended(&self.code) {
+            self.restore_registers(registers);
+            return Err(Error::InterpreterError(e));
+        }
+        self.env = env;
+
+        match shown {
+            Some((ty, true)) => println!(" : {ty}"),
+            Some((ty, false)) => println!("<value> : {ty}"),
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// Print the type of an expression, without running it.
+    fn print_type(&self, line: &str) -> Result<(), Error> {
+        let (program, _) = self.check(&format!("{line};"))?;
+        let (expr, env) = program.last_statement(&self.env);
+        // Types are shown as they were declared, so named types keep their names.
+        println!(
+            "{}",
+            expr.get_type_checked(&env, 0).map_err(Error::LirError)?
+        );
+        Ok(())
+    }
+
+    /// Print the assembly code an expression is compiled to, in the session's environment.
+    fn print_asm(&self, line: &str) -> Result<(), Error> {
+        let code = format!("{line};");
+        let (program, _) = self.check(&code)?;
+        let (expr, mut env) = program.last_statement(&self.env);
+        let mut core_asm = CoreProgram::default();
+        if expr
+            .clone()
+            .compile_expr(&mut env.clone(), &mut core_asm)
+            .is_ok()
+        {
+            println!("{core_asm}");
+        } else {
+            let mut std_asm = StandardProgram::default();
+            expr.clone()
+                .compile_expr(&mut env, &mut std_asm)
+                .map_err(|e| Error::LirError(e).annotate_with_source(&code))?;
+            println!("{std_asm}");
+        }
+        Ok(())
+    }
+
+    /// Run a line entered into the REPL. This returns `false` when the REPL should exit.
+    fn run_line(&mut self, line: &str, diagnostics: &DiagnosticOptions) -> Result<bool, Error> {
+        let (command, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let rest = rest.trim();
+        match command {
+            ":quit" | ":q" => return Ok(false),
+            ":help" | ":h" => println!("{HELP}"),
+            ":reset" => *self = Self::new(self.call_stack_size),
+            ":type" | ":t" => self.print_type(rest)?,
+            ":asm" => self.print_asm(rest)?,
+            _ if command.starts_with(':') => {
+                println!("Unknown command `{command}`, try `:help`")
+            }
+            _ => self.eval(line, diagnostics)?,
+        }
+        Ok(true)
+    }
+}
+
+/// Does some code have an unclosed bracket, string, or comment? If so, the REPL
+/// keeps reading lines until it's complete.
+fn is_incomplete(code: &str) -> bool {
+    let mut depth = 0;
+    let mut chars = code.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth -= 1,
+            '"' | '\'' => loop {
+                match chars.next() {
+                    Some('\\') => {
+                        chars.next();
+                    }
+                    Some(end) if end == c => break,
+                    Some(_) => {}
+                    None => return true,
+                }
+            },
+            '#' => while chars.next_if(|&c| c != '\n').is_some() {},
+            '/' if chars.next_if_eq(&'/').is_some() => {
+                while chars.next_if(|&c| c != '\n').is_some() {}
+            }
+            '/' if chars.next_if_eq(&'*').is_some() => {
+                let mut comment_depth = 1;
+                while comment_depth > 0 {
+                    match chars.next() {
+                        Some('/') if chars.next_if_eq(&'*').is_some() => comment_depth += 1,
+                        Some('*') if chars.next_if_eq(&'/').is_some() => comment_depth -= 1,
+                        Some(_) => {}
+                        None => return true,
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    depth > 0
+}
+
+/// Run the REPL on stdin until it's closed or `:quit` is entered. The prompts
+/// are only printed when stdin is a terminal.
+pub(crate) fn repl(call_stack_size: usize, diagnostics: &DiagnosticOptions) -> io::Result<()> {
+    let stdin = io::stdin();
+    let interactive = stdin.is_terminal();
+    let mut session = Session::new(call_stack_size);
+    let prompt = |prompt: &str| -> io::Result<()> {
+        if interactive {
+            print!("{prompt}");
+            io::stdout().flush()?;
+        }
+        Ok(())
+    };
+
+    loop {
+        prompt(">>> ")?;
+        let mut input = String::new();
+        loop {
+            // Stdin is only locked while a line is read, so the lines run can read from it too.
+            if stdin.read_line(&mut input)? == 0 {
+                return Ok(());
+            }
+            if !input.ends_with('\n') {
+                input.push('\n');
+            }
+            if !is_incomplete(&input) {
+                break;
+            }
+            prompt("... ")?;
+        }
+
+        let line = input.trim();
+        if line.is_empty() {
+            continue;
+        }
+        match session.run_line(line, diagnostics) {
+            Ok(true) => {}
+            Ok(false) => return Ok(()),
+            Err(e) => diagnostics.report_error(&e),
+        }
+        io::stdout().flush()?;
+    }
+}
@@ -95,6 +95,9 @@ impl Architecture for SageOS {
             StandardOp::Div => "reg.f /= ptr->f;".to_string(),
             StandardOp::Rem => "reg.f = fmod(reg.f, ptr->f);".to_string(),
             StandardOp::Pow => "reg.f = pow(reg.f, ptr->f);".to_string(),
+            StandardOp::ShiftLeft => "reg.i = ptr->i <= 0 ? reg.i : ptr->i >= 64 ? 0 : (int64_t)((uint64_t)reg.i << ptr->i);".to_string(),
+            StandardOp::ShiftRight => "reg.i = ptr->i <= 0 ? reg.i : ptr->i >= 64 ? (reg.i < 0 ? -1 : 0) : reg.i >> ptr->i;".to_string(),
+            StandardOp::LogicalShiftRight => "reg.i = ptr->i <= 0 ? reg.i : ptr->i >= 64 ? 0 : (int64_t)((uint64_t)reg.i >> ptr->i);".to_string(),
             StandardOp::IsNonNegative => "reg.i = reg.f >= 0;".to_string(),
             StandardOp::Alloc => "reg.p = (cell*)salloc(reg.i * sizeof(reg));".to_string(),
             StandardOp::Free => "sfree((void*)reg.p);".to_string(),
@@ -2,6 +2,7 @@
 //!
 //! This module implements an interpreter for the Core virtual machine
 //! variant.
+use super::{JumpTable, Profile, SourceMap};
 use crate::vm::{CoreOp, CoreProgram, Device, StandardDevice};
 
 impl Default for CoreInterpreter<StandardDevice> {
@@ -11,11 +12,12 @@ impl Default for CoreInterpreter<StandardDevice> {
             pointer: 0,
             register: 0,
             cells: vec![],
-            functions: vec![],
+            jumps: JumpTable::default(),
             calls: vec![],
             refs: vec![],
             i: 0,
             done: false,
+            profile: None,
         }
     }
 }
@@ -33,9 +35,9 @@ where
     register: i64,
     /// The turing tape (composed of integer cells)
     cells: Vec<i64>,
-    /// The addresses of defined functions. `functions[N]` is the
-    /// instruction pointer for the Nth function's code.
-    functions: Vec<usize>,
+    /// The instructions each block instruction jumps to, and the
+    /// addresses of the program's functions, resolved before it's run.
+    jumps: JumpTable,
     /// The call stack of instruction pointers. Whenever a function
     /// is called, the instruction pointer is pushed here. Whenever
     /// a function returns, its instruction pointer is popped from here.
@@ -47,6 +49,8 @@ where
     i: usize,
     /// Is the interpreter finished interpreting?s
     done: bool,
+    /// The profiler, if the interpreter is in profiling mode.
+    profile: Option<Profile>,
 }
 
 impl<T> CoreInterpreter<T>
@@ -59,14 +63,32 @@ where
             pointer: 0,
             register: 0,
             cells: vec![],
-            functions: vec![],
+            jumps: JumpTable::default(),
             calls: vec![],
             refs: vec![],
             i: 0,
             done: false,
+            profile: None,
         }
     }
 
+    /// Count the instructions the program runs by opcode and by function
+    /// while it runs (see the `profile` module).
+    pub fn with_profile(mut self) -> Self {
+        self.profile = Some(Profile::default());
+        self
+    }
+
+    /// Get the profile of the program so far, if the interpreter is in profiling mode.
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    /// Take the interpreter's I/O device.
+    pub fn into_device(self) -> T {
+        self.device
+    }
+
     /// Fetch the current instruction pointed to in the program
     fn fetch<'a>(&self, code: &'a CoreProgram) -> Option<&'a CoreOp> {
         if self.i < code.0.len() {
@@ -102,43 +124,18 @@ where
     }
 
     /// Call the Nth function defined in the program, where N is the value of the register.
-    fn call(&mut self, code: &CoreProgram) -> Result<(), String> {
-        // If the function has been defined
-        if self.functions.len() > self.register as usize {
-            // Push the current instruction pointer to the call stack
-            self.calls.push(self.i);
-            self.i = self.functions[self.register as usize];
-            Ok(())
-        } else {
-            // If the function hasn't been defined yet, we'll have to find it.
-
-            // Push the return address onto the call stack.
-            self.calls.push(self.i);
-            // Scan all the function definitions from the start of the program until we find it.
-            self.i = 0;
-            let mut count = -1;
-            while count < self.register {
-                // Every time we find a function, it will increment `count`.
-                match self.fetch(code) {
-                    Some(CoreOp::Function) => {
-                        count += 1;
-                    }
-                    Some(_) => {}
-                    None => return Err(format!("function {} not defined", self.register)),
-                }
-                // If `count` hasn't reached the function we want,
-                // keep going.
-                if count < self.register {
-                    self.i += 1;
+    fn call(&mut self) -> Result<(), String> {
+        match self.jumps.function(self.register) {
+            Some(function) => {
+                // Push the return address onto the call stack, and jump to the function.
+                self.calls.push(self.i);
+                self.i = function;
+                if let Some(profile) = &mut self.profile {
+                    profile.call(self.register as usize);
                 }
+                Ok(())
             }
-
-            // If we've reached the function we want, add it to the definitions.
-            if !self.functions.contains(&self.i) {
-                self.functions.push(self.i);
-                self.functions.sort()
-            }
-            Ok(())
+            None => Err(format!("function {} not defined", self.register)),
         }
     }
 
@@ -146,84 +143,21 @@ where
     fn ret(&mut self) {
         // If we're returning from a function, jump to the old instruction pointer.
         if let Some(old) = self.calls.pop() {
-            self.i = old
+            self.i = old;
+            if let Some(profile) = &mut self.profile {
+                profile.ret();
+            }
         } else {
             // Otherwise, we're finished with the program.
             self.done = true
         }
     }
 
-    /// Jump to the matching "Else" for this "If" instruction.
-    fn jmp_to_else(&mut self, code: &CoreProgram) {
-        let mut matching = 1;
-        while matching > 0 {
-            self.i += 1;
-            match self.fetch(code) {
-                Some(CoreOp::If) | Some(CoreOp::While) | Some(CoreOp::Function) => matching += 1,
-                Some(CoreOp::End) => matching -= 1,
-                Some(CoreOp::Else) if matching == 1 => return,
-                _ => {}
-            }
-        }
-    }
-
-    /// Jump to the matching "End" for this "Else", "While",
-    /// or "Function" instruction.
-    fn jmp_to_end(&mut self, code: &CoreProgram) {
-        let mut matching = 1;
-        while matching > 0 {
-            self.i += 1;
-            match self.fetch(code) {
-                Some(CoreOp::If) | Some(CoreOp::While) | Some(CoreOp::Function) => matching += 1,
-                Some(CoreOp::End) => matching -= 1,
-                _ => {}
-            }
-        }
-    }
-
-    /// Jump back to the matching instruction for a given "End" instruction.
-    ///
-    /// ```hs
-    /// If (ends up here)
-    ///     ...
-    /// End (calls jmp_back_to_matching)
-    /// ```
-    fn jmp_back_to_matching(&mut self, code: &CoreProgram) {
-        let mut matching = 1;
-        while matching > 0 {
-            self.i -= 1;
-            match self.fetch(code) {
-                Some(CoreOp::If) | Some(CoreOp::While) | Some(CoreOp::Function) => matching -= 1,
-                Some(CoreOp::End) => matching += 1,
-                _ => {}
-            }
-        }
-    }
-
-    /// Get the matching instruction for a given "End" instruction.
-    ///
-    /// ```hs
-    /// If (this function would return this instruction)
-    ///    ...
-    /// End (called on this as the current instruction)
-    /// ```
-    fn get_matching_for_end<'a>(&mut self, code: &'a CoreProgram) -> Option<&'a CoreOp> {
-        let mut matching = 1;
-        let mut i = self.i;
-        while matching > 0 && i > 0 {
-            i -= 1;
-            match code.0[i] {
-                CoreOp::If | CoreOp::While | CoreOp::Function => matching -= 1,
-                CoreOp::End => matching += 1,
-                _ => {}
-            }
-        }
-
-        if matching == 0 {
-            Some(&code.0[i])
-        } else {
-            None
-        }
+    /// Jump to the instruction matched with the current block instruction:
+    /// the "Else" or "End" of an "If", or the "End" of an "Else", "While",
+    /// or "Function".
+    fn jump(&mut self) {
+        self.i = self.jumps.jump(self.i)
     }
 
     /// Get the current cell pointed to on the turing tape.
@@ -235,44 +169,55 @@ where
         &mut self.cells[self.pointer]
     }
 
-    /// Run a core program using this interpreter and its device.
-    pub fn run(mut self, code: &CoreProgram) -> Result<T, String> {
+    /// Run a core program using this interpreter and its device, like `run`,
+    /// but keep the interpreter to inspect its state afterwards.
+    pub fn execute(&mut self, code: &CoreProgram) -> Result<(), String> {
+        // Match up the blocks and functions once, so branches don't scan the program.
+        self.jumps = JumpTable::new(code.0.iter().map(Some));
+        if let Some(profile) = &mut self.profile {
+            *profile = Profile::new(&code.0, SourceMap::new(code.0.iter().map(Some)));
+        }
         while !self.done {
             self.step(code)?
         }
+        Ok(())
+    }
+
+    /// Run a core program using this interpreter and its device.
+    pub fn run(mut self, code: &CoreProgram) -> Result<T, String> {
+        self.execute(code)?;
         Ok(self.device)
     }
 
     /// Run a single step of the interpreter.
     fn step(&mut self, code: &CoreProgram) -> Result<(), String> {
         if let Some(op) = self.fetch(code) {
+            if let Some(profile) = &mut self.profile {
+                profile.record(self.i);
+            }
             match op {
                 CoreOp::Comment(_) => {}
                 CoreOp::Set(n) => self.register = *n,
-                CoreOp::Function => {
-                    if !self.functions.contains(&self.i) {
-                        self.functions.push(self.i);
-                        self.functions.sort()
-                    }
-                    self.jmp_to_end(code)
-                }
-                CoreOp::Call => self.call(code)?,
+                CoreOp::Function => self.jump(),
+                CoreOp::Call => self.call()?,
                 CoreOp::Return => self.ret(),
                 CoreOp::While => {
                     if self.register == 0 {
-                        self.jmp_to_end(code)
+                        self.jump()
                     }
                 }
                 CoreOp::If => {
                     if self.register == 0 {
-                        self.jmp_to_else(code)
+                        self.jump()
                     }
                 }
-                CoreOp::Else => self.jmp_to_end(code),
+                CoreOp::Else => self.jump(),
                 CoreOp::End => {
                     if self.register != 0 {
-                        if let Some(CoreOp::While) = self.get_matching_for_end(code) {
-                            self.jmp_back_to_matching(code)
+                        // Loop back to the start of a "While" block.
+                        let start = self.jumps.jump(self.i);
+                        if let Some(CoreOp::While) = code.0.get(start) {
+                            self.i = start
                         }
                     }
                 }
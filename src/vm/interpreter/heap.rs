@@ -0,0 +1,209 @@
+//! # Heap Module
+//!
+//! This module implements the allocator the standard interpreter uses for the
+//! `Alloc` and `Free` instructions. The heap is a region of the turing tape
+//! after the stack. Freed blocks are kept in a free list, and are reused by
+//! later allocations (split to size, and merged with their freed neighbors).
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+/// The number of cells left between the tape used before the first allocation
+/// and the start of the heap, for the stack to grow into.
+const STACK_SPACE: usize = 30000;
+
+/// Statistics about the heap usage of a program.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HeapStats {
+    /// The number of allocations made.
+    pub allocations: usize,
+    /// The number of blocks freed.
+    pub frees: usize,
+    /// The number of blocks which are allocated and haven't been freed.
+    pub live_blocks: usize,
+    /// The number of cells in the blocks which are allocated and haven't been freed.
+    pub live_cells: usize,
+    /// The most cells that were allocated at once.
+    pub peak_cells: usize,
+    /// The number of cells the heap spans on the tape, including the freed blocks.
+    pub heap_cells: usize,
+}
+
+/// The allocator for the heap of the standard interpreter.
+#[derive(Clone, Debug, Default)]
+pub(super) struct Heap {
+    /// The address the heap starts at, decided by the first allocation.
+    start: Option<usize>,
+    /// The address after the last block of the heap.
+    end: usize,
+    /// The address after the last block the heap has ever had.
+    high_water: usize,
+    /// The number of unused cells reserved after each block, to catch
+    /// accesses past the end of a block.
+    guard: usize,
+    /// The size of each allocated block, by its address.
+    allocated: BTreeMap<usize, usize>,
+    /// The size of each free block, by its address.
+    free_by_addr: BTreeMap<usize, usize>,
+    /// The free blocks, ordered by their size and then their address.
+    free_by_size: BTreeSet<(usize, usize)>,
+    /// The addresses of freed blocks, to tell double frees apart from bad pointers.
+    freed: HashSet<usize>,
+    /// The statistics which aren't computed from the blocks.
+    stats: HeapStats,
+}
+
+/// The part of the heap an address on the tape belongs to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum Region {
+    /// The address is before the heap, in the globals or the stack.
+    Outside,
+    /// The address is in an allocated block.
+    Allocated,
+    /// The address is in a block which was freed, and hasn't been allocated again.
+    Freed,
+    /// The address is in the heap, but not in any block (like a guard cell).
+    OutOfBounds,
+}
+
+impl Heap {
+    /// Create a heap which reserves unused cells after each block.
+    pub(super) fn with_guard(guard: usize) -> Self {
+        Self {
+            guard,
+            ..Self::default()
+        }
+    }
+
+    /// Allocate a block of cells, and return its address. The length of the
+    /// tape is used to place the heap on the first allocation.
+    pub(super) fn alloc(&mut self, size: usize, tape_len: usize) -> usize {
+        let start = *self.start.get_or_insert_with(|| {
+            // This is the address the first block has always been given.
+            if tape_len < STACK_SPACE {
+                tape_len + STACK_SPACE - 1
+            } else {
+                tape_len
+            }
+        });
+        if self.end < start {
+            self.end = start;
+        }
+        // Every block gets its own address, even if it's empty.
+        let size = size.max(1);
+        let reserved = size + self.guard;
+
+        // Use the smallest free block which fits, and free what's left of it.
+        let addr = match self.free_by_size.range((reserved, 0)..).next().copied() {
+            Some((free_size, addr)) => {
+                self.remove_free(addr, free_size);
+                if free_size > reserved {
+                    self.insert_free(addr + reserved, free_size - reserved);
+                }
+                addr
+            }
+            None => {
+                self.end += reserved;
+                self.high_water = self.high_water.max(self.end);
+                self.end - reserved
+            }
+        };
+
+        self.allocated.insert(addr, size);
+        self.freed.remove(&addr);
+        self.stats.allocations += 1;
+        self.stats.live_cells += size;
+        self.stats.peak_cells = self.stats.peak_cells.max(self.stats.live_cells);
+        addr
+    }
+
+    /// Free the block at an address, and return its size.
+    pub(super) fn free(&mut self, addr: i64) -> Result<usize, String> {
+        let addr = usize::try_from(addr)
+            .map_err(|_| format!("address {addr} isn't the start of a heap allocation"))?;
+        let size = match self.allocated.remove(&addr) {
+            Some(size) => size,
+            None if self.freed.contains(&addr) => {
+                return Err(format!("address {addr} was already freed"))
+            }
+            None => {
+                return Err(format!(
+                    "address {addr} isn't the start of a heap allocation"
+                ))
+            }
+        };
+        self.freed.insert(addr);
+        self.stats.frees += 1;
+        self.stats.live_cells -= size;
+
+        // Merge the block with the free blocks on either side of it.
+        let (mut block_addr, mut block_size) = (addr, size + self.guard);
+        if let Some((&prev_addr, &prev_size)) = self.free_by_addr.range(..addr).next_back() {
+            if prev_addr + prev_size == addr {
+                self.remove_free(prev_addr, prev_size);
+                block_addr = prev_addr;
+                block_size += prev_size;
+            }
+        }
+        if let Some(&next_size) = self.free_by_addr.get(&(addr + size + self.guard)) {
+            self.remove_free(addr + size + self.guard, next_size);
+            block_size += next_size;
+        }
+
+        // A free block at the end of the heap is given back to the tape.
+        if block_addr + block_size == self.end {
+            self.end = block_addr;
+        } else {
+            self.insert_free(block_addr, block_size);
+        }
+        Ok(size)
+    }
+
+    /// Get the allocated blocks, as their addresses and sizes.
+    pub(super) fn blocks(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.allocated.iter().map(|(&addr, &size)| (addr, size))
+    }
+
+    /// Find the part of the heap an address belongs to.
+    pub(super) fn region(&self, addr: usize) -> Region {
+        match self.start {
+            Some(start) if addr >= start => {}
+            _ => return Region::Outside,
+        }
+        if let Some((&block, &size)) = self.allocated.range(..=addr).next_back() {
+            if addr < block + size {
+                return Region::Allocated;
+            }
+        }
+        if let Some((&block, &size)) = self.free_by_addr.range(..=addr).next_back() {
+            if addr < block + size {
+                return Region::Freed;
+            }
+        }
+        // The blocks freed at the end of the heap were given back to the tape.
+        if self.end <= addr && addr < self.high_water {
+            Region::Freed
+        } else {
+            Region::OutOfBounds
+        }
+    }
+
+    /// Get the statistics about the heap usage so far.
+    pub(super) fn stats(&self) -> HeapStats {
+        HeapStats {
+            live_blocks: self.allocated.len(),
+            heap_cells: self.start.map_or(0, |start| self.end - start),
+            ..self.stats
+        }
+    }
+
+    /// Add a block to the free list.
+    fn insert_free(&mut self, addr: usize, size: usize) {
+        self.free_by_addr.insert(addr, size);
+        self.free_by_size.insert((size, addr));
+    }
+
+    /// Remove a block from the free list.
+    fn remove_free(&mut self, addr: usize, size: usize) {
+        self.free_by_addr.remove(&addr);
+        self.free_by_size.remove(&(size, addr));
+    }
+}
@@ -0,0 +1,100 @@
+//! # Jump Table Module
+//!
+//! This module resolves the control flow of a virtual machine program before
+//! it's run. Every block instruction is matched with the instruction it jumps
+//! to, and every function is matched with its entry point, so the interpreters
+//! can branch in constant time instead of scanning through the program.
+use crate::vm::CoreOp;
+
+/// The jumps for the block instructions of a program, and the entry points of its functions.
+#[derive(Clone, Debug, Default)]
+pub(super) struct JumpTable {
+    /// The instruction each block instruction jumps to, indexed by the block instruction.
+    ///
+    /// - An `If` jumps to its `Else`, or to its `End` if it has no `Else`.
+    /// - An `Else`, `While`, or `Function` jumps to its `End`.
+    /// - An `End` jumps back to the `If`, `While`, or `Function` it closes.
+    ///
+    /// Instructions without a match jump past the end of the program, except
+    /// for an `End`, which "jumps" to itself.
+    jumps: Vec<usize>,
+    /// The entry point of each function, in the order they're defined.
+    functions: Vec<usize>,
+}
+
+impl JumpTable {
+    /// Resolve the jumps of a program. The instructions which aren't core
+    /// instructions (like the standard ones) are given as `None`.
+    pub(super) fn new<'a>(ops: impl IntoIterator<Item = Option<&'a CoreOp>>) -> Self {
+        let mut table = Self::default();
+        table.extend(ops);
+        table
+    }
+
+    /// Resolve the jumps of instructions added to the end of the program.
+    /// Their blocks can't be closed by the instructions before them.
+    pub(super) fn extend<'a>(&mut self, ops: impl IntoIterator<Item = Option<&'a CoreOp>>) {
+        let first = self.jumps.len();
+        let Self { jumps, functions } = self;
+        // The open blocks, whether they're `If` blocks, and the `Else` instructions found in them.
+        let mut blocks: Vec<(usize, bool, Vec<usize>)> = vec![];
+
+        for (i, op) in (first..).zip(ops) {
+            jumps.push(usize::MAX);
+            match op {
+                Some(CoreOp::Function) => {
+                    functions.push(i);
+                    blocks.push((i, false, vec![]));
+                }
+                Some(CoreOp::If) => blocks.push((i, true, vec![])),
+                Some(CoreOp::While) => blocks.push((i, false, vec![])),
+                Some(CoreOp::Else) => {
+                    if let Some((start, is_if, elses)) = blocks.last_mut() {
+                        if *is_if && elses.is_empty() {
+                            jumps[*start] = i;
+                        }
+                        elses.push(i);
+                    }
+                }
+                Some(CoreOp::End) => match blocks.pop() {
+                    Some((start, _, elses)) => {
+                        if jumps[start] == usize::MAX {
+                            jumps[start] = i;
+                        }
+                        for else_ in elses {
+                            jumps[else_] = i;
+                        }
+                        jumps[i] = start;
+                    }
+                    None => jumps[i] = i,
+                },
+                _ => {}
+            }
+        }
+
+        // The blocks which are never closed jump past the end of the program.
+        let len = jumps.len();
+        for jump in &mut jumps[first..] {
+            if *jump == usize::MAX {
+                *jump = len;
+            }
+        }
+    }
+
+    /// Get the number of instructions resolved.
+    pub(super) fn len(&self) -> usize {
+        self.jumps.len()
+    }
+
+    /// Get the instruction a block instruction jumps to.
+    pub(super) fn jump(&self, i: usize) -> usize {
+        self.jumps[i]
+    }
+
+    /// Get the entry point of the Nth function defined in the program.
+    pub(super) fn function(&self, n: i64) -> Option<usize> {
+        usize::try_from(n)
+            .ok()
+            .and_then(|n| self.functions.get(n).copied())
+    }
+}
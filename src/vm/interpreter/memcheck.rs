@@ -0,0 +1,236 @@
+//! # Memory Checker Module
+//!
+//! This module implements the memory checker the standard interpreter runs
+//! with in its memcheck mode. It follows the program's accesses to the tape,
+//! and reports:
+//!
+//! - Reads and writes of blocks which were freed.
+//! - Reads and writes outside of any allocated block on the heap. Every block
+//!   is followed by a guard cell, so accesses just past its end are caught.
+//! - Uses of uninitialized heap cells. Like valgrind, copying an uninitialized
+//!   value around is allowed, and only branching on it, writing it out, using
+//!   it as an address, or passing it to an instruction like `Alloc` or `Free`
+//!   is reported.
+//! - Blocks which were never freed when the program exits.
+//!
+//! Each error is reported once for the instruction which caused it, along with
+//! the source code location of the instruction if the program records them.
+use super::{heap::Region, Heap, SourceMap};
+use crate::parse::SourceCodeLocation;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+};
+
+/// The number of guard cells reserved after each block on the heap.
+pub(super) const GUARD_CELLS: usize = 1;
+
+/// How an instruction accessed a cell.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Access {
+    /// The instruction read the cell.
+    Read,
+    /// The instruction wrote to the cell.
+    Write,
+}
+
+/// The kinds of memory errors the memory checker finds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MemoryErrorKind {
+    /// A cell in a block which was freed was accessed.
+    UseAfterFree(Access),
+    /// A cell on the heap outside of any allocated block was accessed.
+    OutOfBounds(Access),
+    /// A value read from a cell which was never written to was used.
+    UninitializedRead,
+    /// Some blocks allocated by the instruction were never freed.
+    Leak {
+        /// The number of blocks which were never freed.
+        blocks: usize,
+        /// The number of cells in the blocks.
+        cells: usize,
+    },
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Read => write!(f, "read"),
+            Self::Write => write!(f, "write"),
+        }
+    }
+}
+
+/// A memory error found by the memory checker.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MemoryError {
+    /// The kind of error.
+    pub kind: MemoryErrorKind,
+    /// The address of the cell accessed (or for an uninitialized value, the
+    /// heap cell it was first read from), or the first block leaked.
+    pub address: usize,
+    /// The instruction which accessed the cell, or allocated the leaked blocks.
+    pub instruction: usize,
+    /// The source code location of the instruction, if the program recorded it.
+    pub location: Option<SourceCodeLocation>,
+}
+
+impl fmt::Display for MemoryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            MemoryErrorKind::UseAfterFree(access) => write!(
+                f,
+                "invalid {access} of freed address {} by instruction #{}",
+                self.address, self.instruction
+            ),
+            MemoryErrorKind::OutOfBounds(access) => write!(
+                f,
+                "invalid {access} of address {} outside of any allocation by instruction #{}",
+                self.address, self.instruction
+            ),
+            MemoryErrorKind::UninitializedRead => write!(
+                f,
+                "use of uninitialized value read from address {} by instruction #{}",
+                self.address, self.instruction
+            ),
+            MemoryErrorKind::Leak { blocks, cells } => write!(
+                f,
+                "{cells} cell(s) in {blocks} block(s) allocated by instruction #{} were never freed",
+                self.instruction
+            ),
+        }
+    }
+}
+
+/// The state of the memory checker for a running program.
+#[derive(Clone, Debug, Default)]
+pub(super) struct Memcheck {
+    /// The source code locations of the program's instructions.
+    source_map: SourceMap,
+    /// The cells on the tape which hold uninitialized values, and the
+    /// heap cells their values were first read from.
+    uninitialized: HashMap<usize, usize>,
+    /// The heap cell the uninitialized value in the register was first
+    /// read from, if it holds one.
+    register: Option<usize>,
+    /// The instruction which allocated each block on the heap, by its address.
+    allocations: HashMap<usize, usize>,
+    /// The errors found so far, and the errors already reported for each instruction.
+    errors: Vec<MemoryError>,
+    reported: HashSet<(MemoryErrorKind, usize)>,
+}
+
+impl Memcheck {
+    /// Check a program with the given source code locations.
+    pub(super) fn new(source_map: SourceMap) -> Self {
+        Self {
+            source_map,
+            ..Self::default()
+        }
+    }
+
+    /// Get the errors found so far.
+    pub(super) fn errors(&self) -> &[MemoryError] {
+        &self.errors
+    }
+
+    /// Report an error, unless the instruction already reported the same kind of error.
+    fn report(&mut self, kind: MemoryErrorKind, address: usize, instruction: usize) {
+        if self.reported.insert((kind, instruction)) {
+            self.errors.push(MemoryError {
+                kind,
+                address,
+                instruction,
+                location: self.source_map.location(instruction).cloned(),
+            });
+        }
+    }
+
+    /// Check that an instruction can access a cell on the tape.
+    fn check_access(&mut self, heap: &Heap, addr: usize, access: Access, instruction: usize) {
+        match heap.region(addr) {
+            Region::Outside | Region::Allocated => {}
+            Region::Freed => self.report(MemoryErrorKind::UseAfterFree(access), addr, instruction),
+            Region::OutOfBounds => {
+                self.report(MemoryErrorKind::OutOfBounds(access), addr, instruction)
+            }
+        }
+    }
+
+    /// An instruction read a cell into the register, replacing its value.
+    pub(super) fn load(&mut self, heap: &Heap, addr: usize, instruction: usize) {
+        self.check_access(heap, addr, Access::Read, instruction);
+        self.register = self.uninitialized.get(&addr).copied();
+    }
+
+    /// An instruction combined a cell with the value in the register.
+    pub(super) fn combine(&mut self, heap: &Heap, addr: usize, instruction: usize) {
+        self.check_access(heap, addr, Access::Read, instruction);
+        if self.register.is_none() {
+            self.register = self.uninitialized.get(&addr).copied();
+        }
+    }
+
+    /// An instruction wrote the register to a cell.
+    pub(super) fn store(&mut self, heap: &Heap, addr: usize, instruction: usize) {
+        self.check_access(heap, addr, Access::Write, instruction);
+        match self.register {
+            Some(origin) => self.uninitialized.insert(addr, origin),
+            None => self.uninitialized.remove(&addr),
+        };
+    }
+
+    /// An instruction used a cell as an address.
+    pub(super) fn use_cell(&mut self, heap: &Heap, addr: usize, instruction: usize) {
+        self.check_access(heap, addr, Access::Read, instruction);
+        if let Some(&origin) = self.uninitialized.get(&addr) {
+            self.report(MemoryErrorKind::UninitializedRead, origin, instruction);
+        }
+    }
+
+    /// An instruction set the register to an initialized value.
+    pub(super) fn define(&mut self) {
+        self.register = None;
+    }
+
+    /// An instruction used the value in the register.
+    pub(super) fn use_register(&mut self, instruction: usize) {
+        if let Some(origin) = self.register {
+            self.report(MemoryErrorKind::UninitializedRead, origin, instruction);
+            // The error is only reported where the value is first used.
+            self.register = None;
+        }
+    }
+
+    /// An instruction allocated a block, whose cells are uninitialized.
+    pub(super) fn alloc(&mut self, addr: usize, size: usize, instruction: usize) {
+        self.uninitialized
+            .extend((addr..addr + size).map(|cell| (cell, cell)));
+        self.allocations.insert(addr, instruction);
+        self.register = None;
+    }
+
+    /// An instruction freed a block.
+    pub(super) fn free(&mut self, addr: usize, size: usize) {
+        for cell in addr..addr + size {
+            self.uninitialized.remove(&cell);
+        }
+        self.allocations.remove(&addr);
+    }
+
+    /// Report the blocks which were never freed when the program exited.
+    pub(super) fn exit(&mut self, heap: &Heap) {
+        // Leaks are grouped by the instruction which allocated them.
+        let mut leaks = BTreeMap::new();
+        for (addr, size) in heap.blocks() {
+            let instruction = self.allocations[&addr];
+            let (first, blocks, cells) = leaks.entry(instruction).or_insert((addr, 0, 0));
+            *first = addr.min(*first);
+            *blocks += 1;
+            *cells += size;
+        }
+        for (instruction, (addr, blocks, cells)) in leaks {
+            self.report(MemoryErrorKind::Leak { blocks, cells }, addr, instruction);
+        }
+    }
+}
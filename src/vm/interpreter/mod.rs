@@ -10,6 +10,19 @@ use crate::side_effects::{FFIBinding, Input, InputMode, Output, OutputMode};
 
 use log::{error, trace, warn};
 
+mod heap;
+use heap::Heap;
+pub use heap::HeapStats;
+mod jumps;
+use jumps::JumpTable;
+mod memcheck;
+use memcheck::Memcheck;
+pub use memcheck::{Access, MemoryError, MemoryErrorKind};
+mod profile;
+pub use profile::{FunctionProfile, Profile};
+mod source_map;
+pub use source_map::{FunctionName, SourceMap};
+
 mod core;
 pub use self::core::*;
 mod std;
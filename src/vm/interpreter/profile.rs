@@ -0,0 +1,292 @@
+//! # Profiler Module
+//!
+//! This module implements the profiler the interpreters run with in their
+//! profiling mode. It counts the instructions a program runs by opcode and by
+//! the function which ran them, and keeps track of the call stacks they were
+//! run in. A profile is reported as a summary table (with its `Display`
+//! implementation), or as folded stacks for flamegraph tools like
+//! `flamegraph.pl` and `inferno-flamegraph`.
+//!
+//! Functions are named with the names the compiler recorded for them (see
+//! `Compiler::source_locations`), or with their index if it didn't.
+use super::{FunctionName, SourceMap};
+use crate::vm::CoreOp;
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap},
+    fmt,
+};
+
+/// A frame in the tree of the call stacks a program ran.
+#[derive(Clone, Debug)]
+struct Frame {
+    /// The function run in the frame, or `None` for the top level of the program.
+    function: Option<usize>,
+    /// The frame which called this one. The top level is its own parent.
+    parent: usize,
+    /// The frames called from this one, by the function they run.
+    children: HashMap<usize, usize>,
+    /// The number of instructions run in the frame itself.
+    instructions: u64,
+}
+
+/// The instructions run by a function of a profiled program.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FunctionProfile {
+    /// The index the function is called with, or `None` for the top level of the program.
+    pub function: Option<usize>,
+    /// The names of the function, if they were recorded.
+    pub name: Option<FunctionName>,
+    /// The number of times the function was called.
+    pub calls: u64,
+    /// The number of instructions run by the function itself.
+    pub instructions: u64,
+    /// The number of instructions run by the function and the functions it called.
+    pub total_instructions: u64,
+}
+
+impl fmt::Display for FunctionProfile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.name, self.function) {
+            (
+                Some(FunctionName {
+                    mangled,
+                    common: Some(common),
+                }),
+                _,
+            ) => {
+                write!(f, "{common} ({mangled})")
+            }
+            (Some(name), _) => write!(f, "{name}"),
+            (None, Some(n)) => write!(f, "function #{n}"),
+            (None, None) => write!(f, "<top level>"),
+        }
+    }
+}
+
+/// The instructions a program ran, by opcode and by function.
+#[derive(Clone, Debug)]
+pub struct Profile {
+    /// The source code locations and the function names of the program.
+    source_map: SourceMap,
+    /// The opcode of each instruction in the program, or `None` for comments,
+    /// which aren't counted since they don't do anything.
+    opcodes: Vec<Option<String>>,
+    /// The number of times each instruction was run.
+    counts: Vec<u64>,
+    /// The number of times each function was called, by its index.
+    calls: HashMap<usize, u64>,
+    /// The tree of call stacks the program ran, starting with the top level.
+    frames: Vec<Frame>,
+    /// The frame running now.
+    current: usize,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self::new::<CoreOp>(&[], SourceMap::default())
+    }
+}
+
+impl Profile {
+    /// Profile a program with the given source code locations and function names.
+    pub(super) fn new<T: fmt::Display>(ops: &[T], source_map: SourceMap) -> Self {
+        let opcodes = ops
+            .iter()
+            .map(|op| {
+                let op = op.to_string();
+                // The opcode is the first word of the instruction.
+                (!op.starts_with("//"))
+                    .then(|| op.split(' ').next().unwrap_or_default().to_string())
+            })
+            .collect();
+        Self {
+            source_map,
+            opcodes,
+            counts: vec![0; ops.len()],
+            calls: HashMap::new(),
+            frames: vec![Frame {
+                function: None,
+                parent: 0,
+                children: HashMap::new(),
+                instructions: 0,
+            }],
+            current: 0,
+        }
+    }
+
+    /// An instruction is about to be run.
+    pub(super) fn record(&mut self, instruction: usize) {
+        if let Some(Some(_)) = self.opcodes.get(instruction) {
+            self.counts[instruction] += 1;
+            self.frames[self.current].instructions += 1;
+        }
+    }
+
+    /// The Nth function defined in the program was called.
+    pub(super) fn call(&mut self, function: usize) {
+        *self.calls.entry(function).or_default() += 1;
+        let (parent, next) = (self.current, self.frames.len());
+        self.current = *self.frames[parent].children.entry(function).or_insert(next);
+        if self.current == next {
+            self.frames.push(Frame {
+                function: Some(function),
+                parent,
+                children: HashMap::new(),
+                instructions: 0,
+            });
+        }
+    }
+
+    /// The running function returned.
+    pub(super) fn ret(&mut self) {
+        self.current = self.frames[self.current].parent;
+    }
+
+    /// Get the total number of instructions the program ran.
+    pub fn instructions(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Get the number of instructions run with each opcode, most first.
+    pub fn opcodes(&self) -> Vec<(String, u64)> {
+        let mut opcodes: BTreeMap<String, u64> = BTreeMap::new();
+        for (opcode, count) in self.opcodes.iter().zip(&self.counts) {
+            if let (Some(opcode), 1..) = (opcode, count) {
+                *opcodes.entry(opcode.clone()).or_default() += count;
+            }
+        }
+        let mut opcodes: Vec<_> = opcodes.into_iter().collect();
+        opcodes.sort_by(|(_, a), (_, b)| b.cmp(a));
+        opcodes
+    }
+
+    /// Get the instructions run by each function (and the top level of the
+    /// program), most instructions run by the function itself first.
+    pub fn functions(&self) -> Vec<FunctionProfile> {
+        // The instructions run in each frame and the frames it called.
+        // Frames are always added after the frame which called them.
+        let mut totals: Vec<u64> = self.frames.iter().map(|frame| frame.instructions).collect();
+        for (i, frame) in self.frames.iter().enumerate().skip(1).rev() {
+            totals[frame.parent] += totals[i];
+        }
+
+        let mut functions = BTreeMap::new();
+        // The number of frames running each function on the current stack.
+        let mut running: HashMap<Option<usize>, usize> = HashMap::new();
+        self.walk(|frame, entering| {
+            let function = self.frames[frame].function;
+            let running = running.entry(function).or_default();
+            if !entering {
+                *running -= 1;
+                return;
+            }
+            let profile = functions
+                .entry(function)
+                .or_insert_with(|| FunctionProfile {
+                    function,
+                    name: function.and_then(|n| self.source_map.function_name(n).cloned()),
+                    calls: function.map_or(0, |n| self.calls[&n]),
+                    instructions: 0,
+                    total_instructions: 0,
+                });
+            profile.instructions += self.frames[frame].instructions;
+            // The instructions of a recursive call are already in the total of the call around it.
+            if *running == 0 {
+                profile.total_instructions += totals[frame];
+            }
+            *running += 1;
+        });
+
+        let mut functions: Vec<_> = functions.into_values().collect();
+        functions.sort_by_key(|function| Reverse(function.instructions));
+        functions
+    }
+
+    /// Get the call stacks the program ran instructions in, in the folded format
+    /// read by flamegraph tools: each line has the functions on the stack, from the
+    /// top level in, separated by semicolons, and the number of instructions run.
+    pub fn folded_stacks(&self) -> String {
+        let mut lines = vec![];
+        let mut stack = vec![];
+        self.walk(|frame, entering| {
+            let frame = &self.frames[frame];
+            if !entering {
+                stack.pop();
+                return;
+            }
+            stack.push(self.frame_name(frame.function));
+            if frame.instructions > 0 {
+                lines.push(format!("{} {}", stack.join(";"), frame.instructions));
+            }
+        });
+        lines.sort();
+        lines.into_iter().map(|line| line + "\n").collect()
+    }
+
+    /// Get the name of a function in a folded stack.
+    fn frame_name(&self, function: Option<usize>) -> String {
+        match function {
+            Some(n) => match self.source_map.function_name(n) {
+                // Semicolons separate the frames of a stack.
+                Some(name) => name.to_string().replace(';', ","),
+                None => format!("function #{n}"),
+            },
+            None => "<top level>".to_string(),
+        }
+    }
+
+    /// Visit the frames of the call tree depth first, with whether
+    /// the frame is being entered or left.
+    fn walk(&self, mut visit: impl FnMut(usize, bool)) {
+        // The tree can be as deep as the program recursed, so it isn't walked recursively.
+        let mut frames = vec![(0, true)];
+        while let Some((frame, entering)) = frames.pop() {
+            visit(frame, entering);
+            if entering {
+                frames.push((frame, false));
+                frames.extend(
+                    self.frames[frame]
+                        .children
+                        .values()
+                        .map(|&child| (child, true)),
+                );
+            }
+        }
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let total = self.instructions();
+        let percent = |count: u64| count as f64 * 100.0 / total.max(1) as f64;
+        writeln!(f, "Ran {total} instructions.")?;
+
+        writeln!(f)?;
+        writeln!(f, "Instructions by opcode:")?;
+        writeln!(f, "{:>12} {:>7}  opcode", "count", "%")?;
+        for (opcode, count) in self.opcodes() {
+            writeln!(f, "{count:>12} {:>6.2}%  {opcode}", percent(count))?;
+        }
+
+        writeln!(f)?;
+        writeln!(f, "Instructions by function:")?;
+        writeln!(
+            f,
+            "{:>10} {:>12} {:>7} {:>12} {:>7}  function",
+            "calls", "self", "%", "total", "%"
+        )?;
+        for function in self.functions() {
+            writeln!(
+                f,
+                "{:>10} {:>12} {:>6.2}% {:>12} {:>6.2}%  {function}",
+                function.calls,
+                function.instructions,
+                percent(function.instructions),
+                function.total_instructions,
+                percent(function.total_instructions),
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,131 @@
+//! # Source Map Module
+//!
+//! This module maps the instructions of a virtual machine program back to the
+//! source code they were compiled from. The compiler records the locations and
+//! the names of the functions in comments when it's asked to (see
+//! `Compiler::source_locations`). Each instruction belongs to the last location
+//! written before it, and to the function it's in.
+use crate::{parse::SourceCodeLocation, vm::CoreOp};
+use std::fmt;
+
+/// The names of a function defined in a program.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct FunctionName {
+    /// The unique name the compiler gave the function.
+    pub mangled: String,
+    /// The name the function was declared with, if it has one.
+    pub common: Option<String>,
+}
+
+impl fmt::Display for FunctionName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.common.as_ref().unwrap_or(&self.mangled))
+    }
+}
+
+/// The source code location of each instruction in a program, and the function
+/// each instruction belongs to.
+#[derive(Clone, Debug, Default)]
+pub struct SourceMap {
+    /// The index of the location of each instruction, and the
+    /// function it's in, if they're known.
+    instructions: Vec<(Option<usize>, Option<usize>)>,
+    /// The locations found in the program.
+    locations: Vec<SourceCodeLocation>,
+    /// The names of each function defined in the program, if they were recorded.
+    functions: Vec<Option<FunctionName>>,
+}
+
+impl SourceMap {
+    /// Map the instructions of a program to their locations. The instructions
+    /// which aren't core instructions (like the standard ones) are given as `None`.
+    pub fn new<'a>(ops: impl IntoIterator<Item = Option<&'a CoreOp>>) -> Self {
+        let mut instructions = vec![];
+        let mut locations = vec![];
+        let mut functions: Vec<Option<FunctionName>> = vec![];
+        let (mut location, mut function): (_, Option<usize>) = (None, None);
+        // The open blocks, and the location and function before each function block.
+        // A function's code doesn't belong to the code written before it.
+        let mut blocks = vec![];
+
+        for op in ops {
+            match op {
+                Some(CoreOp::Comment(comment)) => {
+                    if let Some(loc) = SourceCodeLocation::from_comment(comment) {
+                        locations.push(loc);
+                        location = Some(locations.len() - 1);
+                    } else if let (Some(name), Some(n)) =
+                        (comment.strip_prefix("function "), function)
+                    {
+                        functions[n].get_or_insert_with(|| FunctionName {
+                            mangled: name.to_string(),
+                            common: None,
+                        });
+                    } else if let (Some(name), Some(Some(names))) = (
+                        comment.strip_prefix("declared as "),
+                        function.map(|n| functions[n].as_mut()),
+                    ) {
+                        names.common.get_or_insert_with(|| name.to_string());
+                    }
+                }
+                Some(CoreOp::Function) => {
+                    blocks.push(Some((location, function)));
+                    functions.push(None);
+                    location = None;
+                    function = Some(functions.len() - 1);
+                }
+                Some(CoreOp::If | CoreOp::While) => blocks.push(None),
+                _ => {}
+            }
+            instructions.push((location, function));
+            if let Some(CoreOp::End) = op {
+                if let Some(Some(before)) = blocks.pop() {
+                    (location, function) = before;
+                }
+            }
+        }
+        Self {
+            instructions,
+            locations,
+            functions,
+        }
+    }
+
+    /// Get the source code location of an instruction, if it's known.
+    pub fn location(&self, instruction: usize) -> Option<&SourceCodeLocation> {
+        self.instructions
+            .get(instruction)
+            .and_then(|(n, _)| n.map(|n| &self.locations[n]))
+    }
+
+    /// Get the function an instruction is in, as the index it's called with.
+    pub fn function(&self, instruction: usize) -> Option<usize> {
+        self.instructions.get(instruction).and_then(|(_, n)| *n)
+    }
+
+    /// Get the names of the Nth function defined in the program, if they were recorded.
+    pub fn function_name(&self, function: usize) -> Option<&FunctionName> {
+        self.functions.get(function)?.as_ref()
+    }
+
+    /// Get the instructions where the code for a line of source code starts, in the
+    /// file with the given name (or in the source code without a file name).
+    /// A line has an instruction for each place its code is entered from other code.
+    pub fn instructions_at_line(&self, filename: Option<&str>, line: usize) -> Vec<usize> {
+        let at_line = |n: Option<usize>| {
+            n.is_some_and(|n| {
+                let loc = &self.locations[n];
+                loc.line == line && loc.filename.as_deref() == filename
+            })
+        };
+        let mut result = vec![];
+        let mut previous = None;
+        for (i, &(n, _)) in self.instructions.iter().enumerate() {
+            if at_line(n) && !at_line(previous) {
+                result.push(i);
+            }
+            previous = n;
+        }
+        result
+    }
+}
@@ -3,6 +3,9 @@
 //! This module implements an interpreter for the Standard virtual machine
 //! variant.
 
+use super::{
+    memcheck::GUARD_CELLS, Heap, HeapStats, JumpTable, Memcheck, MemoryError, Profile, SourceMap,
+};
 use crate::vm::{CoreOp, Device, StandardDevice, StandardOp, StandardProgram};
 
 /// A function to reinterpret the bits of an integer as a float.
@@ -15,6 +18,14 @@ pub fn as_int(n: f64) -> i64 {
     n.to_bits() as i64
 }
 
+/// The core instructions of a standard program, with the standard instructions as `None`.
+fn core_ops(code: &[StandardOp]) -> impl Iterator<Item = Option<&CoreOp>> {
+    code.iter().map(|op| match op {
+        StandardOp::CoreOp(op) => Some(op),
+        _ => None,
+    })
+}
+
 impl Default for StandardInterpreter<StandardDevice> {
     fn default() -> Self {
         Self::new(StandardDevice::default())
@@ -34,9 +45,15 @@ where
     register: i64,
     /// The turing tape (composed of integer cells)
     cells: Vec<i64>,
-    /// The addresses of defined functions. `functions[N]` is the
-    /// instruction pointer for the Nth function's code.
-    functions: Vec<usize>,
+    /// The allocator for the heap at the end of the tape.
+    heap: Heap,
+    /// The memory checker, if the interpreter is in memcheck mode.
+    memcheck: Option<Memcheck>,
+    /// The profiler, if the interpreter is in profiling mode.
+    profile: Option<Profile>,
+    /// The instructions each block instruction jumps to, and the
+    /// addresses of the program's functions, resolved before it's run.
+    jumps: JumpTable,
     /// The call stack of instruction pointers. Whenever a function
     /// is called, the instruction pointer is pushed here. Whenever
     /// a function returns, its instruction pointer is popped from here.
@@ -60,7 +77,10 @@ where
             pointer: 0,
             register: 0,
             cells: vec![],
-            functions: vec![],
+            heap: Heap::default(),
+            memcheck: None,
+            profile: None,
+            jumps: JumpTable::default(),
             calls: vec![],
             refs: vec![],
             i: 0,
@@ -68,6 +88,34 @@ where
         }
     }
 
+    /// Check the program's memory accesses while it runs, and report
+    /// the memory errors it makes (see the `memcheck` module).
+    pub fn with_memcheck(mut self) -> Self {
+        self.heap = Heap::with_guard(GUARD_CELLS);
+        self.memcheck = Some(Memcheck::default());
+        self
+    }
+
+    /// Get the memory errors found so far, if the interpreter is in memcheck mode.
+    /// The leaks are found when the program exits.
+    pub fn memory_errors(&self) -> &[MemoryError] {
+        self.memcheck
+            .as_ref()
+            .map_or(&[], |memcheck| memcheck.errors())
+    }
+
+    /// Count the instructions the program runs by opcode and by function
+    /// while it runs (see the `profile` module).
+    pub fn with_profile(mut self) -> Self {
+        self.profile = Some(Profile::default());
+        self
+    }
+
+    /// Get the profile of the program so far, if the interpreter is in profiling mode.
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
     /// Fetch the current instruction pointed to in the program
     fn fetch<'a>(&self, code: &'a StandardProgram) -> Option<&'a StandardOp> {
         if self.i < code.0.len() {
@@ -103,43 +151,18 @@ where
     }
 
     /// Call the Nth function defined in the program, where N is the value of the register.
-    fn call(&mut self, code: &StandardProgram) -> Result<(), String> {
-        // If the function has been defined
-        if self.functions.len() > self.register as usize {
-            // Push the current instruction pointer to the call stack
-            self.calls.push(self.i);
-            self.i = self.functions[self.register as usize];
-            Ok(())
-        } else {
-            // If the function hasn't been defined yet, we'll have to find it.
-
-            // Push the return address onto the call stack.
-            self.calls.push(self.i);
-            // Scan all the function definitions from the start of the program until we find it.
-            self.i = 0;
-            let mut count = -1;
-            while count < self.register {
-                // Every time we find a function, it will increment `count`.
-                match self.fetch(code) {
-                    Some(StandardOp::CoreOp(CoreOp::Function)) => {
-                        count += 1;
-                    }
-                    Some(_) => {}
-                    None => return Err(format!("function {} not defined", self.register)),
-                }
-                // If `count` hasn't reached the function we want,
-                // keep going.
-                if count < self.register {
-                    self.i += 1;
+    fn call(&mut self) -> Result<(), String> {
+        match self.jumps.function(self.register) {
+            Some(function) => {
+                // Push the return address onto the call stack, and jump to the function.
+                self.calls.push(self.i);
+                self.i = function;
+                if let Some(profile) = &mut self.profile {
+                    profile.call(self.register as usize);
                 }
+                Ok(())
             }
-
-            // If we've reached the function we want, add it to the definitions.
-            if !self.functions.contains(&self.i) {
-                self.functions.push(self.i);
-                self.functions.sort()
-            }
-            Ok(())
+            None => Err(format!("function {} not defined", self.register)),
         }
     }
 
@@ -147,145 +170,215 @@ where
     fn ret(&mut self) {
         // If we're returning from a function, jump to the old instruction pointer.
         if let Some(old) = self.calls.pop() {
-            self.i = old
+            self.i = old;
+            if let Some(profile) = &mut self.profile {
+                profile.ret();
+            }
         } else {
             // Otherwise, we're finished with the program.
             self.done = true
         }
     }
 
-    /// Jump to the matching "Else" for this "If" instruction.
-    fn jmp_to_else(&mut self, code: &StandardProgram) {
-        let mut matching = 1;
-        while matching > 0 {
-            self.i += 1;
-            match self.fetch(code) {
-                Some(StandardOp::CoreOp(CoreOp::If))
-                | Some(StandardOp::CoreOp(CoreOp::While))
-                | Some(StandardOp::CoreOp(CoreOp::Function)) => matching += 1,
-                Some(StandardOp::CoreOp(CoreOp::End)) => matching -= 1,
-                Some(StandardOp::CoreOp(CoreOp::Else)) if matching == 1 => return,
-                _ => {}
-            }
-        }
+    /// Jump to the instruction matched with the current block instruction:
+    /// the "Else" or "End" of an "If", or the "End" of an "Else", "While",
+    /// or "Function".
+    fn jump(&mut self) {
+        self.i = self.jumps.jump(self.i)
     }
 
-    /// Jump to the matching "End" for this "Else", "While",
-    /// or "Function" instruction.
-    fn jmp_to_end(&mut self, code: &StandardProgram) {
-        let mut matching = 1;
-        while matching > 0 {
-            self.i += 1;
-            match self.fetch(code) {
-                Some(StandardOp::CoreOp(CoreOp::If))
-                | Some(StandardOp::CoreOp(CoreOp::While))
-                | Some(StandardOp::CoreOp(CoreOp::Function)) => matching += 1,
-                Some(StandardOp::CoreOp(CoreOp::End)) => matching -= 1,
-                _ => {}
-            }
+    /// Get the current cell pointed to on the turing tape.
+    fn get_cell(&mut self) -> &mut i64 {
+        while self.pointer >= self.cells.len() {
+            self.cells.extend(vec![0; 1000]);
         }
+
+        &mut self.cells[self.pointer]
     }
 
-    /// Jump back to the matching instruction for a given "End" instruction.
-    ///
-    /// ```hs
-    /// If (ends up here)
-    ///     ...
-    /// End (calls jmp_back_to_matching)
-    /// ```
-    fn jmp_back_to_matching(&mut self, code: &StandardProgram) {
-        let mut matching = 1;
-        while matching > 0 {
-            self.i -= 1;
-            match self.fetch(code) {
-                Some(StandardOp::CoreOp(CoreOp::If))
-                | Some(StandardOp::CoreOp(CoreOp::While))
-                | Some(StandardOp::CoreOp(CoreOp::Function)) => matching -= 1,
-                Some(StandardOp::CoreOp(CoreOp::End)) => matching += 1,
-                _ => {}
-            }
-        }
+    /// Get the statistics about the heap usage of the program so far.
+    pub fn heap_stats(&self) -> HeapStats {
+        self.heap.stats()
     }
 
-    /// Get the matching instruction for a given "End" instruction.
-    ///
-    /// ```hs
-    /// If (this function would return this instruction)
-    ///    ...
-    /// End (called on this as the current instruction)
-    /// ```
-    fn get_matching_for_end<'a>(&mut self, code: &'a StandardProgram) -> Option<&'a StandardOp> {
-        let mut matching = 1;
-        let mut i = self.i;
-        while matching > 0 && i > 0 {
-            i -= 1;
-            match code.0[i] {
-                StandardOp::CoreOp(CoreOp::If)
-                | StandardOp::CoreOp(CoreOp::While)
-                | StandardOp::CoreOp(CoreOp::Function) => matching -= 1,
-                StandardOp::CoreOp(CoreOp::End) => matching += 1,
-                _ => {}
-            }
-        }
+    /// Get the value in the register.
+    pub fn register(&self) -> i64 {
+        self.register
+    }
 
-        if matching == 0 {
-            Some(&code.0[i])
-        } else {
-            None
-        }
+    /// Get the current pointer on the turing tape.
+    pub fn pointer(&self) -> usize {
+        self.pointer
     }
 
-    /// Get the current cell pointed to on the turing tape.
-    fn get_cell(&mut self) -> &mut i64 {
-        while self.pointer >= self.cells.len() {
-            self.cells.extend(vec![0; 1000]);
-        }
+    /// Get the cells of the turing tape which have been used so far.
+    /// The cells after them hold zero.
+    pub fn tape(&self) -> &[i64] {
+        &self.cells
+    }
 
-        &mut self.cells[self.pointer]
+    /// Get the stack of pointers saved by the dereferences which haven't been undone.
+    pub fn refs(&self) -> &[usize] {
+        &self.refs
     }
 
-    /// Run a core program using this interpreter and its device.
-    pub fn run(mut self, code: &StandardProgram) -> Result<T, String> {
+    /// Get the call stack, as the instructions which called each function being run.
+    pub fn calls(&self) -> &[usize] {
+        &self.calls
+    }
+
+    /// Get the index of the next instruction to run.
+    pub fn instruction(&self) -> usize {
+        self.i
+    }
+
+    /// Is the interpreter finished running the program?
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// Get the interpreter's I/O device.
+    pub fn device(&self) -> &T {
+        &self.device
+    }
+
+    /// Take the interpreter's I/O device.
+    pub fn into_device(self) -> T {
+        self.device
+    }
+
+    /// Run a standard program using this interpreter and its device, like `run`,
+    /// but keep the interpreter to inspect its state afterwards.
+    pub fn execute(&mut self, code: &StandardProgram) -> Result<(), String> {
+        self.load(code);
         while !self.done {
             self.step(code)?
         }
+        if let Some(memcheck) = &mut self.memcheck {
+            memcheck.exit(&self.heap);
+        }
+        Ok(())
+    }
 
+    /// Run the instructions added to the end of a standard program since the interpreter
+    /// last ran it, for a program which is run while it's still being written, like the
+    /// lines entered into the REPL. The state left by the instructions before them is
+    /// kept, like the tape and the register.
+    pub fn execute_appended(&mut self, code: &StandardProgram) -> Result<(), String> {
+        if !self.done {
+            // An error may have stopped the instructions run before in the middle of
+            // a function, so go back to where the program started: at the start of
+            // the tape, outside of any function.
+            self.pointer = 0;
+            self.calls.clear();
+            self.refs.clear();
+        }
+        self.i = self.jumps.len();
+        self.jumps.extend(core_ops(&code.0[self.i..]));
+        self.done = false;
+        while !self.done {
+            self.step(code)?
+        }
+        Ok(())
+    }
+
+    /// Prepare to run a standard program one instruction at a time with `step`.
+    pub fn load(&mut self, code: &StandardProgram) {
+        let core_ops = || core_ops(&code.0);
+        // Match up the blocks and functions once, so branches don't scan the program.
+        self.jumps = JumpTable::new(core_ops());
+        if let Some(memcheck) = &mut self.memcheck {
+            *memcheck = Memcheck::new(SourceMap::new(core_ops()));
+        }
+        if let Some(profile) = &mut self.profile {
+            *profile = Profile::new(&code.0, SourceMap::new(core_ops()));
+        }
+    }
+
+    /// Run a standard program using this interpreter and its device.
+    pub fn run(mut self, code: &StandardProgram) -> Result<T, String> {
+        self.execute(code)?;
         Ok(self.device)
     }
 
-    /// Run a single step of the interpreter.
-    fn step(&mut self, code: &StandardProgram) -> Result<(), String> {
+    /// Check the memory accesses of an instruction before it's run, in memcheck mode.
+    fn check_memory(&mut self, op: &StandardOp, code: &StandardProgram) {
+        let (heap, addr, i) = (&self.heap, self.pointer, self.i);
+        let memcheck = match &mut self.memcheck {
+            Some(memcheck) => memcheck,
+            None => return,
+        };
+        match op {
+            StandardOp::CoreOp(op) => match op {
+                CoreOp::Save => memcheck.store(heap, addr, i),
+                CoreOp::Restore => memcheck.load(heap, addr, i),
+                CoreOp::Deref => memcheck.use_cell(heap, addr, i),
+                CoreOp::Index
+                | CoreOp::BitwiseNand
+                | CoreOp::Add
+                | CoreOp::Sub
+                | CoreOp::Mul
+                | CoreOp::Div
+                | CoreOp::Rem => memcheck.combine(heap, addr, i),
+                CoreOp::Set(_) | CoreOp::Where | CoreOp::Get(_) => memcheck.define(),
+                CoreOp::If | CoreOp::While | CoreOp::Call | CoreOp::Put(_) => {
+                    memcheck.use_register(i)
+                }
+                // Only the "End" of a "While" block branches on the register.
+                CoreOp::End => {
+                    if let Some(StandardOp::CoreOp(CoreOp::While)) = code.0.get(self.jumps.jump(i))
+                    {
+                        memcheck.use_register(i)
+                    }
+                }
+                _ => {}
+            },
+            StandardOp::Add
+            | StandardOp::Sub
+            | StandardOp::Mul
+            | StandardOp::Div
+            | StandardOp::Rem
+            | StandardOp::Pow
+            | StandardOp::ShiftLeft
+            | StandardOp::ShiftRight
+            | StandardOp::LogicalShiftRight => memcheck.combine(heap, addr, i),
+            StandardOp::Set(_) | StandardOp::Peek => memcheck.define(),
+            StandardOp::Poke | StandardOp::Alloc | StandardOp::Free => memcheck.use_register(i),
+            _ => {}
+        }
+    }
+
+    /// Run a single instruction of a program prepared with `load`.
+    pub fn step(&mut self, code: &StandardProgram) -> Result<(), String> {
         if let Some(op) = self.fetch(code) {
+            self.check_memory(op, code);
+            if let Some(profile) = &mut self.profile {
+                profile.record(self.i);
+            }
             match op {
                 StandardOp::CoreOp(core_op) => match core_op {
                     CoreOp::Comment(_) => {}
                     CoreOp::Set(n) => self.register = *n,
-                    CoreOp::Function => {
-                        if !self.functions.contains(&self.i) {
-                            self.functions.push(self.i);
-                            self.functions.sort()
-                        }
-                        self.jmp_to_end(code)
-                    }
-                    CoreOp::Call => self.call(code)?,
+                    CoreOp::Function => self.jump(),
+                    CoreOp::Call => self.call()?,
                     CoreOp::Return => self.ret(),
                     CoreOp::While => {
                         if self.register == 0 {
-                            self.jmp_to_end(code)
+                            self.jump()
                         }
                     }
                     CoreOp::If => {
                         if self.register == 0 {
-                            self.jmp_to_else(code)
+                            self.jump()
                         }
                     }
-                    CoreOp::Else => self.jmp_to_end(code),
+                    CoreOp::Else => self.jump(),
                     CoreOp::End => {
                         if self.register != 0 {
-                            if let Some(StandardOp::CoreOp(CoreOp::While)) =
-                                self.get_matching_for_end(code)
-                            {
-                                self.jmp_back_to_matching(code)
+                            // Loop back to the start of a "While" block.
+                            let start = self.jumps.jump(self.i);
+                            if let Some(StandardOp::CoreOp(CoreOp::While)) = code.0.get(start) {
+                                self.i = start
                             }
                         }
                     }
@@ -385,6 +478,27 @@ where
                 StandardOp::Pow => {
                     self.register = as_int(as_float(self.register).powf(as_float(*self.get_cell())))
                 }
+                StandardOp::ShiftLeft => {
+                    let n = *self.get_cell();
+                    if n > 0 {
+                        self.register = self.register.checked_shl(n.min(64) as u32).unwrap_or(0)
+                    }
+                }
+                StandardOp::ShiftRight => {
+                    let n = *self.get_cell();
+                    if n > 0 {
+                        // Shifting by 63 bits already fills the register with the sign bit.
+                        self.register >>= n.min(63)
+                    }
+                }
+                StandardOp::LogicalShiftRight => {
+                    let n = *self.get_cell();
+                    if n > 0 {
+                        self.register = (self.register as u64)
+                            .checked_shr(n.min(64) as u32)
+                            .unwrap_or(0) as i64
+                    }
+                }
 
                 StandardOp::Poke => {
                     self.device.poke(self.register)?;
@@ -394,19 +508,34 @@ where
                 }
 
                 StandardOp::Alloc => {
-                    // If the virtual machine doesn't have a thousand cells,
-                    // allocate some.
-                    if self.cells.len() < 30000 {
-                        self.cells.extend(vec![0; 30000]);
+                    let size = usize::try_from(self.register).map_err(|_| {
+                        format!(
+                            "Instruction #{} tried to allocate {} cells.",
+                            self.i, self.register
+                        )
+                    })?;
+                    let addr = self.heap.alloc(size, self.cells.len());
+                    // Make sure the block is on the tape, and clear what a freed block held.
+                    if self.cells.len() < addr + size {
+                        self.cells.resize(addr + size, 0);
+                    }
+                    self.cells[addr..addr + size].fill(0);
+                    if let Some(memcheck) = &mut self.memcheck {
+                        memcheck.alloc(addr, size.max(1), self.i);
                     }
-                    // Save the address of where the new cells will start.
-                    let result = self.cells.len() - 1;
-                    // Allocate new space at the end of the type.
-                    self.cells.extend(vec![0; self.register as usize]);
                     // Store the address of the new space in the register.
-                    self.register = result as i64;
+                    self.register = addr as i64;
+                }
+                // Freeing the NULL pointer does nothing.
+                StandardOp::Free if self.register == crate::NULL => {}
+                StandardOp::Free => {
+                    let size = self.heap.free(self.register).map_err(|e| {
+                        format!("Instruction #{} tried to free memory, but {e}.", self.i)
+                    })?;
+                    if let Some(memcheck) = &mut self.memcheck {
+                        memcheck.free(self.register as usize, size);
+                    }
                 }
-                StandardOp::Free => {}
                 StandardOp::Call(binding) => {
                     self.device.ffi_call(binding, Some(&mut self.cells))?;
                 }
@@ -291,6 +291,18 @@ pub enum StandardOp {
     /// Store the value of the register (as a float) to the power of the value pointed to on the tape (as a float) into the register.
     Pow,
 
+    /// Shift the register (as an integer) left by the number of bits pointed to on the tape.
+    /// Shifting by a negative number of bits leaves the register unchanged.
+    ShiftLeft,
+    /// Shift the register (as an integer) right by the number of bits pointed to on the tape,
+    /// copying the sign bit into the vacated bits.
+    /// Shifting by a negative number of bits leaves the register unchanged.
+    ShiftRight,
+    /// Shift the register (as an integer) right by the number of bits pointed to on the tape,
+    /// filling the vacated bits with zeros.
+    /// Shifting by a negative number of bits leaves the register unchanged.
+    LogicalShiftRight,
+
     /// Get a value from the input interface / device and store it in the register.
     /// This is intended to function something like system calls for using any external
     /// functionality that can't be implemented in the virtual machine, such as I/O or OS operations.
@@ -334,6 +346,9 @@ impl fmt::Display for StandardOp {
             StandardOp::ACos => write!(f, "acos"),
             StandardOp::ATan => write!(f, "atan"),
             StandardOp::Pow => write!(f, "pow"),
+            StandardOp::ShiftLeft => write!(f, "shl"),
+            StandardOp::ShiftRight => write!(f, "shr"),
+            StandardOp::LogicalShiftRight => write!(f, "lshr"),
             StandardOp::Peek => write!(f, "peek"),
             StandardOp::Poke => write!(f, "poke"),
             StandardOp::Call(binding) => write!(f, "call {}", binding),
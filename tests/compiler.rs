@@ -0,0 +1,336 @@
+use sage::{
+    compiler::{self, with_stack_size, DEFAULT_STACK_SIZE_MB},
+    lir,
+    parse::SourceCodeLocation,
+    vm::{Access, MemoryErrorKind, TestingDevice},
+    Compiler, SourceType, Target,
+};
+
+#[test]
+fn test_compile_and_run() {
+    // The compiler's results aren't `Send`, so they're used inside the thread.
+    let output = with_stack_size(DEFAULT_STACK_SIZE_MB, || {
+        let artifacts = Compiler::new("let x = 5; println(x * 2);")
+            .compile()
+            .unwrap();
+        assert!(artifacts.lir.is_some());
+        assert!(artifacts.asm.is_some());
+        assert!(artifacts.vm.is_some());
+        assert!(artifacts.output.is_none());
+        artifacts.run(TestingDevice::new("")).unwrap().output_str()
+    });
+    assert_eq!(output, "10\n");
+}
+
+#[test]
+fn test_compile_stages() {
+    with_stack_size(DEFAULT_STACK_SIZE_MB, || {
+        // Only the stages the target needs are compiled.
+        let artifacts = Compiler::new("println(1);")
+            .target(Target::Lir)
+            .compile()
+            .unwrap();
+        assert!(artifacts.lir.is_some());
+        assert!(artifacts.asm.is_none());
+        assert!(artifacts.vm.is_none());
+
+        let artifacts = Compiler::new("println(1);")
+            .target(Target::C)
+            .compile()
+            .unwrap();
+        assert_eq!(artifacts.output, Some(artifacts.build(Target::C).unwrap()));
+        assert!(artifacts.build(Target::X86).is_ok());
+
+        // Later stages can be used as the source.
+        let vm_code = match artifacts.vm.unwrap() {
+            Ok(vm_code) => vm_code.to_string(),
+            Err(vm_code) => vm_code.to_string(),
+        };
+        let artifacts = Compiler::new(vm_code)
+            .source_type(SourceType::StdVM)
+            .compile()
+            .unwrap();
+        assert!(artifacts.lir.is_none());
+        let device = artifacts.run(TestingDevice::new("")).unwrap();
+        assert_eq!(device.output_str(), "1\n");
+    })
+}
+
+#[test]
+fn test_compile_errors() {
+    with_stack_size(DEFAULT_STACK_SIZE_MB, || {
+        // Floats need the standard variant.
+        let result = Compiler::new("println(1.5);")
+            .target(Target::CoreVM)
+            .compile();
+        assert!(matches!(result, Err(compiler::Error::InvalidSource(_))));
+
+        // Virtual machine code can't be compiled back to assembly code.
+        let vm_code = match Compiler::new("println(1);").compile().unwrap().vm {
+            Some(Ok(vm_code)) => vm_code.to_string(),
+            _ => unreachable!(),
+        };
+        let result = Compiler::new(vm_code)
+            .source_type(SourceType::StdVM)
+            .target(Target::StdASM)
+            .compile();
+        assert!(matches!(result, Err(compiler::Error::InvalidSource(_))));
+
+        let result = Compiler::new("println(").compile();
+        assert!(matches!(result, Err(compiler::Error::Parse(_))));
+
+        let result = Compiler::new("println(x);").compile();
+        assert!(matches!(result, Err(compiler::Error::Lir(_))));
+
+        // The same error found twice at the same location is only reported once.
+        let err = lir::Error::many([
+            lir::Error::SymbolNotDefined("x".to_string()),
+            lir::Error::many([
+                lir::Error::SymbolNotDefined("x".to_string()),
+                lir::Error::SymbolNotDefined("y".to_string()),
+            ]),
+        ]);
+        assert_eq!(err.errors().len(), 2);
+    })
+}
+
+#[test]
+fn test_compile_warnings() {
+    with_stack_size(DEFAULT_STACK_SIZE_MB, || {
+        let (result, warnings) = Compiler::new("let x = 5; println(1);")
+            .filename("test.sg")
+            .compile_with_warnings();
+        assert!(result.is_ok());
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].name(), "unused-variable");
+    })
+}
+
+#[test]
+fn test_memcheck() {
+    with_stack_size(DEFAULT_STACK_SIZE_MB, || {
+        let src = "let p = alloc(sizeof<Int>()) as &mut Int;\n*p = 5;\nfree(p);\nprintln(*p);";
+        let artifacts = Compiler::new(src)
+            .filename("test.sg")
+            .source_locations(true)
+            .compile()
+            .unwrap();
+        let (result, errors) = artifacts.run_with_memcheck(TestingDevice::new(""));
+        assert!(result.is_ok());
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, MemoryErrorKind::UseAfterFree(Access::Read));
+
+        // The error points to the line which read the freed memory.
+        let location = errors[0].location.as_ref().unwrap();
+        assert_eq!(location.filename.as_deref(), Some("test.sg"));
+        assert_eq!(location.line, 4);
+    })
+}
+
+#[test]
+fn test_profile() {
+    with_stack_size(DEFAULT_STACK_SIZE_MB, || {
+        let src = "def square(x: Int): Int = x * x;\nprintln(square(2) + square(3));";
+        let artifacts = Compiler::new(src).source_locations(true).compile().unwrap();
+        let (result, profile) = artifacts.run_with_profile(TestingDevice::new(""));
+        assert_eq!(result.unwrap().output_str(), "13\n");
+
+        // The functions are named with the names they were declared with.
+        let square = profile
+            .functions()
+            .into_iter()
+            .find(|f| f.name.as_ref().and_then(|name| name.common.as_deref()) == Some("square"))
+            .unwrap();
+        assert_eq!(square.calls, 2);
+        assert!(square.instructions > 0);
+        assert!(profile
+            .folded_stacks()
+            .lines()
+            .any(|line| line.starts_with("<top level>;square ")));
+    })
+}
+
+#[test]
+fn test_import_namespace_conflict() {
+    with_stack_size(DEFAULT_STACK_SIZE_MB, || {
+        // Both modules would be imported under the `math` namespace.
+        let src = "import \"modules/math.sg\";\nimport \"modules/option.sg\" as math;";
+        let result = Compiler::new(src)
+            .filename("examples/frontend/conflict.sg")
+            .compile();
+        match result {
+            Err(compiler::Error::Parse(e)) => {
+                assert!(e.contains("namespace `math` is already used"));
+                assert!(e.contains(":2:"));
+            }
+            _ => panic!("expected a parse error"),
+        }
+
+        let src = "import \"modules/math.sg\";\nimport \"modules/option.sg\" as opt;\nprintln(math::square(2));";
+        let result = Compiler::new(src)
+            .filename("examples/frontend/conflict.sg")
+            .compile();
+        assert!(result.is_ok());
+    })
+}
+
+#[test]
+fn test_closure_records() {
+    with_stack_size(DEFAULT_STACK_SIZE_MB, || {
+        // Closures which capture by reference keep their records on the stack,
+        // so they don't need the heap, and nothing leaks.
+        let src = "def twice(f: Int -> Int, x: Int): Int = f(f(x));\n\
+                   def square(x: Int): Int = x * x;\n\
+                   let mut total = 0;\n\
+                   for let mut i = 0; i < 3; i += 1 {\n\
+                       let add = |x: Int| { total += x; };\n\
+                       add(i);\n\
+                       total = twice(|x: Int| x + total, 0);\n\
+                   }\n\
+                   println(total, \" \", twice(square, 3));";
+        let artifacts = Compiler::new(src).target(Target::CoreVM).compile().unwrap();
+        let device = artifacts.run(TestingDevice::new("")).unwrap();
+        assert_eq!(device.output_str(), "8 81\n");
+
+        let artifacts = Compiler::new(src).source_locations(true).compile().unwrap();
+        let (result, errors) = artifacts.run_with_memcheck(TestingDevice::new(""));
+        assert_eq!(result.unwrap().output_str(), "8 81\n");
+        assert!(errors.is_empty());
+
+        // Escaping closures are allocated on the heap, and their records are never freed.
+        let src = "def make_adder(n: Int): Int -> Int {\n\
+                       return move |x: Int| x + n;\n\
+                   }\n\
+                   for let mut i = 0; i < 3; i += 1 {\n\
+                       println(make_adder(i)(1));\n\
+                   }";
+        let artifacts = Compiler::new(src).source_locations(true).compile().unwrap();
+        let (result, errors) = artifacts.run_with_memcheck(TestingDevice::new(""));
+        assert_eq!(result.unwrap().output_str(), "1\n2\n3\n");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].kind,
+            MemoryErrorKind::Leak {
+                blocks: 3,
+                cells: 6
+            }
+        );
+        assert_eq!(errors[0].location.as_ref().unwrap().line, 2);
+    })
+}
+
+#[test]
+fn test_error_labels() {
+    with_stack_size(DEFAULT_STACK_SIZE_MB, || {
+        let src = "def half(n: Int, x: Float): Float = x / 2.0;\nprintln(half(1, 3));";
+        let err = match Compiler::new(src).filename("test.sg").compile() {
+            Err(compiler::Error::Lir(err)) => err,
+            _ => panic!("expected a type error"),
+        };
+
+        // The error points at the argument, rather than the whole call.
+        let location = err.location().unwrap();
+        assert_eq!((location.line, location.column), (2, 17));
+        assert_eq!(location.length, Some(1));
+
+        // It's labeled with the parameter the argument is passed to.
+        let labels = err.labels();
+        assert_eq!(labels.len(), 1);
+        let (location, message) = labels[0];
+        assert_eq!((location.line, location.column), (1, 18));
+        assert_eq!(
+            &src[location.offset..location.offset + location.length.unwrap()],
+            "x: Float"
+        );
+        assert_eq!(message, "parameter `x` is declared here with type Float");
+        assert_eq!(
+            err.notes(),
+            vec!["convert the Int to Float with `as Float`"]
+        );
+
+        // Calls with the wrong number of arguments point at the procedure's signature.
+        let src = "def half(x: Float): Float = x / 2.0;\nprintln(half(1.0, 2.0));";
+        let err = match Compiler::new(src).filename("test.sg").compile() {
+            Err(compiler::Error::Lir(err)) => err,
+            _ => panic!("expected a type error"),
+        };
+        let labels = err.labels();
+        assert_eq!(labels.len(), 1);
+        assert_eq!(
+            &src[labels[0].0.offset..labels[0].0.offset + labels[0].0.length.unwrap()],
+            "def half(x: Float): Float"
+        );
+        assert_eq!(
+            labels[0].1,
+            "procedure is declared here with 1 parameter(s)"
+        );
+        assert!(err.notes().is_empty());
+
+        // A closure parameter whose type can't be inferred points at the closure,
+        // rather than at the statement after it.
+        let src = "let g = |x| x;\nprintln(1);";
+        let err = match Compiler::new(src).filename("test.sg").compile() {
+            Err(compiler::Error::Lir(err)) => err,
+            _ => panic!("expected a type error"),
+        };
+        let location = err.location().unwrap();
+        assert_eq!((location.line, location.column), (1, 9));
+        assert_eq!(
+            &src[location.offset..location.offset + location.length.unwrap()],
+            "|x| x"
+        );
+    })
+}
+
+#[test]
+fn test_trait_errors() {
+    fn compile_error(src: &str) -> Box<lir::Error> {
+        match Compiler::new(src).filename("test.sg").compile() {
+            Err(compiler::Error::Lir(err)) => err,
+            _ => panic!("expected a type error"),
+        }
+    }
+    fn source_at<'a>(src: &'a str, location: &SourceCodeLocation) -> &'a str {
+        &src[location.offset..location.offset + location.length.unwrap()]
+    }
+
+    with_stack_size(DEFAULT_STACK_SIZE_MB, || {
+        let header = "trait Show { def show(self: &Self); }\nstruct P { x: Int }\n";
+
+        // A second implementation of a trait for a type points at both implementations.
+        let src = format!(
+            "{header}impl Show for P {{ def show(self: &P) {{ println(1); }} }}\n\
+            impl Show for P {{ def show(self: &P) {{ println(2); }} }}\nprintln(0);"
+        );
+        let err = compile_error(&src);
+        assert_eq!(
+            err.to_string(),
+            "conflicting implementations of trait Show for P"
+        );
+        let location = err.location().unwrap();
+        assert_eq!(location.line, 4);
+        assert_eq!(source_at(&src, location), "impl Show for P");
+        let labels = err.labels();
+        assert_eq!(labels.len(), 1);
+        assert_eq!(labels[0].0.line, 3);
+        assert_eq!(labels[0].1, "first implemented here");
+
+        // An incomplete implementation is reported where it's declared, even if it's unused.
+        let err = compile_error(&format!("{header}impl Show for P {{}}\nprintln(0);"));
+        assert_eq!(
+            err.to_string(),
+            "implementation of trait Show for P is missing method show"
+        );
+        assert_eq!(err.location().unwrap().line, 3);
+
+        // An unknown trait in a bound is reported once, at the procedure's signature.
+        let src = "def g<T: Nope>(x: T): Int { return 1; }\nprintln(g(1));";
+        let err = compile_error(src);
+        assert_eq!(err.errors().len(), 1);
+        assert_eq!(err.to_string(), "trait Nope not defined");
+        assert_eq!(
+            source_at(src, err.location().unwrap()),
+            "def g<T: Nope>(x: T): Int"
+        );
+    })
+}
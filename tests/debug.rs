@@ -0,0 +1,128 @@
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+const PROGRAM: &str = "def fib(n: Int): Int {
+    if n < 2 {
+        return n;
+    }
+    return fib(n - 1) + fib(n - 2);
+}
+
+let x = fib(5);
+println(x);
+";
+
+/// Debug a program with some commands as input, and get what the debugger prints to stdout.
+/// Each test has its own copy of the program, since they run at the same time.
+fn run_debugger(name: &str, program: &str, commands: &str) -> String {
+    let file = std::env::temp_dir().join(format!("sage-debug-{name}-{}.sg", std::process::id()));
+    std::fs::write(&file, program).unwrap();
+    let mut debugger = Command::new(env!("CARGO_BIN_EXE_sage"))
+        .arg("debug")
+        .arg(&file)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    debugger
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(commands.as_bytes())
+        .unwrap();
+
+    let output = debugger.wait_with_output().unwrap();
+    assert!(output.status.success());
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn test_debug_breakpoints() {
+    let stdout = run_debugger(
+        "breakpoints",
+        PROGRAM,
+        "break :3\ncontinue\nbacktrace\ndelete 1\ncontinue\n",
+    );
+    // The program stops at the line, in the innermost call.
+    assert!(stdout.contains("Stopped at breakpoint 1."));
+    assert!(stdout.contains("     3 |         return n;"));
+    let frames: Vec<_> = stdout
+        .lines()
+        .filter(|line| line.starts_with('#'))
+        .collect();
+    assert!(frames.iter().any(|frame| frame.starts_with("#0 fib at")));
+    assert!(frames.iter().any(|frame| frame.starts_with("#4 fib at")));
+    assert!(frames
+        .iter()
+        .any(|frame| frame.starts_with("#5 <top level> at")));
+    // After the breakpoint is deleted, the program runs to the end.
+    assert!(stdout.ends_with("5\nThe program exited.\n"));
+}
+
+#[test]
+fn test_debug_line_breakpoint_state() {
+    let stdout = run_debugger(
+        "line-state",
+        "let a = 1;\nlet b = a + 2;\nprintln(b);\n",
+        "break :3\ncontinue\nwatch 8205\nwatch 8206\nstep\ncontinue\n",
+    );
+    // The line is only entered once, so the breakpoint has a single instruction.
+    let breakpoint = stdout
+        .lines()
+        .find(|line| line.starts_with("Breakpoint 1 at "))
+        .unwrap();
+    assert!(!breakpoint.contains(','), "{breakpoint}");
+    // The program stops after the variables the line uses are defined,
+    // just after the call stack, and before anything is printed.
+    let stop = stdout.find("Stopped at breakpoint 1.").unwrap();
+    assert!(stdout[stop..].contains("     3 | println(b);"));
+    assert!(stdout.contains("Watching cell 8205, which holds 1."));
+    assert!(stdout.contains("Watching cell 8206, which holds 3."));
+    // Stepping stays on the line, instead of going back to an earlier one.
+    let step = stdout.rfind("Watching cell").unwrap();
+    assert!(stdout[step..].contains(":3:1"));
+    assert!(!stdout[step..].contains(":1:1"));
+    assert!(stdout.ends_with("3\nThe program exited.\n"));
+}
+
+#[test]
+fn test_debug_stepping() {
+    let stdout = run_debugger(
+        "stepping",
+        PROGRAM,
+        "break :5\ncontinue\ndelete\nfinish\nbacktrace\nstep\n\nprint\nlist\n",
+    );
+    // Finishing the first call to `fib` returns to the top level.
+    assert!(stdout.contains("#0 <top level> at"));
+    // An empty line repeats the last step.
+    let positions: Vec<usize> = stdout
+        .lines()
+        .filter(|line| line.starts_with('#') && !line.contains(" at "))
+        .map(|line| line[1..line.find(' ').unwrap()].parse().unwrap())
+        .collect();
+    let n = positions.len();
+    assert_eq!(positions[n - 3] + 1, positions[n - 2]);
+    assert_eq!(positions[n - 2], positions[n - 1]);
+    assert!(stdout.contains("register = "));
+    let current: Vec<_> = stdout
+        .lines()
+        .filter(|line| line.starts_with("> #"))
+        .collect();
+    assert_eq!(current.len(), 1);
+}
+
+#[test]
+fn test_debug_watchpoints() {
+    let stdout = run_debugger(
+        "watchpoints",
+        PROGRAM,
+        "watch 0\ncontinue\ninfo\nunwatch 0\ncontinue\n",
+    );
+    assert!(stdout.contains("Watching cell 0, which holds 0."));
+    assert!(stdout.contains("Cell 0 changed from 0 to "));
+    assert!(stdout.contains("Stopped watching cell 0."));
+    assert!(stdout.ends_with("The program exited.\n"));
+}
@@ -0,0 +1,105 @@
+use std::{fs::write, process::Command};
+
+/// A program with an unused variable, and a call with an argument of the wrong type.
+const PROGRAM: &str = "def half(x: Float): Float = x / 2.0;
+def one(): Int {
+    let unused = 5;
+    return 1;
+}
+println(half(3), one());
+";
+
+#[test]
+fn test_json_diagnostics() {
+    let dir = std::env::temp_dir().join(format!("sage-diagnostics-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("errors.sg");
+    write(&file, PROGRAM).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_sage"))
+        .arg(&file)
+        .args(["--message-format", "json"])
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+
+    // Each diagnostic is a JSON object on its own line.
+    let diagnostics: Vec<serde_json::Value> = stderr
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(diagnostics.len(), 2, "{stderr}");
+    for diagnostic in &diagnostics {
+        for field in [
+            "severity", "code", "message", "file", "span", "line", "column", "labels", "notes",
+        ] {
+            assert!(
+                diagnostic.get(field).is_some(),
+                "missing {field} in {diagnostic}"
+            );
+        }
+        assert_eq!(diagnostic["file"], file.to_string_lossy().as_ref());
+    }
+
+    let warning = &diagnostics[0];
+    assert_eq!(warning["severity"], "warning");
+    assert_eq!(warning["code"], "W0001");
+    assert_eq!(warning["line"], 3);
+    assert_eq!(warning["column"], 5);
+    let start = PROGRAM.find("let unused").unwrap();
+    assert_eq!(warning["span"]["start"], start);
+    assert_eq!(warning["labels"], serde_json::json!([]));
+
+    // The error points at the argument, and is labeled with the parameter it's passed to.
+    let error = &diagnostics[1];
+    assert_eq!(error["severity"], "error");
+    assert_eq!(error["code"], "E0022");
+    assert_eq!(error["line"], 6);
+    assert_eq!(error["column"], 14);
+    assert_eq!(
+        error["notes"],
+        serde_json::json!(["convert the Int to Float with `as Float`"])
+    );
+    let labels = error["labels"].as_array().unwrap();
+    assert_eq!(labels.len(), 1);
+    assert_eq!(labels[0]["line"], 1);
+    assert_eq!(labels[0]["column"], 10);
+    assert_eq!(
+        labels[0]["message"],
+        "parameter `x` is declared here with type Float"
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_exit_status() {
+    let dir = std::env::temp_dir().join(format!("sage-exit-status-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let run = |name: &str, src: &str, args: &[&str]| {
+        let file = dir.join(name);
+        write(&file, src).unwrap();
+        Command::new(env!("CARGO_BIN_EXE_sage"))
+            .arg(&file)
+            .args(args)
+            .output()
+            .unwrap()
+            .status
+    };
+
+    // A warning only fails the build when it's denied.
+    let warning = "let unused = 5;\nprintln(1);";
+    assert!(run("warning.sg", warning, &[]).success());
+    assert!(!run("warning.sg", warning, &["--deny-warnings"]).success());
+    assert!(!run("warning.sg", warning, &["--deny", "unused-variable"]).success());
+
+    assert!(!run("errors.sg", PROGRAM, &[]).success());
+
+    // Memory errors found in memcheck mode fail the run too.
+    let use_after_free =
+        "let p = alloc(sizeof<Int>()) as &mut Int;\n*p = 5;\nfree(p);\nprintln(*p);";
+    assert!(run("free.sg", use_after_free, &[]).success());
+    assert!(!run("free.sg", use_after_free, &["--memcheck"]).success());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,93 @@
+use std::{fs::read_to_string, path::Path, process::Command};
+
+const PROGRAM: &str = "examples/frontend/shifts.sg";
+
+/// Run the compiler on the example program with some arguments.
+fn sage(args: &[&str]) -> std::process::Output {
+    Command::new(env!("CARGO_BIN_EXE_sage"))
+        .arg(PROGRAM)
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn test_emit_stages() {
+    let dir = std::env::temp_dir().join(format!("sage-emit-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let out = |name: &str| dir.join(name).to_string_lossy().to_string();
+    let read = |name: &str| read_to_string(Path::new(&out(name))).unwrap();
+
+    // Every stage is written from one compile.
+    let output = sage(&["--emit", "lir,core-asm,std-asm,vm,c,x86", "-o", &out("all")]);
+    assert!(output.status.success());
+
+    // The stages are the same as the ones written by compiling to each target.
+    for (target, extension, emitted) in [
+        ("core-asm", "asm.sg", "core.asm.sg"),
+        ("std-asm", "asm.sg", "std.asm.sg"),
+        ("std-vm", "vm.sg", "vm.sg"),
+        ("c", "c", "c"),
+        ("x86", "s", "s"),
+    ] {
+        let output = sage(&["-t", target, "-o", &out(target)]);
+        assert!(output.status.success());
+        assert_eq!(
+            read(&format!("all.{emitted}")),
+            read(&format!("{target}.{extension}")),
+            "the emitted {target} code is different"
+        );
+    }
+
+    // The LIR is spread over several lines, with the statements of blocks indented.
+    let lir = read("all.lir.sg");
+    assert!(lir.lines().count() > 10);
+    assert!(lir.contains("\n    "));
+
+    // The LIR can't be emitted from virtual machine code, but the other stages still are.
+    let output = Command::new(env!("CARGO_BIN_EXE_sage"))
+        .arg(out("all.vm.sg"))
+        .args(["-s", "core-vm", "--emit", "lir,vm", "-o", &out("vm")])
+        .output()
+        .unwrap();
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("cannot emit LIR code"));
+    assert_eq!(read("vm.vm.sg"), read("all.vm.sg"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_lir_alternate_format() {
+    let program = "def f(n: Int): Int {
+    let mut m = n;
+    while m > 0 { m -= 1; }
+    return m;
+}
+let x = f(3);
+println(x);
+";
+    let lir = format!("{:#}", sage::frontend::parse(program, None).unwrap());
+    // Each block is indented under the line it starts on, and each declaration's
+    // body starts on the line after it.
+    assert!(
+        lir.ends_with(
+            "proc(n: Int) -> Int = let m = n in
+{
+    while (m > 0) &mut m -= 1;
+    return m
+} f
+x = f(3) in
+{
+    {
+        put x;
+        put '\\n';
+        None
+    };
+    None
+}"
+        ),
+        "{lir}"
+    );
+}
@@ -46,12 +46,19 @@ fn test_frontend_examples_helper() {
                 .join(file_name.clone())
                 .with_extension("txt");
             let correct_error_path = PathBuf::from("examples/test-output")
-                .join(file_name)
+                .join(file_name.clone())
                 .with_extension("error.txt");
+            let correct_warnings_path = PathBuf::from("examples/test-output")
+                .join(file_name)
+                .with_extension("warnings.txt");
             let correct_error = match read_to_string(&correct_error_path) {
                 Ok(contents) => Some(contents.replace("\r\n", "\n")),
                 Err(_) => None,
             };
+            let correct_warnings = match read_to_string(&correct_warnings_path) {
+                Ok(contents) => Some(contents.replace("\r\n", "\n")),
+                Err(_) => None,
+            };
             let correct_output_text = match read_to_string(&correct_output_path) {
                 Ok(contents) => contents.replace("\r\n", "\n"),
                 Err(_) if correct_error.is_none() => {
@@ -71,7 +78,17 @@ fn test_frontend_examples_helper() {
             let frontend_code = parse_frontend(&frontend_src, path.to_str())
                 .unwrap_or_else(|_| panic!("Could not parse `{path:?}`"));
             drop(frontend_src);
-            let asm_code = frontend_code.compile();
+            let (asm_code, warnings) = frontend_code.compile_with_warnings();
+
+            if let Some(correct_warnings) = correct_warnings {
+                let text = warnings
+                    .iter()
+                    .map(|warning| format!("{warning}\n"))
+                    .collect::<String>();
+                if text != correct_warnings {
+                    panic!("{text:?} != {correct_warnings:?}, warnings did not match correct warnings for program {path:?}")
+                }
+            }
 
             if let Err(ref e) = asm_code {
                 if let Some(correct_error) = correct_error {
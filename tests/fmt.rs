@@ -0,0 +1,156 @@
+use sage::parse::format_frontend;
+use std::{
+    fs::{read_to_string, write},
+    path::PathBuf,
+    process::Command,
+};
+
+const UNFORMATTED: &str = r#"// A header comment.
+/* A block
+   comment */
+import "foo.sg"  as foo;;
+struct   Point{x:Int,y:Int,}
+enum Shape { Circle(Float), Rect{w:Float, h:Float} , }
+
+
+
+def area(s:Shape):Float{ // trailing
+    match s {
+        of Circle(r)=>3.14*r*r, // a circle
+        // a rectangle
+        of Rect{w,h}=>w*h
+    }
+}
+def f<T:Show+Eq>(x:&mut T,y:[Int*4]):(Int,Int)->Int=|a:Int,b:Int|a+b;
+let xs=[1,2,
+        3,4];
+let t = (1,);
+let p = {x=1, /* inline */ y=2};
+let q = foo(1, // first
+    2);
+let mut n = -5;
+'outer: for i in 0..10 {
+    if i==3 { continue 'outer; } elif i>4 {break;} else { n += -i; }
+    /* own line */
+}
+let y = x? + sizeof<Int>() + (n as Float ? 1 : 2);
+let z = new Point of Foo (1) ; # hash comment
+let c = |  | 5;
+let d = move || {1;};
+let e = &mut x.y[0];
+let g = !*p;
+"#;
+
+const FORMATTED: &str = r#"// A header comment.
+/* A block
+   comment */
+import "foo.sg" as foo;
+struct Point {
+    x: Int,
+    y: Int
+}
+enum Shape {
+    Circle(Float),
+    Rect {w: Float, h: Float}
+}
+
+def area(s: Shape): Float { // trailing
+    match s {
+        of Circle(r) => 3.14 * r * r, // a circle
+        // a rectangle
+        of Rect {w, h} => w * h
+    }
+}
+def f<T: Show + Eq>(x: &mut T, y: [Int * 4]): (Int, Int) -> Int = |a: Int, b: Int| a + b;
+let xs = [1, 2,
+    3, 4];
+let t = (1,);
+let p = {x = 1, /* inline */ y = 2};
+let q = foo(1, // first
+    2);
+let mut n = -5;
+'outer: for i in 0..10 {
+    if i == 3 {
+        continue 'outer;
+    } elif i > 4 {
+        break;
+    } else {
+        n += -i;
+    }
+    /* own line */
+}
+let y = x? + sizeof<Int>() + (n as Float ? 1 : 2);
+let z = new Point of Foo(1); # hash comment
+let c = || 5;
+let d = move || {
+    1;
+};
+let e = &mut x.y[0];
+let g = !*p;
+"#;
+
+#[test]
+fn test_format() {
+    let formatted = format_frontend(UNFORMATTED, None).unwrap();
+    assert_eq!(formatted, FORMATTED);
+    // Formatting is idempotent.
+    assert_eq!(format_frontend(&formatted, None).unwrap(), formatted);
+}
+
+#[test]
+fn test_format_examples() {
+    // Parsing most examples overflows the tiny stack for tests.
+    let child = std::thread::Builder::new()
+        .stack_size(512 * 1024 * 1024)
+        .spawn(|| {
+            for example in ["closures", "match-guards", "pattern", "traits"] {
+                let path = format!("examples/frontend/{example}.sg");
+                let code = read_to_string(&path).unwrap();
+                let formatted = format_frontend(&code, Some(&path)).unwrap();
+                assert_eq!(
+                    format_frontend(&formatted, Some(&path)).unwrap(),
+                    formatted,
+                    "formatting `{path}` is not idempotent"
+                );
+            }
+        })
+        .unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+fn test_format_check() {
+    let dir = std::env::temp_dir().join(format!("sage-fmt-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let formatted = dir.join("formatted.sg");
+    let unformatted = dir.join("unformatted.sg");
+    write(&formatted, FORMATTED).unwrap();
+    write(&unformatted, UNFORMATTED).unwrap();
+
+    let sage_fmt = |check: bool, files: &[&PathBuf]| {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_sage"));
+        command.arg("fmt").args(files);
+        if check {
+            command.arg("--check");
+        }
+        command.output().unwrap()
+    };
+
+    // Checking doesn't write to the files, and fails if any of them would change.
+    let output = sage_fmt(true, &[&formatted]);
+    assert!(output.status.success());
+    let output = sage_fmt(true, &[&formatted, &unformatted]);
+    assert!(!output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        format!("Would reformat {}\n", unformatted.display())
+    );
+    assert_eq!(read_to_string(&unformatted).unwrap(), UNFORMATTED);
+
+    // Otherwise, the files are formatted in place.
+    let output = sage_fmt(false, &[&unformatted]);
+    assert!(output.status.success());
+    assert_eq!(read_to_string(&unformatted).unwrap(), FORMATTED);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,251 @@
+use serde_json::{json, Value};
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+const URI: &str = "file:///sage-lsp-test/point.sg";
+
+const PROGRAM: &str = r#"struct Point {
+    x: Int,
+    y: Int
+}
+
+impl Point {
+    def make(x: Int, y: Int): Point {
+        return {x=x, y=y};
+    }
+
+    def sum(self: &Point): Int = self.x + self.y;
+}
+
+def twice(n: Int): Int {
+    let m = n * 2;
+    return m;
+}
+
+let p = Point.make(1, 2);
+let total = twice(p.x);
+println(total + True);
+"#;
+
+/// Drive the language server with a script of JSON-RPC messages,
+/// and get the messages it sends back, and whether it exited cleanly.
+fn run_session(messages: Vec<Value>) -> (Vec<Value>, bool) {
+    let mut server = Command::new(env!("CARGO_BIN_EXE_sage-lsp"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let mut stdin = server.stdin.take().unwrap();
+    for mut message in messages {
+        message["jsonrpc"] = json!("2.0");
+        let body = message.to_string();
+        write!(stdin, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+    }
+    drop(stdin);
+
+    let output = server.wait_with_output().unwrap();
+    let mut stdout = String::from_utf8(output.stdout).unwrap();
+    let mut responses = vec![];
+    while let Some((header, rest)) = stdout.split_once("\r\n\r\n") {
+        let length: usize = header
+            .strip_prefix("Content-Length: ")
+            .unwrap()
+            .parse()
+            .unwrap();
+        responses.push(serde_json::from_str(&rest[..length]).unwrap());
+        stdout = rest[length..].to_string();
+    }
+    (responses, output.status.success())
+}
+
+fn position(id: i64, method: &str, line: usize, character: usize) -> Value {
+    json!({
+        "id": id,
+        "method": method,
+        "params": {
+            "textDocument": { "uri": URI },
+            "position": { "line": line, "character": character },
+        },
+    })
+}
+
+fn response(responses: &[Value], id: i64) -> &Value {
+    &responses
+        .iter()
+        .find(|response| response["id"] == json!(id))
+        .unwrap_or_else(|| panic!("no response to request {id}"))["result"]
+}
+
+fn diagnostics(responses: &[Value]) -> Vec<&Value> {
+    responses
+        .iter()
+        .filter(|response| response["method"] == json!("textDocument/publishDiagnostics"))
+        .map(|response| &response["params"]["diagnostics"])
+        .collect()
+}
+
+#[test]
+fn test_lsp_session() {
+    let (responses, exited_cleanly) = run_session(vec![
+        json!({ "id": 1, "method": "initialize", "params": {} }),
+        json!({ "method": "initialized", "params": {} }),
+        json!({
+            "method": "textDocument/didOpen",
+            "params": {
+                "textDocument": { "uri": URI, "languageId": "sage", "version": 1, "text": PROGRAM },
+            },
+        }),
+        // The type of the local variable `m`.
+        position(2, "textDocument/hover", 15, 12),
+        // The type of `self` in a method.
+        position(3, "textDocument/hover", 10, 35),
+        // The declaration of `twice`.
+        position(4, "textDocument/definition", 19, 14),
+        // The fields and methods of `p`.
+        position(5, "textDocument/completion", 19, 20),
+        json!({ "id": 6, "method": "shutdown" }),
+        json!({ "method": "exit" }),
+    ]);
+    assert!(exited_cleanly);
+
+    let capabilities = &response(&responses, 1)["capabilities"];
+    assert_eq!(capabilities["hoverProvider"], json!(true));
+    assert_eq!(capabilities["definitionProvider"], json!(true));
+
+    // The type error is reported when the document is opened, pointing at the argument.
+    let diagnostics = diagnostics(&responses);
+    assert_eq!(diagnostics.len(), 1);
+    let diagnostic = &diagnostics[0][0];
+    assert_eq!(diagnostic["code"], json!("E0017"));
+    assert_eq!(
+        diagnostic["range"]["start"],
+        json!({"line": 20, "character": 8})
+    );
+    assert_eq!(
+        diagnostic["message"],
+        json!("invalid binary operation + for types Int and Bool")
+    );
+
+    assert_eq!(
+        response(&responses, 2)["contents"]["value"],
+        json!("```sage\nm: Int\n```")
+    );
+    assert_eq!(
+        response(&responses, 3)["contents"]["value"],
+        json!("```sage\nself: &{x: Int, y: Int}\n```")
+    );
+    assert_eq!(
+        response(&responses, 4),
+        &json!({
+            "uri": URI,
+            "range": {
+                "start": {"line": 13, "character": 4},
+                "end": {"line": 13, "character": 9},
+            },
+        })
+    );
+
+    let labels: Vec<_> = response(&responses, 5)["items"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|item| item["label"].as_str().unwrap())
+        .collect();
+    assert_eq!(labels, vec!["x", "y", "make", "sum"]);
+}
+
+#[test]
+fn test_lsp_error_tolerance() {
+    let broken = PROGRAM.replace("let total = twice(p.x);", "let total = twice(p.");
+    let fixed = PROGRAM.replace("total + True", "total");
+    let (responses, exited_cleanly) = run_session(vec![
+        json!({ "id": 1, "method": "initialize", "params": {} }),
+        json!({
+            "method": "textDocument/didOpen",
+            "params": {
+                "textDocument": { "uri": URI, "languageId": "sage", "version": 1, "text": PROGRAM },
+            },
+        }),
+        // While the document doesn't parse, the last version which did is used.
+        json!({
+            "method": "textDocument/didChange",
+            "params": {
+                "textDocument": { "uri": URI, "version": 2 },
+                "contentChanges": [{ "text": broken }],
+            },
+        }),
+        position(2, "textDocument/completion", 19, 20),
+        position(3, "textDocument/hover", 15, 12),
+        json!({
+            "method": "textDocument/didSave",
+            "params": { "textDocument": { "uri": URI }, "text": broken },
+        }),
+        json!({
+            "method": "textDocument/didSave",
+            "params": { "textDocument": { "uri": URI }, "text": fixed },
+        }),
+        json!({ "id": 4, "method": "textDocument/formatting", "params": {} }),
+        json!({ "id": 5, "method": "shutdown" }),
+        json!({ "method": "exit" }),
+    ]);
+    assert!(exited_cleanly);
+
+    let labels: Vec<_> = response(&responses, 2)["items"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|item| item["label"].as_str().unwrap())
+        .collect();
+    assert_eq!(labels, vec!["x", "y", "make", "sum"]);
+    assert_eq!(
+        response(&responses, 3)["contents"]["value"],
+        json!("```sage\nm: Int\n```")
+    );
+
+    // Diagnostics are published when the document is opened and saved.
+    let diagnostics = diagnostics(&responses);
+    assert_eq!(diagnostics.len(), 3);
+    // The syntax error points at where the parser gave up.
+    assert_eq!(diagnostics[1][0]["range"]["start"]["line"], json!(20));
+    assert_eq!(diagnostics[2], &json!([]));
+
+    // Unsupported requests get an error instead of a result.
+    let unsupported = responses
+        .iter()
+        .find(|response| response["id"] == json!(4))
+        .unwrap();
+    assert_eq!(unsupported["error"]["code"], json!(-32601));
+}
+
+#[test]
+fn test_lsp_internal_error() {
+    // The size of this array overflows while its variable is defined, which
+    // panics when overflow checks are enabled.
+    if !cfg!(debug_assertions) {
+        return;
+    }
+    let text = "let x: [Int * 9223372036854775807] = [];\nprintln(x);\n";
+    let (responses, exited_cleanly) = run_session(vec![
+        json!({ "id": 1, "method": "initialize", "params": {} }),
+        json!({
+            "method": "textDocument/didOpen",
+            "params": {
+                "textDocument": { "uri": URI, "languageId": "sage", "version": 1, "text": text },
+            },
+        }),
+        json!({ "id": 2, "method": "shutdown" }),
+        json!({ "method": "exit" }),
+    ]);
+    assert!(exited_cleanly);
+
+    // The panic is reported instead of an empty list of diagnostics.
+    let diagnostics = diagnostics(&responses);
+    assert_eq!(diagnostics.len(), 1);
+    let message = diagnostics[0][0]["message"].as_str().unwrap();
+    assert!(message.starts_with("internal compiler error while type checking: "));
+    assert!(message.contains("overflow"));
+}
@@ -0,0 +1,121 @@
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+/// Run the REPL with some lines of input, and get what it prints to stdout and stderr.
+fn run_repl(input: &str) -> (String, String) {
+    let mut repl = Command::new(env!("CARGO_BIN_EXE_sage"))
+        .arg("repl")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    repl.stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+
+    let output = repl.wait_with_output().unwrap();
+    assert!(output.status.success());
+    (
+        String::from_utf8(output.stdout).unwrap(),
+        String::from_utf8(output.stderr).unwrap(),
+    )
+}
+
+#[test]
+fn test_repl_session() {
+    let (stdout, stderr) = run_repl(
+        r#"struct Point { x: Int, y: Int }
+impl Point {
+    def make(x: Int, y: Int): Point = {x=x, y=y};
+    def sum(self: &Point): Int = self.x + self.y;
+}
+let mut p = Point.make(1, 2);
+println("made a point");
+p.x = 10;
+p.sum()
+def twice(n: Int): Int = n * 2;
+twice(p.y)
+:type p.sum
+:type twice(1) > 3
+println("only once");
+"#,
+    );
+    assert_eq!(
+        stdout,
+        "made a point
+12 : Int
+4 : Int
+def(&{x: Int, y: Int}) -> Int
+Bool
+only once
+"
+    );
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_repl_type_names() {
+    let (stdout, stderr) = run_repl(
+        "struct P { x: Int }
+let p: P = {x = 3};
+:type p
+:type &p
+:type p.x
+",
+    );
+    assert_eq!(stdout, "P\n&P\nInt\n");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_repl_commands() {
+    let (stdout, stderr) = run_repl(
+        "let x = 5;
+:asm x + 1
+:reset
+1 + 2
+x
+:quit
+3 + 4
+",
+    );
+    assert_eq!(
+        stdout,
+        "push [FP + 1]
+next SP
+set [SP], 1
+add [SP], [SP - 1]
+pop
+
+3 : Int
+"
+    );
+    // After resetting, `x` isn't defined anymore.
+    assert!(stderr.contains("symbol x not defined"));
+}
+
+#[test]
+fn test_repl_runs_lines_once() {
+    let (stdout, stderr) = run_repl(
+        r#"let a = 7;
+def hi(): Int { println("hi"); return 1; }
+hi() + a
+let p = alloc(1) as &mut Int;
+def release(p: &mut Int): Int { free(p); return 1; }
+release(p)
+let b = release(p);
+a
+b
+"#,
+    );
+    assert_eq!(stdout, "hi\n8 : Int\n1 : Int\n7 : Int\n");
+    // A line which fails while it's running doesn't declare anything,
+    // and leaves the variables before it the way they were.
+    assert!(stderr.contains("was already freed"));
+    assert!(stderr.contains("symbol b not defined"));
+}
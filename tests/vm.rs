@@ -134,3 +134,214 @@ fn test_pointers() {
 
     assert_eq!(device.output_vals(), vec![b, a]);
 }
+
+#[test]
+fn test_control_flow() {
+    let program = CoreProgram(vec![
+        CoreOp::Function, // Define a function which prints the tape value after the pointer
+        CoreOp::Move(1),
+        CoreOp::Restore,
+        CoreOp::Put(Output::stdout_char()),
+        CoreOp::Move(-1),
+        CoreOp::Return,
+        CoreOp::End,
+        CoreOp::Set(3), // Count down from 3
+        CoreOp::Save,
+        CoreOp::Restore,
+        CoreOp::While,
+        CoreOp::Set(-2), // If the counter isn't 2, print it
+        CoreOp::Add,
+        CoreOp::If,
+        CoreOp::Restore,
+        CoreOp::Move(1),
+        CoreOp::Save,
+        CoreOp::Move(-1),
+        CoreOp::Else, // Otherwise, print 20
+        CoreOp::Set(20),
+        CoreOp::Move(1),
+        CoreOp::Save,
+        CoreOp::Move(-1),
+        CoreOp::End,
+        CoreOp::Set(0), // Call the function
+        CoreOp::Call,
+        CoreOp::Set(-1), // Decrement the counter
+        CoreOp::Add,
+        CoreOp::Save,
+        CoreOp::End,
+    ]);
+
+    let device = CoreInterpreter::new(TestingDevice::default())
+        .run(&program)
+        .unwrap();
+    assert_eq!(device.output_vals(), vec![3, 20, 1]);
+
+    let device = StandardInterpreter::new(TestingDevice::default())
+        .run(&program.into())
+        .unwrap();
+    assert_eq!(device.output_vals(), vec![3, 20, 1]);
+}
+
+#[test]
+fn test_heap() {
+    let program = StandardProgram(vec![
+        StandardOp::CoreOp(CoreOp::Set(4)), // Allocate 4 cells, and save the address
+        StandardOp::Alloc,
+        StandardOp::CoreOp(CoreOp::Put(Output::stdout_char())),
+        StandardOp::CoreOp(CoreOp::Save),
+        StandardOp::CoreOp(CoreOp::Set(1)), // Allocate 1 cell after them
+        StandardOp::Alloc,
+        StandardOp::CoreOp(CoreOp::Put(Output::stdout_char())),
+        StandardOp::CoreOp(CoreOp::Restore), // Free the 4 cells
+        StandardOp::Free,
+        StandardOp::CoreOp(CoreOp::Set(2)), // Allocate 2 cells, which reuses the freed ones
+        StandardOp::Alloc,
+        StandardOp::CoreOp(CoreOp::Put(Output::stdout_char())),
+        StandardOp::CoreOp(CoreOp::Set(3)), // Allocate 3 cells, which don't fit in the rest
+        StandardOp::Alloc,
+        StandardOp::CoreOp(CoreOp::Put(Output::stdout_char())),
+    ]);
+
+    let mut interpreter = StandardInterpreter::new(TestingDevice::default());
+    interpreter.execute(&program).unwrap();
+    let addresses = interpreter.device().output_vals();
+    assert_eq!(addresses[1], addresses[0] + 4);
+    assert_eq!(addresses[2], addresses[0]);
+    assert_eq!(addresses[3], addresses[0] + 5);
+    assert_eq!(
+        interpreter.heap_stats(),
+        HeapStats {
+            allocations: 4,
+            frees: 1,
+            live_blocks: 3,
+            live_cells: 6,
+            peak_cells: 6,
+            heap_cells: 8,
+        }
+    );
+
+    // Freeing the same block twice is an error.
+    let mut double_free = program.clone();
+    double_free.0.insert(9, StandardOp::Free);
+    let result = StandardInterpreter::new(TestingDevice::default()).run(&double_free);
+    assert!(result.unwrap_err().contains("already freed"));
+
+    // So is freeing a pointer which didn't come from the heap.
+    let mut bad_free = program;
+    bad_free.0.insert(8, StandardOp::CoreOp(CoreOp::Set(1)));
+    let result = StandardInterpreter::new(TestingDevice::default()).run(&bad_free);
+    assert!(result
+        .unwrap_err()
+        .contains("isn't the start of a heap allocation"));
+}
+
+#[test]
+fn test_memcheck() {
+    let program = StandardProgram(vec![
+        StandardOp::CoreOp(CoreOp::Set(2)), // Allocate 2 cells, and save the address
+        StandardOp::Alloc,
+        StandardOp::CoreOp(CoreOp::Save),
+        StandardOp::CoreOp(CoreOp::Deref), // Branch on the second cell, which isn't initialized
+        StandardOp::CoreOp(CoreOp::Move(1)),
+        StandardOp::CoreOp(CoreOp::Restore),
+        StandardOp::CoreOp(CoreOp::If),
+        StandardOp::CoreOp(CoreOp::End),
+        StandardOp::CoreOp(CoreOp::Move(1)), // Write past the end of the block
+        StandardOp::CoreOp(CoreOp::Save),
+        StandardOp::CoreOp(CoreOp::Refer), // Free the block, and read it afterwards
+        StandardOp::CoreOp(CoreOp::Restore),
+        StandardOp::Free,
+        StandardOp::CoreOp(CoreOp::Deref),
+        StandardOp::CoreOp(CoreOp::Restore),
+        StandardOp::CoreOp(CoreOp::Refer),
+        StandardOp::CoreOp(CoreOp::Set(3)), // Allocate 3 cells, and never free them
+        StandardOp::Alloc,
+    ]);
+
+    let mut interpreter = StandardInterpreter::new(TestingDevice::default()).with_memcheck();
+    interpreter.execute(&program).unwrap();
+    let errors: Vec<_> = interpreter
+        .memory_errors()
+        .iter()
+        .map(|err| (err.kind, err.instruction))
+        .collect();
+    assert_eq!(
+        errors,
+        vec![
+            (MemoryErrorKind::UninitializedRead, 6),
+            (MemoryErrorKind::OutOfBounds(Access::Write), 9),
+            (MemoryErrorKind::UseAfterFree(Access::Read), 14),
+            (
+                MemoryErrorKind::Leak {
+                    blocks: 1,
+                    cells: 3
+                },
+                17
+            ),
+        ]
+    );
+
+    // Without memcheck mode, nothing is reported.
+    let mut interpreter = StandardInterpreter::new(TestingDevice::default());
+    interpreter.execute(&program).unwrap();
+    assert!(interpreter.memory_errors().is_empty());
+}
+
+#[test]
+fn test_profile() {
+    let program = CoreProgram(vec![
+        CoreOp::Function, // Function 0 calls function 1
+        CoreOp::Set(1),
+        CoreOp::Call,
+        CoreOp::Return,
+        CoreOp::End,
+        CoreOp::Function, // Function 1 just returns
+        CoreOp::Comment("comments aren't counted".to_string()),
+        CoreOp::Set(2),
+        CoreOp::Return,
+        CoreOp::End,
+        CoreOp::Set(0), // Call function 0, and then function 1
+        CoreOp::Call,
+        CoreOp::Set(1),
+        CoreOp::Call,
+    ]);
+
+    let mut interpreter = CoreInterpreter::new(TestingDevice::default()).with_profile();
+    interpreter.execute(&program).unwrap();
+    let profile = interpreter.profile().unwrap().clone();
+    assert_eq!(profile.instructions(), 13);
+    assert_eq!(
+        profile.opcodes(),
+        vec![
+            ("set".to_string(), 5),
+            ("call".to_string(), 3),
+            ("ret".to_string(), 3),
+            ("fun".to_string(), 2),
+        ]
+    );
+    let functions: Vec<_> = profile
+        .functions()
+        .iter()
+        .map(|f| (f.function, f.calls, f.instructions, f.total_instructions))
+        .collect();
+    assert_eq!(
+        functions,
+        vec![(None, 0, 6, 13), (Some(1), 2, 4, 4), (Some(0), 1, 3, 5)]
+    );
+    assert_eq!(
+        profile.folded_stacks(),
+        "<top level> 6\n\
+         <top level>;function #0 3\n\
+         <top level>;function #0;function #1 2\n\
+         <top level>;function #1 2\n"
+    );
+
+    // The standard interpreter profiles the same program the same way.
+    let mut interpreter = StandardInterpreter::new(TestingDevice::default()).with_profile();
+    interpreter
+        .execute(&StandardProgram::from(program))
+        .unwrap();
+    assert_eq!(
+        interpreter.profile().unwrap().folded_stacks(),
+        profile.folded_stacks()
+    );
+}